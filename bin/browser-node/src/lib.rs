@@ -27,11 +27,13 @@ use futures::{
 use libp2p::wasm_ext::{ffi, ExtTransport};
 use std::{
     collections::HashMap,
+    convert::TryFrom as _,
     num::{NonZeroU32, NonZeroU64},
+    time::Duration,
 };
 use substrate_lite::{
-    chain, chain::chain_information::babe, chain::sync::headers_optimistic, chain_spec, database,
-    header, json_rpc, network,
+    chain, chain::sync::headers_optimistic, chain_spec, database, finality::grandpa, header,
+    json_rpc, network,
 };
 use wasm_bindgen::prelude::*;
 
@@ -81,17 +83,20 @@ pub async fn start_client(chain_spec: String) -> Result<BrowserLightClient, JsVa
     // the genesis block.
     let chain_information = match local_storage.chain_information() {
         Ok(Some(i)) => {
-            let babe_genesis_config = babe::BabeGenesisConfiguration::from_genesis_storage(|k| {
-                chain_spec
-                    .genesis_storage()
-                    .find(|(k2, _)| *k2 == k)
-                    .map(|(_, v)| v.to_owned())
-            })
-            .unwrap();
+            let consensus_genesis_config =
+                chain::chain_information::ConsensusGenesisConfiguration::from_genesis_storage(
+                    |k| {
+                        chain_spec
+                            .genesis_storage()
+                            .find(|(k2, _)| *k2 == k)
+                            .map(|(_, v)| v.to_owned())
+                    },
+                )
+                .unwrap();
 
             chain::chain_information::ChainInformationConfig {
                 chain_information: i,
-                babe_genesis_config,
+                consensus_genesis_config,
             }
         }
         Err(database::local_storage_light::AccessError::StorageAccess(err)) => {
@@ -206,20 +211,29 @@ async fn start_sync(
             sources_capacity: 32,
             source_selection_randomness_seed: rand::random(),
             blocks_request_granularity: NonZeroU32::new(128).unwrap(),
+            max_requests_per_source: NonZeroU32::new(4).unwrap(),
             download_ahead_blocks: {
                 // Assuming a verification speed of 1k blocks/sec and a 95% latency of one second,
                 // the number of blocks to download ahead of time in order to not block is 1000.
                 1024
             },
+            max_slot_drift: 1,
+            recent_blocks_kept: {
+                // GrandPa commits normally target blocks close to the head of the chain.
+                // Keeping a few blocks is enough to verify them.
+                32
+            },
         },
     );
 
     async move {
         let mut peers_source_id_map = HashMap::new();
         let mut block_requests_finished = stream::FuturesUnordered::new();
+        // Latest GrandPa neighbor packet sent to the network. `None` at startup.
+        let mut grandpa_neighbor_packet_sent = None;
 
         loop {
-            while let Some(action) = sync.next_request_action() {
+            while let Some(action) = sync.next_request_action(now()) {
                 match action {
                     headers_optimistic::RequestAction::Start {
                         start,
@@ -250,7 +264,7 @@ async fn start_sync(
 
             // Verify blocks that have been fetched from queries.
             loop {
-                match sync.process_one() {
+                match sync.process_one(now()) {
                     headers_optimistic::ProcessOneOutcome::Idle => break,
                     headers_optimistic::ProcessOneOutcome::Updated {
                         best_block_hash,
@@ -266,6 +280,7 @@ async fn start_sync(
                         reason,
                         new_best_block_hash,
                         new_best_block_number,
+                        ..
                     } => {
                         web_sys::console::warn_1(&JsValue::from_str(&format!(
                             "⚠️ Sync error ⚠️ {}",
@@ -285,6 +300,16 @@ async fn start_sync(
                 yield_once().await;
             }
 
+            // Notify the peers of the new finalized block, if it has changed, so that they send
+            // the GrandPa commits that are relevant to us.
+            let neighbor_packet = grandpa_neighbor_packet(sync.as_chain_information());
+            if grandpa_neighbor_packet_sent != Some(neighbor_packet) {
+                grandpa_neighbor_packet_sent = Some(neighbor_packet);
+                let _ = to_network
+                    .send(ToNetwork::SetGrandpaNeighborPacket(neighbor_packet))
+                    .await;
+            }
+
             // TODO: save less often
             let _ = to_db_save_tx.send(sync.as_chain_information().into()).await;
 
@@ -307,6 +332,23 @@ async fn start_sync(
                                 rq.abort();
                             }
                         },
+                        ToSync::BlockAnnounce { peer_id, number } => {
+                            // Requests for the announced block, if any, are started at the next
+                            // iteration of the loop.
+                            if let Some(id) = peers_source_id_map.get(&peer_id) {
+                                sync.source_best_block_update(*id, number);
+                            }
+                        },
+                        ToSync::GrandpaCommit(commit) => {
+                            // Commits that can't be verified, for example because they target
+                            // a block that hasn't been downloaded yet, are ignored.
+                            if let Ok((number, hash)) = sync.grandpa_commit(&commit) {
+                                web_sys::console::log_1(&JsValue::from_str(&format!(
+                                    "Finalized block: #{} {:?}",
+                                    number, hash
+                                )));
+                            }
+                        },
                     }
                 },
 
@@ -314,7 +356,7 @@ async fn start_sync(
                     // `result` is an error if the block request got cancelled by the sync state
                     // machine.
                     if let Ok(result) = result {
-                        let _ = sync.finish_request(request_id, result.unwrap().map(|v| v.into_iter()));
+                        let _ = sync.finish_request(request_id, result.unwrap().map(|v| v.into_iter()), now());
                     }
                 },
             }
@@ -325,6 +367,26 @@ async fn start_sync(
 enum ToSync {
     NewPeer(network::PeerId),
     PeerDisconnected(network::PeerId),
+    /// The given peer has announced a block, normally its new best block.
+    BlockAnnounce {
+        peer_id: network::PeerId,
+        number: u64,
+    },
+    /// A GrandPa commit has been received from the network.
+    GrandpaCommit(grandpa::gossip::CommitMessage),
+}
+
+/// Builds the GrandPa neighbor packet corresponding to the given finalized chain.
+fn grandpa_neighbor_packet(
+    chain_information: chain::chain_information::ChainInformationRef,
+) -> grandpa::gossip::NeighborPacket {
+    grandpa::gossip::NeighborPacket {
+        // This node doesn't run a GrandPa voter, and as such doesn't take part in any round.
+        round: 0,
+        authorities_set_id: chain_information.grandpa_after_finalized_block_authorities_set_id,
+        commit_finalized_height: u32::try_from(chain_information.finalized_block_header.number)
+            .unwrap_or(u32::max_value()),
+    }
 }
 
 async fn start_network(
@@ -355,6 +417,7 @@ async fn start_network(
                 chain_spec.genesis_storage(),
             )
             .hash(),
+            role: network::Role::Light,
             wasm_external_transport: Some(ExtTransport::new(ffi::websocket_transport())),
         })
         .await
@@ -396,17 +459,21 @@ async fn start_network(
                                 }
                             };
                         },
+                        ToNetwork::SetGrandpaNeighborPacket(packet) => {
+                            network.set_grandpa_neighbor_packet(packet);
+                        },
                     }
                 },
 
                 event = network.next_event().fuse() => {
                     match event {
-                        network::Event::BlockAnnounce(header) => {
-                            // TODO:
+                        network::Event::BlockAnnounce { peer_id, header } => {
+                            // TODO: punish peers sending invalid headers
                             if let Ok(decoded) = header::decode(&header.0) {
-                                web_sys::console::log_1(&JsValue::from_str(&format!(
-                                    "Received block announce: {}", decoded.number
-                                )));
+                                let _ = to_sync.send(ToSync::BlockAnnounce {
+                                    peer_id,
+                                    number: decoded.number,
+                                }).await;
                             }
                         }
                         network::Event::BlocksRequestFinished { id, result } => {
@@ -424,6 +491,15 @@ async fn start_network(
                             );
                         }
                         network::Event::CallRequestFinished { .. } => todo!(),
+                        network::Event::WarpSyncRequestFinished { .. } => unreachable!(),
+                        // This node doesn't download the storage of blocks from the network,
+                        // and never starts state requests.
+                        network::Event::StateRequestFinished { .. } => {}
+                        network::Event::GrandpaCommit { commit, .. } => {
+                            let _ = to_sync.send(ToSync::GrandpaCommit(commit)).await;
+                        }
+                        // This node doesn't run a GrandPa voter.
+                        network::Event::GrandpaGossip { .. } => {}
                         network::Event::Connected(peer_id) => {
                             let _ = to_sync.send(ToSync::NewPeer(peer_id)).await;
                         }
@@ -446,6 +522,16 @@ enum ToNetwork {
             Result<Vec<headers_optimistic::RequestSuccessBlock>, headers_optimistic::RequestFail>,
         >,
     },
+    /// Sets the neighbor packet sent to peers on the GrandPa protocol.
+    SetGrandpaNeighborPacket(grandpa::gossip::NeighborPacket),
+}
+
+/// Returns the time elapsed since the UNIX epoch.
+///
+/// `std::time::Instant` isn't available in the browser, and the JavaScript `Date` is used
+/// instead.
+fn now() -> Duration {
+    Duration::from_secs_f64(js_sys::Date::now() / 1000.0)
 }
 
 /// Use in an asynchronous context to interrupt the current task execution and schedule it back.
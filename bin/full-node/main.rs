@@ -26,6 +26,7 @@ use futures::{
 use std::{
     borrow::Cow,
     collections::BTreeMap,
+    convert::TryFrom as _,
     fs,
     net::{SocketAddr, ToSocketAddrs as _},
     num::{NonZeroU32, NonZeroU64},
@@ -33,12 +34,14 @@ use std::{
     pin::Pin,
     sync::{atomic::Ordering, Arc},
     thread,
-    time::Duration,
+    time::{Duration, SystemTime},
 };
 use structopt::StructOpt as _;
 use substrate_lite::{
     chain::{self, sync::full_optimistic},
-    chain_spec, header, network,
+    chain_spec,
+    finality::grandpa,
+    header, network,
 };
 
 fn main() {
@@ -324,11 +327,13 @@ async fn start_sync(
             },
             source_selection_randomness_seed: rand::random(),
             blocks_request_granularity: NonZeroU32::new(128).unwrap(),
+            max_requests_per_source: NonZeroU32::new(4).unwrap(),
             download_ahead_blocks: {
                 // Assuming a verification speed of 1k blocks/sec and a 95% latency of one second,
                 // the number of blocks to download ahead of time in order to not block is 1000.
                 1024
             },
+            max_slot_drift: 1,
         });
 
     let mut finalized_block_storage = BTreeMap::<Vec<u8>, Vec<u8>>::new();
@@ -340,10 +345,14 @@ async fn start_sync(
     async move {
         let mut peers_source_id_map = hashbrown::HashMap::<_, _, fnv::FnvBuildHasher>::default();
         let mut block_requests_finished = stream::FuturesUnordered::new();
+        // Reference point passed to the sync state machine in order to measure time.
+        let start_instant = std::time::Instant::now();
+        // Latest GrandPa neighbor packet sent to the network. `None` at startup.
+        let mut grandpa_neighbor_packet_sent = None;
 
         loop {
             // Verify blocks that have been fetched from queries.
-            let mut process = sync.process_one();
+            let mut process = sync.process_one(unix_time());
             loop {
                 match process {
                     full_optimistic::ProcessOne::Idle { sync: s } => {
@@ -354,7 +363,7 @@ async fn start_sync(
                         sync: s,
                         finalized_blocks,
                     } => {
-                        process = s.process_one();
+                        process = s.process_one(unix_time());
 
                         if let Some(last_finalized) = finalized_blocks.last() {
                             let mut lock = sync_state.lock().await;
@@ -362,17 +371,29 @@ async fn start_sync(
                             lock.finalized_block_number = last_finalized.header.number;
                         }
 
-                        for block in finalized_blocks {
-                            for (key, value) in block.storage_top_trie_changes {
-                                if let Some(value) = value {
-                                    finalized_block_storage.insert(key, value);
-                                } else {
-                                    let _was_there = finalized_block_storage.remove(&key);
-                                    // TODO: if a block inserts a new value, then removes it in the next block, the key will remain in `finalized_block_storage`; either solve this or document this
-                                    // assert!(_was_there.is_some());
-                                }
+                        apply_finalized_blocks(&mut finalized_block_storage, finalized_blocks);
+                    }
+
+                    full_optimistic::ProcessOne::Reset {
+                        sync: s,
+                        reason,
+                        banned_source,
+                    } => {
+                        // Printing a line overwrites the informant, which prints itself again
+                        // below.
+                        let banned_peer = banned_source.and_then(|source_id| {
+                            peers_source_id_map
+                                .iter()
+                                .find(|(_, id)| **id == source_id)
+                                .map(|(peer_id, _)| peer_id)
+                        });
+                        match banned_peer {
+                            Some(peer_id) => {
+                                eprintln!("Sync error: {} (banned {})", reason, peer_id)
                             }
+                            None => eprintln!("Sync error: {}", reason),
                         }
+                        process = s.process_one(unix_time());
                     }
 
                     full_optimistic::ProcessOne::InProgress {
@@ -428,10 +449,20 @@ async fn start_sync(
                 lock.best_block_number = sync.best_block_number();
             }
 
+            // Notify the peers of the new finalized block, if it has changed, so that they send
+            // the GrandPa commits that are relevant to us.
+            let neighbor_packet = grandpa_neighbor_packet(sync.as_chain_information());
+            if grandpa_neighbor_packet_sent != Some(neighbor_packet) {
+                grandpa_neighbor_packet_sent = Some(neighbor_packet);
+                let _ = to_network
+                    .send(ToNetwork::SetGrandpaNeighborPacket(neighbor_packet))
+                    .await;
+            }
+
             // Start requests that need to be started.
             // Note that this is done after calling `process_one`, as the processing of pending
             // blocks can result in new requests but not the contrary.
-            while let Some(action) = sync.next_request_action() {
+            while let Some(action) = sync.next_request_action(start_instant.elapsed()) {
                 match action {
                     full_optimistic::RequestAction::Start {
                         start,
@@ -460,6 +491,20 @@ async fn start_sync(
                 }
             }
 
+            // Blocks that belong to a future slot are verified again, at the next iteration of
+            // the loop, once their slot has come.
+            let future_block_delay = sync
+                .next_future_block_time()
+                .map(|when| when.checked_sub(unix_time()).unwrap_or_default());
+            let mut future_block_timer = async move {
+                match future_block_delay {
+                    Some(delay) => futures_timer::Delay::new(delay).await,
+                    None => future::pending().await,
+                }
+            }
+            .boxed()
+            .fuse();
+
             futures::select! {
                 message = to_sync.next() => {
                     let message = match message {
@@ -479,6 +524,26 @@ async fn start_sync(
                                 rq.abort();
                             }
                         },
+                        ToSync::BlockAnnounce { peer_id, number } => {
+                            // Requests for the announced block, if any, are started at the next
+                            // iteration of the loop.
+                            if let Some(id) = peers_source_id_map.get(&peer_id) {
+                                sync.source_best_block_update(*id, number);
+                            }
+                        },
+                        ToSync::GrandpaCommit(commit) => {
+                            // Commits that can't be verified, for example because they target
+                            // a block that hasn't been downloaded yet, are ignored.
+                            if let Ok(finalized_blocks) = sync.grandpa_commit(&commit) {
+                                if let Some(last_finalized) = finalized_blocks.last() {
+                                    let mut lock = sync_state.lock().await;
+                                    lock.finalized_block_hash = last_finalized.header.hash();
+                                    lock.finalized_block_number = last_finalized.header.number;
+                                }
+
+                                apply_finalized_blocks(&mut finalized_block_storage, finalized_blocks);
+                            }
+                        },
                     }
                 },
 
@@ -486,9 +551,12 @@ async fn start_sync(
                     // `result` is an error if the block request got cancelled by the sync state
                     // machine.
                     if let Ok(result) = result {
-                        let _ = sync.finish_request(request_id, result.unwrap().map(|v| v.into_iter()));
+                        let result = result.unwrap_or(Err(full_optimistic::RequestFail::BlocksUnavailable));
+                        let _ = sync.finish_request(request_id, result.map(|v| v.into_iter()), start_instant.elapsed());
                     }
                 },
+
+                () = future_block_timer => {},
             }
         }
     }
@@ -497,6 +565,45 @@ async fn start_sync(
 enum ToSync {
     NewPeer(network::PeerId),
     PeerDisconnected(network::PeerId),
+    /// The given peer has announced a block, normally its new best block.
+    BlockAnnounce {
+        peer_id: network::PeerId,
+        number: u64,
+    },
+    /// A GrandPa commit has been received from the network.
+    GrandpaCommit(grandpa::gossip::CommitMessage),
+}
+
+/// Applies the storage changes of the given newly-finalized blocks, in increasing block number,
+/// to the storage of the finalized block.
+fn apply_finalized_blocks(
+    finalized_block_storage: &mut BTreeMap<Vec<u8>, Vec<u8>>,
+    finalized_blocks: Vec<full_optimistic::Block>,
+) {
+    for block in finalized_blocks {
+        for (key, value) in block.storage_top_trie_changes {
+            if let Some(value) = value {
+                finalized_block_storage.insert(key, value);
+            } else {
+                let _was_there = finalized_block_storage.remove(&key);
+                // TODO: if a block inserts a new value, then removes it in the next block, the key will remain in `finalized_block_storage`; either solve this or document this
+                // assert!(_was_there.is_some());
+            }
+        }
+    }
+}
+
+/// Builds the GrandPa neighbor packet corresponding to the given finalized chain.
+fn grandpa_neighbor_packet(
+    chain_information: chain::chain_information::ChainInformationRef,
+) -> grandpa::gossip::NeighborPacket {
+    grandpa::gossip::NeighborPacket {
+        // This node doesn't run a GrandPa voter, and as such doesn't take part in any round.
+        round: 0,
+        authorities_set_id: chain_information.grandpa_after_finalized_block_authorities_set_id,
+        commit_finalized_height: u32::try_from(chain_information.finalized_block_header.number)
+            .unwrap_or(u32::max_value()),
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -537,6 +644,7 @@ async fn start_network(
                 chain_spec.genesis_storage(),
             )
             .hash(),
+            role: network::Role::Full,
             wasm_external_transport: None,
         })
         .await
@@ -578,32 +686,51 @@ async fn start_network(
                                 }
                             };
                         },
+                        ToNetwork::SetGrandpaNeighborPacket(packet) => {
+                            network.set_grandpa_neighbor_packet(packet);
+                        },
                     }
                 },
 
                 event = network.next_event().fuse() => {
                     match event {
-                        network::Event::BlockAnnounce(header) => {
+                        network::Event::BlockAnnounce { peer_id, header } => {
+                            // TODO: punish peers sending invalid headers
                             if let Ok(header) = header::decode(&header.0) {
                                 network_state.best_network_block_height.store(header.number, Ordering::Relaxed);
+                                let _ = to_sync.send(ToSync::BlockAnnounce {
+                                    peer_id,
+                                    number: header.number,
+                                }).await;
                             }
                         }
                         network::Event::CallRequestFinished { .. } => unreachable!(),
+                        network::Event::WarpSyncRequestFinished { .. } => unreachable!(),
+                        // This node doesn't download the storage of blocks from the network,
+                        // and never starts state requests.
+                        network::Event::StateRequestFinished { .. } => {}
                         network::Event::BlocksRequestFinished { id, result } => {
                             let send_back = block_requests.remove(&id).unwrap();
+                            // Responses where the header or the body of a block is missing are
+                            // treated as if the blocks were unavailable.
                             let _: Result<_, _> = send_back.send(result
-                                .map(|list| {
+                                .and_then(|list| {
                                     list.into_iter().map(|block| {
-                                        full_optimistic::RequestSuccessBlock {
-                                            scale_encoded_header: block.header.unwrap().0,
-                                            scale_encoded_extrinsics: block.body.unwrap().into_iter().map(|e| e.0).collect(), // TODO: overhead
+                                        Ok(full_optimistic::RequestSuccessBlock {
+                                            scale_encoded_header: block.header.ok_or(())?.0,
+                                            scale_encoded_extrinsics: block.body.ok_or(())?.into_iter().map(|e| e.0).collect(), // TODO: overhead
                                             scale_encoded_justification: block.justification,
-                                        }
+                                        })
                                     }).collect()
                                 })
                                 .map_err(|()| full_optimistic::RequestFail::BlocksUnavailable) // TODO:
                             );
                         }
+                        network::Event::GrandpaCommit { commit, .. } => {
+                            let _ = to_sync.send(ToSync::GrandpaCommit(commit)).await;
+                        }
+                        // This node doesn't run a GrandPa voter.
+                        network::Event::GrandpaGossip { .. } => {}
                         network::Event::Connected(peer_id) => {
                             network_state.num_network_connections.fetch_add(1, Ordering::Relaxed);
                             let _ = to_sync.send(ToSync::NewPeer(peer_id)).await;
@@ -635,4 +762,13 @@ enum ToNetwork {
             Result<Vec<full_optimistic::RequestSuccessBlock>, full_optimistic::RequestFail>,
         >,
     },
+    /// Sets the neighbor packet sent to peers on the GrandPa protocol.
+    SetGrandpaNeighborPacket(grandpa::gossip::NeighborPacket),
+}
+
+/// Returns the time elapsed since the Unix epoch.
+fn unix_time() -> Duration {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
 }
@@ -19,6 +19,7 @@ const PROTOS: &[&str] = &[
     "src/network/schema/api.v1.proto",
     "src/network/schema/finality.v1.proto",
     "src/network/schema/light.v1.proto",
+    "src/network/schema/warp_sync.v1.proto",
 ];
 
 fn main() {
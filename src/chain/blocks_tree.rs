@@ -57,6 +57,12 @@
 //!
 //! Additionally, a [`NonFinalizedTree::verify_justification`] method is provided in order to
 //! verify the correctness of a [justification](crate::finality::justification).
+//!
+//! Amongst all the blocks of the tree, one is designated as the best block, according to the
+//! BABE fork choice rule. Whenever an insertion or a finalization changes the best block, a
+//! [`BestBlockChanged`] is provided. It contains the list of blocks that are retracted from and
+//! enacted into the best chain, which subscribers to the head of the chain can use in order to
+//! follow reorganizations.
 
 // TODO: expand this doc ^
 // TODO: this module is an essential part of the code and needs clean up and testing
@@ -64,16 +70,24 @@
 use crate::{
     chain::{chain_information, fork_tree},
     executor,
-    finality::justification,
+    finality::{grandpa, justification},
     header,
     trie::calculate_root,
-    verify::{self, babe},
+    verify::{self, aura, babe},
 };
 
-use alloc::sync::Arc;
-use core::{cmp, convert::TryFrom as _, fmt, mem};
+use alloc::{collections::BTreeSet, sync::Arc, vec::Vec};
+use core::{
+    cmp,
+    convert::TryFrom as _,
+    fmt, mem,
+    num::{NonZeroU64, NonZeroUsize},
+    time::Duration,
+};
 use hashbrown::HashMap;
 
+mod tests;
+
 /// Configuration for the [`NonFinalizedTree`].
 #[derive(Debug, Clone)]
 pub struct Config {
@@ -82,6 +96,31 @@ pub struct Config {
 
     /// Pre-allocated size of the chain, in number of non-finalized blocks.
     pub blocks_capacity: usize,
+
+    /// Maximum number of non-finalized blocks that the chain can hold, or `None` for no limit.
+    ///
+    /// When this limit is reached, inserting a new block evicts the fork tip that is the least
+    /// likely to ever become part of the best chain. If the new block is itself less likely than
+    /// any other fork tip, it is refused.
+    ///
+    /// The ancestors of the new block are never evicted, as the new block couldn't be inserted
+    /// without them. Consequently, once the non-finalized blocks form a single chain that has
+    /// reached this limit, no block can be inserted, not even on top of the best block, until a
+    /// new block is finalized. The limit should therefore be well above the number of blocks
+    /// that are expected to be produced between two finalized blocks, and
+    /// [`Config::max_non_finalized_depth`] should be lower than this limit.
+    ///
+    /// > **Note**: Without a limit, the memory usage of the chain grows without bound if
+    /// >           finality stalls, and peers can flood the chain with valid-looking forks.
+    pub max_non_finalized_blocks: Option<NonZeroUsize>,
+
+    /// Maximum difference between the height of a non-finalized block and the height of the
+    /// finalized block, or `None` for no limit. Blocks above this limit are refused.
+    pub max_non_finalized_depth: Option<NonZeroU64>,
+
+    /// Number of slots the Aura or BABE slot of a block is allowed to be ahead of the current
+    /// slot. Blocks further in the future are refused. See [`HeaderVerifyError::FutureBlock`].
+    pub max_slot_drift: u64,
 }
 
 /// Holds state about the current state of the chain for the purpose of verifying headers.
@@ -90,29 +129,19 @@ pub struct NonFinalizedTree<T> {
     finalized_block_header: header::Header,
     /// Hash of [`NonFinalizedTree::finalized_block_header`].
     finalized_block_hash: [u8; 32],
-    /// Grandpa authorities set ID of the block right after the finalized block.
-    grandpa_after_finalized_block_authorities_set_id: u64,
-    /// List of GrandPa authorities that need to finalize the block right after the finalized
-    /// block.
-    grandpa_finalized_triggered_authorities: Vec<header::GrandpaAuthority>,
-    /// Change in the GrandPa authorities list that has been scheduled by a block that is already
-    /// finalized but not triggered yet. These changes will for sure happen. Contains the block
-    /// number where the changes are to be triggered.
-    grandpa_finalized_scheduled_change: Option<(u64, Vec<header::GrandpaAuthority>)>,
-
-    /// Configuration for BABE, retrieved from the genesis block.
-    babe_genesis_config: chain_information::babe::BabeGenesisConfiguration,
-
-    /// See [`chain_information::ChainInformation::babe_finalized_block_epoch_information`].
-    babe_finalized_block_epoch_information:
-        Option<Arc<(header::BabeNextEpoch, header::BabeNextConfig)>>,
-
-    /// See [`chain_information::ChainInformation::babe_finalized_next_epoch_transition`].
-    babe_finalized_next_epoch_transition:
-        Option<Arc<(header::BabeNextEpoch, header::BabeNextConfig)>>,
-
-    /// If block 1 is finalized, contains its slot number.
-    babe_finalized_block1_slot_number: Option<u64>,
+    /// GrandPa-related information about the finalized block.
+    finalized_grandpa: GrandpaState,
+
+    /// Consensus-related information about the finalized block.
+    finalized_consensus: FinalizedConsensus,
+    /// See [`chain_information::ChainInformation::babe_finalized_block_weight`].
+    babe_finalized_block_weight: u64,
+    /// See [`Config::max_non_finalized_blocks`].
+    max_non_finalized_blocks: Option<NonZeroUsize>,
+    /// See [`Config::max_non_finalized_depth`].
+    max_non_finalized_depth: Option<NonZeroU64>,
+    /// See [`Config::max_slot_drift`].
+    max_slot_drift: u64,
     /// Container for non-finalized blocks.
     blocks: fork_tree::ForkTree<Block<T>>,
     /// Index within [`NonFinalizedTree::blocks`] of the current best block. `None` if and only
@@ -126,18 +155,255 @@ struct Block<T> {
     /// Cache of the hash of the block. Always equal to the hash of the header stored in this
     /// same struct.
     hash: [u8; 32],
-    /// If this block is block #1 of the chain, contains its babe slot number. Otherwise, contains
-    /// the slot number of the block #1 that is an ancestor of this block.
-    babe_block1_slot_number: u64,
-    /// Information about the Babe epoch the block belongs to. `None` if the block belongs to
-    /// epoch #0.
-    babe_current_epoch: Option<Arc<(header::BabeNextEpoch, header::BabeNextConfig)>>,
-    /// Information about the Babe epoch the block belongs to.
-    babe_next_epoch: Arc<(header::BabeNextEpoch, header::BabeNextConfig)>,
+    /// Consensus-related information about the block.
+    consensus: BlockConsensus,
+    /// Number of blocks authored using a BABE primary slot claim between the genesis block
+    /// (excluded) and this block (included). Used by the fork choice rule. Always 0 for chains
+    /// that don't use BABE.
+    babe_weight: u64,
     /// Opaque data decided by the user.
     user_data: T,
 }
 
+/// Consensus-related information about the finalized block of a [`NonFinalizedTree`].
+enum FinalizedConsensus {
+    Aura {
+        /// Configuration for Aura, retrieved from the genesis block.
+        genesis_config: chain_information::aura::AuraGenesisConfiguration,
+        /// See [`chain_information::ChainInformation::aura_finalized_authorities_list`].
+        authorities_list: Arc<Vec<header::AuraAuthority>>,
+    },
+    Babe {
+        /// Configuration for BABE, retrieved from the genesis block.
+        genesis_config: chain_information::babe::BabeGenesisConfiguration,
+        /// See [`chain_information::ChainInformation::babe_finalized_block_epoch_information`].
+        block_epoch_information: Option<Arc<(header::BabeNextEpoch, header::BabeNextConfig)>>,
+        /// See [`chain_information::ChainInformation::babe_finalized_next_epoch_transition`].
+        next_epoch_transition: Option<Arc<(header::BabeNextEpoch, header::BabeNextConfig)>>,
+        /// If block 1 is finalized, contains its slot number.
+        block1_slot_number: Option<u64>,
+        /// See
+        /// [`chain_information::ChainInformation::babe_finalized_epoch_randomness_contributions`].
+        epoch_randomness_contributions: Option<Vec<[u8; 32]>>,
+        /// See [`chain_information::ChainInformation::babe_finalized_disabled_authorities`].
+        disabled_authorities: Arc<Vec<u32>>,
+    },
+}
+
+/// Consensus-related information about a non-finalized block of a [`NonFinalizedTree`].
+enum BlockConsensus {
+    Aura {
+        /// List of Aura authorities that must author the children of this block.
+        authorities_list: Arc<Vec<header::AuraAuthority>>,
+    },
+    Babe {
+        /// If this block is block #1 of the chain, contains its babe slot number. Otherwise,
+        /// contains the slot number of the block #1 that is an ancestor of this block.
+        block1_slot_number: u64,
+        /// Information about the Babe epoch the block belongs to. `None` if the block belongs to
+        /// epoch #0.
+        current_epoch: Option<Arc<(header::BabeNextEpoch, header::BabeNextConfig)>>,
+        /// Information about the Babe epoch the block belongs to.
+        next_epoch: Arc<(header::BabeNextEpoch, header::BabeNextConfig)>,
+        /// Value that the block contributes to the randomness of a future Babe epoch. `None` if
+        /// the block doesn't contain any VRF output, in other words if it is a secondary plain
+        /// slot claim.
+        randomness_contribution: Option<[u8; 32]>,
+        /// Indices of the authorities of the Babe epoch of the block that have been disabled by
+        /// this block or by its ancestors belonging to the same epoch.
+        disabled_authorities: Arc<Vec<u32>>,
+    },
+}
+
+/// State of GrandPa at a certain block, either the finalized block or a non-finalized block as
+/// if it was finalized.
+///
+/// Also used by the [warp syncing](super::sync::warp_sync), which only ever sees the blocks that
+/// change the list of authorities.
+#[derive(Debug, Clone)]
+pub(crate) struct GrandpaState {
+    /// See
+    /// [`chain_information::ChainInformation::grandpa_after_finalized_block_authorities_set_id`].
+    pub(crate) authorities_set_id: u64,
+    /// See [`chain_information::ChainInformation::grandpa_finalized_triggered_authorities`].
+    pub(crate) triggered_authorities: Vec<header::GrandpaAuthority>,
+    /// See [`chain_information::ChainInformation::grandpa_finalized_scheduled_change`].
+    pub(crate) scheduled_change: Option<(u64, Vec<header::GrandpaAuthority>)>,
+    /// See [`chain_information::ChainInformation::grandpa_finalized_forced_change`].
+    pub(crate) forced_change: Option<(u64, Vec<header::GrandpaAuthority>)>,
+    /// See [`chain_information::ChainInformation::grandpa_finalized_disabled_authorities`].
+    pub(crate) disabled_authorities: Vec<u64>,
+    /// See [`chain_information::ChainInformation::grandpa_finalized_pause_state`].
+    pub(crate) pause_state: chain_information::GrandpaPauseState,
+}
+
+impl GrandpaState {
+    /// Returns the height of the block that must be finalized before any of its descendants can
+    /// be, because its finalization triggers a scheduled change or a pause.
+    fn finality_barrier(&self) -> Option<u64> {
+        let change = self.scheduled_change.as_ref().map(|(n, _)| *n);
+        let pause = match self.pause_state {
+            chain_information::GrandpaPauseState::PendingPause {
+                trigger_block_height,
+            } => Some(trigger_block_height),
+            _ => None,
+        };
+
+        match (change, pause) {
+            (Some(a), Some(b)) => Some(cmp::min(a, b)),
+            (Some(a), None) => Some(a),
+            (None, Some(b)) => Some(b),
+            (None, None) => None,
+        }
+    }
+
+    /// Returns `true` if the authorities are currently not voting.
+    fn is_paused(&self) -> bool {
+        match self.pause_state {
+            chain_information::GrandpaPauseState::Live
+            | chain_information::GrandpaPauseState::PendingPause { .. } => false,
+            chain_information::GrandpaPauseState::Paused
+            | chain_information::GrandpaPauseState::PendingResume { .. } => true,
+        }
+    }
+
+    /// Updates the state to account for the given block, which must be a child of the block the
+    /// state corresponds to.
+    ///
+    /// Changes that are triggered by the import of a block, as opposed to its finalization, are
+    /// applied. A forced change whose triggering block has been skipped is applied as well.
+    pub(crate) fn import_block(&mut self, block_header: header::HeaderRef) {
+        // A forced change whose triggering block has been skipped, which can only happen when
+        // warp syncing, is applied before the forced change of this block, if any.
+        if self
+            .forced_change
+            .as_ref()
+            .map_or(false, |(n, _)| *n < block_header.number)
+        {
+            let (_, new_authorities) = self.forced_change.take().unwrap();
+            self.apply_authorities_change(new_authorities);
+        }
+
+        for grandpa_digest_item in block_header.digest.logs().filter_map(|d| match d {
+            header::DigestItemRef::GrandpaConsensus(gp) => Some(gp),
+            _ => None,
+        }) {
+            match grandpa_digest_item {
+                header::GrandpaConsensusLogRef::ScheduledChange(change) => {
+                    let trigger_block_height = block_header
+                        .number
+                        .checked_add(u64::from(change.delay))
+                        .unwrap();
+                    self.scheduled_change = Some((
+                        trigger_block_height,
+                        change.next_authorities.map(Into::into).collect(),
+                    ));
+                }
+                header::GrandpaConsensusLogRef::ForcedChange { change, .. } => {
+                    // The `reset_block_height` is only relevant to the authorities, who need to
+                    // restart voting from this block.
+                    let trigger_block_height = block_header
+                        .number
+                        .checked_add(u64::from(change.delay))
+                        .unwrap();
+                    self.forced_change = Some((
+                        trigger_block_height,
+                        change.next_authorities.map(Into::into).collect(),
+                    ));
+                }
+                header::GrandpaConsensusLogRef::OnDisabled(authority_index) => {
+                    if !self.disabled_authorities.contains(&authority_index) {
+                        self.disabled_authorities.push(authority_index);
+                    }
+                }
+                header::GrandpaConsensusLogRef::Pause(delay) => {
+                    // The runtime only schedules a pause while the authorities are live.
+                    if self.pause_state == chain_information::GrandpaPauseState::Live {
+                        self.pause_state = chain_information::GrandpaPauseState::PendingPause {
+                            trigger_block_height: block_header
+                                .number
+                                .checked_add(u64::from(delay))
+                                .unwrap(),
+                        };
+                    }
+                }
+                header::GrandpaConsensusLogRef::Resume(delay) => {
+                    // The runtime only schedules a resume while the authorities are paused.
+                    if self.pause_state == chain_information::GrandpaPauseState::Paused {
+                        self.pause_state = chain_information::GrandpaPauseState::PendingResume {
+                            trigger_block_height: block_header
+                                .number
+                                .checked_add(u64::from(delay))
+                                .unwrap(),
+                        };
+                    }
+                }
+            }
+        }
+
+        if self
+            .forced_change
+            .as_ref()
+            .map_or(false, |(n, _)| *n <= block_header.number)
+        {
+            let (_, new_authorities) = self.forced_change.take().unwrap();
+            self.apply_authorities_change(new_authorities);
+        }
+
+        if let chain_information::GrandpaPauseState::PendingResume {
+            trigger_block_height,
+        } = self.pause_state
+        {
+            if trigger_block_height <= block_header.number {
+                self.pause_state = chain_information::GrandpaPauseState::Live;
+            }
+        }
+    }
+
+    /// Updates the state to account for the finalization of the block the state corresponds to,
+    /// whose height is passed as parameter.
+    ///
+    /// Changes that are triggered by the finalization of a block are applied.
+    pub(crate) fn finalize_block(&mut self, block_number: u64) {
+        if self
+            .scheduled_change
+            .as_ref()
+            .map_or(false, |(n, _)| *n <= block_number)
+        {
+            let (_, new_authorities) = self.scheduled_change.take().unwrap();
+            self.apply_authorities_change(new_authorities);
+        }
+
+        if let chain_information::GrandpaPauseState::PendingPause {
+            trigger_block_height,
+        } = self.pause_state
+        {
+            if trigger_block_height <= block_number {
+                self.pause_state = chain_information::GrandpaPauseState::Paused;
+            }
+        }
+    }
+
+    /// Replaces the list of authorities with a new one.
+    fn apply_authorities_change(&mut self, new_authorities: Vec<header::GrandpaAuthority>) {
+        self.triggered_authorities = new_authorities;
+        self.authorities_set_id += 1;
+        self.disabled_authorities.clear();
+    }
+}
+
+impl<T> Block<T> {
+    /// Returns the key by which blocks are compared in order to determine the best block, and
+    /// which one to evict when the chain is full. Lower means less likely to become part of the
+    /// best chain.
+    ///
+    /// The hash is part of the key so that ties are always broken in the same way, independently
+    /// of the order in which blocks have been inserted.
+    fn fork_choice_key(&self) -> (u64, u64, [u8; 32]) {
+        (self.babe_weight, self.header.number, self.hash)
+    }
+}
+
 impl<T> NonFinalizedTree<T> {
     /// Initializes a new queue.
     ///
@@ -146,100 +412,99 @@ impl<T> NonFinalizedTree<T> {
     /// Panics if the chain information is incorrect.
     ///
     pub fn new(config: Config) -> Self {
-        if config
-            .chain_information_config
-            .chain_information
-            .finalized_block_header
-            .number
-            >= 1
-        {
-            assert!(config
-                .chain_information_config
-                .chain_information
-                .babe_finalized_block1_slot_number
-                .is_some());
-            assert!(config
-                .chain_information_config
-                .chain_information
-                .babe_finalized_next_epoch_transition
-                .is_some());
-        } else {
+        let chain_information = config.chain_information_config.chain_information;
+
+        if chain_information.finalized_block_header.number == 0 {
             assert_eq!(
-                config
-                    .chain_information_config
-                    .chain_information
-                    .grandpa_after_finalized_block_authorities_set_id,
+                chain_information.grandpa_after_finalized_block_authorities_set_id,
                 0
             );
-            assert!(config
-                .chain_information_config
-                .chain_information
-                .babe_finalized_next_epoch_transition
-                .is_none());
-            assert!(config
-                .chain_information_config
-                .chain_information
-                .babe_finalized_block_epoch_information
-                .is_none());
         }
 
-        // TODO: also check that babe_finalized_block_epoch_information is None if and only if block is in epoch #0
+        let finalized_consensus = match config.chain_information_config.consensus_genesis_config {
+            chain_information::ConsensusGenesisConfiguration::Aura(genesis_config) => {
+                if chain_information.finalized_block_header.number >= 1 {
+                    assert!(chain_information.aura_finalized_authorities_list.is_some());
+                }
 
-        let finalized_block_hash = config
-            .chain_information_config
-            .chain_information
-            .finalized_block_header
-            .hash();
+                let authorities_list = chain_information
+                    .aura_finalized_authorities_list
+                    .unwrap_or_else(|| genesis_config.authorities_list.clone());
 
-        if let Some(scheduled) = config
-            .chain_information_config
-            .chain_information
+                FinalizedConsensus::Aura {
+                    genesis_config,
+                    authorities_list: Arc::new(authorities_list),
+                }
+            }
+            chain_information::ConsensusGenesisConfiguration::Babe(genesis_config) => {
+                if chain_information.finalized_block_header.number >= 1 {
+                    assert!(chain_information
+                        .babe_finalized_block1_slot_number
+                        .is_some());
+                    assert!(chain_information
+                        .babe_finalized_next_epoch_transition
+                        .is_some());
+                } else {
+                    assert!(chain_information
+                        .babe_finalized_next_epoch_transition
+                        .is_none());
+                    assert!(chain_information
+                        .babe_finalized_block_epoch_information
+                        .is_none());
+                }
+
+                // TODO: also check that babe_finalized_block_epoch_information is None if and only if block is in epoch #0
+
+                FinalizedConsensus::Babe {
+                    genesis_config,
+                    block_epoch_information: chain_information
+                        .babe_finalized_block_epoch_information
+                        .map(Arc::new),
+                    next_epoch_transition: chain_information
+                        .babe_finalized_next_epoch_transition
+                        .map(Arc::new),
+                    block1_slot_number: chain_information.babe_finalized_block1_slot_number,
+                    epoch_randomness_contributions: chain_information
+                        .babe_finalized_epoch_randomness_contributions,
+                    disabled_authorities: Arc::new(
+                        chain_information.babe_finalized_disabled_authorities,
+                    ),
+                }
+            }
+        };
+
+        let finalized_block_hash = chain_information.finalized_block_header.hash();
+
+        if let Some(scheduled) = chain_information
             .grandpa_finalized_scheduled_change
             .as_ref()
         {
-            assert!(
-                scheduled.0
-                    > config
-                        .chain_information_config
-                        .chain_information
-                        .finalized_block_header
-                        .number
-            );
+            assert!(scheduled.0 > chain_information.finalized_block_header.number);
+        }
+        if let Some(forced) = chain_information.grandpa_finalized_forced_change.as_ref() {
+            assert!(forced.0 > chain_information.finalized_block_header.number);
+            assert!(chain_information
+                .grandpa_finalized_scheduled_change
+                .is_none());
         }
 
         NonFinalizedTree {
-            finalized_block_header: config
-                .chain_information_config
-                .chain_information
-                .finalized_block_header,
+            finalized_block_header: chain_information.finalized_block_header,
             finalized_block_hash,
-            grandpa_after_finalized_block_authorities_set_id: config
-                .chain_information_config
-                .chain_information
-                .grandpa_after_finalized_block_authorities_set_id,
-            grandpa_finalized_triggered_authorities: config
-                .chain_information_config
-                .chain_information
-                .grandpa_finalized_triggered_authorities,
-            grandpa_finalized_scheduled_change: config
-                .chain_information_config
-                .chain_information
-                .grandpa_finalized_scheduled_change,
-            babe_genesis_config: config.chain_information_config.babe_genesis_config,
-            babe_finalized_block1_slot_number: config
-                .chain_information_config
-                .chain_information
-                .babe_finalized_block1_slot_number,
-            babe_finalized_block_epoch_information: config
-                .chain_information_config
-                .chain_information
-                .babe_finalized_block_epoch_information
-                .map(Arc::new),
-            babe_finalized_next_epoch_transition: config
-                .chain_information_config
-                .chain_information
-                .babe_finalized_next_epoch_transition
-                .map(Arc::new),
+            finalized_grandpa: GrandpaState {
+                authorities_set_id: chain_information
+                    .grandpa_after_finalized_block_authorities_set_id,
+                triggered_authorities: chain_information.grandpa_finalized_triggered_authorities,
+                scheduled_change: chain_information.grandpa_finalized_scheduled_change,
+                forced_change: chain_information.grandpa_finalized_forced_change,
+                disabled_authorities: chain_information.grandpa_finalized_disabled_authorities,
+                pause_state: chain_information.grandpa_finalized_pause_state,
+            },
+            finalized_consensus,
+            babe_finalized_block_weight: chain_information.babe_finalized_block_weight,
+            max_non_finalized_blocks: config.max_non_finalized_blocks,
+            max_non_finalized_depth: config.max_non_finalized_depth,
+            max_slot_drift: config.max_slot_drift,
             blocks: fork_tree::ForkTree::with_capacity(config.blocks_capacity),
             current_best: None,
         }
@@ -274,25 +539,61 @@ impl<T> NonFinalizedTree<T> {
     /// Builds a [`chain_information::ChainInformationRef`] struct that might later be used to
     /// build a new [`NonFinalizedTree`].
     pub fn as_chain_information(&self) -> chain_information::ChainInformationRef {
-        chain_information::ChainInformationRef {
+        let mut info = chain_information::ChainInformationRef {
             finalized_block_header: (&self.finalized_block_header).into(),
-            babe_finalized_block1_slot_number: self.babe_finalized_block1_slot_number,
-            babe_finalized_block_epoch_information: self
-                .babe_finalized_block_epoch_information
-                .as_ref()
-                .map(|info| ((&info.0).into(), info.1)),
-            babe_finalized_next_epoch_transition: self
-                .babe_finalized_next_epoch_transition
-                .as_ref()
-                .map(|info| ((&info.0).into(), info.1)),
+            babe_finalized_block1_slot_number: None,
+            babe_finalized_block_weight: self.babe_finalized_block_weight,
+            babe_finalized_epoch_randomness_contributions: None,
+            babe_finalized_disabled_authorities: &[],
+            babe_finalized_block_epoch_information: None,
+            babe_finalized_next_epoch_transition: None,
+            aura_finalized_authorities_list: None,
             grandpa_after_finalized_block_authorities_set_id: self
-                .grandpa_after_finalized_block_authorities_set_id,
-            grandpa_finalized_triggered_authorities: &self.grandpa_finalized_triggered_authorities,
+                .finalized_grandpa
+                .authorities_set_id,
+            grandpa_finalized_triggered_authorities: &self.finalized_grandpa.triggered_authorities,
             grandpa_finalized_scheduled_change: self
-                .grandpa_finalized_scheduled_change
+                .finalized_grandpa
+                .scheduled_change
+                .as_ref()
+                .map(|(n, l)| (*n, &l[..])),
+            grandpa_finalized_forced_change: self
+                .finalized_grandpa
+                .forced_change
                 .as_ref()
                 .map(|(n, l)| (*n, &l[..])),
+            grandpa_finalized_disabled_authorities: &self.finalized_grandpa.disabled_authorities,
+            grandpa_finalized_pause_state: self.finalized_grandpa.pause_state,
+        };
+
+        match &self.finalized_consensus {
+            FinalizedConsensus::Aura {
+                authorities_list, ..
+            } => {
+                info.aura_finalized_authorities_list = Some(&authorities_list[..]);
+            }
+            FinalizedConsensus::Babe {
+                block_epoch_information,
+                next_epoch_transition,
+                block1_slot_number,
+                epoch_randomness_contributions,
+                disabled_authorities,
+                ..
+            } => {
+                info.babe_finalized_block1_slot_number = *block1_slot_number;
+                info.babe_finalized_epoch_randomness_contributions =
+                    epoch_randomness_contributions.as_ref().map(|c| &c[..]);
+                info.babe_finalized_disabled_authorities = &disabled_authorities[..];
+                info.babe_finalized_block_epoch_information = block_epoch_information
+                    .as_ref()
+                    .map(|info| ((&info.0).into(), info.1));
+                info.babe_finalized_next_epoch_transition = next_epoch_transition
+                    .as_ref()
+                    .map(|info| ((&info.0).into(), info.1));
+            }
         }
+
+        info
     }
 
     /// Returns the header of the latest finalized block.
@@ -323,6 +624,13 @@ impl<T> NonFinalizedTree<T> {
         }
     }
 
+    /// Returns true if the block with the given hash is in the chain and isn't finalized.
+    ///
+    /// > **Note**: This returns `false` for the latest finalized block.
+    pub fn contains_non_finalized_block(&self, hash: &[u8; 32]) -> bool {
+        self.blocks.find(|b| b.hash == *hash).is_some()
+    }
+
     /// Verifies the given block.
     ///
     /// The verification is performed in the context of the chain. In particular, the
@@ -330,10 +638,14 @@ impl<T> NonFinalizedTree<T> {
     ///
     /// If the verification succeeds, an [`HeaderInsert`] object might be returned which can be
     /// used to then insert the block in the chain.
+    ///
+    /// `now_from_unix_epoch` must be the time elapsed since the Unix epoch, and is used in order
+    /// to refuse blocks whose slot hasn't started yet.
     #[must_use]
     pub fn verify_header(
         &mut self,
         scale_encoded_header: Vec<u8>,
+        now_from_unix_epoch: Duration,
     ) -> Result<HeaderVerifySuccess<T>, HeaderVerifyError> {
         // TODO: lots of code here is duplicated from verify_body
 
@@ -346,55 +658,21 @@ impl<T> NonFinalizedTree<T> {
 
         let hash = header::hash_from_scale_encoded_header(&scale_encoded_header);
 
-        if self.blocks.find(|b| b.hash == hash).is_some() {
-            return Ok(HeaderVerifySuccess::Duplicate);
-        }
-
-        // Try to find the parent block in the tree of known blocks.
-        // `Some` with an index of the parent within the tree of unfinalized blocks.
-        // `None` means that the parent is the finalized block.
-        //
-        // The parent hash is first checked against `self.current_best`, as it is most likely
-        // that new blocks are built on top of the current best.
-        let parent_tree_index = if self.current_best.map_or(false, |best| {
-            *decoded_header.parent_hash == self.blocks.get(best).unwrap().hash
-        }) {
-            Some(self.current_best.unwrap())
-        } else if *decoded_header.parent_hash == self.finalized_block_hash {
-            None
-        } else {
-            let parent_hash = *decoded_header.parent_hash;
-            match self.blocks.find(|b| b.hash == parent_hash) {
-                Some(parent) => Some(parent),
-                None => {
-                    return Err(HeaderVerifyError::BadParent { parent_hash });
-                }
+        let Prechecked {
+            parent_tree_index,
+            babe_weight,
+            is_new_best,
+            evicted,
+        } = match self.precheck(decoded_header.clone(), hash) {
+            Ok(p) => p,
+            Err(HeaderPrecheckError::Duplicate) => return Ok(HeaderVerifySuccess::Duplicate),
+            Err(HeaderPrecheckError::BadParent { parent_hash }) => {
+                return Err(HeaderVerifyError::BadParent { parent_hash })
             }
-        };
-
-        // Try to find the slot number of block 1.
-        // If block 1 is finalized, this information is found in
-        // `babe_finalized_block1_slot_number`, otherwise the information is found in the parent
-        // node in the fork tree.
-        let block1_slot_number = if let Some(val) = self.babe_finalized_block1_slot_number {
-            debug_assert!(parent_tree_index.map_or(true, |p_idx| self
-                .blocks
-                .get(p_idx)
-                .unwrap()
-                .babe_block1_slot_number
-                == val));
-            Some(val)
-        } else if let Some(parent_tree_index) = parent_tree_index {
-            Some(
-                self.blocks
-                    .get(parent_tree_index)
-                    .unwrap()
-                    .babe_block1_slot_number,
-            )
-        } else {
-            // Can only happen if parent is the block #0.
-            assert_eq!(decoded_header.number, 1);
-            None
+            Err(HeaderPrecheckError::LimitReached(err)) => {
+                return Err(HeaderVerifyError::LimitReached(err))
+            }
+            Err(HeaderPrecheckError::InvalidHeader(_)) => unreachable!(),
         };
 
         let parent_block_header = if let Some(parent_tree_index) = parent_tree_index {
@@ -403,15 +681,38 @@ impl<T> NonFinalizedTree<T> {
             &self.finalized_block_header
         };
 
+        let parent_epoch_randomness_contributions =
+            if decoded_header.digest.babe_epoch_information().is_some() {
+                self.babe_epoch_randomness_contributions(parent_tree_index)
+            } else {
+                None
+            };
+
+        let consensus = match &self.finalized_consensus {
+            FinalizedConsensus::Aura { genesis_config, .. } => {
+                verify::header_only::ConfigConsensus::Aura {
+                    genesis_configuration: genesis_config,
+                    current_authorities: header::AuraAuthoritiesIter::from_slice_of_authorities(
+                        self.aura_authorities(parent_tree_index),
+                    ),
+                    max_slot_drift: self.max_slot_drift,
+                }
+            }
+            FinalizedConsensus::Babe { genesis_config, .. } => {
+                verify::header_only::ConfigConsensus::Babe {
+                    genesis_configuration: genesis_config,
+                    block1_slot_number: self.babe_block1_slot_number(parent_tree_index),
+                    max_slot_drift: self.max_slot_drift,
+                    parent_epoch_randomness_contributions: parent_epoch_randomness_contributions
+                        .as_deref(),
+                    parent_disabled_authorities: self.babe_disabled_authorities(parent_tree_index),
+                }
+            }
+        };
+
         let mut process = verify::header_only::verify(verify::header_only::Config {
-            babe_genesis_configuration: &self.babe_genesis_config,
-            block1_slot_number,
-            now_from_unix_epoch: {
-                // TODO: is it reasonable to use the stdlib here?
-                //std::time::SystemTime::UNIX_EPOCH.elapsed().unwrap()
-                // TODO: this is commented out because of Wasm support
-                core::time::Duration::new(0, 0)
-            },
+            consensus,
+            now_from_unix_epoch,
             block_header: decoded_header.clone(),
             parent_block_header: parent_block_header.into(),
         });
@@ -419,26 +720,30 @@ impl<T> NonFinalizedTree<T> {
         let result = loop {
             match process {
                 verify::header_only::Verify::Finished(Ok(result)) => break result,
+                verify::header_only::Verify::Finished(Err(
+                    verify::header_only::Error::AuraVerification(
+                        aura::VerifyError::TooFarInFuture { slot_number, .. },
+                    ),
+                ))
+                | verify::header_only::Verify::Finished(Err(
+                    verify::header_only::Error::BabeVerification(
+                        babe::VerifyError::TooFarInFuture { slot_number, .. },
+                    ),
+                )) => {
+                    return Err(HeaderVerifyError::FutureBlock {
+                        verifiable_from: self
+                            .slot_start_time(slot_number.saturating_sub(self.max_slot_drift)),
+                    });
+                }
                 verify::header_only::Verify::Finished(Err(err)) => {
                     return Err(HeaderVerifyError::VerificationFailed(err));
                 }
                 verify::header_only::Verify::ReadyToRun(run) => process = run.run(),
                 verify::header_only::Verify::BabeEpochInformation(epoch_info_rq) => {
-                    let epoch_info = if let Some(parent_tree_index) = parent_tree_index {
-                        let parent = self.blocks.get(parent_tree_index).unwrap();
-                        if epoch_info_rq.same_epoch_as_parent() {
-                            parent.babe_current_epoch.as_ref().unwrap()
-                        } else {
-                            &parent.babe_next_epoch
-                        }
-                    } else if epoch_info_rq.same_epoch_as_parent() {
-                        self.babe_finalized_block_epoch_information
-                            .as_ref()
-                            .unwrap()
-                    } else {
-                        self.babe_finalized_next_epoch_transition.as_ref().unwrap()
-                    };
-
+                    let epoch_info = self.babe_epoch_information(
+                        parent_tree_index,
+                        epoch_info_rq.same_epoch_as_parent(),
+                    );
                     process = epoch_info_rq
                         .inject_epoch((From::from(&epoch_info.0), epoch_info.1))
                         .run();
@@ -446,86 +751,27 @@ impl<T> NonFinalizedTree<T> {
             }
         };
 
-        let is_new_best = if let Some(current_best) = self.current_best {
-            is_better_block(
-                &self.blocks,
-                current_best,
-                parent_tree_index,
-                decoded_header.clone(),
-            )
+        let best_block_change = if is_new_best {
+            Some(self.best_block_change(parent_tree_index, Some(hash)))
         } else {
-            true
+            None
         };
 
-        let babe_block1_slot_number = block1_slot_number.unwrap_or_else(|| {
-            debug_assert_eq!(decoded_header.number, 1);
-            result.slot_number
-        });
-
-        let babe_current_epoch = if result.babe_epoch_transition_target.is_some() {
-            if let Some(parent_tree_index) = parent_tree_index {
-                Some(
-                    self.blocks
-                        .get(parent_tree_index)
-                        .unwrap()
-                        .babe_next_epoch
-                        .clone(),
-                )
-            } else {
-                self.babe_finalized_next_epoch_transition.clone()
-            }
-        } else if let Some(parent_tree_index) = parent_tree_index {
-            self.blocks
-                .get(parent_tree_index)
-                .unwrap()
-                .babe_current_epoch
-                .clone()
-        } else {
-            self.babe_finalized_block_epoch_information.clone()
+        let (slot_number, babe_randomness_contribution) = match result {
+            verify::header_only::Success::Aura { slot_number, .. } => (slot_number, None),
+            verify::header_only::Success::Babe {
+                slot_number,
+                randomness_contribution,
+                ..
+            } => (slot_number, randomness_contribution),
         };
 
-        let babe_next_epoch = match (
-            decoded_header.digest.babe_epoch_information(),
+        let consensus = self.block_consensus_after(
             parent_tree_index,
-            &self.babe_finalized_next_epoch_transition,
-        ) {
-            (Some((ref new_epoch, Some(new_config))), _, _) => {
-                Arc::new((new_epoch.clone().into(), new_config))
-            }
-            (Some((ref new_epoch, None)), Some(parent_tree_index), _) => {
-                let new_config = self
-                    .blocks
-                    .get(parent_tree_index)
-                    .unwrap()
-                    .babe_next_epoch
-                    .1
-                    .clone();
-                Arc::new((new_epoch.clone().into(), new_config))
-            }
-            (Some((ref new_epoch, None)), None, Some(finalized_next)) => {
-                Arc::new((new_epoch.clone().into(), finalized_next.1))
-            }
-            (Some((ref new_epoch, None)), None, None) => Arc::new((
-                new_epoch.clone().into(),
-                self.babe_genesis_config.epoch0_configuration(),
-            )),
-            (None, Some(parent_tree_index), _) => self
-                .blocks
-                .get(parent_tree_index)
-                .unwrap()
-                .babe_next_epoch
-                .clone(),
-            (None, None, _) => {
-                // Block 1 always contains a Babe epoch transition. Consequently, this block
-                // can't be reached for block 1.
-                // `babe_finalized_next_epoch_transition` is `None` only if the finalized
-                // block is 0.
-                // `babe_finalized_next_epoch_transition` is therefore always `Some`
-                // Q.E.D.
-                debug_assert_ne!(decoded_header.number, 1);
-                self.babe_finalized_next_epoch_transition.clone().unwrap()
-            }
-        };
+            &decoded_header,
+            slot_number,
+            babe_randomness_contribution,
+        );
 
         Ok(HeaderVerifySuccess::Insert {
             block_height: decoded_header.number,
@@ -534,11 +780,12 @@ impl<T> NonFinalizedTree<T> {
                 chain: self,
                 parent_tree_index,
                 is_new_best,
+                best_block_change,
+                evicted,
                 header: decoded_header.into(),
                 hash,
-                babe_block1_slot_number,
-                babe_current_epoch,
-                babe_next_epoch,
+                consensus,
+                babe_weight,
             },
         })
     }
@@ -553,7 +800,14 @@ impl<T> NonFinalizedTree<T> {
     /// finished or the process aborted, at which point the [`NonFinalizedTree`] can be retrieved
     /// back. The state of the [`NonFinalizedTree`] isn't modified until [`BodyInsert::insert`] is
     /// called after the end of the verification.
-    pub fn verify_body<I, E>(self, scale_encoded_header: Vec<u8>, body: I) -> BodyVerifyStep1<T, I>
+    ///
+    /// See [`NonFinalizedTree::verify_header`] for the meaning of `now_from_unix_epoch`.
+    pub fn verify_body<I, E>(
+        self,
+        scale_encoded_header: Vec<u8>,
+        body: I,
+        now_from_unix_epoch: Duration,
+    ) -> BodyVerifyStep1<T, I>
     where
         I: ExactSizeIterator<Item = E> + Clone,
         E: AsRef<[u8]> + Clone,
@@ -565,8 +819,331 @@ impl<T> NonFinalizedTree<T> {
 
         let hash = header::hash_from_scale_encoded_header(&scale_encoded_header);
 
+        let (parent_tree_index, evicted) = match self.precheck(decoded_header.clone(), hash) {
+            Ok(p) => (p.parent_tree_index, p.evicted),
+            Err(HeaderPrecheckError::Duplicate) => return BodyVerifyStep1::Duplicate(self),
+            Err(HeaderPrecheckError::BadParent { parent_hash }) => {
+                return BodyVerifyStep1::BadParent {
+                    chain: self,
+                    parent_hash,
+                }
+            }
+            Err(HeaderPrecheckError::LimitReached(error)) => {
+                return BodyVerifyStep1::LimitReached { chain: self, error }
+            }
+            Err(HeaderPrecheckError::InvalidHeader(_)) => unreachable!(),
+        };
+
+        BodyVerifyStep1::ParentRuntimeRequired(BodyVerifyRuntimeRequired {
+            chain: self,
+            header: decoded_header.into(),
+            parent_tree_index,
+            evicted,
+            body,
+            now_from_unix_epoch,
+        })
+    }
+
+    /// Verifies the given justification.
+    ///
+    /// The verification is performed in the context of the chain. In particular, the
+    /// verification will fail if the target block isn't already in the chain.
+    ///
+    /// If the verification succeeds, a [`JustificationApply`] object will be returned which can
+    /// be used to apply the finalization.
+    // TODO: expand the documentation about how blocks with authorities changes have to be finalized before any further block can be finalized
+    pub fn verify_justification(
+        &mut self,
+        scale_encoded_justification: &[u8],
+    ) -> Result<JustificationApply<T>, JustificationVerifyError> {
+        // Turn justification into a strongly-typed struct.
+        let decoded = justification::decode::decode(&scale_encoded_justification)
+            .map_err(JustificationVerifyError::InvalidJustification)?;
+
+        // Find in the list of non-finalized blocks the one targeted by the justification.
+        let block_index = match self.blocks.find(|b| b.hash == *decoded.target_hash) {
+            Some(idx) => idx,
+            None => {
+                return Err(JustificationVerifyError::UnknownTargetBlock {
+                    block_number: From::from(decoded.target_number),
+                    block_hash: *decoded.target_hash,
+                });
+            }
+        };
+
+        // Determine the state of GrandPa at the target block, by walking through all the blocks
+        // between the latest finalized one and the target block.
+        //
+        // If any of these blocks triggers a GrandPa authorities change or a pause once it is
+        // finalized, then this triggering block must be finalized before any of its descendants.
+        // The finalization of the descendants is otherwise unsecure, as it would be performed
+        // by the wrong authorities.
+        let mut grandpa = self.finalized_grandpa.clone();
+        for node in self.blocks.root_to_node_path(block_index) {
+            let block = self.blocks.get(node).unwrap();
+
+            if let Some(barrier) = grandpa.finality_barrier() {
+                if barrier < block.header.number {
+                    let block_to_finalize_hash = self
+                        .blocks
+                        .node_to_root_path(block_index)
+                        .filter_map(|b| {
+                            let b = self.blocks.get(b).unwrap();
+                            if b.header.number == barrier {
+                                Some(b.hash)
+                            } else {
+                                None
+                            }
+                        })
+                        .next()
+                        .unwrap();
+                    return Err(JustificationVerifyError::TooFarAhead {
+                        justification_block_number: u64::from(decoded.target_number),
+                        justification_block_hash: *decoded.target_hash,
+                        block_to_finalize_number: barrier,
+                        block_to_finalize_hash,
+                    });
+                }
+            }
+
+            grandpa.import_block((&block.header).into());
+        }
+
+        // Authorities that have paused can't finalize any block.
+        if grandpa.is_paused() {
+            return Err(JustificationVerifyError::AuthoritiesPaused);
+        }
+
+        // As per above check, the authorities in `grandpa` are the ones that are supposed to
+        // finalize the target block. Like Substrate, the precommits of disabled authorities are
+        // counted, and the threshold is calculated against the full list of authorities.
+        let equivocations = justification::verify::verify(justification::verify::Config {
+            justification: decoded,
+            authorities_set_id: grandpa.authorities_set_id,
+            authorities_list: grandpa
+                .triggered_authorities
+                .iter()
+                .map(header::GrandpaAuthorityRef::from),
+        })
+        .map_err(JustificationVerifyError::VerificationFailed)?;
+
+        // Justification has been successfully verified!
+        Ok(JustificationApply {
+            chain: self,
+            to_finalize: block_index,
+            equivocations,
+        })
+    }
+
+    /// Verifies the given GrandPa commit, for example received through the GrandPa gossip
+    /// protocol.
+    ///
+    /// A commit is similar to a justification, except that it doesn't contain the headers of the
+    /// blocks targeted by the pre-commits. These headers are taken from the chain, then the
+    /// commit is verified the same way as [`NonFinalizedTree::verify_justification`].
+    pub fn verify_grandpa_commit(
+        &mut self,
+        commit: &grandpa::gossip::CommitMessage,
+    ) -> Result<JustificationApply<T>, JustificationVerifyError> {
+        let mut votes_ancestries = Vec::new();
+        let mut in_votes_ancestries = BTreeSet::new();
+
+        for precommit in &commit.precommits {
+            // Pre-commits targeting blocks that aren't in the chain can't be proven to descend
+            // from the commit target, and fail the verification below.
+            let block_index = match self.blocks.find(|b| b.hash == precommit.vote.target_hash) {
+                Some(idx) => idx,
+                None => continue,
+            };
+
+            // Blocks between the target of the pre-commit (included) and the target of the
+            // commit (excluded). Not added if the latter isn't an ancestor of the former.
+            let mut ancestry = Vec::new();
+            let mut reached_target = false;
+            for node in self.blocks.node_to_root_path(block_index) {
+                let block = self.blocks.get(node).unwrap();
+                if block.hash == commit.target_hash {
+                    reached_target = true;
+                    break;
+                }
+                ancestry.push(block);
+            }
+
+            if !reached_target {
+                continue;
+            }
+
+            for block in ancestry {
+                if in_votes_ancestries.insert(block.hash) {
+                    votes_ancestries.push(block.header.scale_encoding().fold(
+                        Vec::new(),
+                        |mut out, buffer| {
+                            out.extend_from_slice(buffer.as_ref());
+                            out
+                        },
+                    ));
+                }
+            }
+        }
+
+        let justification = commit.scale_encoded_justification(votes_ancestries.iter());
+        self.verify_justification(&justification)
+    }
+
+    /// Sets the latest known finalized block. Trying to verify a block that isn't a descendant of
+    /// that block will fail.
+    ///
+    /// The block must have been passed to [`NonFinalizedTree::verify_header`].
+    ///
+    /// Returns an iterator containing the now-finalized blocks in decreasing block numbers. In
+    /// other words, the first element of the iterator is always the block whose hash is the
+    /// `block_hash` passed as parameter.
+    ///
+    /// > **Note**: This function returns blocks in decreasing block number, because any other
+    /// >           ordering would incur a performance cost. While returning blocks in increasing
+    /// >           block number would often be more convenient, the overhead of doing so is
+    /// >           moved to the user.
+    ///
+    /// The pruning is completely performed, even if the iterator is dropped eagerly.
+    pub fn set_finalized_block(
+        &mut self,
+        block_hash: &[u8; 32],
+    ) -> Result<SetFinalizedBlockIter<T>, SetFinalizedError> {
+        let block_index = match self.blocks.find(|b| b.hash == *block_hash) {
+            Some(idx) => idx,
+            None => return Err(SetFinalizedError::UnknownBlock),
+        };
+
+        Ok(self.set_finalized_block_inner(block_index))
+    }
+
+    /// Private function that does the same as [`NonFinalizedTree::set_finalized_block`].
+    fn set_finalized_block_inner(
+        &mut self,
+        block_index: fork_tree::NodeIndex,
+    ) -> SetFinalizedBlockIter<T> {
+        let target_block_height = self.blocks.get_mut(block_index).unwrap().header.number;
+
+        // If the current best block doesn't descend from the new finalized block, it is about to
+        // be pruned. The new best block is then the best amongst the descendants of the new
+        // finalized block, or the new finalized block itself if it doesn't have any descendant.
+        // Since the pruning doesn't remove any descendant of the new finalized block,
+        // `current_best` can be updated now.
+        let best_block_change = match self.current_best {
+            Some(best) if !self.blocks.is_ancestor(block_index, best) => {
+                let new_best = self
+                    .blocks
+                    .iter_unordered()
+                    .filter(|(index, _)| {
+                        *index != block_index && self.blocks.is_ancestor(block_index, *index)
+                    })
+                    .max_by_key(|(_, block)| block.fork_choice_key())
+                    .map(|(index, _)| index);
+                let change = self.best_block_change(Some(new_best.unwrap_or(block_index)), None);
+                self.current_best = new_best;
+                Some(change)
+            }
+            _ => None,
+        };
+
+        // Update the GrandPa state by finalizing, in order, all the blocks between the latest
+        // finalized block and the new finalized block.
+        for node in self.blocks.root_to_node_path(block_index) {
+            let header = &self.blocks.get(node).unwrap().header;
+            self.finalized_grandpa.import_block(header.into());
+            self.finalized_grandpa.finalize_block(header.number);
+        }
+
+        let new_epoch_randomness_contributions =
+            self.babe_epoch_randomness_contributions(Some(block_index));
+
+        let new_finalized_block = self.blocks.get_mut(block_index).unwrap();
+
+        match (
+            &mut self.finalized_consensus,
+            &new_finalized_block.consensus,
+        ) {
+            (
+                FinalizedConsensus::Aura {
+                    authorities_list, ..
+                },
+                BlockConsensus::Aura {
+                    authorities_list: new_authorities_list,
+                },
+            ) => {
+                *authorities_list = new_authorities_list.clone();
+            }
+            (
+                FinalizedConsensus::Babe {
+                    block_epoch_information,
+                    next_epoch_transition,
+                    block1_slot_number,
+                    epoch_randomness_contributions,
+                    disabled_authorities,
+                    ..
+                },
+                BlockConsensus::Babe {
+                    block1_slot_number: new_block1_slot_number,
+                    current_epoch,
+                    next_epoch,
+                    disabled_authorities: new_disabled_authorities,
+                    ..
+                },
+            ) => {
+                *block_epoch_information = current_epoch.clone();
+                *next_epoch_transition = Some(next_epoch.clone());
+                *epoch_randomness_contributions = new_epoch_randomness_contributions;
+                *disabled_authorities = new_disabled_authorities.clone();
+
+                if block1_slot_number.is_none() {
+                    debug_assert!(new_finalized_block.header.number >= 1);
+                    *block1_slot_number = Some(*new_block1_slot_number);
+                }
+            }
+            _ => unreachable!(),
+        }
+
+        self.babe_finalized_block_weight = new_finalized_block.babe_weight;
+
+        mem::swap(
+            &mut self.finalized_block_header,
+            &mut new_finalized_block.header,
+        );
+        self.finalized_block_hash = self.finalized_block_header.hash();
+
+        SetFinalizedBlockIter {
+            iter: self.blocks.prune_ancestors(block_index),
+            current_best: &mut self.current_best,
+            best_block_change,
+        }
+    }
+
+    /// Checks whether a header would be accepted by [`NonFinalizedTree::verify_header`] or
+    /// [`NonFinalizedTree::verify_body`], without performing the actual verification.
+    ///
+    /// This checks that the header can be decoded, isn't already in the chain, has a known
+    /// parent, and that inserting it would stay within the limits passed in the [`Config`].
+    /// It is meant to be used in order to cheaply discard headers, for example when they are
+    /// announced by peers.
+    ///
+    /// > **Note**: Success doesn't guarantee that the verification will succeed, as the
+    /// >           authenticity of the header isn't verified.
+    pub fn precheck_header(&self, scale_encoded_header: &[u8]) -> Result<(), HeaderPrecheckError> {
+        let decoded_header =
+            header::decode(scale_encoded_header).map_err(HeaderPrecheckError::InvalidHeader)?;
+        let hash = header::hash_from_scale_encoded_header(scale_encoded_header);
+        self.precheck(decoded_header, hash).map(|_| ())
+    }
+
+    /// Common implementation of [`NonFinalizedTree::precheck_header`] and of the first steps of
+    /// the verification of a block.
+    fn precheck(
+        &self,
+        header: header::HeaderRef,
+        hash: [u8; 32],
+    ) -> Result<Prechecked, HeaderPrecheckError> {
         if self.blocks.find(|b| b.hash == hash).is_some() {
-            return BodyVerifyStep1::Duplicate(self);
+            return Err(HeaderPrecheckError::Duplicate);
         }
 
         // Try to find the parent block in the tree of known blocks.
@@ -576,273 +1153,544 @@ impl<T> NonFinalizedTree<T> {
         // The parent hash is first checked against `self.current_best`, as it is most likely
         // that new blocks are built on top of the current best.
         let parent_tree_index = if self.current_best.map_or(false, |best| {
-            *decoded_header.parent_hash == self.blocks.get(best).unwrap().hash
+            *header.parent_hash == self.blocks.get(best).unwrap().hash
         }) {
             Some(self.current_best.unwrap())
-        } else if *decoded_header.parent_hash == self.finalized_block_hash {
+        } else if *header.parent_hash == self.finalized_block_hash {
             None
         } else {
-            let parent_hash = *decoded_header.parent_hash;
+            let parent_hash = *header.parent_hash;
             match self.blocks.find(|b| b.hash == parent_hash) {
                 Some(parent) => Some(parent),
-                None => {
-                    return BodyVerifyStep1::BadParent {
-                        chain: self,
-                        parent_hash,
+                None => return Err(HeaderPrecheckError::BadParent { parent_hash }),
+            }
+        };
+
+        if let Some(max_depth) = self.max_non_finalized_depth {
+            if header
+                .number
+                .saturating_sub(self.finalized_block_header.number)
+                > max_depth.get()
+            {
+                return Err(HeaderPrecheckError::LimitReached(
+                    LimitError::TooFarAboveFinalized {
+                        block_number: header.number,
+                        finalized_block_number: self.finalized_block_header.number,
+                    },
+                ));
+            }
+        }
+
+        let babe_weight = self.babe_weight(parent_tree_index, header.clone());
+        let is_new_best = self.is_better_than_best(babe_weight, header.number, &hash);
+
+        let evicted = match self.max_non_finalized_blocks {
+            Some(max) if self.blocks.len() >= max.get() => {
+                match self.eviction_candidate(parent_tree_index, is_new_best) {
+                    Some(index)
+                        if is_new_best
+                            || self.blocks.get(index).unwrap().fork_choice_key()
+                                < (babe_weight, header.number, hash) =>
+                    {
+                        Some(index)
                     }
+                    _ => return Err(HeaderPrecheckError::LimitReached(LimitError::TooManyBlocks)),
+                }
+            }
+            _ => None,
+        };
+
+        Ok(Prechecked {
+            parent_tree_index,
+            babe_weight,
+            is_new_best,
+            evicted,
+        })
+    }
+
+    /// Returns the block to remove from the tree in order to make room for a new block whose
+    /// parent is `parent_tree_index`.
+    ///
+    /// Only fork tips are candidates for removal. The ancestors of the new block are never
+    /// candidates, nor are the blocks of the best chain unless `new_block_is_best` is `true`.
+    /// Returns `None` if there isn't any candidate, for example if the non-finalized blocks form a
+    /// single chain and the new block is built on top of it.
+    /// Amongst the candidates, the one with the lowest BABE weight, then lowest height, then
+    /// lowest hash is chosen, which makes the choice deterministic.
+    fn eviction_candidate(
+        &self,
+        parent_tree_index: Option<fork_tree::NodeIndex>,
+        new_block_is_best: bool,
+    ) -> Option<fork_tree::NodeIndex> {
+        self.blocks
+            .iter_unordered()
+            .filter(|(index, _)| self.blocks.is_leaf(*index))
+            .filter(|(index, _)| {
+                parent_tree_index.map_or(true, |parent| !self.blocks.is_ancestor(*index, parent))
+            })
+            .filter(|(index, _)| {
+                new_block_is_best
+                    || self
+                        .current_best
+                        .map_or(true, |best| !self.blocks.is_ancestor(*index, best))
+            })
+            .min_by_key(|(_, block)| block.fork_choice_key())
+            .map(|(index, _)| index)
+    }
+
+    /// Removes from the tree a block previously returned by
+    /// [`NonFinalizedTree::eviction_candidate`]. Does nothing if `None`.
+    fn evict(&mut self, evicted: Option<fork_tree::NodeIndex>) {
+        if let Some(evicted) = evicted {
+            if self.current_best == Some(evicted) {
+                // The block being inserted is the new best block and will overwrite this field.
+                self.current_best = None;
+            }
+            self.blocks.remove_leaf(evicted);
+        }
+    }
+
+    /// Returns the BABE randomness contributions of the blocks of the epoch of the given block,
+    /// from the first block of that epoch to the given block included. `None` designates the
+    /// finalized block.
+    ///
+    /// Returns `None` if these contributions aren't known, which can happen if the epoch has
+    /// started before the finalized block that the tree has been initialized with.
+    fn babe_epoch_randomness_contributions(
+        &self,
+        block: Option<fork_tree::NodeIndex>,
+    ) -> Option<Vec<[u8; 32]>> {
+        // Contributions are gathered in reverse order, from the given block to the start of the
+        // epoch.
+        let mut contributions = Vec::new();
+
+        if let Some(block) = block {
+            for index in self.blocks.node_to_root_path(block) {
+                let block = self.blocks.get(index).unwrap();
+                if let BlockConsensus::Babe {
+                    randomness_contribution,
+                    ..
+                } = &block.consensus
+                {
+                    contributions.extend(*randomness_contribution);
+                }
+                if block.header.digest.babe_epoch_information().is_some() {
+                    contributions.reverse();
+                    return Some(contributions);
+                }
+            }
+        }
+
+        // The start of the epoch hasn't been reached, meaning that the finalized block belongs
+        // to the same epoch.
+        let finalized = match &self.finalized_consensus {
+            FinalizedConsensus::Babe {
+                epoch_randomness_contributions,
+                ..
+            } => epoch_randomness_contributions.as_ref()?,
+            FinalizedConsensus::Aura { .. } => return None,
+        };
+        contributions.extend(finalized.iter().rev().copied());
+        contributions.reverse();
+        Some(contributions)
+    }
+
+    /// Returns the list of Aura authorities that must author the children of the given block.
+    /// `None` designates the finalized block.
+    ///
+    /// # Panic
+    ///
+    /// Panics if the chain doesn't use Aura.
+    ///
+    fn aura_authorities(
+        &self,
+        block: Option<fork_tree::NodeIndex>,
+    ) -> &Arc<Vec<header::AuraAuthority>> {
+        match (
+            block.map(|index| &self.blocks.get(index).unwrap().consensus),
+            &self.finalized_consensus,
+        ) {
+            (Some(BlockConsensus::Aura { authorities_list }), _) => authorities_list,
+            (
+                None,
+                FinalizedConsensus::Aura {
+                    authorities_list, ..
+                },
+            ) => authorities_list,
+            _ => unreachable!(),
+        }
+    }
+
+    /// Returns the slot number of the block #1 that is an ancestor of the child of the given
+    /// block, or `None` if that child is block #1. `None` designates the finalized block.
+    ///
+    /// # Panic
+    ///
+    /// Panics if the chain doesn't use BABE.
+    ///
+    fn babe_block1_slot_number(&self, block: Option<fork_tree::NodeIndex>) -> Option<u64> {
+        match (
+            block.map(|index| &self.blocks.get(index).unwrap().consensus),
+            &self.finalized_consensus,
+        ) {
+            (
+                Some(BlockConsensus::Babe {
+                    block1_slot_number, ..
+                }),
+                _,
+            ) => Some(*block1_slot_number),
+            (
+                None,
+                FinalizedConsensus::Babe {
+                    block1_slot_number, ..
+                },
+            ) => *block1_slot_number,
+            _ => unreachable!(),
+        }
+    }
+
+    /// Returns the information about the BABE epoch of a child of the given block. `None`
+    /// designates the finalized block.
+    ///
+    /// `same_epoch_as_parent` must be true if the child belongs to the same epoch as the given
+    /// block.
+    ///
+    /// # Panic
+    ///
+    /// Panics if the chain doesn't use BABE.
+    ///
+    fn babe_epoch_information(
+        &self,
+        block: Option<fork_tree::NodeIndex>,
+        same_epoch_as_parent: bool,
+    ) -> &Arc<(header::BabeNextEpoch, header::BabeNextConfig)> {
+        match (
+            block.map(|index| &self.blocks.get(index).unwrap().consensus),
+            &self.finalized_consensus,
+        ) {
+            (
+                Some(BlockConsensus::Babe {
+                    current_epoch,
+                    next_epoch,
+                    ..
+                }),
+                _,
+            ) => {
+                if same_epoch_as_parent {
+                    current_epoch.as_ref().unwrap()
+                } else {
+                    next_epoch
                 }
             }
+            (
+                None,
+                FinalizedConsensus::Babe {
+                    block_epoch_information,
+                    next_epoch_transition,
+                    ..
+                },
+            ) => {
+                if same_epoch_as_parent {
+                    block_epoch_information.as_ref().unwrap()
+                } else {
+                    next_epoch_transition.as_ref().unwrap()
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    /// Returns the time, since the Unix epoch, at which the given slot starts.
+    fn slot_start_time(&self, slot_number: u64) -> Duration {
+        match &self.finalized_consensus {
+            FinalizedConsensus::Aura { genesis_config, .. } => {
+                aura::slot_start_time(slot_number, genesis_config)
+            }
+            FinalizedConsensus::Babe { genesis_config, .. } => {
+                babe::slot_start_time(slot_number, genesis_config)
+            }
+        }
+    }
+
+    /// Returns the indices of the BABE authorities disabled as of the given block. `None`
+    /// designates the finalized block. Always empty if the chain doesn't use BABE.
+    fn babe_disabled_authorities(&self, block: Option<fork_tree::NodeIndex>) -> &[u32] {
+        match (
+            block.map(|index| &self.blocks.get(index).unwrap().consensus),
+            &self.finalized_consensus,
+        ) {
+            (
+                Some(BlockConsensus::Babe {
+                    disabled_authorities,
+                    ..
+                }),
+                _,
+            )
+            | (
+                None,
+                FinalizedConsensus::Babe {
+                    disabled_authorities,
+                    ..
+                },
+            ) => &disabled_authorities[..],
+            _ => &[],
+        }
+    }
+
+    /// Returns the indices of the BABE authorities disabled as of a block with the given header
+    /// and whose parent is the given block. `None` designates the finalized block.
+    ///
+    /// # Panic
+    ///
+    /// Panics if the chain doesn't use BABE.
+    ///
+    fn babe_disabled_authorities_after(
+        &self,
+        parent: Option<fork_tree::NodeIndex>,
+        header: &header::HeaderRef,
+    ) -> Arc<Vec<u32>> {
+        let parent_disabled = match (
+            parent.map(|index| &self.blocks.get(index).unwrap().consensus),
+            &self.finalized_consensus,
+        ) {
+            (
+                Some(BlockConsensus::Babe {
+                    disabled_authorities,
+                    ..
+                }),
+                _,
+            )
+            | (
+                None,
+                FinalizedConsensus::Babe {
+                    disabled_authorities,
+                    ..
+                },
+            ) => disabled_authorities,
+            _ => unreachable!(),
         };
 
-        // Try to find the slot number of block 1.
-        // If block 1 is finalized, this information is found in
-        // `babe_finalized_block1_slot_number`, otherwise the information is found in the parent
-        // node in the fork tree.
-        let block1_slot_number = if let Some(val) = self.babe_finalized_block1_slot_number {
-            debug_assert!(parent_tree_index.map_or(true, |p_idx| self
-                .blocks
-                .get(p_idx)
-                .unwrap()
-                .babe_block1_slot_number
-                == val));
-            Some(val)
-        } else if let Some(parent_tree_index) = parent_tree_index {
-            Some(
-                self.blocks
-                    .get(parent_tree_index)
-                    .unwrap()
-                    .babe_block1_slot_number,
-            )
+        let newly_disabled = header.digest.logs().filter_map(|item| match item {
+            header::DigestItemRef::BabeConsensus(header::BabeConsensusLogRef::OnDisabled(
+                index,
+            )) => Some(index),
+            _ => None,
+        });
+
+        // Authorities are no longer disabled after an epoch change. Since the list is shared
+        // between blocks, a new one is only allocated if it is modified.
+        if header.digest.babe_epoch_information().is_some() {
+            let mut list = newly_disabled.collect::<Vec<_>>();
+            list.sort_unstable();
+            list.dedup();
+            Arc::new(list)
         } else {
-            // Can only happen if parent is the block #0.
-            assert_eq!(decoded_header.number, 1);
-            None
-        };
+            let mut newly_disabled = newly_disabled
+                .filter(|index| !parent_disabled.contains(index))
+                .peekable();
+            if newly_disabled.peek().is_none() {
+                return parent_disabled.clone();
+            }
 
-        BodyVerifyStep1::ParentRuntimeRequired(BodyVerifyRuntimeRequired {
-            chain: self,
-            header: decoded_header.into(),
-            parent_tree_index,
-            body,
-            block1_slot_number,
-        })
+            let mut list = (**parent_disabled).clone();
+            list.extend(newly_disabled);
+            list.sort_unstable();
+            list.dedup();
+            Arc::new(list)
+        }
     }
 
-    /// Verifies the given justification.
-    ///
-    /// The verification is performed in the context of the chain. In particular, the
-    /// verification will fail if the target block isn't already in the chain.
+    /// Builds the consensus-related information of a successfully-verified block with the given
+    /// header and whose parent is the given block. `None` designates the finalized block.
     ///
-    /// If the verification succeeds, a [`JustificationApply`] object will be returned which can
-    /// be used to apply the finalization.
-    // TODO: expand the documentation about how blocks with authorities changes have to be finalized before any further block can be finalized
-    pub fn verify_justification(
-        &mut self,
-        scale_encoded_justification: &[u8],
-    ) -> Result<JustificationApply<T>, JustificationVerifyError> {
-        // Turn justification into a strongly-typed struct.
-        let decoded = justification::decode::decode(&scale_encoded_justification)
-            .map_err(JustificationVerifyError::InvalidJustification)?;
+    /// `slot_number` must be the slot number of the block, and `babe_randomness_contribution`
+    /// the value that it contributes to the randomness of a future BABE epoch, if any.
+    fn block_consensus_after(
+        &self,
+        parent_tree_index: Option<fork_tree::NodeIndex>,
+        header: &header::HeaderRef,
+        slot_number: u64,
+        babe_randomness_contribution: Option<[u8; 32]>,
+    ) -> BlockConsensus {
+        match &self.finalized_consensus {
+            FinalizedConsensus::Aura { .. } => {
+                // The list of authorities of the children of the block is the one announced by
+                // the block, if any, or otherwise the same as the one of the parent.
+                let new_list = header
+                    .digest
+                    .logs()
+                    .filter_map(|item| match item {
+                        header::DigestItemRef::AuraConsensus(
+                            header::AuraConsensusLogRef::AuthoritiesChange(list),
+                        ) => Some(list),
+                        _ => None,
+                    })
+                    .last();
 
-        // Find in the list of non-finalized blocks the one targeted by the justification.
-        let block_index = match self.blocks.find(|b| b.hash == *decoded.target_hash) {
-            Some(idx) => idx,
-            None => {
-                return Err(JustificationVerifyError::UnknownTargetBlock {
-                    block_number: From::from(decoded.target_number),
-                    block_hash: *decoded.target_hash,
-                });
+                let authorities_list = match new_list {
+                    Some(list) => Arc::new(list.map(Into::into).collect()),
+                    None => self.aura_authorities(parent_tree_index).clone(),
+                };
+
+                BlockConsensus::Aura { authorities_list }
             }
-        };
+            FinalizedConsensus::Babe {
+                genesis_config,
+                block_epoch_information,
+                next_epoch_transition,
+                ..
+            } => {
+                let (parent_current_epoch, parent_next_epoch) = match parent_tree_index
+                    .map(|index| &self.blocks.get(index).unwrap().consensus)
+                {
+                    Some(BlockConsensus::Babe {
+                        current_epoch,
+                        next_epoch,
+                        ..
+                    }) => (current_epoch.clone(), Some(next_epoch.clone())),
+                    Some(BlockConsensus::Aura { .. }) => unreachable!(),
+                    None => (
+                        block_epoch_information.clone(),
+                        next_epoch_transition.clone(),
+                    ),
+                };
+
+                let block1_slot_number = self
+                    .babe_block1_slot_number(parent_tree_index)
+                    .unwrap_or_else(|| {
+                        debug_assert_eq!(header.number, 1);
+                        slot_number
+                    });
 
-        // If any block between the latest finalized one and the target block trigger any GrandPa
-        // authorities change, then we need to finalize that triggering block (or any block
-        // after or including the one that schedules these changes) before finalizing the one
-        // targeted by the justification.
-        // TODO: rethink and reexplain this ^
+                let epoch_change = header.digest.babe_epoch_information();
 
-        // Find out the next block height where an authority change will be triggered.
-        let earliest_trigger = {
-            // Scheduled change that is already finalized.
-            let scheduled = self
-                .grandpa_finalized_scheduled_change
-                .as_ref()
-                .map(|(n, _)| *n);
-
-            // First change that would be scheduled if we finalize the target block.
-            let would_happen = {
-                let mut trigger_height = None;
-                // TODO: lot of boilerplate code here
-                for node in self.blocks.root_to_node_path(block_index) {
-                    let header = &self.blocks.get(node).unwrap().header;
-                    for grandpa_digest_item in header.digest.logs().filter_map(|d| match d {
-                        header::DigestItemRef::GrandpaConsensus(gp) => Some(gp),
-                        _ => None,
-                    }) {
-                        match grandpa_digest_item {
-                            header::GrandpaConsensusLogRef::ScheduledChange(change) => {
-                                let trigger_block_height =
-                                    header.number.checked_add(u64::from(change.delay)).unwrap();
-                                match trigger_height {
-                                    Some(_) => panic!("invalid block!"), // TODO: this problem is not checked during block verification
-                                    None => trigger_height = Some(trigger_block_height),
-                                }
-                            }
-                            _ => {} // TODO: unimplemented
-                        }
+                let current_epoch = if epoch_change.is_some() {
+                    parent_next_epoch.clone()
+                } else {
+                    parent_current_epoch
+                };
+
+                let next_epoch = match (epoch_change, parent_next_epoch) {
+                    (Some((new_epoch, Some(new_config))), _) => {
+                        Arc::new((new_epoch.into(), new_config))
+                    }
+                    (Some((new_epoch, None)), Some(parent_next_epoch)) => {
+                        Arc::new((new_epoch.into(), parent_next_epoch.1))
+                    }
+                    (Some((new_epoch, None)), None) => {
+                        Arc::new((new_epoch.into(), genesis_config.epoch0_configuration()))
                     }
+                    (None, Some(parent_next_epoch)) => parent_next_epoch,
+                    (None, None) => {
+                        // Block 1 always contains a Babe epoch transition. Consequently, this
+                        // block can't be reached for block 1.
+                        // The next epoch of the parent is `None` only if the parent is the
+                        // finalized block and is block 0.
+                        // The next epoch of the parent is therefore always `Some`
+                        // Q.E.D.
+                        unreachable!()
+                    }
+                };
+
+                BlockConsensus::Babe {
+                    block1_slot_number,
+                    current_epoch,
+                    next_epoch,
+                    randomness_contribution: babe_randomness_contribution,
+                    disabled_authorities: self
+                        .babe_disabled_authorities_after(parent_tree_index, header),
                 }
-                trigger_height
-            };
-
-            match (scheduled, would_happen) {
-                (Some(a), Some(b)) => Some(cmp::min(a, b)),
-                (Some(a), None) => Some(a),
-                (None, Some(b)) => Some(b),
-                (None, None) => None,
-            }
-        };
-
-        // As explained above, `target_number` must be <= `earliest_trigger`, otherwise the
-        // finalization is unsecure.
-        if let Some(earliest_trigger) = earliest_trigger {
-            if u64::from(decoded.target_number) > earliest_trigger {
-                let block_to_finalize_hash = self
-                    .blocks
-                    .node_to_root_path(block_index)
-                    .filter_map(|b| {
-                        let b = self.blocks.get(b).unwrap();
-                        if b.header.number == earliest_trigger {
-                            Some(b.hash)
-                        } else {
-                            None
-                        }
-                    })
-                    .next()
-                    .unwrap();
-                return Err(JustificationVerifyError::TooFarAhead {
-                    justification_block_number: u64::from(decoded.target_number),
-                    justification_block_hash: *decoded.target_hash,
-                    block_to_finalize_number: earliest_trigger,
-                    block_to_finalize_hash,
-                });
             }
         }
+    }
 
-        // Find which authorities are supposed to finalize the target block.
-        let authorities_list = self
-            .grandpa_finalized_scheduled_change
-            .as_ref()
-            .filter(|(trigger_height, _)| *trigger_height < u64::from(decoded.target_number))
-            .map(|(_, list)| list)
-            .unwrap_or(&self.grandpa_finalized_triggered_authorities);
-
-        // As per above check, we know that the authorities of the target block are either the
-        // same as the ones of the latest finalized block, or the ones contained in the header of
-        // the latest finalized block.
-        justification::verify::verify(justification::verify::Config {
-            justification: decoded,
-            authorities_set_id: self.grandpa_after_finalized_block_authorities_set_id,
-            authorities_list: authorities_list.iter().map(|a| a.public_key),
-        })
-        .map_err(JustificationVerifyError::VerificationFailed)?;
+    /// Returns the BABE weight of a block whose parent is the given block. `None` designates
+    /// the finalized block.
+    fn babe_weight(
+        &self,
+        parent_tree_index: Option<fork_tree::NodeIndex>,
+        header: header::HeaderRef,
+    ) -> u64 {
+        let parent_weight = match parent_tree_index {
+            Some(index) => self.blocks.get(index).unwrap().babe_weight,
+            None => self.babe_finalized_block_weight,
+        };
 
-        // Justification has been successfully verified!
-        Ok(JustificationApply {
-            chain: self,
-            to_finalize: block_index,
-        })
+        if header
+            .digest
+            .babe_pre_runtime()
+            .map_or(false, |pr| pr.is_primary())
+        {
+            parent_weight + 1
+        } else {
+            parent_weight
+        }
     }
 
-    /// Sets the latest known finalized block. Trying to verify a block that isn't a descendant of
-    /// that block will fail.
-    ///
-    /// The block must have been passed to [`NonFinalizedTree::verify_header`].
-    ///
-    /// Returns an iterator containing the now-finalized blocks in decreasing block numbers. In
-    /// other words, the first element of the iterator is always the block whose hash is the
-    /// `block_hash` passed as parameter.
+    /// Returns true if a block with the given BABE weight and number is better than the current
+    /// best block.
     ///
-    /// > **Note**: This function returns blocks in decreasing block number, because any other
-    /// >           ordering would incur a performance cost. While returning blocks in increasing
-    /// >           block number would often be more convenient, the overhead of doing so is
-    /// >           moved to the user.
+    /// The block with the highest BABE weight is preferred. If two blocks have the same weight,
+    /// the one with the highest number is preferred. If there is an equality again, the one with
+    /// the highest hash is preferred. See [`Block::fork_choice_key`].
     ///
-    /// The pruning is completely performed, even if the iterator is dropped eagerly.
-    pub fn set_finalized_block(
-        &mut self,
-        block_hash: &[u8; 32],
-    ) -> Result<SetFinalizedBlockIter<T>, SetFinalizedError> {
-        let block_index = match self.blocks.find(|b| b.hash == *block_hash) {
-            Some(idx) => idx,
-            None => return Err(SetFinalizedError::UnknownBlock),
+    /// > **Note**: Since the weight of a block is always superior or equal to the weight of its
+    /// >           parent, a descendant of the current best block is always preferred to it.
+    fn is_better_than_best(&self, babe_weight: u64, number: u64, hash: &[u8; 32]) -> bool {
+        let best_key = match self.current_best {
+            Some(index) => self.blocks.get(index).unwrap().fork_choice_key(),
+            None => (
+                self.babe_finalized_block_weight,
+                self.finalized_block_header.number,
+                self.finalized_block_hash,
+            ),
         };
 
-        Ok(self.set_finalized_block_inner(block_index))
+        (babe_weight, number, *hash) > best_key
     }
 
-    /// Private function that does the same as [`NonFinalizedTree::set_finalized_block`].
-    fn set_finalized_block_inner(
-        &mut self,
-        block_index: fork_tree::NodeIndex,
-    ) -> SetFinalizedBlockIter<T> {
-        let target_block_height = self.blocks.get_mut(block_index).unwrap().header.number;
-
-        // Update the scheduled GrandPa change with the latest scheduled-but-non-finalized change
-        // that could be found.
-        self.grandpa_finalized_scheduled_change = None;
-        for node in self.blocks.root_to_node_path(block_index) {
-            let node = self.blocks.get(node).unwrap();
-            //node.header.number
-            for grandpa_digest_item in node.header.digest.logs().filter_map(|d| match d {
-                header::DigestItemRef::GrandpaConsensus(gp) => Some(gp),
-                _ => None,
-            }) {
-                match grandpa_digest_item {
-                    header::GrandpaConsensusLogRef::ScheduledChange(change) => {
-                        let trigger_block_height = node
-                            .header
-                            .number
-                            .checked_add(u64::from(change.delay))
-                            .unwrap();
-                        if trigger_block_height > target_block_height {
-                            self.grandpa_finalized_scheduled_change = Some((
-                                trigger_block_height,
-                                change.next_authorities.map(Into::into).collect(),
-                            ));
-                        } else {
-                            self.grandpa_finalized_triggered_authorities =
-                                change.next_authorities.map(Into::into).collect();
-                            self.grandpa_after_finalized_block_authorities_set_id += 1;
-                        }
-                    }
-                    _ => {} // TODO: unimplemented
-                }
+    /// Builds the [`BestBlockChanged`] corresponding to the current best block being replaced
+    /// with another block.
+    ///
+    /// If `new_child_hash` is `None`, then `new_best` is the new best block. Otherwise,
+    /// `new_best` is the parent of the new best block, which isn't in the tree yet, and
+    /// `new_child_hash` its hash. In both cases, `None` designates the finalized block.
+    fn best_block_change(
+        &self,
+        new_best: Option<fork_tree::NodeIndex>,
+        new_child_hash: Option<[u8; 32]>,
+    ) -> BestBlockChanged {
+        let hash = |index| self.blocks.get(index).unwrap().hash;
+
+        let (retracted, mut enacted) = match (self.current_best, new_best) {
+            (Some(old_best), Some(new_best)) => {
+                let (ascend, descend) = self.blocks.ascend_and_descend(old_best, new_best);
+                let retracted = ascend.map(hash).collect::<Vec<_>>();
+                let mut enacted = descend.map(hash).collect::<Vec<_>>();
+                enacted.reverse();
+                (retracted, enacted)
             }
-        }
-
-        let new_finalized_block = self.blocks.get_mut(block_index).unwrap();
-
-        self.babe_finalized_block_epoch_information =
-            new_finalized_block.babe_current_epoch.clone();
-        self.babe_finalized_next_epoch_transition =
-            Some(new_finalized_block.babe_next_epoch.clone());
-
-        mem::swap(
-            &mut self.finalized_block_header,
-            &mut new_finalized_block.header,
-        );
-        self.finalized_block_hash = self.finalized_block_header.hash();
+            (Some(old_best), None) => (
+                self.blocks.node_to_root_path(old_best).map(hash).collect(),
+                Vec::new(),
+            ),
+            (None, Some(new_best)) => (
+                Vec::new(),
+                self.blocks.root_to_node_path(new_best).map(hash).collect(),
+            ),
+            (None, None) => (Vec::new(), Vec::new()),
+        };
 
-        if self.babe_finalized_block1_slot_number.is_none() {
-            debug_assert!(self.finalized_block_header.number >= 1);
-            self.babe_finalized_block1_slot_number =
-                Some(new_finalized_block.babe_block1_slot_number);
-        }
+        enacted.extend(new_child_hash);
 
-        SetFinalizedBlockIter {
-            iter: self.blocks.prune_ancestors(block_index),
-            current_best: &mut self.current_best,
+        BestBlockChanged {
+            old: self.best_block_hash(),
+            new: new_child_hash
+                .or_else(|| new_best.map(hash))
+                .unwrap_or(self.finalized_block_hash),
+            reorg_depth: u64::try_from(retracted.len()).unwrap(),
+            retracted,
+            enacted,
         }
     }
 }
@@ -877,6 +1725,13 @@ pub enum BodyVerifyStep1<T, I> {
         parent_hash: [u8; 32],
     },
 
+    /// Inserting the block would exceed the limits passed in the [`Config`].
+    LimitReached {
+        chain: NonFinalizedTree<T>,
+        /// Limit in question.
+        error: LimitError,
+    },
+
     /// Verification is pending. In order to continue, a [`executor::WasmVmPrototype`] of the
     /// runtime of the parent block must be provided.
     ParentRuntimeRequired(BodyVerifyRuntimeRequired<T, I>),
@@ -890,8 +1745,10 @@ pub struct BodyVerifyRuntimeRequired<T, I> {
     chain: NonFinalizedTree<T>,
     header: header::Header,
     parent_tree_index: Option<fork_tree::NodeIndex>,
+    /// Block to remove from the tree in order to make room for this one.
+    evicted: Option<fork_tree::NodeIndex>,
     body: I,
-    block1_slot_number: Option<u64>,
+    now_from_unix_epoch: Duration,
 }
 
 impl<T, I, E> BodyVerifyRuntimeRequired<T, I>
@@ -954,16 +1811,42 @@ where
             &self.chain.finalized_block_header
         };
 
+        let parent_epoch_randomness_contributions =
+            if self.header.digest.babe_epoch_information().is_some() {
+                self.chain
+                    .babe_epoch_randomness_contributions(self.parent_tree_index)
+            } else {
+                None
+            };
+
+        let consensus = match &self.chain.finalized_consensus {
+            FinalizedConsensus::Aura { genesis_config, .. } => {
+                verify::header_body::ConfigConsensus::Aura {
+                    genesis_configuration: genesis_config,
+                    current_authorities: header::AuraAuthoritiesIter::from_slice_of_authorities(
+                        self.chain.aura_authorities(self.parent_tree_index),
+                    ),
+                    max_slot_drift: self.chain.max_slot_drift,
+                }
+            }
+            FinalizedConsensus::Babe { genesis_config, .. } => {
+                verify::header_body::ConfigConsensus::Babe {
+                    genesis_configuration: genesis_config,
+                    block1_slot_number: self.chain.babe_block1_slot_number(self.parent_tree_index),
+                    max_slot_drift: self.chain.max_slot_drift,
+                    parent_epoch_randomness_contributions: parent_epoch_randomness_contributions
+                        .as_deref(),
+                    parent_disabled_authorities: self
+                        .chain
+                        .babe_disabled_authorities(self.parent_tree_index),
+                }
+            }
+        };
+
         let process = verify::header_body::verify(verify::header_body::Config {
             parent_runtime,
-            babe_genesis_configuration: &self.chain.babe_genesis_config,
-            block1_slot_number: self.block1_slot_number,
-            now_from_unix_epoch: {
-                // TODO: is it reasonable to use the stdlib here?
-                //std::time::SystemTime::UNIX_EPOCH.elapsed().unwrap()
-                // TODO: this is commented out because of Wasm support
-                core::time::Duration::new(0, 0)
-            },
+            consensus,
+            now_from_unix_epoch: self.now_from_unix_epoch,
             block_header: (&self.header).into(),
             parent_block_header: parent_block_header.into(),
             block_body: self.body,
@@ -975,8 +1858,8 @@ where
             BodyVerifyShared {
                 chain: self.chain,
                 parent_tree_index: self.parent_tree_index,
+                evicted: self.evicted,
                 header: self.header,
-                block1_slot_number: self.block1_slot_number,
             },
         )
     }
@@ -992,7 +1875,7 @@ where
 /// Holds ownership of both the block to verify and the [`NonFinalizedTree`].
 #[must_use]
 pub enum BodyVerifyStep2<T> {
-    /// Verification is over.
+    /// Verification is over and the block is valid.
     ///
     /// Use the provided [`BodyInsert`] to insert the block in the chain if desired.
     Finished {
@@ -1006,8 +1889,25 @@ pub enum BodyVerifyStep2<T> {
         /// Pass this value to [`BodyVerifyRuntimeRequired::resume`] when verifying a children of
         /// this block in order to considerably speed up the verification.
         top_trie_root_calculation_cache: calculate_root::CalculationCache,
-        /// Outcome of the verification.
-        result: Result<BodyInsert<T>, HeaderVerifyError>, // TODO: BodyVerifyError, or rename the error to be common
+        /// Object that allows inserting the block in the chain.
+        insert: BodyInsert<T>,
+    },
+    /// Verification is over and the block is invalid. The block should be thrown away.
+    Error {
+        /// Value that was passed to [`NonFinalizedTree::verify_body`], unmodified.
+        chain: NonFinalizedTree<T>,
+        /// Reason why the block is invalid.
+        error: verify::header_body::Error,
+    },
+    /// The slot of the block is too far ahead of the current slot, even accounting for
+    /// [`Config::max_slot_drift`]. The block isn't necessarily invalid and should be verified
+    /// again later.
+    FutureBlock {
+        /// Value that was passed to [`NonFinalizedTree::verify_body`], unmodified.
+        chain: NonFinalizedTree<T>,
+        /// Time elapsed since the Unix epoch from which the block is no longer considered as
+        /// being in the future.
+        verifiable_from: Duration,
     },
     /// Loading a storage value is required in order to continue.
     StorageGet(StorageGet<T>),
@@ -1020,8 +1920,8 @@ pub enum BodyVerifyStep2<T> {
 struct BodyVerifyShared<T> {
     chain: NonFinalizedTree<T>,
     parent_tree_index: Option<fork_tree::NodeIndex>,
+    evicted: Option<fork_tree::NodeIndex>,
     header: header::Header,
-    block1_slot_number: Option<u64>,
 }
 
 impl<T> BodyVerifyStep2<T> {
@@ -1032,139 +1932,89 @@ impl<T> BodyVerifyStep2<T> {
                     // TODO: lots of code in common with header verification
 
                     // Block verification is successful!
-                    let is_new_best = if let Some(current_best) = chain.chain.current_best {
-                        is_better_block(
-                            &chain.chain.blocks,
-                            current_best,
-                            chain.parent_tree_index,
-                            (&chain.header).into(),
-                        )
-                    } else {
-                        true
-                    };
-
-                    let babe_block1_slot_number = chain.block1_slot_number.unwrap_or_else(|| {
-                        debug_assert_eq!(chain.header.number, 1);
-                        success.slot_number
-                    });
-
-                    let babe_current_epoch = if success.babe_epoch_transition_target.is_some() {
-                        if let Some(parent_tree_index) = chain.parent_tree_index {
-                            Some(
-                                chain
-                                    .chain
-                                    .blocks
-                                    .get(parent_tree_index)
-                                    .unwrap()
-                                    .babe_next_epoch
-                                    .clone(),
-                            )
-                        } else {
-                            chain.chain.babe_finalized_next_epoch_transition.clone()
-                        }
-                    } else if let Some(parent_tree_index) = chain.parent_tree_index {
+                    let hash = chain.header.hash();
+                    let babe_weight = chain
+                        .chain
+                        .babe_weight(chain.parent_tree_index, (&chain.header).into());
+                    let is_new_best =
                         chain
                             .chain
-                            .blocks
-                            .get(parent_tree_index)
-                            .unwrap()
-                            .babe_current_epoch
-                            .clone()
+                            .is_better_than_best(babe_weight, chain.header.number, &hash);
+                    let best_block_change = if is_new_best {
+                        Some(
+                            chain
+                                .chain
+                                .best_block_change(chain.parent_tree_index, Some(hash)),
+                        )
                     } else {
-                        chain.chain.babe_finalized_block_epoch_information.clone()
+                        None
                     };
 
-                    let babe_next_epoch = match (
-                        chain.header.digest.babe_epoch_information(),
-                        chain.parent_tree_index,
-                        &chain.chain.babe_finalized_next_epoch_transition,
-                    ) {
-                        (Some((ref new_epoch, Some(new_config))), _, _) => {
-                            Arc::new((new_epoch.clone().into(), new_config))
-                        }
-                        (Some((ref new_epoch, None)), Some(parent_tree_index), _) => {
-                            let new_config = chain
-                                .chain
-                                .blocks
-                                .get(parent_tree_index)
-                                .unwrap()
-                                .babe_next_epoch
-                                .1
-                                .clone();
-                            Arc::new((new_epoch.clone().into(), new_config))
-                        }
-                        (Some((ref new_epoch, None)), None, Some(finalized_next)) => {
-                            Arc::new((new_epoch.clone().into(), finalized_next.1))
-                        }
-                        (Some((ref new_epoch, None)), None, None) => Arc::new((
-                            new_epoch.clone().into(),
-                            chain.chain.babe_genesis_config.epoch0_configuration(),
-                        )),
-                        (None, Some(parent_tree_index), _) => chain
-                            .chain
-                            .blocks
-                            .get(parent_tree_index)
-                            .unwrap()
-                            .babe_next_epoch
-                            .clone(),
-                        (None, None, _) => {
-                            // Block 1 always contains a Babe epoch transition. Consequently, this block
-                            // can't be reached for block 1.
-                            // `babe_finalized_next_epoch_transition` is `None` only if the finalized
-                            // block is 0.
-                            // `babe_finalized_next_epoch_transition` is therefore always `Some`
-                            // Q.E.D.
-                            debug_assert_ne!(chain.header.number, 1);
-                            chain
-                                .chain
-                                .babe_finalized_next_epoch_transition
-                                .clone()
-                                .unwrap()
+                    let (slot_number, babe_randomness_contribution) = match success.consensus {
+                        verify::header_body::SuccessConsensus::Aura { slot_number, .. } => {
+                            (slot_number, None)
                         }
+                        verify::header_body::SuccessConsensus::Babe {
+                            slot_number,
+                            randomness_contribution,
+                            ..
+                        } => (slot_number, randomness_contribution),
                     };
 
-                    let hash = chain.header.hash();
+                    let consensus = chain.chain.block_consensus_after(
+                        chain.parent_tree_index,
+                        &(&chain.header).into(),
+                        slot_number,
+                        babe_randomness_contribution,
+                    );
 
                     return BodyVerifyStep2::Finished {
                         parent_runtime: success.parent_runtime,
                         storage_top_trie_changes: success.storage_top_trie_changes,
                         offchain_storage_changes: success.offchain_storage_changes,
                         top_trie_root_calculation_cache: success.top_trie_root_calculation_cache,
-                        result: Ok(BodyInsert {
+                        insert: BodyInsert {
                             chain: chain.chain,
                             parent_tree_index: chain.parent_tree_index,
                             is_new_best,
+                            best_block_change,
+                            evicted: chain.evicted,
                             header: chain.header,
                             hash,
-                            babe_block1_slot_number,
-                            babe_current_epoch,
-                            babe_next_epoch,
-                        }),
+                            consensus,
+                            babe_weight,
+                        },
                     };
                 }
-                verify::header_body::Verify::Finished(Err(err)) => todo!("verify err: {:?}", err),
-                verify::header_body::Verify::BabeEpochInformation(epoch_info_rq) => {
-                    let epoch_info = if let Some(parent_tree_index) = chain.parent_tree_index {
-                        let parent = chain.chain.blocks.get(parent_tree_index).unwrap();
-                        if epoch_info_rq.same_epoch_as_parent() {
-                            parent.babe_current_epoch.as_ref().unwrap()
-                        } else {
-                            &parent.babe_next_epoch
-                        }
-                    } else if epoch_info_rq.same_epoch_as_parent() {
-                        chain
-                            .chain
-                            .babe_finalized_block_epoch_information
-                            .as_ref()
-                            .unwrap()
-                    } else {
-                        chain
-                            .chain
-                            .babe_finalized_next_epoch_transition
-                            .as_ref()
-                            .unwrap()
+                verify::header_body::Verify::Finished(Err(
+                    verify::header_body::Error::AuraVerification(
+                        aura::VerifyError::TooFarInFuture { slot_number, .. },
+                    ),
+                ))
+                | verify::header_body::Verify::Finished(Err(
+                    verify::header_body::Error::BabeVerification(
+                        babe::VerifyError::TooFarInFuture { slot_number, .. },
+                    ),
+                )) => {
+                    let verifiable_from = chain
+                        .chain
+                        .slot_start_time(slot_number.saturating_sub(chain.chain.max_slot_drift));
+                    return BodyVerifyStep2::FutureBlock {
+                        chain: chain.chain,
+                        verifiable_from,
                     };
-
+                }
+                verify::header_body::Verify::Finished(Err(error)) => {
+                    return BodyVerifyStep2::Error {
+                        chain: chain.chain,
+                        error,
+                    }
+                }
+                verify::header_body::Verify::BabeEpochInformation(epoch_info_rq) => {
+                    let epoch_info = chain.chain.babe_epoch_information(
+                        chain.parent_tree_index,
+                        epoch_info_rq.same_epoch_as_parent(),
+                    );
                     inner = epoch_info_rq.inject_epoch((From::from(&epoch_info.0), epoch_info.1));
                 }
                 verify::header_body::Verify::StorageGet(inner) => {
@@ -1385,26 +2235,30 @@ pub struct HeaderInsert<'c, T> {
     chain: &'c mut NonFinalizedTree<T>,
     /// Copy of the value in [`HeaderVerifySuccess::is_new_best`].
     is_new_best: bool,
+    /// Notification to report if the block is inserted. `Some` if and only if `is_new_best`.
+    best_block_change: Option<BestBlockChanged>,
+    /// Block to remove from the tree in order to make room for this one.
+    evicted: Option<fork_tree::NodeIndex>,
     /// Index of the parent in [`NonFinalizedTree::blocks`].
     parent_tree_index: Option<fork_tree::NodeIndex>,
     header: header::Header,
     hash: [u8; 32],
-    babe_block1_slot_number: u64,
-    babe_current_epoch: Option<Arc<(header::BabeNextEpoch, header::BabeNextConfig)>>,
-    babe_next_epoch: Arc<(header::BabeNextEpoch, header::BabeNextConfig)>,
+    consensus: BlockConsensus,
+    babe_weight: u64,
 }
 
 impl<'c, T> HeaderInsert<'c, T> {
     /// Inserts the block with the given user data.
     pub fn insert(self, user_data: T) {
+        self.chain.evict(self.evicted);
+
         let new_node_index = self.chain.blocks.insert(
             self.parent_tree_index,
             Block {
                 header: self.header,
                 hash: self.hash,
-                babe_block1_slot_number: self.babe_block1_slot_number,
-                babe_current_epoch: self.babe_current_epoch,
-                babe_next_epoch: self.babe_next_epoch,
+                consensus: self.consensus,
+                babe_weight: self.babe_weight,
                 user_data,
             },
         );
@@ -1414,6 +2268,21 @@ impl<'c, T> HeaderInsert<'c, T> {
         }
     }
 
+    /// Returns the change in the best block that inserting the block causes, or `None` if the
+    /// block doesn't become the new best block.
+    pub fn best_block_change(&self) -> Option<&BestBlockChanged> {
+        self.best_block_change.as_ref()
+    }
+
+    /// Returns the hash of the block that inserting the block removes from the chain in order to
+    /// stay within [`Config::max_non_finalized_blocks`], if any.
+    ///
+    /// The removed block is a fork tip that isn't part of the best chain.
+    pub fn evicted_block_hash(&self) -> Option<[u8; 32]> {
+        self.evicted
+            .map(|index| self.chain.blocks.get(index).unwrap().hash)
+    }
+
     /// Destroys the object without inserting the block in the chain. Returns the block header.
     pub fn into_header(self) -> header::Header {
         self.header
@@ -1437,10 +2306,61 @@ pub enum HeaderVerifyError {
         /// Hash of the parent block in question.
         parent_hash: [u8; 32],
     },
+    /// Inserting the block would exceed the limits passed in the [`Config`].
+    LimitReached(LimitError),
+    /// The slot of the block is too far ahead of the current slot, even accounting for
+    /// [`Config::max_slot_drift`]. The block isn't necessarily invalid and should be
+    /// verified again later.
+    #[display(fmt = "The block belongs to a future slot.")]
+    FutureBlock {
+        /// Time elapsed since the Unix epoch from which the block is no longer considered as
+        /// being in the future.
+        verifiable_from: Duration,
+    },
     /// The block verification has failed. The block is invalid and should be thrown away.
     VerificationFailed(verify::header_only::Error),
 }
 
+/// Error potentially returned by [`NonFinalizedTree::precheck_header`].
+#[derive(Debug, derive_more::Display)]
+pub enum HeaderPrecheckError {
+    /// Error while decoding the header.
+    InvalidHeader(header::Error),
+    /// The block is already in the chain.
+    Duplicate,
+    /// The parent of the block isn't known.
+    #[display(fmt = "The parent of the block isn't known.")]
+    BadParent {
+        /// Hash of the parent block in question.
+        parent_hash: [u8; 32],
+    },
+    /// Inserting the block would exceed the limits passed in the [`Config`].
+    LimitReached(LimitError),
+}
+
+/// Limit passed in the [`Config`] that inserting a block would exceed.
+#[derive(Debug, derive_more::Display)]
+pub enum LimitError {
+    /// The height of the block is too far above the height of the finalized block.
+    /// See [`Config::max_non_finalized_depth`].
+    #[display(
+        fmt = "Block #{} is too far above the finalized block #{}",
+        block_number,
+        finalized_block_number
+    )]
+    TooFarAboveFinalized {
+        /// Height of the block.
+        block_number: u64,
+        /// Height of the finalized block.
+        finalized_block_number: u64,
+    },
+    /// The chain is full, and the block is less likely to become part of the best chain than
+    /// the blocks that could be removed to make room for it, or no block can be removed because
+    /// all the non-finalized blocks are ancestors of the block.
+    /// See [`Config::max_non_finalized_blocks`].
+    TooManyBlocks,
+}
+
 /// Returned by [`NonFinalizedTree::verify_justification`] on success.
 ///
 /// As long as [`JustificationApply::apply`] isn't called, the underlying [`NonFinalizedTree`]
@@ -1449,6 +2369,7 @@ pub enum HeaderVerifyError {
 pub struct JustificationApply<'c, T> {
     chain: &'c mut NonFinalizedTree<T>,
     to_finalize: fork_tree::NodeIndex,
+    equivocations: Vec<justification::verify::Equivocation>,
 }
 
 impl<'c, T> JustificationApply<'c, T> {
@@ -1474,6 +2395,12 @@ impl<'c, T> JustificationApply<'c, T> {
     pub fn is_current_best_block(&self) -> bool {
         Some(self.to_finalize) == self.chain.current_best
     }
+
+    /// Returns the equivocations found in the justification. They don't prevent the
+    /// justification from being applied, but can be reported.
+    pub fn equivocations(&self) -> &[justification::verify::Equivocation] {
+        &self.equivocations
+    }
 }
 
 impl<'c, T> fmt::Debug for JustificationApply<'c, T> {
@@ -1511,6 +2438,9 @@ pub enum JustificationVerifyError {
         /// Hash of the block to finalize first.
         block_to_finalize_hash: [u8; 32],
     },
+    /// The GrandPa authorities have been paused by the runtime and can't finalize the block
+    /// targeted by the justification.
+    AuthoritiesPaused,
     /// The justification verification has failed. The justification is invalid and should be
     /// thrown away.
     VerificationFailed(justification::verify::Error),
@@ -1521,6 +2451,20 @@ pub enum JustificationVerifyError {
 pub struct SetFinalizedBlockIter<'a, T> {
     iter: fork_tree::PruneAncestorsIter<'a, Block<T>>,
     current_best: &'a mut Option<fork_tree::NodeIndex>,
+    best_block_change: Option<BestBlockChanged>,
+}
+
+impl<'a, T> SetFinalizedBlockIter<'a, T> {
+    /// Returns the change in the best block that the finalization causes, or `None` if the best
+    /// block is unchanged.
+    ///
+    /// The best block changes if the previous best block doesn't descend from the newly
+    /// finalized block, in which case it is discarded. The best block amongst the descendants
+    /// of the newly finalized block, or the newly finalized block itself, becomes the new best
+    /// block.
+    pub fn best_block_change(&self) -> Option<&BestBlockChanged> {
+        self.best_block_change.as_ref()
+    }
 }
 
 impl<'a, T> Iterator for SetFinalizedBlockIter<'a, T> {
@@ -1548,11 +2492,50 @@ impl<'a, T> Drop for SetFinalizedBlockIter<'a, T> {
     fn drop(&mut self) {
         // Make sure the iteration goes to the end.
         while let Some(_) = self.next() {}
-
-        // TODO: update current_best with the new best block
     }
 }
 
+/// Outcome of [`NonFinalizedTree::precheck`].
+struct Prechecked {
+    /// Index of the parent in [`NonFinalizedTree::blocks`]. `None` if the parent is the
+    /// finalized block.
+    parent_tree_index: Option<fork_tree::NodeIndex>,
+    /// BABE weight of the block.
+    babe_weight: u64,
+    /// `true` if the block would become the new best block.
+    is_new_best: bool,
+    /// Block to remove from the tree in order to make room for this one.
+    evicted: Option<fork_tree::NodeIndex>,
+}
+
+/// Notification that the best block of a [`NonFinalizedTree`] has changed.
+///
+/// Obtained through [`HeaderInsert::best_block_change`], [`BodyInsert::best_block_change`], or
+/// [`SetFinalizedBlockIter::best_block_change`], and meant to be relayed to the subscribers
+/// interested in the head of the chain.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BestBlockChanged {
+    /// Hash of the previous best block.
+    pub old: [u8; 32],
+    /// Hash of the new best block.
+    pub new: [u8; 32],
+    /// Number of blocks of the previous best chain, starting from the previous best block, that
+    /// are no longer part of the best chain. `0` if the new best block is a descendant of the
+    /// previous best block. Always equal to the length of [`BestBlockChanged::retracted`].
+    pub reorg_depth: u64,
+    /// Hashes of the blocks that were part of the previous best chain and no longer are,
+    /// ordered from the previous best block (included) to the common ancestor with the new best
+    /// chain (excluded).
+    ///
+    /// Blocks whose state was built upon must be reverted in this order.
+    pub retracted: Vec<[u8; 32]>,
+    /// Hashes of the blocks that are part of the new best chain and weren't part of the previous
+    /// one, ordered from the child of the common ancestor to the new best block (included).
+    ///
+    /// Never empty.
+    pub enacted: Vec<[u8; 32]>,
+}
+
 /// Error that can happen when setting the finalized block.
 #[derive(Debug, derive_more::Display)]
 pub enum SetFinalizedError {
@@ -1565,13 +2548,16 @@ pub enum SetFinalizedError {
 pub struct BodyInsert<T> {
     chain: NonFinalizedTree<T>,
     is_new_best: bool,
+    /// Notification to report if the block is inserted. `Some` if and only if `is_new_best`.
+    best_block_change: Option<BestBlockChanged>,
+    /// Block to remove from the tree in order to make room for this one.
+    evicted: Option<fork_tree::NodeIndex>,
     /// Index of the parent in [`NonFinalizedTree::blocks`].
     parent_tree_index: Option<fork_tree::NodeIndex>,
     header: header::Header,
     hash: [u8; 32],
-    babe_block1_slot_number: u64,
-    babe_current_epoch: Option<Arc<(header::BabeNextEpoch, header::BabeNextConfig)>>,
-    babe_next_epoch: Arc<(header::BabeNextEpoch, header::BabeNextConfig)>,
+    consensus: BlockConsensus,
+    babe_weight: u64,
 }
 
 impl<T> BodyInsert<T> {
@@ -1580,16 +2566,32 @@ impl<T> BodyInsert<T> {
         (&self.header).into()
     }
 
+    /// Returns the change in the best block that inserting the block causes, or `None` if the
+    /// block doesn't become the new best block.
+    pub fn best_block_change(&self) -> Option<&BestBlockChanged> {
+        self.best_block_change.as_ref()
+    }
+
+    /// Returns the hash of the block that inserting the block removes from the chain in order to
+    /// stay within [`Config::max_non_finalized_blocks`], if any.
+    ///
+    /// The removed block is a fork tip that isn't part of the best chain.
+    pub fn evicted_block_hash(&self) -> Option<[u8; 32]> {
+        self.evicted
+            .map(|index| self.chain.blocks.get(index).unwrap().hash)
+    }
+
     /// Inserts the block with the given user data.
     pub fn insert(mut self, user_data: T) -> NonFinalizedTree<T> {
+        self.chain.evict(self.evicted);
+
         let new_node_index = self.chain.blocks.insert(
             self.parent_tree_index,
             Block {
                 header: self.header,
                 hash: self.hash,
-                babe_block1_slot_number: self.babe_block1_slot_number,
-                babe_current_epoch: self.babe_current_epoch,
-                babe_next_epoch: self.babe_next_epoch,
+                consensus: self.consensus,
+                babe_weight: self.babe_weight,
                 user_data,
             },
         );
@@ -1649,88 +2651,3 @@ impl<'a, T> BlockAccess<'a, T> {
         &mut self.tree.blocks.get_mut(self.node_index).unwrap().user_data
     }
 }
-
-/// Accepts as parameter a container of blocks and indices within this container.
-///
-/// Returns true if `maybe_new_best` on top of `maybe_new_best_parent` is a better block compared
-/// to `old_best`.
-fn is_better_block<T>(
-    blocks: &fork_tree::ForkTree<Block<T>>,
-    old_best: fork_tree::NodeIndex,
-    maybe_new_best_parent: Option<fork_tree::NodeIndex>,
-    maybe_new_best: header::HeaderRef,
-) -> bool {
-    debug_assert!(
-        maybe_new_best_parent.map_or(true, |p_idx| blocks.get(p_idx).unwrap().hash
-            == *maybe_new_best.parent_hash)
-    );
-
-    if maybe_new_best_parent.map_or(false, |p_idx| blocks.is_ancestor(old_best, p_idx)) {
-        // A descendant is always preferred to its ancestor.
-        true
-    } else {
-        // In order to determine whether the new block is our new best:
-        //
-        // - Find the common ancestor between the current best and the new block's parent.
-        // - Count the number of Babe primary slot claims between the common ancestor and
-        //   the current best.
-        // - Count the number of Babe primary slot claims between the common ancestor and
-        //   the new block's parent. Add one if the new block has a Babe primary slot
-        //   claim.
-        // - If the number for the new block is strictly superior, then the new block is
-        //   out new best.
-        //
-        let (ascend, descend) = blocks.ascend_and_descend(old_best, maybe_new_best_parent.unwrap());
-
-        // TODO: what if there's a mix of Babe and non-Babe blocks here?
-
-        let curr_best_primary_slots: usize = ascend
-            .map(|i| {
-                if blocks
-                    .get(i)
-                    .unwrap()
-                    .header
-                    .digest
-                    .babe_pre_runtime()
-                    .map_or(false, |pr| pr.is_primary())
-                {
-                    1
-                } else {
-                    0
-                }
-            })
-            .sum();
-
-        let new_block_primary_slots = {
-            if maybe_new_best
-                .digest
-                .babe_pre_runtime()
-                .map_or(false, |pr| pr.is_primary())
-            {
-                1
-            } else {
-                0
-            }
-        };
-
-        let parent_primary_slots: usize = descend
-            .map(|i| {
-                if blocks
-                    .get(i)
-                    .unwrap()
-                    .header
-                    .digest
-                    .babe_pre_runtime()
-                    .map_or(false, |pr| pr.is_primary())
-                {
-                    1
-                } else {
-                    0
-                }
-            })
-            .sum();
-
-        // Note the strictly superior. If there is an equality, we keep the current best.
-        parent_primary_slots + new_block_primary_slots > curr_best_primary_slots
-    }
-}
@@ -0,0 +1,906 @@
+// Substrate-lite
+// Copyright (C) 2019-2020  Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Tests of the [`NonFinalizedTree`].
+//!
+//! The tests in this module build headers that are valid according to the BABE or Aura
+//! algorithms using test keys, and focus on what the tree tracks between blocks: the state of
+//! the consensus and finality algorithms, the best block, and the limits of the tree.
+
+#![cfg(test)]
+
+use super::{
+    BestBlockChanged, Config, HeaderPrecheckError, HeaderVerifyError, HeaderVerifySuccess,
+    JustificationVerifyError, LimitError, NonFinalizedTree,
+};
+use crate::{
+    chain::chain_information,
+    finality::{grandpa, justification},
+    header, test_utils, verify,
+};
+
+use core::{
+    convert::TryFrom as _,
+    num::{NonZeroU64, NonZeroUsize},
+    time::Duration,
+};
+use rand::SeedableRng as _;
+
+/// Number of slots in each BABE epoch of the test chains.
+const SLOTS_PER_EPOCH: u64 = 8;
+/// Slot number of block #1 of the test chains.
+const BLOCK1_SLOT_NUMBER: u64 = 1000;
+/// Duration of a slot of the test chains, in milliseconds.
+const SLOT_DURATION_MS: u64 = 6000;
+/// Time at which all the slots used by the tests have started.
+const NOW: Duration = Duration::from_millis((BLOCK1_SLOT_NUMBER + 1000) * SLOT_DURATION_MS);
+
+/// Keys of the single BABE authority and of the single GrandPa authority of the test chains.
+struct Authorities {
+    babe: schnorrkel::Keypair,
+    grandpa: ed25519_dalek::Keypair,
+}
+
+impl Authorities {
+    fn new() -> Self {
+        Authorities {
+            babe: test_utils::sr25519_keypair(1),
+            grandpa: test_utils::ed25519_keypair(2),
+        }
+    }
+
+    /// Information about the given BABE epoch.
+    ///
+    /// The randomness of each epoch is derived from the one of the previous epoch, assuming
+    /// that the blocks of the epoch before haven't contributed any randomness. In other words,
+    /// the tests only use primary slot claims in an epoch if the chain doesn't go further than
+    /// the start of the next one.
+    fn epoch(&self, epoch_number: u64) -> header::BabeNextEpoch {
+        let randomness = match epoch_number.checked_sub(1) {
+            None => [0; 32],
+            Some(previous) => {
+                let mut hash = blake2_rfc::blake2b::Blake2b::new(32);
+                hash.update(&self.epoch(previous).randomness);
+                hash.update(&epoch_number.to_le_bytes());
+                let mut out = [0; 32];
+                out.copy_from_slice(hash.finalize().as_bytes());
+                out
+            }
+        };
+
+        header::BabeNextEpoch {
+            authorities: vec![header::BabeAuthority {
+                public_key: self.babe.public.to_bytes(),
+                weight: 1,
+            }],
+            randomness,
+        }
+    }
+
+    /// Builds a [`NonFinalizedTree`] whose finalized block is the genesis block.
+    fn tree(&self) -> NonFinalizedTree<()> {
+        NonFinalizedTree::new(self.config())
+    }
+
+    /// Builds the configuration of a [`NonFinalizedTree`] whose finalized block is the genesis
+    /// block.
+    fn config(&self) -> Config {
+        Config {
+            chain_information_config: chain_information::ChainInformationConfig {
+                chain_information: chain_information::ChainInformation {
+                    finalized_block_header: header::decode(&genesis()).unwrap().into(),
+                    babe_finalized_block1_slot_number: None,
+                    babe_finalized_block_weight: 0,
+                    babe_finalized_epoch_randomness_contributions: Some(Vec::new()),
+                    babe_finalized_disabled_authorities: Vec::new(),
+                    babe_finalized_block_epoch_information: None,
+                    babe_finalized_next_epoch_transition: None,
+                    aura_finalized_authorities_list: None,
+                    grandpa_after_finalized_block_authorities_set_id: 0,
+                    grandpa_finalized_triggered_authorities: vec![header::GrandpaAuthority {
+                        public_key: self.grandpa.public.to_bytes(),
+                        weight: 1,
+                    }],
+                    grandpa_finalized_scheduled_change: None,
+                    grandpa_finalized_forced_change: None,
+                    grandpa_finalized_disabled_authorities: Vec::new(),
+                    grandpa_finalized_pause_state: chain_information::GrandpaPauseState::Live,
+                },
+                consensus_genesis_config: chain_information::ConsensusGenesisConfiguration::Babe(
+                    chain_information::babe::BabeGenesisConfiguration::from_parts(
+                        SLOT_DURATION_MS,
+                        SLOTS_PER_EPOCH,
+                        // Nearly all the primary slot claims are below the threshold.
+                        (999, 1000),
+                        header::BabeAllowedSlots::PrimaryAndSecondaryPlainSlots,
+                        self.epoch(0),
+                    ),
+                ),
+            },
+            blocks_capacity: 32,
+            max_non_finalized_blocks: None,
+            max_non_finalized_depth: None,
+            max_slot_drift: 0,
+        }
+    }
+
+    /// Builds a child of `parent` in the slot `BLOCK1_SLOT_NUMBER + slot`, with the given
+    /// additional digest log items.
+    ///
+    /// If `primary` is `true`, the block is a primary slot claim. Otherwise, it is a secondary
+    /// plain slot claim.
+    fn block(
+        &self,
+        parent: &[u8],
+        slot: u64,
+        primary: bool,
+        logs: Vec<header::DigestItem>,
+    ) -> Vec<u8> {
+        let parent_hash = header::hash_from_scale_encoded_header(parent);
+        let parent = header::decode(parent).unwrap();
+        let number = parent.number + 1;
+        let slot_number = BLOCK1_SLOT_NUMBER + slot;
+        let epoch_number = slot / SLOTS_PER_EPOCH;
+
+        let pre_digest = if primary {
+            let transcript = {
+                let mut transcript = merlin::Transcript::new(&b"BABE"[..]);
+                transcript.append_u64(b"slot number", slot_number);
+                transcript.append_u64(b"current epoch", epoch_number);
+                transcript.append_message(
+                    b"chain randomness",
+                    &self.epoch(epoch_number).randomness[..],
+                );
+                transcript
+            };
+            // The randomness used to generate the proof is taken from the "extra" transcript.
+            let (vrf_in_out, vrf_proof, _) = self.babe.vrf_sign_extra(
+                transcript,
+                schnorrkel::context::attach_rng(
+                    merlin::Transcript::new(b"VRF"),
+                    rand_chacha::ChaCha20Rng::seed_from_u64(slot_number),
+                ),
+            );
+            header::BabePreDigest::Primary(header::BabePrimaryPreDigest {
+                authority_index: 0,
+                slot_number,
+                vrf_output: vrf_in_out.to_output().to_bytes(),
+                vrf_proof: vrf_proof.to_bytes(),
+            })
+        } else {
+            header::BabePreDigest::SecondaryPlain(header::BabeSecondaryPlainPreDigest {
+                authority_index: 0,
+                slot_number,
+            })
+        };
+
+        let mut digest = vec![header::DigestItem::BabePreDigest(pre_digest)];
+
+        // The first block of each epoch announces the information about the next epoch.
+        let parent_epoch_number = parent
+            .digest
+            .babe_pre_runtime()
+            .map(|pre_digest| (pre_digest.slot_number() - BLOCK1_SLOT_NUMBER) / SLOTS_PER_EPOCH);
+        if parent_epoch_number != Some(epoch_number) {
+            digest.push(header::DigestItem::BabeConsensus(
+                header::BabeConsensusLog::NextEpochData(self.epoch(epoch_number + 1)),
+            ));
+        }
+
+        digest.extend(logs);
+
+        let pre_seal_hash = header::hash_from_scale_encoded_header(test_utils::encode_header(
+            &parent_hash,
+            number,
+            &digest,
+        ));
+        let signature = self.babe.sign(schnorrkel::context::attach_rng(
+            schnorrkel::signing_context(b"substrate").bytes(&pre_seal_hash),
+            rand_chacha::ChaCha20Rng::seed_from_u64(number),
+        ));
+        digest.push(header::DigestItem::BabeSeal(signature.to_bytes()));
+
+        test_utils::encode_header(&parent_hash, number, &digest)
+    }
+
+    /// Builds a chain of `num_blocks` secondary slot claims in consecutive slots, starting from
+    /// the genesis block. The returned list is indexed by block number and includes the genesis
+    /// block.
+    ///
+    /// `logs` returns the additional digest log items of the block with the given number.
+    fn chain(
+        &self,
+        num_blocks: u64,
+        logs: impl Fn(u64) -> Vec<header::DigestItem>,
+    ) -> Vec<Vec<u8>> {
+        let mut chain = vec![genesis()];
+        for number in 1..=num_blocks {
+            let block = self.block(chain.last().unwrap(), number - 1, false, logs(number));
+            chain.push(block);
+        }
+        chain
+    }
+
+    /// Builds a justification for the given block, signed by the GrandPa authority of the
+    /// genesis block.
+    fn justification(&self, header: &[u8]) -> Vec<u8> {
+        test_utils::justification_signed_by(&self.grandpa, 0, number(header), &hash(header))
+    }
+}
+
+/// Builds the SCALE encoding of the genesis block of the test chains.
+fn genesis() -> Vec<u8> {
+    test_utils::encode_header(&[0; 32], 0, &[])
+}
+
+/// Returns the hash of the given SCALE-encoded header.
+fn hash(header: &[u8]) -> [u8; 32] {
+    header::hash_from_scale_encoded_header(header)
+}
+
+/// Returns the number of the given SCALE-encoded header.
+fn number(header: &[u8]) -> u64 {
+    header::decode(header).unwrap().number
+}
+
+/// Verifies the given header and inserts it in the tree. Returns the change of best block that
+/// the insertion has caused, if any.
+///
+/// # Panic
+///
+/// Panics if the header fails to verify or is already in the tree.
+///
+fn insert(tree: &mut NonFinalizedTree<()>, header: &[u8]) -> Option<BestBlockChanged> {
+    match tree.verify_header(header.to_vec(), NOW).unwrap() {
+        HeaderVerifySuccess::Insert { insert, .. } => {
+            let best_block_change = insert.best_block_change().cloned();
+            insert.insert(());
+            best_block_change
+        }
+        HeaderVerifySuccess::Duplicate => panic!(),
+    }
+}
+
+/// Builds a [`NonFinalizedTree`] whose finalized block is the genesis block, and inserts all the
+/// non-genesis blocks of `chain` into it.
+fn tree_with_chain(authorities: &Authorities, chain: &[Vec<u8>]) -> NonFinalizedTree<()> {
+    let mut tree = authorities.tree();
+    for header in &chain[1..] {
+        insert(&mut tree, header);
+    }
+    tree
+}
+
+/// Builds a GrandPa digest log item.
+fn grandpa_log(log: header::GrandpaConsensusLog) -> Vec<header::DigestItem> {
+    vec![header::DigestItem::GrandpaConsensus(log)]
+}
+
+#[test]
+fn fork_choice_by_babe_weight() {
+    let authorities = Authorities::new();
+    let mut tree = authorities.tree();
+
+    let a1 = authorities.block(&genesis(), 0, false, Vec::new());
+    let a2 = authorities.block(&a1, 1, false, Vec::new());
+    let a3 = authorities.block(&a2, 2, false, Vec::new());
+    for header in &[&a1, &a2, &a3] {
+        insert(&mut tree, header);
+    }
+    assert_eq!(tree.best_block_hash(), hash(&a3));
+
+    // A single primary slot claim outweighs any number of secondary slot claims.
+    let b1 = authorities.block(&genesis(), 4, true, Vec::new());
+    let change = insert(&mut tree, &b1).unwrap();
+    assert_eq!(change.old, hash(&a3));
+    assert_eq!(change.new, hash(&b1));
+    assert_eq!(change.reorg_depth, 3);
+    assert_eq!(tree.best_block_hash(), hash(&b1));
+
+    // With an equal weight, the highest block is the best.
+    let b2 = authorities.block(&b1, 5, false, Vec::new());
+    let change = insert(&mut tree, &b2).unwrap();
+    assert_eq!(change.new, hash(&b2));
+    assert_eq!(change.reorg_depth, 0);
+
+    let a4 = authorities.block(&a3, 3, false, Vec::new());
+    assert!(insert(&mut tree, &a4).is_none());
+    assert_eq!(tree.best_block_hash(), hash(&b2));
+
+    let a5 = authorities.block(&a4, 6, true, Vec::new());
+    let change = insert(&mut tree, &a5).unwrap();
+    assert_eq!(change.old, hash(&b2));
+    assert_eq!(change.new, hash(&a5));
+    assert_eq!(change.reorg_depth, 2);
+}
+
+#[test]
+fn best_block_recomputed_on_finalization() {
+    let authorities = Authorities::new();
+    let mut tree = authorities.tree();
+
+    let a1 = authorities.block(&genesis(), 0, false, Vec::new());
+    let a2 = authorities.block(&a1, 1, false, Vec::new());
+    let a3 = authorities.block(&a2, 2, false, Vec::new());
+    let b1 = authorities.block(&genesis(), 3, true, Vec::new());
+    for header in &[&a1, &a2, &a3, &b1] {
+        insert(&mut tree, header);
+    }
+    assert_eq!(tree.best_block_hash(), hash(&b1));
+
+    // Finalizing a block of the other fork prunes the best block.
+    let mut finalized = tree.set_finalized_block(&hash(&a1)).unwrap();
+    let change = finalized.best_block_change().cloned().unwrap();
+    assert_eq!(finalized.by_ref().count(), 1);
+    drop(finalized);
+    assert_eq!(change.old, hash(&b1));
+    assert_eq!(change.new, hash(&a3));
+    assert_eq!(change.reorg_depth, 1);
+    assert_eq!(tree.best_block_hash(), hash(&a3));
+
+    // Finalizing an ancestor of the best block doesn't change it.
+    let finalized = tree.set_finalized_block(&hash(&a2)).unwrap();
+    assert!(finalized.best_block_change().is_none());
+    drop(finalized);
+    assert_eq!(tree.best_block_hash(), hash(&a3));
+}
+
+#[test]
+fn fork_choice_ties_broken_by_hash() {
+    let authorities = Authorities::new();
+
+    // Two siblings with the same weight and number. The one with the highest hash is the best,
+    // whatever the order in which they are inserted.
+    let a1 = authorities.block(&genesis(), 0, false, Vec::new());
+    let b1 = authorities.block(&genesis(), 1, false, Vec::new());
+    let highest = std::cmp::max(hash(&a1), hash(&b1));
+    for (first, second) in &[(&a1, &b1), (&b1, &a1)] {
+        let mut tree = authorities.tree();
+        insert(&mut tree, first);
+        insert(&mut tree, second);
+        assert_eq!(tree.best_block_hash(), highest);
+    }
+
+    // The same rule applies when the best block is recomputed on finalization.
+    let c2 = authorities.block(&a1, 2, false, Vec::new());
+    let d2 = authorities.block(&a1, 3, false, Vec::new());
+    let e1 = authorities.block(&genesis(), 4, true, Vec::new());
+    let highest = std::cmp::max(hash(&c2), hash(&d2));
+    for (first, second) in &[(&c2, &d2), (&d2, &c2)] {
+        let mut tree = authorities.tree();
+        for header in &[&a1, first, second, &e1] {
+            insert(&mut tree, header);
+        }
+        assert_eq!(tree.best_block_hash(), hash(&e1));
+        let finalized = tree.set_finalized_block(&hash(&a1)).unwrap();
+        drop(finalized);
+        assert_eq!(tree.best_block_hash(), highest);
+    }
+}
+
+#[test]
+fn reorg_reports_retracted_and_enacted_blocks() {
+    let authorities = Authorities::new();
+    let mut tree = authorities.tree();
+
+    let a1 = authorities.block(&genesis(), 0, false, Vec::new());
+    let a2 = authorities.block(&a1, 1, false, Vec::new());
+    let a3 = authorities.block(&a2, 2, false, Vec::new());
+
+    // Block #1 is the first best block. All the blocks from the finalized block are enacted.
+    let change = insert(&mut tree, &a1).unwrap();
+    assert_eq!(change.old, hash(&genesis()));
+    assert!(change.retracted.is_empty());
+    assert_eq!(change.enacted, vec![hash(&a1)]);
+
+    for header in &[&a2, &a3] {
+        let change = insert(&mut tree, header).unwrap();
+        assert!(change.retracted.is_empty());
+        assert_eq!(change.enacted, vec![hash(header)]);
+    }
+
+    // Fork starting after block #1.
+    let b2 = authorities.block(&a1, 3, false, Vec::new());
+    assert!(insert(&mut tree, &b2).is_none());
+    let b3 = authorities.block(&b2, 4, true, Vec::new());
+    let change = insert(&mut tree, &b3).unwrap();
+    assert_eq!(
+        change,
+        BestBlockChanged {
+            old: hash(&a3),
+            new: hash(&b3),
+            reorg_depth: 2,
+            retracted: vec![hash(&a3), hash(&a2)],
+            enacted: vec![hash(&b2), hash(&b3)],
+        }
+    );
+
+    // Switching back to the first fork.
+    let a4 = authorities.block(&a3, 5, true, Vec::new());
+    let change = insert(&mut tree, &a4).unwrap();
+    assert_eq!(change.retracted, vec![hash(&b3), hash(&b2)]);
+    assert_eq!(change.enacted, vec![hash(&a2), hash(&a3), hash(&a4)]);
+}
+
+#[test]
+fn too_far_above_finalized() {
+    let authorities = Authorities::new();
+    let chain = authorities.chain(3, |_| Vec::new());
+    let mut tree = NonFinalizedTree::new(Config {
+        max_non_finalized_depth: Some(NonZeroU64::new(2).unwrap()),
+        ..authorities.config()
+    });
+
+    insert(&mut tree, &chain[1]);
+    insert(&mut tree, &chain[2]);
+
+    assert!(matches!(
+        tree.precheck_header(&chain[3]),
+        Err(HeaderPrecheckError::LimitReached(
+            LimitError::TooFarAboveFinalized {
+                block_number: 3,
+                finalized_block_number: 0,
+            }
+        ))
+    ));
+    assert!(matches!(
+        tree.verify_header(chain[3].clone(), NOW),
+        Err(HeaderVerifyError::LimitReached(
+            LimitError::TooFarAboveFinalized { .. }
+        ))
+    ));
+
+    // Finalizing a block makes room above it.
+    drop(tree.set_finalized_block(&hash(&chain[1])).unwrap());
+    assert!(tree.precheck_header(&chain[3]).is_ok());
+    insert(&mut tree, &chain[3]);
+}
+
+#[test]
+fn too_many_blocks() {
+    let authorities = Authorities::new();
+    let mut tree = NonFinalizedTree::new(Config {
+        max_non_finalized_blocks: Some(NonZeroUsize::new(3).unwrap()),
+        ..authorities.config()
+    });
+
+    let a1 = authorities.block(&genesis(), 0, false, Vec::new());
+    let a2 = authorities.block(&a1, 1, false, Vec::new());
+    let b1 = authorities.block(&genesis(), 2, false, Vec::new());
+    for header in &[&a1, &a2, &b1] {
+        insert(&mut tree, header);
+    }
+
+    // Extending the best chain evicts the tip of the other fork.
+    let a3 = authorities.block(&a2, 3, false, Vec::new());
+    match tree.verify_header(a3.clone(), NOW).unwrap() {
+        HeaderVerifySuccess::Insert { insert, .. } => {
+            assert_eq!(insert.evicted_block_hash(), Some(hash(&b1)));
+            insert.insert(());
+        }
+        HeaderVerifySuccess::Duplicate => panic!(),
+    }
+    assert!(!tree.contains_non_finalized_block(&hash(&b1)));
+
+    // A new fork is less likely than the best chain, which isn't evicted.
+    let c1 = authorities.block(&genesis(), 4, false, Vec::new());
+    assert!(matches!(
+        tree.precheck_header(&c1),
+        Err(HeaderPrecheckError::LimitReached(LimitError::TooManyBlocks))
+    ));
+
+    // All the non-finalized blocks are ancestors of a block built on top of the best block, and
+    // none of them can be evicted.
+    let a4 = authorities.block(&a3, 5, true, Vec::new());
+    assert!(matches!(
+        tree.verify_header(a4.clone(), NOW),
+        Err(HeaderVerifyError::LimitReached(LimitError::TooManyBlocks))
+    ));
+
+    drop(tree.set_finalized_block(&hash(&a1)).unwrap());
+    insert(&mut tree, &a4);
+}
+
+#[test]
+fn precheck_header() {
+    let authorities = Authorities::new();
+    let chain = authorities.chain(2, |_| Vec::new());
+    let mut tree = authorities.tree();
+
+    assert!(tree.precheck_header(&chain[1]).is_ok());
+    insert(&mut tree, &chain[1]);
+    assert!(matches!(
+        tree.precheck_header(&chain[1]),
+        Err(HeaderPrecheckError::Duplicate)
+    ));
+
+    let orphan = authorities.block(&chain[2], 2, false, Vec::new());
+    match tree.precheck_header(&orphan) {
+        Err(HeaderPrecheckError::BadParent { parent_hash }) => {
+            assert_eq!(parent_hash, hash(&chain[2]))
+        }
+        _ => panic!(),
+    }
+
+    assert!(matches!(
+        tree.precheck_header(&[0; 8]),
+        Err(HeaderPrecheckError::InvalidHeader(_))
+    ));
+
+    // The authenticity of the header isn't verified.
+    let mut forged = chain[2].clone();
+    *forged.last_mut().unwrap() ^= 0xff;
+    assert!(tree.precheck_header(&forged).is_ok());
+    assert!(tree.verify_header(forged, NOW).is_err());
+}
+
+#[test]
+fn blocks_from_future_slots_are_refused() {
+    let authorities = Authorities::new();
+    let chain = authorities.chain(3, |_| Vec::new());
+
+    let mut tree = NonFinalizedTree::new(Config {
+        max_slot_drift: 1,
+        ..authorities.config()
+    });
+
+    let slot_duration = Duration::from_millis(SLOT_DURATION_MS);
+    let block1_slot_start = Duration::from_millis(BLOCK1_SLOT_NUMBER * SLOT_DURATION_MS);
+
+    // Block #2 is one slot ahead, which the drift allows.
+    for header in &chain[1..=2] {
+        match tree.verify_header(header.clone(), block1_slot_start) {
+            Ok(HeaderVerifySuccess::Insert { insert, .. }) => insert.insert(()),
+            _ => panic!(),
+        }
+    }
+
+    // Block #3 is two slots ahead. It can be verified as soon as it is only one slot ahead.
+    match tree.verify_header(chain[3].clone(), block1_slot_start) {
+        Err(HeaderVerifyError::FutureBlock { verifiable_from }) => {
+            assert_eq!(verifiable_from, block1_slot_start + slot_duration);
+        }
+        _ => panic!(),
+    }
+    assert!(matches!(
+        tree.verify_header(chain[3].clone(), block1_slot_start + slot_duration),
+        Ok(HeaderVerifySuccess::Insert { .. })
+    ));
+}
+
+#[test]
+fn babe_disabled_authorities() {
+    let authorities = Authorities::new();
+    let disable = || {
+        vec![header::DigestItem::BabeConsensus(
+            header::BabeConsensusLog::OnDisabled(0),
+        )]
+    };
+
+    // Block #2 disables the only authority, which can't author block #3.
+    let chain = authorities.chain(2, |n| if n == 2 { disable() } else { Vec::new() });
+    let mut tree = tree_with_chain(&authorities, &chain);
+    let block3 = authorities.block(&chain[2], 2, false, Vec::new());
+    assert!(matches!(
+        tree.verify_header(block3, NOW),
+        Err(HeaderVerifyError::VerificationFailed(
+            verify::header_only::Error::BabeVerification(
+                verify::babe::VerifyError::DisabledAuthority
+            )
+        ))
+    ));
+
+    // The authority is enabled again once the next epoch starts.
+    let chain = authorities.chain(SLOTS_PER_EPOCH + 2, |n| {
+        if n == SLOTS_PER_EPOCH {
+            disable()
+        } else {
+            Vec::new()
+        }
+    });
+    tree_with_chain(&authorities, &chain);
+}
+
+#[test]
+fn aura_authorities_change() {
+    let aura_authorities = (0..2)
+        .map(|n| test_utils::sr25519_keypair(10 + n))
+        .collect::<Vec<_>>();
+    let authorities_list = |authorities: &[schnorrkel::Keypair]| {
+        authorities
+            .iter()
+            .map(|kp| header::AuraAuthority {
+                public_key: kp.public.to_bytes(),
+            })
+            .collect::<Vec<_>>()
+    };
+
+    let aura_block = |parent: &[u8],
+                      slot_number: u64,
+                      author: &schnorrkel::Keypair,
+                      logs: Vec<header::DigestItem>| {
+        let parent_hash = hash(parent);
+        let number = header::decode(parent).unwrap().number + 1;
+        let mut digest = vec![header::DigestItem::AuraPreDigest(header::AuraPreDigest {
+            slot_number,
+        })];
+        digest.extend(logs);
+        let pre_seal_hash = header::hash_from_scale_encoded_header(test_utils::encode_header(
+            &parent_hash,
+            number,
+            &digest,
+        ));
+        let signature = author.sign(schnorrkel::context::attach_rng(
+            schnorrkel::signing_context(b"substrate").bytes(&pre_seal_hash),
+            rand_chacha::ChaCha20Rng::seed_from_u64(number),
+        ));
+        digest.push(header::DigestItem::AuraSeal(signature.to_bytes()));
+        test_utils::encode_header(&parent_hash, number, &digest)
+    };
+
+    let mut tree = NonFinalizedTree::new(Config {
+        chain_information_config: chain_information::ChainInformationConfig {
+            chain_information: chain_information::ChainInformation {
+                babe_finalized_epoch_randomness_contributions: Some(Vec::new()),
+                ..Authorities::new()
+                    .config()
+                    .chain_information_config
+                    .chain_information
+            },
+            consensus_genesis_config: chain_information::ConsensusGenesisConfiguration::Aura(
+                chain_information::aura::AuraGenesisConfiguration {
+                    authorities_list: authorities_list(&aura_authorities),
+                    slot_duration: NonZeroU64::new(SLOT_DURATION_MS).unwrap(),
+                    signature_scheme: chain_information::aura::AuraSignatureScheme::Sr25519,
+                },
+            ),
+        },
+        ..Authorities::new().config()
+    });
+
+    // Block #1 hands over authorship to the second authority alone, starting from block #2.
+    let new_authorities = &aura_authorities[1..];
+    let announce = header::DigestItem::AuraConsensus(header::AuraConsensusLog::AuthoritiesChange(
+        authorities_list(new_authorities),
+    ));
+    let author = &aura_authorities[usize::try_from(BLOCK1_SLOT_NUMBER % 2).unwrap()];
+    let block1 = aura_block(&genesis(), BLOCK1_SLOT_NUMBER, author, vec![announce]);
+    insert(&mut tree, &block1);
+
+    let block2 = aura_block(
+        &block1,
+        BLOCK1_SLOT_NUMBER + 1,
+        &new_authorities[0],
+        Vec::new(),
+    );
+    insert(&mut tree, &block2);
+
+    // The first authority is no longer allowed to author blocks, even in the slots that used to
+    // be attributed to it.
+    let slot_number = BLOCK1_SLOT_NUMBER + 2;
+    assert_eq!(slot_number % 2, BLOCK1_SLOT_NUMBER % 2);
+    let block3 = aura_block(&block2, slot_number, &aura_authorities[0], Vec::new());
+    assert!(matches!(
+        tree.verify_header(block3, NOW),
+        Err(HeaderVerifyError::VerificationFailed(
+            verify::header_only::Error::AuraVerification(verify::aura::VerifyError::BadSignature)
+        ))
+    ));
+    let block3 = aura_block(&block2, slot_number, &new_authorities[0], Vec::new());
+    insert(&mut tree, &block3);
+}
+
+#[test]
+fn grandpa_forced_change() {
+    let authorities = Authorities::new();
+    let new_authority = test_utils::ed25519_keypair(3);
+
+    // Block #3 forces a change of authorities, triggered at block #5.
+    let chain = authorities.chain(6, |n| {
+        if n == 3 {
+            grandpa_log(header::GrandpaConsensusLog::ForcedChange {
+                reset_block_height: 0,
+                change: test_utils::grandpa_change_to(&new_authority, 2),
+            })
+        } else {
+            Vec::new()
+        }
+    });
+
+    let mut tree = tree_with_chain(&authorities, &chain);
+
+    // Block #4 is still finalized by the old authorities.
+    let justification = test_utils::justification_signed_by(&new_authority, 1, 4, &hash(&chain[4]));
+    assert!(matches!(
+        tree.verify_justification(&justification),
+        Err(JustificationVerifyError::VerificationFailed(
+            justification::verify::Error::NotAuthority(_)
+        ))
+    ));
+
+    // The change doesn't need to wait for block #5 to be finalized, and the old authorities can
+    // no longer finalize anything after it.
+    let justification = authorities.justification(&chain[6]);
+    assert!(matches!(
+        tree.verify_justification(&justification),
+        Err(JustificationVerifyError::VerificationFailed(
+            justification::verify::Error::NotAuthority(_)
+        ))
+    ));
+    let justification = test_utils::justification_signed_by(&new_authority, 1, 6, &hash(&chain[6]));
+    tree.verify_justification(&justification).unwrap().apply();
+
+    let info = chain_information::ChainInformation::from(tree.as_chain_information());
+    assert_eq!(info.grandpa_after_finalized_block_authorities_set_id, 1);
+    assert_eq!(
+        info.grandpa_finalized_triggered_authorities,
+        test_utils::grandpa_change_to(&new_authority, 0).next_authorities
+    );
+    assert!(info.grandpa_finalized_forced_change.is_none());
+}
+
+#[test]
+fn grandpa_forced_change_pending_after_finalized_block() {
+    let authorities = Authorities::new();
+    let new_authority = test_utils::ed25519_keypair(3);
+
+    let chain = authorities.chain(6, |n| {
+        if n == 2 {
+            grandpa_log(header::GrandpaConsensusLog::ForcedChange {
+                reset_block_height: 0,
+                change: test_utils::grandpa_change_to(&new_authority, 3),
+            })
+        } else {
+            Vec::new()
+        }
+    });
+
+    // Finalizing block #3 leaves the forced change, triggered at block #5, pending.
+    let mut tree = tree_with_chain(&authorities, &chain);
+    let justification = authorities.justification(&chain[3]);
+    tree.verify_justification(&justification).unwrap().apply();
+
+    let info = chain_information::ChainInformation::from(tree.as_chain_information());
+    assert_eq!(info.grandpa_after_finalized_block_authorities_set_id, 0);
+    assert_eq!(
+        info.grandpa_finalized_forced_change
+            .as_ref()
+            .map(|(n, _)| *n),
+        Some(5)
+    );
+
+    let justification = test_utils::justification_signed_by(&new_authority, 1, 5, &hash(&chain[5]));
+    tree.verify_justification(&justification).unwrap().apply();
+}
+
+#[test]
+fn grandpa_disabled_authority_still_finalizes() {
+    let authorities = Authorities::new();
+    let chain = authorities.chain(3, |n| {
+        if n == 2 {
+            grandpa_log(header::GrandpaConsensusLog::OnDisabled(0))
+        } else {
+            Vec::new()
+        }
+    });
+
+    let mut tree = tree_with_chain(&authorities, &chain);
+
+    // The disabled authority is tracked, but its precommits are still counted.
+    let justification = authorities.justification(&chain[3]);
+    tree.verify_justification(&justification).unwrap().apply();
+    assert_eq!(
+        tree.as_chain_information()
+            .grandpa_finalized_disabled_authorities,
+        &[0]
+    );
+}
+
+#[test]
+fn grandpa_pause_and_resume() {
+    let authorities = Authorities::new();
+
+    // Block #2 pauses the authorities after block #3 is finalized, and block #6 resumes them
+    // starting from block #7.
+    let chain = authorities.chain(8, |n| match n {
+        2 => grandpa_log(header::GrandpaConsensusLog::Pause(1)),
+        6 => grandpa_log(header::GrandpaConsensusLog::Resume(1)),
+        _ => Vec::new(),
+    });
+
+    let mut tree = tree_with_chain(&authorities, &chain);
+
+    let justification = authorities.justification(&chain[4]);
+    assert!(matches!(
+        tree.verify_justification(&justification),
+        Err(JustificationVerifyError::TooFarAhead {
+            block_to_finalize_number: 3,
+            ..
+        })
+    ));
+
+    let justification = authorities.justification(&chain[3]);
+    tree.verify_justification(&justification).unwrap().apply();
+    assert_eq!(
+        tree.as_chain_information().grandpa_finalized_pause_state,
+        chain_information::GrandpaPauseState::Paused
+    );
+
+    for header in &chain[4..=6] {
+        let justification = authorities.justification(header);
+        assert!(matches!(
+            tree.verify_justification(&justification),
+            Err(JustificationVerifyError::AuthoritiesPaused)
+        ));
+    }
+
+    let justification = authorities.justification(&chain[8]);
+    tree.verify_justification(&justification).unwrap().apply();
+    assert_eq!(
+        tree.as_chain_information().grandpa_finalized_pause_state,
+        chain_information::GrandpaPauseState::Live
+    );
+}
+
+/// Builds a GrandPa commit for the block `target`, containing a single pre-commit for the block
+/// `precommit_target` signed by the GrandPa authority.
+fn grandpa_commit(
+    authorities: &Authorities,
+    target: &[u8],
+    precommit_target: &[u8],
+) -> grandpa::gossip::CommitMessage {
+    let precommit = test_utils::precommit(
+        &authorities.grandpa,
+        1,
+        0,
+        &hash(precommit_target),
+        number(precommit_target),
+    );
+    test_utils::grandpa_commit(1, 0, &hash(target), number(target), vec![precommit])
+}
+
+#[test]
+fn grandpa_commit_with_precommit_on_descendant() {
+    let authorities = Authorities::new();
+    let chain = authorities.chain(5, |_| Vec::new());
+
+    // The headers between the pre-commit target and the commit target are taken from the tree.
+    let mut tree = tree_with_chain(&authorities, &chain);
+    let commit = grandpa_commit(&authorities, &chain[2], &chain[5]);
+    tree.verify_grandpa_commit(&commit).unwrap().apply();
+    assert_eq!(tree.finalized_block_header().number, 2);
+
+    let commit = grandpa_commit(&authorities, &chain[4], &chain[4]);
+    tree.verify_grandpa_commit(&commit).unwrap().apply();
+    assert_eq!(tree.finalized_block_header().number, 4);
+}
+
+#[test]
+fn grandpa_commit_with_precommit_on_ancestor() {
+    let authorities = Authorities::new();
+    let chain = authorities.chain(5, |_| Vec::new());
+
+    let mut tree = tree_with_chain(&authorities, &chain);
+    let commit = grandpa_commit(&authorities, &chain[4], &chain[3]);
+    assert!(matches!(
+        tree.verify_grandpa_commit(&commit),
+        Err(JustificationVerifyError::VerificationFailed(
+            justification::verify::Error::PrecommitNotDescendant(_)
+        ))
+    ));
+    assert_eq!(tree.finalized_block_header().number, 0);
+}
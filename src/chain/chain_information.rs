@@ -38,10 +38,12 @@
 //! They also do not contain the past history of the chain. It is, however, similarly possible to
 //! for instance download the history from other nodes.
 
-use crate::{finality::grandpa, header};
+use crate::{executor, finality::grandpa, header};
 
 use alloc::vec::Vec;
+use core::convert::TryFrom as _;
 
+pub mod aura;
 pub mod babe;
 
 /// Information about the latest finalized block and state found in its ancestors.
@@ -55,6 +57,30 @@ pub struct ChainInformation {
     /// ancestor of the finalized block.
     pub babe_finalized_block1_slot_number: Option<u64>,
 
+    /// Number of blocks between the genesis block (excluded) and the finalized block (included)
+    /// that have been authored using a BABE primary slot claim.
+    ///
+    /// The BABE fork choice rule designates as best block the block with the highest weight
+    /// (i.e. the highest number of primary slot claims in its ancestry), and uses the block
+    /// number in order to break ties.
+    pub babe_finalized_block_weight: u64,
+
+    /// Values that the blocks of the Babe epoch of the finalized block contribute to the
+    /// randomness of the epoch after the next one, from the first block of this epoch to the
+    /// finalized block included. Blocks that don't contain any VRF output, in other words
+    /// secondary plain slot claims, don't contribute anything and are skipped.
+    ///
+    /// `None` if unknown, for example after a warp sync, in which case the randomness announced
+    /// by the first block of the next epoch can't be verified. Should be `Some` and empty if the
+    /// finalized block is block #0.
+    pub babe_finalized_epoch_randomness_contributions: Option<Vec<[u8; 32]>>,
+
+    /// Indices of the authorities of the Babe epoch of the finalized block that have been
+    /// disabled by the finalized block or by its ancestors belonging to the same epoch.
+    ///
+    /// Must be empty if the finalized block is block #0.
+    pub babe_finalized_disabled_authorities: Vec<u32>,
+
     /// Babe epoch information about the epoch the finalized block belongs to.
     ///
     /// Must be `None` if and only if the finalized block is block #0 or belongs to epoch #0.
@@ -67,6 +93,12 @@ pub struct ChainInformation {
     pub babe_finalized_next_epoch_transition:
         Option<(header::BabeNextEpoch, header::BabeNextConfig)>,
 
+    /// List of Aura authorities that must author the block right after the finalized block.
+    ///
+    /// Must be `None` if the chain doesn't use Aura. Can be `None` if the finalized block is
+    /// block #0, in which case the list found in the [`aura::AuraGenesisConfiguration`] is used.
+    pub aura_finalized_authorities_list: Option<Vec<header::AuraAuthority>>,
+
     /// Grandpa authorities set ID of the block right after finalized block.
     ///
     /// If the finalized block is the genesis, should be 0. Otherwise,
@@ -93,6 +125,35 @@ pub struct ChainInformation {
     /// >           `height(block_with_log_item) + N`. If `N` is 0, then the block where the
     /// >           change is triggered is the same as the one where it is scheduled.
     pub grandpa_finalized_scheduled_change: Option<(u64, Vec<header::GrandpaAuthority>)>,
+
+    /// Change in the GrandPa authorities list that has been forced by a block that is already
+    /// finalized, but the change is not triggered yet. Contains the block number where the
+    /// change is to be triggered.
+    ///
+    /// Contrary to scheduled changes, forced changes are triggered as soon as the triggering
+    /// block is imported, without waiting for it to be finalized. The triggering block and its
+    /// descendants must be finalized using the new list of authorities. Forced changes are used
+    /// in order to recover from a situation where the current authorities are unable to
+    /// finalize blocks.
+    ///
+    /// At most one of this field and [`ChainInformation::grandpa_finalized_scheduled_change`]
+    /// can be `Some`. The block height must always be strictly superior to the height found in
+    /// [`ChainInformation::finalized_block_header`].
+    pub grandpa_finalized_forced_change: Option<(u64, Vec<header::GrandpaAuthority>)>,
+
+    /// Indices within [`ChainInformation::grandpa_finalized_triggered_authorities`] of the
+    /// authorities that have been disabled by the finalized block or by its ancestors.
+    ///
+    /// This information is tracked for informative purposes only. Like Substrate, the
+    /// precommits of disabled authorities are still counted when verifying justifications and
+    /// commits.
+    ///
+    /// Disabled authorities are enabled again when the list of authorities changes.
+    pub grandpa_finalized_disabled_authorities: Vec<u64>,
+
+    /// Whether the GrandPa authorities that need to finalize the block right after the finalized
+    /// block are voting.
+    pub grandpa_finalized_pause_state: GrandpaPauseState,
 }
 
 impl ChainInformation {
@@ -115,11 +176,18 @@ impl ChainInformation {
         Ok(ChainInformation {
             finalized_block_header: crate::calculate_genesis_block_header(genesis_storage),
             babe_finalized_block1_slot_number: None,
+            babe_finalized_block_weight: 0,
+            babe_finalized_epoch_randomness_contributions: Some(Vec::new()),
+            babe_finalized_disabled_authorities: Vec::new(),
             babe_finalized_block_epoch_information: None,
             babe_finalized_next_epoch_transition: None,
+            aura_finalized_authorities_list: None,
             grandpa_after_finalized_block_authorities_set_id: 0,
             grandpa_finalized_scheduled_change: None,
             grandpa_finalized_triggered_authorities: grandpa_genesis_config.initial_authorities,
+            grandpa_finalized_forced_change: None,
+            grandpa_finalized_disabled_authorities: Vec::new(),
+            grandpa_finalized_pause_state: GrandpaPauseState::Live,
         })
     }
 }
@@ -129,12 +197,20 @@ impl<'a> From<ChainInformationRef<'a>> for ChainInformation {
         ChainInformation {
             finalized_block_header: info.finalized_block_header.into(),
             babe_finalized_block1_slot_number: info.babe_finalized_block1_slot_number,
+            babe_finalized_block_weight: info.babe_finalized_block_weight,
+            babe_finalized_epoch_randomness_contributions: info
+                .babe_finalized_epoch_randomness_contributions
+                .map(|c| c.to_vec()),
+            babe_finalized_disabled_authorities: info.babe_finalized_disabled_authorities.to_vec(),
             babe_finalized_block_epoch_information: info
                 .babe_finalized_block_epoch_information
                 .map(|(e, c)| (e.clone().into(), c)),
             babe_finalized_next_epoch_transition: info
                 .babe_finalized_next_epoch_transition
                 .map(|(e, c)| (e.clone().into(), c)),
+            aura_finalized_authorities_list: info
+                .aura_finalized_authorities_list
+                .map(|l| l.to_vec()),
             grandpa_after_finalized_block_authorities_set_id: info
                 .grandpa_after_finalized_block_authorities_set_id,
             grandpa_finalized_triggered_authorities: info
@@ -143,15 +219,61 @@ impl<'a> From<ChainInformationRef<'a>> for ChainInformation {
             grandpa_finalized_scheduled_change: info
                 .grandpa_finalized_scheduled_change
                 .map(|(n, l)| (n, l.into())),
+            grandpa_finalized_forced_change: info
+                .grandpa_finalized_forced_change
+                .map(|(n, l)| (n, l.into())),
+            grandpa_finalized_disabled_authorities: info
+                .grandpa_finalized_disabled_authorities
+                .to_vec(),
+            grandpa_finalized_pause_state: info.grandpa_finalized_pause_state,
         }
     }
 }
 
+/// State of the GrandPa pause mechanism.
+///
+/// The runtime can ask the authorities to stop voting, for example during an upgrade of the
+/// chain, then to resume voting later. While the authorities are paused, no block can be
+/// finalized.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum GrandpaPauseState {
+    /// Authorities are voting.
+    Live,
+    /// Authorities stop voting after the block with the given height has been finalized. This
+    /// block can still be finalized, but its descendants can't.
+    PendingPause {
+        /// Height of the last block that can be finalized.
+        trigger_block_height: u64,
+    },
+    /// Authorities aren't voting.
+    Paused,
+    /// Authorities resume voting once the block with the given height has been imported. This
+    /// block and its descendants can be finalized.
+    PendingResume {
+        /// Height of the first block that can be finalized again.
+        trigger_block_height: u64,
+    },
+}
+
 /// Error when building the chain information from the genesis storage.
 #[derive(Debug, derive_more::Display)]
 pub enum FromGenesisStorageError {
     /// Error when retrieving the GrandPa configuration.
     GrandpaConfigLoad(grandpa::chain_config::FromGenesisStorageError),
+    /// Runtime couldn't be found in the genesis storage.
+    RuntimeNotFound,
+    /// Failed to decode heap pages from the genesis storage.
+    HeapPagesDecode(core::array::TryFromSliceError),
+    /// Error when initializing the virtual machine.
+    VmInitialization(executor::NewErr),
+    /// Error while retrieving the list of APIs that the runtime supports.
+    CoreVersionLoad,
+    /// Error when retrieving the BABE configuration.
+    BabeConfigLoad(babe::FromVmPrototypeError),
+    /// Error when retrieving the Aura configuration.
+    AuraConfigLoad(aura::FromVmPrototypeError),
+    /// The runtime supports neither BABE nor Aura.
+    UnknownConsensus,
 }
 
 #[derive(Debug, Clone)]
@@ -169,6 +291,15 @@ pub struct ChainInformationRef<'a> {
     /// See equivalent field in [`ChainInformation`].
     pub babe_finalized_block1_slot_number: Option<u64>,
 
+    /// See equivalent field in [`ChainInformation`].
+    pub babe_finalized_block_weight: u64,
+
+    /// See equivalent field in [`ChainInformation`].
+    pub babe_finalized_epoch_randomness_contributions: Option<&'a [[u8; 32]]>,
+
+    /// See equivalent field in [`ChainInformation`].
+    pub babe_finalized_disabled_authorities: &'a [u32],
+
     /// See equivalent field in [`ChainInformation`].
     pub babe_finalized_block_epoch_information:
         Option<(header::BabeNextEpochRef<'a>, header::BabeNextConfig)>,
@@ -177,6 +308,9 @@ pub struct ChainInformationRef<'a> {
     pub babe_finalized_next_epoch_transition:
         Option<(header::BabeNextEpochRef<'a>, header::BabeNextConfig)>,
 
+    /// See equivalent field in [`ChainInformation`].
+    pub aura_finalized_authorities_list: Option<&'a [header::AuraAuthority]>,
+
     /// See equivalent field in [`ChainInformation`].
     pub grandpa_after_finalized_block_authorities_set_id: u64,
 
@@ -185,6 +319,15 @@ pub struct ChainInformationRef<'a> {
 
     /// See equivalent field in [`ChainInformation`].
     pub grandpa_finalized_scheduled_change: Option<(u64, &'a [header::GrandpaAuthority])>,
+
+    /// See equivalent field in [`ChainInformation`].
+    pub grandpa_finalized_forced_change: Option<(u64, &'a [header::GrandpaAuthority])>,
+
+    /// See equivalent field in [`ChainInformation`].
+    pub grandpa_finalized_disabled_authorities: &'a [u64],
+
+    /// See equivalent field in [`ChainInformation`].
+    pub grandpa_finalized_pause_state: GrandpaPauseState,
 }
 
 impl<'a> From<&'a ChainInformation> for ChainInformationRef<'a> {
@@ -192,6 +335,12 @@ impl<'a> From<&'a ChainInformation> for ChainInformationRef<'a> {
         ChainInformationRef {
             finalized_block_header: (&info.finalized_block_header).into(),
             babe_finalized_block1_slot_number: info.babe_finalized_block1_slot_number,
+            babe_finalized_block_weight: info.babe_finalized_block_weight,
+            babe_finalized_epoch_randomness_contributions: info
+                .babe_finalized_epoch_randomness_contributions
+                .as_ref()
+                .map(|c| &c[..]),
+            babe_finalized_disabled_authorities: &info.babe_finalized_disabled_authorities,
             babe_finalized_block_epoch_information: info
                 .babe_finalized_block_epoch_information
                 .as_ref()
@@ -200,6 +349,10 @@ impl<'a> From<&'a ChainInformation> for ChainInformationRef<'a> {
                 .babe_finalized_next_epoch_transition
                 .as_ref()
                 .map(|(i, c)| (i.into(), *c)),
+            aura_finalized_authorities_list: info
+                .aura_finalized_authorities_list
+                .as_ref()
+                .map(|l| &l[..]),
             grandpa_after_finalized_block_authorities_set_id: info
                 .grandpa_after_finalized_block_authorities_set_id,
             grandpa_finalized_triggered_authorities: &info.grandpa_finalized_triggered_authorities,
@@ -207,6 +360,12 @@ impl<'a> From<&'a ChainInformation> for ChainInformationRef<'a> {
                 .grandpa_finalized_scheduled_change
                 .as_ref()
                 .map(|(n, l)| (*n, &l[..])),
+            grandpa_finalized_forced_change: info
+                .grandpa_finalized_forced_change
+                .as_ref()
+                .map(|(n, l)| (*n, &l[..])),
+            grandpa_finalized_disabled_authorities: &info.grandpa_finalized_disabled_authorities,
+            grandpa_finalized_pause_state: info.grandpa_finalized_pause_state,
         }
     }
 }
@@ -217,8 +376,8 @@ pub struct ChainInformationConfig {
     /// Information about the latest finalized block.
     pub chain_information: ChainInformation,
 
-    /// Configuration for BABE, retrieved from the genesis block.
-    pub babe_genesis_config: babe::BabeGenesisConfiguration,
+    /// Configuration of the consensus algorithm, retrieved from the genesis block.
+    pub consensus_genesis_config: ConsensusGenesisConfiguration,
 }
 
 impl ChainInformationConfig {
@@ -229,19 +388,83 @@ impl ChainInformationConfig {
     pub fn from_genesis_storage<'a>(
         genesis_storage: impl Iterator<Item = (&'a [u8], &'a [u8])> + Clone,
     ) -> Result<Self, FromGenesisStorageError> {
-        let babe_genesis_config = babe::BabeGenesisConfiguration::from_genesis_storage(|k| {
+        let consensus_genesis_config = ConsensusGenesisConfiguration::from_genesis_storage(|k| {
             genesis_storage
                 .clone()
                 .find(|(k2, _)| *k2 == k)
                 .map(|(_, v)| v.to_owned())
-        })
-        .unwrap(); // TODO: no unwrap
+        })?;
 
         let chain_information = ChainInformation::from_genesis_storage(genesis_storage)?;
 
         Ok(ChainInformationConfig {
             chain_information,
-            babe_genesis_config,
+            consensus_genesis_config,
         })
     }
 }
+
+/// Configuration of the consensus algorithm of a chain, as extracted from the genesis block.
+#[derive(Debug, Clone)]
+pub enum ConsensusGenesisConfiguration {
+    /// Chain uses the Aura consensus algorithm.
+    Aura(aura::AuraGenesisConfiguration),
+    /// Chain uses the BABE consensus algorithm.
+    Babe(babe::BabeGenesisConfiguration),
+}
+
+impl ConsensusGenesisConfiguration {
+    /// Retrieves the configuration from the storage of the genesis block.
+    ///
+    /// The consensus algorithm is determined from the list of APIs that the runtime of the
+    /// genesis block supports.
+    ///
+    /// Must be passed a closure that returns the storage value corresponding to the given key in
+    /// the genesis block storage.
+    pub fn from_genesis_storage(
+        mut genesis_storage_access: impl FnMut(&[u8]) -> Option<Vec<u8>>,
+    ) -> Result<Self, FromGenesisStorageError> {
+        let wasm_code =
+            genesis_storage_access(b":code").ok_or(FromGenesisStorageError::RuntimeNotFound)?;
+        let heap_pages = if let Some(bytes) = genesis_storage_access(b":heappages") {
+            u64::from_le_bytes(
+                <[u8; 8]>::try_from(&bytes[..])
+                    .map_err(FromGenesisStorageError::HeapPagesDecode)?,
+            )
+        } else {
+            executor::DEFAULT_HEAP_PAGES
+        };
+        let vm = executor::WasmVmPrototype::new(&wasm_code, heap_pages)
+            .map_err(FromGenesisStorageError::VmInitialization)?;
+
+        let (core_version, vm) =
+            executor::core_version(vm).map_err(|()| FromGenesisStorageError::CoreVersionLoad)?;
+
+        // Runtime APIs are identified by the 64 bits BLAKE2 hash of their name.
+        let supports_api = |name: &[u8]| {
+            let id = blake2_rfc::blake2b::blake2b(8, &[], name);
+            core_version
+                .apis
+                .iter()
+                .any(|(api, _)| &api[..] == id.as_bytes())
+        };
+
+        if supports_api(b"BabeApi") {
+            let (config, _) = babe::BabeGenesisConfiguration::from_virtual_machine_prototype(
+                vm,
+                genesis_storage_access,
+            )
+            .map_err(FromGenesisStorageError::BabeConfigLoad)?;
+            Ok(ConsensusGenesisConfiguration::Babe(config))
+        } else if supports_api(b"AuraApi") {
+            let (config, _) = aura::AuraGenesisConfiguration::from_virtual_machine_prototype(
+                vm,
+                genesis_storage_access,
+            )
+            .map_err(FromGenesisStorageError::AuraConfigLoad)?;
+            Ok(ConsensusGenesisConfiguration::Aura(config))
+        } else {
+            Err(FromGenesisStorageError::UnknownConsensus)
+        }
+    }
+}
@@ -112,11 +112,42 @@ impl BabeGenesisConfiguration {
         Ok((outcome, vm_prototype))
     }
 
+    /// Builds a configuration from its individual components, without going through a runtime.
+    #[cfg(test)]
+    pub(crate) fn from_parts(
+        slot_duration: u64,
+        epoch_length: u64,
+        c: (u64, u64),
+        allowed_slots: header::BabeAllowedSlots,
+        epoch0_information: header::BabeNextEpoch,
+    ) -> Self {
+        BabeGenesisConfiguration {
+            inner: OwnedGenesisConfiguration {
+                slot_duration,
+                epoch_length,
+                c,
+                genesis_authorities: epoch0_information
+                    .authorities
+                    .iter()
+                    .map(|a| (a.public_key, a.weight))
+                    .collect(),
+                randomness: epoch0_information.randomness,
+                allowed_slots,
+            },
+            epoch0_information,
+        }
+    }
+
     /// Returns the number of slots contained in each epoch.
     pub fn slots_per_epoch(&self) -> u64 {
         self.inner.epoch_length
     }
 
+    /// Returns the duration of each slot, in milliseconds.
+    pub fn slot_duration(&self) -> u64 {
+        self.inner.slot_duration
+    }
+
     /// Returns the configuration of epoch number 0.
     pub fn epoch0_configuration(&self) -> header::BabeNextConfig {
         header::BabeNextConfig {
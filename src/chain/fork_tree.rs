@@ -126,6 +126,12 @@ impl<T> ForkTree<T> {
         self.nodes.iter().map(|n| &n.1.data)
     }
 
+    /// Returns an iterator to all the node values, alongside with their index, without any
+    /// specific order.
+    pub fn iter_unordered(&self) -> impl Iterator<Item = (NodeIndex, &T)> {
+        self.nodes.iter().map(|(i, n)| (NodeIndex(i), &n.data))
+    }
+
     /// Returns the value of the node with the given index.
     pub fn get(&self, index: NodeIndex) -> Option<&T> {
         self.nodes.get(index.0).map(|n| &n.data)
@@ -136,6 +142,41 @@ impl<T> ForkTree<T> {
         self.nodes.get_mut(index.0).map(|n| &mut n.data)
     }
 
+    /// Returns `true` if the given node doesn't have any child.
+    ///
+    /// # Panic
+    ///
+    /// Panics if the [`NodeIndex`] is invalid.
+    ///
+    pub fn is_leaf(&self, node_index: NodeIndex) -> bool {
+        self.nodes[node_index.0].first_child.is_none()
+    }
+
+    /// Removes the given node from the tree and returns its value.
+    ///
+    /// # Panic
+    ///
+    /// Panics if the [`NodeIndex`] is invalid or if the node has children.
+    ///
+    pub fn remove_leaf(&mut self, node_index: NodeIndex) -> T {
+        assert!(self.is_leaf(node_index));
+        let node = self.nodes.remove(node_index.0);
+
+        if let Some(previous_sibling) = node.previous_sibling {
+            self.nodes[previous_sibling].next_sibling = node.next_sibling;
+        } else if let Some(parent) = node.parent {
+            self.nodes[parent].first_child = node.next_sibling;
+        } else {
+            self.first_root = node.next_sibling;
+        }
+
+        if let Some(next_sibling) = node.next_sibling {
+            self.nodes[next_sibling].previous_sibling = node.previous_sibling;
+        }
+
+        node.data
+    }
+
     /// Removes from the tree:
     ///
     /// - The node passed as parameter.
@@ -557,4 +598,40 @@ mod tests {
     }
 
     // TODO: add more testing for the order of elements returned by `prune_ancestors`
+
+    #[test]
+    fn remove_leaf() {
+        let mut tree = ForkTree::new();
+
+        let node0 = tree.insert(None, 0);
+        let node1 = tree.insert(Some(node0), 1);
+        let node2 = tree.insert(Some(node0), 2);
+        let node3 = tree.insert(Some(node0), 3);
+        let node4 = tree.insert(Some(node2), 4);
+
+        assert!(!tree.is_leaf(node2));
+        assert_eq!(tree.remove_leaf(node4), 4);
+        assert!(tree.is_leaf(node2));
+
+        assert_eq!(tree.remove_leaf(node2), 2);
+        assert!(tree.get(node2).is_none());
+        assert!(!tree.is_leaf(node0));
+
+        let node5 = tree.insert(Some(node3), 5);
+        assert_eq!(
+            tree.node_to_root_path(node5).collect::<Vec<_>>(),
+            &[node5, node3, node0]
+        );
+
+        assert_eq!(tree.remove_leaf(node1), 1);
+        assert_eq!(tree.remove_leaf(node5), 5);
+        assert_eq!(tree.remove_leaf(node3), 3);
+        assert!(tree.is_leaf(node0));
+        assert_eq!(tree.remove_leaf(node0), 0);
+        assert!(tree.is_empty());
+
+        let node6 = tree.insert(None, 6);
+        assert_eq!(tree.iter().collect::<Vec<_>>(), &[&6]);
+        assert!(tree.prune_ancestors(node6).next().is_some());
+    }
 }
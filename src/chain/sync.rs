@@ -45,11 +45,12 @@
 //! nodes and not only rely on incoming connections, as there is otherwise the possibility of a
 //! single actor controlling all said incoming connections.
 
-// TODO: this doc ^ is correct but assumes that there exist multiple syncing strategies, while at
-//       the time of writing only one is implemented
-
-// TODO: pub mod full_all_forks;
+pub mod all_forks;
 pub mod full_optimistic;
 pub mod headers_optimistic;
 // TODO: maybe shouldn't be pub, but creates doc-link errors if private
 pub mod optimistic;
+pub mod state_sync;
+pub mod warp_sync;
+
+mod tests;
@@ -0,0 +1,960 @@
+// Substrate-lite
+// Copyright (C) 2019-2020  Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! All-forks headers syncing.
+//!
+//! Contrary to the optimistic syncing strategies, the [`AllForksSync`] doesn't assume that all
+//! sources form the same chain. Instead, it tracks all the blocks announced by all the sources,
+//! and verifies the headers of all the forks of the chain.
+//!
+//! This syncing strategy is appropriate when the local chain is close to the head of the chain,
+//! where forks are normal. It is however inefficient when the local chain is far behind, in
+//! which case an optimistic syncing strategy should be used.
+//!
+//! # Usage
+//!
+//! Sources of blocks must be registered using [`AllForksSync::add_source`] and unregistered
+//! using [`AllForksSync::remove_source`]. Whenever a source announces a block, call
+//! [`AllForksSync::block_announce`].
+//!
+//! Blocks whose parent is unknown are called *disjoint*. The [`AllForksSync`] keeps track of
+//! these blocks, and asks the API user, through [`AllForksSync::next_request_action`], to
+//! request their ancestors from the sources. The response to these requests must be passed to
+//! [`AllForksSync::finish_ancestry_search`].
+//!
+//! Blocks whose parent is known are verified by calling [`AllForksSync::process_one`].
+//!
+//! GrandPa commits received from the network, for example through the GrandPa gossip protocol,
+//! can be passed to [`AllForksSync::grandpa_commit`] in order to finalize blocks without waiting
+//! for a justification.
+
+use super::super::{blocks_tree, chain_information};
+use crate::{finality::grandpa, header};
+
+use alloc::{collections::BTreeMap, vec::Vec};
+use core::{
+    cmp,
+    convert::TryFrom as _,
+    fmt,
+    num::{NonZeroU32, NonZeroU64, NonZeroUsize},
+    time::Duration,
+};
+
+/// Configuration for the [`AllForksSync`].
+#[derive(Debug)]
+pub struct Config {
+    /// Information about the latest finalized block and its ancestors.
+    pub chain_information_config: chain_information::ChainInformationConfig,
+
+    /// Pre-allocated capacity for the number of block sources.
+    pub sources_capacity: usize,
+
+    /// Pre-allocated capacity for the number of non-finalized blocks.
+    pub blocks_capacity: usize,
+
+    /// Maximum number of non-finalized blocks, or `None` for no limit.
+    /// See [`blocks_tree::Config::max_non_finalized_blocks`].
+    pub max_non_finalized_blocks: Option<NonZeroUsize>,
+
+    /// Maximum difference between the height of a non-finalized block and the height of the
+    /// finalized block, or `None` for no limit.
+    /// See [`blocks_tree::Config::max_non_finalized_depth`].
+    pub max_non_finalized_depth: Option<NonZeroU64>,
+
+    /// Number of slots the Aura or BABE slot of a block is allowed to be ahead of the current slot.
+    /// See [`blocks_tree::Config::max_slot_drift`].
+    ///
+    /// Blocks further in the future are kept aside and verified again once their slot comes.
+    pub max_slot_drift: u64,
+
+    /// Maximum number of blocks whose header hasn't been verified yet that are kept in memory.
+    ///
+    /// Blocks announced when this limit is reached are ignored.
+    pub max_disjoint_headers: usize,
+
+    /// Maximum number of blocks returned by a response.
+    ///
+    /// > **Note**: If blocks are requested from the network, this should match the network
+    /// >           protocol enforced limit.
+    pub blocks_request_granularity: NonZeroU32,
+}
+
+/// All-forks headers syncing.
+pub struct AllForksSync<TRq, TSrc> {
+    /// Chain containing the verified blocks.
+    chain: blocks_tree::NonFinalizedTree<()>,
+
+    /// List of sources of blocks.
+    sources: slab::Slab<Source<TSrc>>,
+
+    /// Blocks that haven't been verified yet, indexed by their number and hash.
+    ///
+    /// The parent of each block in this list is either the latest finalized block, a block in
+    /// [`AllForksSync::chain`], or an other block in this list.
+    disjoint_blocks: BTreeMap<(u64, [u8; 32]), DisjointBlock>,
+
+    /// Requests that are in progress.
+    requests: slab::Slab<Request<TRq>>,
+
+    /// Value passed by [`Config::max_disjoint_headers`].
+    max_disjoint_headers: usize,
+
+    /// Value passed by [`Config::blocks_request_granularity`].
+    blocks_request_granularity: NonZeroU32,
+}
+
+struct Source<TSrc> {
+    user_data: TSrc,
+    /// Number of the best block of this source, as reported by the source.
+    best_block_number: u64,
+    /// Hash of the best block of this source, as reported by the source.
+    best_block_hash: [u8; 32],
+    /// Request in progress towards this source, if any.
+    ongoing_request: Option<RequestId>,
+}
+
+struct DisjointBlock {
+    /// Header of the block, alongside with its decoded parent hash. `None` if the header hasn't
+    /// been downloaded yet, in which case only the number and hash of the block are known.
+    header: Option<DisjointBlockHeader>,
+    /// List of sources that are known to have this block.
+    known_by: Vec<SourceId>,
+}
+
+struct DisjointBlockHeader {
+    parent_hash: [u8; 32],
+    scale_encoded_header: Vec<u8>,
+    scale_encoded_justification: Option<Vec<u8>>,
+    /// If `Some`, the block has been found to belong to a future slot, and must not be verified
+    /// again before the given time since the Unix epoch.
+    verifiable_from: Option<Duration>,
+}
+
+struct Request<TRq> {
+    user_data: TRq,
+    source: SourceId,
+    /// Number of the first requested block.
+    first_block_number: u64,
+    /// Hash of the first requested block.
+    first_block_hash: [u8; 32],
+}
+
+impl<TRq, TSrc> AllForksSync<TRq, TSrc> {
+    /// Builds a new [`AllForksSync`].
+    pub fn new(config: Config) -> Self {
+        let chain = blocks_tree::NonFinalizedTree::new(blocks_tree::Config {
+            chain_information_config: config.chain_information_config,
+            blocks_capacity: config.blocks_capacity,
+            max_non_finalized_blocks: config.max_non_finalized_blocks,
+            max_non_finalized_depth: config.max_non_finalized_depth,
+            max_slot_drift: config.max_slot_drift,
+        });
+
+        AllForksSync {
+            chain,
+            sources: slab::Slab::with_capacity(config.sources_capacity),
+            disjoint_blocks: BTreeMap::new(),
+            requests: slab::Slab::with_capacity(config.sources_capacity),
+            max_disjoint_headers: config.max_disjoint_headers,
+            blocks_request_granularity: config.blocks_request_granularity,
+        }
+    }
+
+    /// Builds a [`chain_information::ChainInformationRef`] struct corresponding to the current
+    /// latest finalized block. Can later be used to reconstruct a chain.
+    pub fn as_chain_information(&self) -> chain_information::ChainInformationRef {
+        self.chain.as_chain_information()
+    }
+
+    /// Returns the header of the finalized block.
+    pub fn finalized_block_header(&self) -> header::HeaderRef {
+        self.chain.finalized_block_header()
+    }
+
+    /// Returns the header of the best block.
+    pub fn best_block_header(&self) -> header::HeaderRef {
+        self.chain.best_block_header()
+    }
+
+    /// Returns the hash of the best block.
+    pub fn best_block_hash(&self) -> [u8; 32] {
+        self.chain.best_block_hash()
+    }
+
+    /// Inform the [`AllForksSync`] of a new potential source of blocks.
+    ///
+    /// The `best_block_number` and `best_block_hash` are the best block of the source, as
+    /// reported by the source itself, for example during a networking handshake.
+    pub fn add_source(
+        &mut self,
+        source: TSrc,
+        best_block_number: u64,
+        best_block_hash: [u8; 32],
+    ) -> SourceId {
+        let source_id = SourceId(self.sources.insert(Source {
+            user_data: source,
+            best_block_number,
+            best_block_hash,
+            ongoing_request: None,
+        }));
+
+        self.insert_unknown_block(source_id, best_block_number, best_block_hash);
+        source_id
+    }
+
+    /// Inform the [`AllForksSync`] that a source of blocks is no longer available.
+    ///
+    /// Returns the user data of the source, and the request that was in progress towards this
+    /// source, if any. This request must no longer be passed to
+    /// [`AllForksSync::finish_ancestry_search`].
+    ///
+    /// # Panic
+    ///
+    /// Panics if the [`SourceId`] is invalid.
+    ///
+    pub fn remove_source(&mut self, source_id: SourceId) -> (TSrc, Option<(RequestId, TRq)>) {
+        let source = self.sources.remove(source_id.0);
+        let request = source
+            .ongoing_request
+            .map(|rq_id| (rq_id, self.requests.remove(rq_id.0).user_data));
+
+        for block in self.disjoint_blocks.values_mut() {
+            block.known_by.retain(|s| *s != source_id);
+        }
+
+        // Blocks whose header is unknown and that no source can provide are discarded, alongside
+        // with their descendants.
+        let unobtainable = self
+            .disjoint_blocks
+            .iter()
+            .filter(|(_, b)| b.header.is_none() && b.known_by.is_empty())
+            .map(|(k, _)| *k)
+            .collect::<Vec<_>>();
+        self.remove_with_descendants(unobtainable);
+
+        (source.user_data, request)
+    }
+
+    /// Returns the user data associated to the given source.
+    ///
+    /// # Panic
+    ///
+    /// Panics if the [`SourceId`] is invalid.
+    ///
+    pub fn source_user_data_mut(&mut self, source_id: SourceId) -> &mut TSrc {
+        &mut self.sources[source_id.0].user_data
+    }
+
+    /// Returns the number and hash of the best block of the given source, as reported by the
+    /// source.
+    ///
+    /// # Panic
+    ///
+    /// Panics if the [`SourceId`] is invalid.
+    ///
+    pub fn source_best_block(&self, source_id: SourceId) -> (u64, &[u8; 32]) {
+        let source = &self.sources[source_id.0];
+        (source.best_block_number, &source.best_block_hash)
+    }
+
+    /// Update the [`AllForksSync`] with the fact that the given source has announced a block.
+    ///
+    /// If `is_best` is true, the block is also considered as the new best block of the source.
+    ///
+    /// # Panic
+    ///
+    /// Panics if the [`SourceId`] is invalid.
+    ///
+    pub fn block_announce(
+        &mut self,
+        source_id: SourceId,
+        announced_scale_encoded_header: Vec<u8>,
+        is_best: bool,
+    ) -> BlockAnnounceOutcome {
+        let decoded = match header::decode(&announced_scale_encoded_header) {
+            Ok(h) => h,
+            Err(err) => return BlockAnnounceOutcome::InvalidHeader(err),
+        };
+
+        let number = decoded.number;
+        let parent_hash = *decoded.parent_hash;
+        let hash = header::hash_from_scale_encoded_header(&announced_scale_encoded_header);
+
+        if is_best {
+            let source = &mut self.sources[source_id.0];
+            source.best_block_number = number;
+            source.best_block_hash = hash;
+        }
+
+        self.insert_header(
+            source_id,
+            number,
+            hash,
+            parent_hash,
+            announced_scale_encoded_header,
+            None,
+        )
+    }
+
+    /// Returns the next ancestry search that should be started, if any.
+    ///
+    /// An ancestry search consists in requesting from a source a block and its ancestors, in
+    /// decreasing block numbers.
+    pub fn next_request_action(&mut self) -> Option<AncestrySearch<TRq, TSrc>> {
+        let sources = &self.sources;
+        let requests = &self.requests;
+
+        // Find a block whose header is unknown, that isn't already being requested, and that
+        // an idle source is known to have.
+        let ((block_number, block_hash), source_id) = self
+            .disjoint_blocks
+            .iter()
+            .filter(|(_, block)| block.header.is_none())
+            .filter(|((_, hash), _)| !requests.iter().any(|(_, rq)| rq.first_block_hash == *hash))
+            .find_map(|(key, block)| {
+                block
+                    .known_by
+                    .iter()
+                    .find(|s| sources[s.0].ongoing_request.is_none())
+                    .map(|s| (*key, *s))
+            })?;
+
+        // Request enough blocks to reach the finalized block, but no more.
+        let finalized_number = self.chain.finalized_block_header().number;
+        let num_blocks = {
+            // Blocks are only ever inserted in `disjoint_blocks` if they are above the finalized
+            // block, and `prune_disjoint_blocks` is called whenever the finalized block changes.
+            debug_assert!(block_number > finalized_number);
+            let num_blocks = cmp::min(
+                u64::from(self.blocks_request_granularity.get()),
+                block_number - finalized_number,
+            );
+            NonZeroU32::new(
+                u32::try_from(num_blocks).expect("capped by blocks_request_granularity"),
+            )
+            .expect("disjoint blocks are always strictly above the finalized block")
+        };
+
+        let source = &mut self.sources[source_id.0];
+        Some(AncestrySearch {
+            source_id,
+            source: &mut source.user_data,
+            first_block_number: block_number,
+            first_block_hash: block_hash,
+            num_blocks,
+            ongoing_request: &mut source.ongoing_request,
+            requests: &mut self.requests,
+        })
+    }
+
+    /// Update the [`AllForksSync`] with the outcome of an ancestry search.
+    ///
+    /// The blocks must be in decreasing block number, and the first block must be the one
+    /// whose hash is [`AncestrySearch::first_block_hash`]. The iterator can be shorter than the
+    /// number of blocks that have been requested.
+    ///
+    /// Returns the user data that was associated to that request.
+    ///
+    /// # Panic
+    ///
+    /// Panics if the [`RequestId`] is invalid.
+    ///
+    pub fn finish_ancestry_search(
+        &mut self,
+        request_id: RequestId,
+        outcome: Result<impl Iterator<Item = RequestSuccessBlock>, RequestFail>,
+    ) -> (TRq, AncestrySearchOutcome) {
+        let request = self.requests.remove(request_id.0);
+        debug_assert_eq!(
+            self.sources[request.source.0].ongoing_request,
+            Some(request_id)
+        );
+        self.sources[request.source.0].ongoing_request = None;
+
+        let blocks = match outcome {
+            Ok(blocks) => blocks,
+            Err(_) => {
+                // The source doesn't have the block. Another source will be tried.
+                self.forget_source_knows(
+                    request.source,
+                    request.first_block_number,
+                    request.first_block_hash,
+                );
+                return (request.user_data, AncestrySearchOutcome::Failed);
+            }
+        };
+
+        let mut expected_number = request.first_block_number;
+        let mut expected_hash = request.first_block_hash;
+        let mut num_inserted = 0;
+
+        for block in blocks {
+            let hash = header::hash_from_scale_encoded_header(&block.scale_encoded_header);
+            if hash != expected_hash {
+                break;
+            }
+
+            // Since the hash matches, the header is necessarily valid.
+            let decoded = match header::decode(&block.scale_encoded_header) {
+                Ok(h) => h,
+                Err(_) => break,
+            };
+            if decoded.number != expected_number {
+                break;
+            }
+
+            let parent_hash = *decoded.parent_hash;
+            let outcome = self.insert_header(
+                request.source,
+                expected_number,
+                hash,
+                parent_hash,
+                block.scale_encoded_header,
+                block.scale_encoded_justification,
+            );
+            num_inserted += 1;
+
+            if !matches!(outcome, BlockAnnounceOutcome::Disjoint) {
+                break;
+            }
+
+            expected_hash = parent_hash;
+            expected_number = match expected_number.checked_sub(1) {
+                Some(n) => n,
+                None => break,
+            };
+        }
+
+        if num_inserted == 0 {
+            // The source has sent back blocks that weren't requested.
+            self.forget_source_knows(
+                request.source,
+                request.first_block_number,
+                request.first_block_hash,
+            );
+            return (request.user_data, AncestrySearchOutcome::SourceMisbehaved);
+        }
+
+        (request.user_data, AncestrySearchOutcome::Success)
+    }
+
+    /// Returns the earliest time, since the Unix epoch, at which a block that has been found to
+    /// belong to a future slot can be verified again, if any.
+    ///
+    /// [`AllForksSync::process_one`] should be called again once this time is reached.
+    pub fn next_future_block_time(&self) -> Option<Duration> {
+        self.disjoint_blocks
+            .values()
+            .filter_map(|block| block.header.as_ref()?.verifiable_from)
+            .min()
+    }
+
+    /// Verifies the header of a single block whose parent is known.
+    ///
+    /// It is encouraged to call this method multiple times in a row until
+    /// [`ProcessOne::Idle`] is returned, interleaving any necessary high-priority operations
+    /// (e.g. processing network sockets) in-between two calls.
+    ///
+    /// `now_from_unix_epoch` must be the time elapsed since the Unix epoch. Blocks whose BABE
+    /// slot is too far ahead of the current slot are kept aside until their slot comes. See
+    /// [`AllForksSync::next_future_block_time`].
+    pub fn process_one(&mut self, now_from_unix_epoch: Duration) -> ProcessOne {
+        // Find the block with the lowest number whose parent is known.
+        let key = {
+            let chain = &self.chain;
+            let finalized_hash = chain.finalized_block_hash();
+            let key = self.disjoint_blocks.iter().find_map(|(key, block)| {
+                let header = block.header.as_ref()?;
+                if header
+                    .verifiable_from
+                    .map_or(false, |from| from > now_from_unix_epoch)
+                {
+                    return None;
+                }
+                if header.parent_hash == finalized_hash
+                    || chain.contains_non_finalized_block(&header.parent_hash)
+                {
+                    Some(*key)
+                } else {
+                    None
+                }
+            });
+
+            match key {
+                Some(k) => k,
+                None => return ProcessOne::Idle,
+            }
+        };
+
+        let (number, hash) = key;
+        let mut block = self.disjoint_blocks.remove(&key).unwrap();
+        let header = block.header.as_ref().unwrap();
+
+        let result = self
+            .chain
+            .verify_header(header.scale_encoded_header.clone(), now_from_unix_epoch);
+        let is_new_best = match result {
+            Ok(blocks_tree::HeaderVerifySuccess::Insert {
+                is_new_best,
+                insert,
+                ..
+            }) => {
+                // The descendants of the block evicted from the chain, if any, can no longer
+                // be verified.
+                let evicted_block_hash = insert.evicted_block_hash();
+                insert.insert(());
+                if let Some(evicted_block_hash) = evicted_block_hash {
+                    self.remove_children_of(evicted_block_hash);
+                }
+                is_new_best
+            }
+            Ok(blocks_tree::HeaderVerifySuccess::Duplicate) => false,
+            Err(blocks_tree::HeaderVerifyError::FutureBlock { verifiable_from }) => {
+                // The block isn't necessarily invalid. Put it back in the queue, and try again
+                // once its slot has come.
+                block.header.as_mut().unwrap().verifiable_from = Some(verifiable_from);
+                self.disjoint_blocks.insert(key, block);
+                return ProcessOne::FutureBlock {
+                    number,
+                    hash,
+                    verifiable_from,
+                };
+            }
+            Err(blocks_tree::HeaderVerifyError::LimitReached(error)) => {
+                // The block isn't invalid, but the chain is full. Its header is forgotten,
+                // while its descendants are kept in case it is announced again once the chain
+                // has room for it.
+                block.header = None;
+                block.known_by.clear();
+                self.disjoint_blocks.insert(key, block);
+                return ProcessOne::LimitReached {
+                    number,
+                    hash,
+                    error,
+                };
+            }
+            Err(error) => {
+                // The descendants of the invalid block are invalid as well.
+                self.remove_children_of(hash);
+                return ProcessOne::HeaderVerifyError {
+                    number,
+                    hash,
+                    error,
+                };
+            }
+        };
+
+        let mut finalized_block = None;
+        if let Some(justification) = block.header.unwrap().scale_encoded_justification {
+            match self.chain.verify_justification(&justification) {
+                Ok(apply) => {
+                    drop(apply.apply());
+                    finalized_block = Some((number, hash));
+                    self.prune_disjoint_blocks();
+                }
+                Err(error) => {
+                    return ProcessOne::JustificationError {
+                        number,
+                        hash,
+                        is_new_best,
+                        error,
+                    };
+                }
+            }
+        }
+
+        ProcessOne::HeaderVerified {
+            number,
+            hash,
+            is_new_best,
+            finalized_block,
+        }
+    }
+
+    /// Verifies the given GrandPa commit and, if it is valid, finalizes the block it targets.
+    ///
+    /// The blocks targeted by the commit and by its pre-commits must have been verified
+    /// beforehand. On success, returns the number and hash of the new finalized block.
+    pub fn grandpa_commit(
+        &mut self,
+        commit: &grandpa::gossip::CommitMessage,
+    ) -> Result<(u64, [u8; 32]), blocks_tree::JustificationVerifyError> {
+        let apply = self.chain.verify_grandpa_commit(commit)?;
+        drop(apply.apply());
+        self.prune_disjoint_blocks();
+        Ok((u64::from(commit.target_number), commit.target_hash))
+    }
+
+    /// Inserts in [`AllForksSync::disjoint_blocks`] a block whose header is known.
+    fn insert_header(
+        &mut self,
+        source_id: SourceId,
+        number: u64,
+        hash: [u8; 32],
+        parent_hash: [u8; 32],
+        scale_encoded_header: Vec<u8>,
+        scale_encoded_justification: Option<Vec<u8>>,
+    ) -> BlockAnnounceOutcome {
+        if number <= self.chain.finalized_block_header().number {
+            return BlockAnnounceOutcome::TooOld;
+        }
+
+        if self.chain.contains_non_finalized_block(&hash) {
+            return BlockAnnounceOutcome::AlreadyInChain;
+        }
+
+        // Headers that the chain would refuse because of its limits aren't worth keeping.
+        if let Err(blocks_tree::HeaderPrecheckError::LimitReached(_)) =
+            self.chain.precheck_header(&scale_encoded_header)
+        {
+            return BlockAnnounceOutcome::Discarded;
+        }
+
+        if !self.disjoint_blocks.contains_key(&(number, hash))
+            && self.disjoint_blocks.len() >= self.max_disjoint_headers
+        {
+            return BlockAnnounceOutcome::Discarded;
+        }
+
+        let block = self
+            .disjoint_blocks
+            .entry((number, hash))
+            .or_insert_with(|| DisjointBlock {
+                header: None,
+                known_by: Vec::new(),
+            });
+        if !block.known_by.contains(&source_id) {
+            block.known_by.push(source_id);
+        }
+        if let Some(header) = &mut block.header {
+            if header.scale_encoded_justification.is_none() {
+                header.scale_encoded_justification = scale_encoded_justification;
+            }
+        } else {
+            block.header = Some(DisjointBlockHeader {
+                parent_hash,
+                scale_encoded_header,
+                scale_encoded_justification,
+                verifiable_from: None,
+            });
+        }
+
+        if parent_hash == self.chain.finalized_block_hash()
+            || self.chain.contains_non_finalized_block(&parent_hash)
+        {
+            return BlockAnnounceOutcome::Queued;
+        }
+
+        // The parent isn't known. If the parent number is inferior or equal to the finalized
+        // block, then this block can never be verified.
+        if number - 1 <= self.chain.finalized_block_header().number {
+            self.remove_with_descendants(vec![(number, hash)]);
+            return BlockAnnounceOutcome::NotFinalizedChain;
+        }
+
+        self.insert_unknown_block(source_id, number - 1, parent_hash);
+        BlockAnnounceOutcome::Disjoint
+    }
+
+    /// Inserts in [`AllForksSync::disjoint_blocks`] a block whose header isn't known, if it
+    /// isn't known yet.
+    fn insert_unknown_block(&mut self, source_id: SourceId, number: u64, hash: [u8; 32]) {
+        if number <= self.chain.finalized_block_header().number
+            || self.chain.contains_non_finalized_block(&hash)
+        {
+            return;
+        }
+
+        if let Some(block) = self.disjoint_blocks.get_mut(&(number, hash)) {
+            if !block.known_by.contains(&source_id) {
+                block.known_by.push(source_id);
+            }
+            return;
+        }
+
+        if self.disjoint_blocks.len() >= self.max_disjoint_headers {
+            return;
+        }
+
+        self.disjoint_blocks.insert(
+            (number, hash),
+            DisjointBlock {
+                header: None,
+                known_by: vec![source_id],
+            },
+        );
+    }
+
+    /// Removes the given source from the list of sources that know the given block. If the
+    /// header of the block is unknown and no source is known to have it, removes the block and
+    /// its descendants.
+    fn forget_source_knows(&mut self, source_id: SourceId, number: u64, hash: [u8; 32]) {
+        let block = match self.disjoint_blocks.get_mut(&(number, hash)) {
+            Some(b) => b,
+            None => return,
+        };
+
+        block.known_by.retain(|s| *s != source_id);
+        if block.header.is_none() && block.known_by.is_empty() {
+            self.remove_with_descendants(vec![(number, hash)]);
+        }
+    }
+
+    /// Removes from [`AllForksSync::disjoint_blocks`] the blocks that can no longer be
+    /// verified after the finalized block has been updated.
+    fn prune_disjoint_blocks(&mut self) {
+        let finalized_number = self.chain.finalized_block_header().number;
+        let finalized_hash = self.chain.finalized_block_hash();
+
+        let mut to_remove = Vec::new();
+        for ((number, hash), block) in &self.disjoint_blocks {
+            if *number <= finalized_number {
+                to_remove.push((*number, *hash));
+                continue;
+            }
+
+            // Blocks right above the finalized block must be its children.
+            if *number == finalized_number + 1
+                && block
+                    .header
+                    .as_ref()
+                    .map_or(false, |h| h.parent_hash != finalized_hash)
+            {
+                to_remove.push((*number, *hash));
+            }
+        }
+
+        self.remove_with_descendants(to_remove);
+    }
+
+    /// Removes from [`AllForksSync::disjoint_blocks`] all the children of the given block, and
+    /// their descendants.
+    fn remove_children_of(&mut self, hash: [u8; 32]) {
+        let children = self
+            .disjoint_blocks
+            .iter()
+            .filter(|(_, b)| b.header.as_ref().map_or(false, |h| h.parent_hash == hash))
+            .map(|(k, _)| *k)
+            .collect();
+        self.remove_with_descendants(children);
+    }
+
+    /// Removes from [`AllForksSync::disjoint_blocks`] the given blocks and all their
+    /// descendants.
+    fn remove_with_descendants(&mut self, mut to_remove: Vec<(u64, [u8; 32])>) {
+        while let Some(key) = to_remove.pop() {
+            if self.disjoint_blocks.remove(&key).is_none() {
+                continue;
+            }
+
+            // Children necessarily have a number equal to the number of their parent plus one.
+            to_remove.extend(
+                self.disjoint_blocks
+                    .range((key.0 + 1, [0; 32])..=(key.0 + 1, [0xff; 32]))
+                    .filter(|(_, b)| b.header.as_ref().map_or(false, |h| h.parent_hash == key.1))
+                    .map(|(k, _)| *k),
+            );
+        }
+    }
+}
+
+impl<TRq, TSrc> fmt::Debug for AllForksSync<TRq, TSrc> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("AllForksSync")
+            .field("best_block_hash", &self.chain.best_block_hash())
+            .field("finalized_block_hash", &self.chain.finalized_block_hash())
+            .field("num_disjoint_blocks", &self.disjoint_blocks.len())
+            .finish()
+    }
+}
+
+/// Identifier for an ongoing request in the [`AllForksSync`].
+#[derive(Debug, Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash)]
+pub struct RequestId(usize);
+
+/// Identifier for a source in the [`AllForksSync`].
+#[derive(Debug, Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash)]
+pub struct SourceId(usize);
+
+/// Ancestry search that should be emitted towards a certain source.
+///
+/// The request has **not** been acknowledged when this struct is returned. You **must** call
+/// [`AncestrySearch::start`] to notify the [`AllForksSync`] that the request has been sent out.
+#[must_use]
+pub struct AncestrySearch<'a, TRq, TSrc> {
+    /// Source where to request blocks from.
+    pub source_id: SourceId,
+    /// User data of source where to request blocks from.
+    pub source: &'a mut TSrc,
+    /// Number of the first block to request.
+    pub first_block_number: u64,
+    /// Hash of the first block to request.
+    pub first_block_hash: [u8; 32],
+    /// Number of blocks to request, including the first one. Always smaller or equal to the
+    /// value passed through [`Config::blocks_request_granularity`].
+    pub num_blocks: NonZeroU32,
+
+    ongoing_request: &'a mut Option<RequestId>,
+    requests: &'a mut slab::Slab<Request<TRq>>,
+}
+
+impl<'a, TRq, TSrc> AncestrySearch<'a, TRq, TSrc> {
+    /// Updates the [`AllForksSync`] with the fact that the request has actually been started.
+    /// Returns the identifier for the request that must later be passed back to
+    /// [`AllForksSync::finish_ancestry_search`].
+    pub fn start(self, user_data: TRq) -> RequestId {
+        let request_id = RequestId(self.requests.insert(Request {
+            user_data,
+            source: self.source_id,
+            first_block_number: self.first_block_number,
+            first_block_hash: self.first_block_hash,
+        }));
+
+        debug_assert!(self.ongoing_request.is_none());
+        *self.ongoing_request = Some(request_id);
+        request_id
+    }
+}
+
+impl<'a, TRq, TSrc> fmt::Debug for AncestrySearch<'a, TRq, TSrc> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("AncestrySearch")
+            .field("source_id", &self.source_id)
+            .field("first_block_number", &self.first_block_number)
+            .field("first_block_hash", &self.first_block_hash)
+            .field("num_blocks", &self.num_blocks)
+            .finish()
+    }
+}
+
+/// Single block in the outcome of an ancestry search. A list of these must be passed to
+/// [`AllForksSync::finish_ancestry_search`].
+#[derive(Debug)]
+pub struct RequestSuccessBlock {
+    /// SCALE-encoded block header.
+    pub scale_encoded_header: Vec<u8>,
+    /// SCALE-encoded justification of this block, or `None` if none is available.
+    pub scale_encoded_justification: Option<Vec<u8>>,
+}
+
+/// Reason why a request has failed.
+#[derive(Debug)]
+pub enum RequestFail {
+    /// Requested blocks aren't available from this source.
+    BlocksUnavailable,
+}
+
+/// Outcome of calling [`AllForksSync::block_announce`].
+#[derive(Debug)]
+pub enum BlockAnnounceOutcome {
+    /// Header is ready to be verified by calling [`AllForksSync::process_one`].
+    Queued,
+    /// The parent of the header isn't known. An ancestry search will be started.
+    Disjoint,
+    /// Announced block is already in the chain.
+    AlreadyInChain,
+    /// Announced block is older than or equal to the latest finalized block.
+    TooOld,
+    /// Announced block isn't a descendant of the latest finalized block.
+    NotFinalizedChain,
+    /// The limit to the number of blocks whose header hasn't been verified yet has been
+    /// reached, or the block exceeds the limits to the number and depth of non-finalized blocks.
+    /// The block has been ignored.
+    Discarded,
+    /// Failed to decode the announced header.
+    InvalidHeader(header::Error),
+}
+
+/// Outcome of calling [`AllForksSync::finish_ancestry_search`].
+#[derive(Debug)]
+pub enum AncestrySearchOutcome {
+    /// The blocks have been added to the list of blocks to verify.
+    Success,
+    /// The request has failed. The source is no longer considered as having the requested
+    /// block.
+    Failed,
+    /// The source has sent back blocks that weren't requested. The source is no longer
+    /// considered as having the requested block.
+    SourceMisbehaved,
+}
+
+/// Outcome of calling [`AllForksSync::process_one`].
+#[derive(Debug)]
+pub enum ProcessOne {
+    /// There was nothing to do.
+    Idle,
+
+    /// A header has been successfully verified and inserted in the chain.
+    HeaderVerified {
+        /// Number of the verified block.
+        number: u64,
+        /// Hash of the verified block.
+        hash: [u8; 32],
+        /// True if the verified block is now the new best block.
+        is_new_best: bool,
+        /// Number and hash of the new finalized block. `None` if the finalized block hasn't
+        /// changed.
+        finalized_block: Option<(u64, [u8; 32])>,
+    },
+
+    /// A header belongs to a BABE slot that is too far in the future. The block has been kept
+    /// aside and will be verified again once `verifiable_from` is reached.
+    FutureBlock {
+        /// Number of the block.
+        number: u64,
+        /// Hash of the block.
+        hash: [u8; 32],
+        /// Time elapsed since the Unix epoch from which the block can be verified.
+        verifiable_from: Duration,
+    },
+
+    /// A header couldn't be inserted because the chain has reached one of its limits. See
+    /// [`Config::max_non_finalized_blocks`] and [`Config::max_non_finalized_depth`].
+    ///
+    /// The header of the block has been discarded, but not its descendants.
+    LimitReached {
+        /// Number of the block.
+        number: u64,
+        /// Hash of the block.
+        hash: [u8; 32],
+        /// Limit that has been reached.
+        error: blocks_tree::LimitError,
+    },
+
+    /// A header has failed to verify. The block and its descendants have been discarded.
+    HeaderVerifyError {
+        /// Number of the invalid block.
+        number: u64,
+        /// Hash of the invalid block.
+        hash: [u8; 32],
+        /// Problem that happened.
+        error: blocks_tree::HeaderVerifyError,
+    },
+
+    /// A header has been successfully verified and inserted in the chain, but its
+    /// justification has failed to verify.
+    JustificationError {
+        /// Number of the verified block.
+        number: u64,
+        /// Hash of the verified block.
+        hash: [u8; 32],
+        /// True if the verified block is now the new best block.
+        is_new_best: bool,
+        /// Problem that happened.
+        error: blocks_tree::JustificationVerifyError,
+    },
+}
@@ -28,14 +28,14 @@
 
 use super::super::{blocks_tree, chain_information};
 use super::optimistic;
-use crate::{executor, header, trie::calculate_root};
+use crate::{executor, finality::grandpa, header, trie, trie::calculate_root, verify};
 
 use alloc::{collections::BTreeMap, vec};
-use core::{convert::TryFrom as _, iter, num::NonZeroU32};
+use core::{convert::TryFrom as _, iter, mem, num::NonZeroU32, time::Duration};
 use hashbrown::{HashMap, HashSet};
 
 pub use optimistic::{
-    FinishRequestOutcome, RequestAction, RequestFail, RequestId, SourceId, Start,
+    FinishRequestOutcome, RequestAction, RequestFail, RequestId, SourceId, SourceReputation, Start,
 };
 
 /// Configuration for the [`OptimisticFullSync`].
@@ -59,6 +59,14 @@ pub struct Config {
     /// >           protocol enforced limit.
     pub blocks_request_granularity: NonZeroU32,
 
+    /// Maximum number of requests that can be in progress at the same time towards a single
+    /// source.
+    ///
+    /// The blocks ahead of the best block are split between all the sources, and requests are
+    /// performed in parallel. Responses can arrive in any order, and are put back in order
+    /// before being verified.
+    pub max_requests_per_source: NonZeroU32,
+
     /// Number of blocks to download ahead of the best block.
     ///
     /// Whenever the latest best block is updated, the state machine will start block
@@ -76,6 +84,10 @@ pub struct Config {
     /// You are encouraged to use something like `rand::random()` to fill this field, except in
     /// situations where determinism/reproducibility is desired.
     pub source_selection_randomness_seed: u64,
+
+    /// Number of slots the Aura or BABE slot of a block is allowed to be ahead of the current slot.
+    /// See [`blocks_tree::Config::max_slot_drift`].
+    pub max_slot_drift: u64,
 }
 
 /// Optimistic headers-only syncing.
@@ -103,6 +115,10 @@ pub struct OptimisticFullSync<TRq, TSrc> {
     /// Underlying helper. Manages sources and requests.
     /// Always `Some`, except during some temporary extractions.
     sync: Option<optimistic::OptimisticSync<TRq, TSrc, RequestSuccessBlock>>,
+
+    /// If `Some`, the next block to verify belongs to a future slot, and must not be verified
+    /// before the given time elapsed since the Unix epoch.
+    future_block_verifiable_from: Option<Duration>,
 }
 
 // TODO: doc
@@ -129,6 +145,11 @@ impl<TRq, TSrc> OptimisticFullSync<TRq, TSrc> {
         let chain = blocks_tree::NonFinalizedTree::new(blocks_tree::Config {
             chain_information_config: config.chain_information_config,
             blocks_capacity: config.blocks_capacity,
+            // Blocks are only ever obtained through requests, and the syncing can't progress
+            // without inserting them.
+            max_non_finalized_blocks: None,
+            max_non_finalized_depth: None,
+            max_slot_drift: config.max_slot_drift,
         });
 
         let best_block_number = chain.best_block_header().number;
@@ -138,10 +159,12 @@ impl<TRq, TSrc> OptimisticFullSync<TRq, TSrc> {
             best_to_finalized_storage_diff: BTreeMap::new(),
             runtime_code_cache: None,
             top_trie_root_calculation_cache: None,
+            future_block_verifiable_from: None,
             sync: Some(optimistic::OptimisticSync::new(optimistic::Config {
                 best_block_number,
                 sources_capacity: config.sources_capacity,
                 blocks_request_granularity: config.blocks_request_granularity,
+                max_requests_per_source: config.max_requests_per_source,
                 download_ahead_blocks: config.download_ahead_blocks,
                 source_selection_randomness_seed: config.source_selection_randomness_seed,
             })),
@@ -170,11 +193,77 @@ impl<TRq, TSrc> OptimisticFullSync<TRq, TSrc> {
         self.chain.best_block_hash()
     }
 
+    /// Verifies the given GrandPa commit and, if it is valid, finalizes the block it targets.
+    ///
+    /// On success, returns the list of blocks that have been finalized, in increasing block
+    /// number. As with [`ProcessOne::Finished`], the storage changes of these blocks must be
+    /// applied to the storage of the finalized block held by the user.
+    pub fn grandpa_commit(
+        &mut self,
+        commit: &grandpa::gossip::CommitMessage,
+    ) -> Result<Vec<Block>, blocks_tree::JustificationVerifyError> {
+        let apply = self.chain.verify_grandpa_commit(commit)?;
+
+        // If the best block is finalized, the storage diff becomes empty. Otherwise, the diff
+        // between the best block and the previous finalized block stays valid on top of the
+        // new finalized block, as it contains all the changes made by the blocks in between.
+        if apply.is_current_best_block() {
+            self.best_to_finalized_storage_diff.clear();
+        }
+
+        // `apply()` returns the blocks in decreasing block number.
+        let mut finalized_blocks = apply.apply().collect::<Vec<_>>();
+        finalized_blocks.reverse();
+        Ok(finalized_blocks)
+    }
+
     /// Inform the [`OptimisticFullSync`] of a new potential source of blocks.
     pub fn add_source(&mut self, source: TSrc) -> SourceId {
         self.sync.as_mut().unwrap().add_source(source)
     }
 
+    /// Returns the current reputation of the given source.
+    ///
+    /// The network layer is encouraged to use this information to disconnect from or ban
+    /// sources that are misbehaving.
+    ///
+    /// # Panic
+    ///
+    /// Panics if the [`SourceId`] is invalid.
+    ///
+    pub fn source_reputation(&self, source: SourceId) -> SourceReputation {
+        self.sync.as_ref().unwrap().source_reputation(source)
+    }
+
+    /// Inform the [`OptimisticFullSync`] of the height of the best block of a source, for example
+    /// after this source has announced a new block.
+    ///
+    /// Blocks above this height will no longer be requested from this source. Call
+    /// [`OptimisticFullSync::next_request_action`] afterwards in order to immediately start requesting
+    /// the newly-announced blocks.
+    ///
+    /// # Panic
+    ///
+    /// Panics if the [`SourceId`] is invalid.
+    ///
+    pub fn source_best_block_update(&mut self, source: SourceId, best_block_number: u64) {
+        self.sync
+            .as_mut()
+            .unwrap()
+            .source_best_block_update(source, best_block_number)
+    }
+
+    /// Returns the height of the best block of the given source, as last reported with
+    /// [`OptimisticFullSync::source_best_block_update`], or `None` if unknown.
+    ///
+    /// # Panic
+    ///
+    /// Panics if the [`SourceId`] is invalid.
+    ///
+    pub fn source_best_block(&self, source: SourceId) -> Option<u64> {
+        self.sync.as_ref().unwrap().source_best_block(source)
+    }
+
     /// Inform the [`OptimisticFullSync`] that a source of blocks is no longer available.
     ///
     /// This automatically cancels all the requests that have been emitted for this source.
@@ -193,14 +282,28 @@ impl<TRq, TSrc> OptimisticFullSync<TRq, TSrc> {
 
     /// Returns an iterator that extracts all requests that need to be started and requests that
     /// need to be cancelled.
-    pub fn next_request_action(&mut self) -> Option<RequestAction<TRq, TSrc, RequestSuccessBlock>> {
-        self.sync.as_mut().unwrap().next_request_action()
+    ///
+    /// `now` must be the time elapsed since an arbitrary moment in the past, and that same
+    /// moment must be used for all the calls to [`OptimisticFullSync::next_request_action`]
+    /// and [`OptimisticFullSync::finish_request`]. It is used to measure the latency of
+    /// requests and to expire the bans of sources.
+    pub fn next_request_action(
+        &mut self,
+        now: Duration,
+    ) -> Option<RequestAction<TRq, TSrc, RequestSuccessBlock>> {
+        self.sync.as_mut().unwrap().next_request_action(now)
     }
 
     /// Update the [`OptimisticFullSync`] with the outcome of a request.
     ///
     /// Returns the user data that was associated to that request.
     ///
+    /// The body of each block is compared with the extrinsics root found in its header. If one
+    /// of the blocks doesn't match, the request is considered as failed, the source is banned,
+    /// and the blocks will be requested again from a different source.
+    ///
+    /// See [`OptimisticFullSync::next_request_action`] for the meaning of `now`.
+    ///
     /// # Panic
     ///
     /// Panics if the [`RequestId`] is invalid.
@@ -209,18 +312,49 @@ impl<TRq, TSrc> OptimisticFullSync<TRq, TSrc> {
         &'a mut self,
         request_id: RequestId,
         outcome: Result<impl Iterator<Item = RequestSuccessBlock>, RequestFail>,
+        now: Duration,
     ) -> (TRq, FinishRequestOutcome<'a, TSrc>) {
+        let outcome = outcome.and_then(|blocks| {
+            let blocks = blocks.collect::<Vec<_>>();
+            if blocks.iter().all(|block| block.body_matches_header()) {
+                Ok(blocks.into_iter())
+            } else {
+                Err(RequestFail::InvalidBlocks)
+            }
+        });
+
         self.sync
             .as_mut()
             .unwrap()
-            .finish_request(request_id, outcome)
+            .finish_request(request_id, outcome, now)
+    }
+
+    /// Returns the earliest time, since the Unix epoch, at which the next block to verify, which
+    /// has been found to belong to a future slot, can be verified again, if any.
+    ///
+    /// [`OptimisticFullSync::process_one`] should be called again once this time is reached.
+    pub fn next_future_block_time(&self) -> Option<Duration> {
+        self.future_block_verifiable_from
     }
 
     /// Process a chunk of blocks in the queue of verification.
     ///
     /// This method takes ownership of the [`OptimisticFullSync`] and starts a verification
     /// process. The [`OptimisticFullSync`] is yielded back at the end of this process.
-    pub fn process_one(mut self) -> ProcessOne<TRq, TSrc> {
+    ///
+    /// `now_from_unix_epoch` must be the time elapsed since the Unix epoch. Blocks whose Aura or
+    /// BABE slot is too far ahead of the current slot are kept in the queue, alongside with the
+    /// blocks that follow them, until their slot comes. See
+    /// [`OptimisticFullSync::next_future_block_time`].
+    pub fn process_one(mut self, now_from_unix_epoch: Duration) -> ProcessOne<TRq, TSrc> {
+        if self
+            .future_block_verifiable_from
+            .map_or(false, |from| from > now_from_unix_epoch)
+        {
+            return ProcessOne::Idle { sync: self };
+        }
+        self.future_block_verifiable_from = None;
+
         let sync = self.sync.take().unwrap();
 
         let to_process = match sync.process_one() {
@@ -237,7 +371,11 @@ impl<TRq, TSrc> OptimisticFullSync<TRq, TSrc> {
             Inner::Start(self.chain),
             ProcessOneShared {
                 pending_encoded_justification: None,
+                pending_header: Vec::new(),
+                pending_body: Vec::new(),
+                now_from_unix_epoch,
                 to_process,
+                num_blocks_started: 0,
                 best_to_finalized_storage_diff: self.best_to_finalized_storage_diff,
                 runtime_code_cache: self.runtime_code_cache,
                 top_trie_root_calculation_cache: self.top_trie_root_calculation_cache,
@@ -247,17 +385,47 @@ impl<TRq, TSrc> OptimisticFullSync<TRq, TSrc> {
     }
 }
 
+/// Single block in the outcome of a request. A list of these must be passed to
+/// [`OptimisticFullSync::finish_request`].
 pub struct RequestSuccessBlock {
+    /// SCALE-encoded block header.
     pub scale_encoded_header: Vec<u8>,
+    /// SCALE-encoded justification of this block, or `None` if none is available.
     pub scale_encoded_justification: Option<Vec<u8>>,
+    /// List of SCALE-encoded extrinsics that form the block's body.
     pub scale_encoded_extrinsics: Vec<Vec<u8>>,
 }
 
+impl RequestSuccessBlock {
+    /// Returns `true` if the header can be decoded and if its extrinsics root matches the body.
+    fn body_matches_header(&self) -> bool {
+        let header = match header::decode(&self.scale_encoded_header) {
+            Ok(h) => h,
+            Err(_) => return false,
+        };
+
+        // The extrinsics root is the Merkle value of a trie whose keys are the SCALE-compact
+        // encoded indices of the extrinsics, and whose values are the extrinsics.
+        let mut trie = trie::Trie::new();
+        for (idx, extrinsic) in self.scale_encoded_extrinsics.iter().enumerate() {
+            let idx = match u32::try_from(idx) {
+                Ok(i) => i,
+                Err(_) => return false,
+            };
+            let key = parity_scale_codec::Encode::encode(&parity_scale_codec::Compact(idx));
+            trie.insert(&key, extrinsic.clone());
+        }
+
+        trie.root_merkle_value(None) == *header.extrinsics_root
+    }
+}
+
 /// State of the processing of blocks.
 pub enum ProcessOne<TRq, TSrc> {
-    /// No processing is necessary.
+    /// No processing is necessary, or the next block to verify belongs to a future slot.
     ///
-    /// Calling [`OptimisticFullSync::process_one`] again is unnecessary.
+    /// Calling [`OptimisticFullSync::process_one`] again is unnecessary, except once
+    /// [`OptimisticFullSync::next_future_block_time`] is reached.
     Idle {
         /// The state machine.
         /// The [`OptimisticFullSync::process_one`] method takes ownership of the
@@ -277,6 +445,21 @@ pub enum ProcessOne<TRq, TSrc> {
         // TODO: consider returning them one at a time?
         finalized_blocks: Vec<Block>,
     },
+    /// An issue happened when verifying a block or justification, resulting in resetting the
+    /// chain to the latest finalized block.
+    ///
+    /// There might be more blocks remaining. Call [`OptimisticFullSync::process_one`] again.
+    Reset {
+        /// The state machine.
+        /// The [`OptimisticFullSync::process_one`] method takes ownership of the
+        /// [`OptimisticFullSync`]. This field yields it back.
+        sync: OptimisticFullSync<TRq, TSrc>,
+        /// Problem that happened and caused the reset.
+        reason: ResetCause,
+        /// Source that has been banned because it is responsible for the problem, if it could
+        /// be determined. See also [`OptimisticFullSync::source_reputation`].
+        banned_source: Option<SourceId>,
+    },
     /// A step in the processing has been completed.
     ///
     /// This variant is returned periodically in order to report on the advancement of the
@@ -305,11 +488,21 @@ enum Inner {
 
 struct ProcessOneShared<TRq, TSrc> {
     pending_encoded_justification: Option<Vec<u8>>,
+    /// Header of the block whose verification is in progress. Kept in order to put the block
+    /// back in the queue if it belongs to a future slot.
+    pending_header: Vec<u8>,
+    /// Body of the block whose verification is in progress.
+    pending_body: Vec<Vec<u8>>,
+    /// Value passed to [`OptimisticFullSync::process_one`].
+    now_from_unix_epoch: Duration,
     to_process: optimistic::ProcessOne<TRq, TSrc, RequestSuccessBlock>,
+    /// Number of blocks of `to_process` whose verification has been started.
+    num_blocks_started: usize,
     best_to_finalized_storage_diff: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
     runtime_code_cache: Option<executor::WasmVmPrototype>,
     top_trie_root_calculation_cache: Option<calculate_root::CalculationCache>,
-    // TODO: make sure we're not throwing this away in case of error
+    /// Blocks finalized during the processing. Always empty in case of error, as the
+    /// processing stops immediately after a finalization.
     finalized_blocks: Vec<Block>,
 }
 
@@ -328,12 +521,17 @@ impl<TRq, TSrc> ProcessOne<TRq, TSrc> {
 
                     if !shared.to_process.blocks.as_slice().is_empty() {
                         let next_block = shared.to_process.blocks.next().unwrap();
+                        shared.num_blocks_started += 1;
                         if let Some(justification) = next_block.scale_encoded_justification {
                             shared.pending_encoded_justification = Some(justification);
                         }
+                        // A copy of the body is kept in order to be later stored in the chain.
+                        shared.pending_header = next_block.scale_encoded_header.clone();
+                        shared.pending_body = next_block.scale_encoded_extrinsics.clone();
                         inner = Inner::Step1(chain.verify_body(
                             next_block.scale_encoded_header,
                             next_block.scale_encoded_extrinsics.into_iter(),
+                            shared.now_from_unix_epoch,
                         ));
                     } else {
                         debug_assert!(shared.to_process.blocks.as_slice().is_empty());
@@ -349,6 +547,7 @@ impl<TRq, TSrc> ProcessOne<TRq, TSrc> {
                                 runtime_code_cache: shared.runtime_code_cache,
                                 top_trie_root_calculation_cache: shared
                                     .top_trie_root_calculation_cache,
+                                future_block_verifiable_from: None,
                                 sync: Some(sync),
                             },
                             finalized_blocks: shared.finalized_blocks,
@@ -357,57 +556,17 @@ impl<TRq, TSrc> ProcessOne<TRq, TSrc> {
                 }
 
                 Inner::Step1(blocks_tree::BodyVerifyStep1::InvalidHeader(chain, error)) => {
-                    // TODO: DRY
-                    let sync = shared
-                        .to_process
-                        .report
-                        .reset_to_finalized(chain.finalized_block_header().number);
-                    break ProcessOne::Finished {
-                        sync: OptimisticFullSync {
-                            chain,
-                            best_to_finalized_storage_diff: Default::default(),
-                            runtime_code_cache: None,
-                            top_trie_root_calculation_cache: None,
-                            sync: Some(sync),
-                        },
-                        finalized_blocks: shared.finalized_blocks,
-                    };
+                    break ProcessOne::reset(chain, shared, ResetCause::InvalidHeader(error));
                 }
 
-                Inner::Step1(blocks_tree::BodyVerifyStep1::Duplicate(chain)) => {
-                    // TODO: DRY
-                    let sync = shared
-                        .to_process
-                        .report
-                        .reset_to_finalized(chain.finalized_block_header().number);
-                    break ProcessOne::Finished {
-                        sync: OptimisticFullSync {
-                            chain,
-                            best_to_finalized_storage_diff: Default::default(),
-                            runtime_code_cache: None,
-                            top_trie_root_calculation_cache: None,
-                            sync: Some(sync),
-                        },
-                        finalized_blocks: shared.finalized_blocks,
-                    };
+                Inner::Step1(blocks_tree::BodyVerifyStep1::Duplicate(chain))
+                | Inner::Step1(blocks_tree::BodyVerifyStep1::BadParent { chain, .. }) => {
+                    break ProcessOne::reset(chain, shared, ResetCause::NonCanonical);
                 }
 
-                Inner::Step1(blocks_tree::BodyVerifyStep1::BadParent { chain, .. }) => {
-                    // TODO: DRY
-                    let sync = shared
-                        .to_process
-                        .report
-                        .reset_to_finalized(chain.finalized_block_header().number);
-                    break ProcessOne::Finished {
-                        sync: OptimisticFullSync {
-                            chain,
-                            best_to_finalized_storage_diff: shared.best_to_finalized_storage_diff,
-                            runtime_code_cache: shared.runtime_code_cache,
-                            top_trie_root_calculation_cache: None,
-                            sync: Some(sync),
-                        },
-                        finalized_blocks: shared.finalized_blocks,
-                    };
+                Inner::Step1(blocks_tree::BodyVerifyStep1::LimitReached { .. }) => {
+                    // The chain is built without any limit.
+                    unreachable!()
                 }
 
                 Inner::Step1(blocks_tree::BodyVerifyStep1::ParentRuntimeRequired(req)) => {
@@ -421,54 +580,48 @@ impl<TRq, TSrc> ProcessOne<TRq, TSrc> {
                     // to store it back after the verification is over.
                     let parent_runtime = match shared.runtime_code_cache.take() {
                         Some(r) => r,
-                        None => {
-                            // TODO: simplify code below
-                            match (
-                                shared.best_to_finalized_storage_diff.get(&b":code"[..]),
-                                shared
-                                    .best_to_finalized_storage_diff
-                                    .get(&b":heappages"[..]),
-                            ) {
-                                (Some(wasm_code), Some(heap_pages)) => {
-                                    let wasm_code =
-                                        wasm_code.as_ref().expect("no runtime code?!?!"); // TODO: what to do?
-                                    let heap_pages = u64::from_le_bytes(
-                                        <[u8; 8]>::try_from(&heap_pages.as_ref().unwrap()[..])
-                                            .unwrap(), // TODO: don't unwrap
-                                    );
-                                    executor::WasmVmPrototype::new(&wasm_code, heap_pages)
-                                        .expect("invalid runtime code?!?!") // TODO: what to do?
-                                }
-                                (Some(wasm_code), None) => {
-                                    return ProcessOne::FinalizedStorageGet(StorageGet {
-                                        inner: StorageGetTarget::HeapPages(
-                                            req,
-                                            wasm_code.as_ref().unwrap().clone(),
-                                        ), // TODO: don't unwrap
-                                        shared,
-                                    });
+                        None => match (
+                            shared.best_to_finalized_storage_diff.get(&b":code"[..]),
+                            shared
+                                .best_to_finalized_storage_diff
+                                .get(&b":heappages"[..]),
+                        ) {
+                            (Some(wasm_code), Some(heap_pages)) => {
+                                let runtime = decode_heap_pages(heap_pages.as_deref())
+                                    .and_then(|hp| build_runtime(wasm_code.as_deref(), hp));
+                                match runtime {
+                                    Ok(r) => r,
+                                    Err(err) => break ProcessOne::reset(req.abort(), shared, err),
                                 }
-                                (None, Some(heap_pages)) => {
-                                    let heap_pages = u64::from_le_bytes(
-                                        <[u8; 8]>::try_from(&heap_pages.as_ref().unwrap()[..])
-                                            .unwrap(), // TODO: don't unwrap
-                                    );
-                                    return ProcessOne::FinalizedStorageGet(StorageGet {
-                                        inner: StorageGetTarget::Runtime(req, heap_pages), // TODO: don't unwrap
-                                        shared,
-                                    });
-                                }
-                                (None, None) => {
-                                    // No cache has been found anywhere in the hierarchy.
-                                    // The user needs to be asked for the storage entry containing the
-                                    // runtime code.
-                                    return ProcessOne::FinalizedStorageGet(StorageGet {
-                                        inner: StorageGetTarget::HeapPagesAndRuntime(req),
-                                        shared,
-                                    });
+                            }
+                            (Some(wasm_code), None) => {
+                                let wasm_code = wasm_code.clone();
+                                return ProcessOne::FinalizedStorageGet(StorageGet {
+                                    inner: StorageGetTarget::HeapPages(req, wasm_code),
+                                    shared,
+                                });
+                            }
+                            (None, Some(heap_pages)) => {
+                                match decode_heap_pages(heap_pages.as_deref()) {
+                                    Ok(heap_pages) => {
+                                        return ProcessOne::FinalizedStorageGet(StorageGet {
+                                            inner: StorageGetTarget::Runtime(req, heap_pages),
+                                            shared,
+                                        });
+                                    }
+                                    Err(err) => break ProcessOne::reset(req.abort(), shared, err),
                                 }
                             }
-                        }
+                            (None, None) => {
+                                // No cache has been found anywhere in the hierarchy.
+                                // The user needs to be asked for the storage entry containing
+                                // the runtime code.
+                                return ProcessOne::FinalizedStorageGet(StorageGet {
+                                    inner: StorageGetTarget::HeapPagesAndRuntime(req),
+                                    shared,
+                                });
+                            }
+                        },
                     };
 
                     inner = Inner::Step2(req.resume(
@@ -482,7 +635,7 @@ impl<TRq, TSrc> ProcessOne<TRq, TSrc> {
                     offchain_storage_changes,
                     top_trie_root_calculation_cache,
                     parent_runtime,
-                    result: Ok(success),
+                    insert,
                 }) => {
                     // Successfully verified block!
                     // Inserting it into the chain and updated all the caches.
@@ -499,10 +652,10 @@ impl<TRq, TSrc> ProcessOne<TRq, TSrc> {
                     }
 
                     let mut chain = {
-                        let header = success.header().into();
-                        success.insert(Block {
+                        let header = insert.header().into();
+                        insert.insert(Block {
                             header,
-                            body: Vec::new(), // TODO: // FIXME: wrong! dummy!
+                            body: mem::take(&mut shared.pending_body),
                             // Set to `Some` below if the justification check success.
                             justification: None,
                             storage_top_trie_changes,
@@ -516,49 +669,59 @@ impl<TRq, TSrc> ProcessOne<TRq, TSrc> {
                     if let Some(justification) = shared.pending_encoded_justification.take() {
                         let mut apply = match chain.verify_justification(&justification) {
                             Ok(a) => a,
-                            Err(_) => todo!(), // TODO:
+                            Err(err) => {
+                                break ProcessOne::reset(
+                                    chain,
+                                    shared,
+                                    ResetCause::JustificationError(err),
+                                );
+                            }
                         };
 
-                        assert!(apply.is_current_best_block()); // TODO: can legitimately fail in case of malicious node
-
-                        // As part of the finalization, put the justification in the chain that's
-                        // going to be reported to the user.
-                        apply.block_user_data().justification = Some(justification);
+                        // The justification is expected to target the block it has been
+                        // provided with. A valid justification targeting one of its ancestors
+                        // is harmless, and is ignored for simplicity.
+                        if apply.is_current_best_block() {
+                            // As part of the finalization, put the justification in the chain
+                            // that's going to be reported to the user.
+                            apply.block_user_data().justification = Some(justification);
+
+                            // Applying the finalization and iterating over the now-finalized
+                            // block. Since `apply()` returns the blocks in decreasing block
+                            // number, we have to revert the list in order to get them in
+                            // increasing block number instead.
+                            // While this intermediary buffering is an overhead, the increased
+                            // code complexity to avoid it is probably not worth the speed gain.
+                            for block in apply.apply().collect::<Vec<_>>().into_iter().rev() {
+                                shared.finalized_blocks.push(block);
+                            }
 
-                        // Applying the finalization and iterating over the now-finalized block.
-                        // Since `apply()` returns the blocks in decreasing block number, we have
-                        // to revert the list in order to get them in increasing block number
-                        // instead.
-                        // While this intermediary buffering is an overhead, the increased code
-                        // complexity to avoid it is probably not worth the speed gain.
-                        for block in apply.apply().collect::<Vec<_>>().into_iter().rev() {
-                            shared.finalized_blocks.push(block);
+                            // Since the best block is now the finalized block, reset the storage
+                            // diff.
+                            debug_assert!(chain.is_empty());
+                            shared.best_to_finalized_storage_diff.clear();
+
+                            // Since the verification process requires querying the finalized
+                            // block storage from the user, we need to report changes to the
+                            // finalized blocks to the user before we can continue.
+                            let sync = shared
+                                .to_process
+                                .report
+                                .update_block_height(chain.best_block_header().number);
+                            break ProcessOne::Finished {
+                                sync: OptimisticFullSync {
+                                    chain,
+                                    best_to_finalized_storage_diff: shared
+                                        .best_to_finalized_storage_diff,
+                                    runtime_code_cache: shared.runtime_code_cache,
+                                    top_trie_root_calculation_cache: shared
+                                        .top_trie_root_calculation_cache,
+                                    future_block_verifiable_from: None,
+                                    sync: Some(sync),
+                                },
+                                finalized_blocks: shared.finalized_blocks,
+                            };
                         }
-
-                        // Since the best block is now the finalized block, reset the storage
-                        // diff.
-                        debug_assert!(chain.is_empty());
-                        shared.best_to_finalized_storage_diff.clear();
-
-                        // Since the verification process requires querying the finalized block
-                        // storage from the user, we need to report changes to the finalized
-                        // blocks to the user before we can continue.
-                        let sync = shared
-                            .to_process
-                            .report
-                            .update_block_height(chain.best_block_header().number);
-                        break ProcessOne::Finished {
-                            sync: OptimisticFullSync {
-                                chain,
-                                best_to_finalized_storage_diff: shared
-                                    .best_to_finalized_storage_diff,
-                                runtime_code_cache: shared.runtime_code_cache,
-                                top_trie_root_calculation_cache: shared
-                                    .top_trie_root_calculation_cache,
-                                sync: Some(sync),
-                            },
-                            finalized_blocks: shared.finalized_blocks,
-                        };
                     }
 
                     // Before looping again, report the progress to the user.
@@ -574,9 +737,38 @@ impl<TRq, TSrc> ProcessOne<TRq, TSrc> {
                     };
                 }
 
-                Inner::Step2(blocks_tree::BodyVerifyStep2::Finished {
-                    result: Err(err), ..
-                }) => todo!("verif failure"),
+                Inner::Step2(blocks_tree::BodyVerifyStep2::Error { chain, error }) => {
+                    break ProcessOne::reset(chain, shared, ResetCause::BlockVerification(error));
+                }
+
+                Inner::Step2(blocks_tree::BodyVerifyStep2::FutureBlock {
+                    chain,
+                    verifiable_from,
+                }) => {
+                    // The block isn't necessarily invalid. It is put back in the queue, alongside
+                    // with the blocks that follow it, and is verified again once its slot has
+                    // come.
+                    debug_assert!(shared.finalized_blocks.is_empty());
+                    let block = RequestSuccessBlock {
+                        scale_encoded_header: mem::take(&mut shared.pending_header),
+                        scale_encoded_justification: shared.pending_encoded_justification.take(),
+                        scale_encoded_extrinsics: mem::take(&mut shared.pending_body),
+                    };
+                    let sync = shared.to_process.report.process_later(
+                        chain.best_block_header().number,
+                        iter::once(block).chain(shared.to_process.blocks),
+                    );
+                    break ProcessOne::Idle {
+                        sync: OptimisticFullSync {
+                            chain,
+                            best_to_finalized_storage_diff: shared.best_to_finalized_storage_diff,
+                            runtime_code_cache: shared.runtime_code_cache,
+                            top_trie_root_calculation_cache: shared.top_trie_root_calculation_cache,
+                            future_block_verifiable_from: Some(verifiable_from),
+                            sync: Some(sync),
+                        },
+                    };
+                }
 
                 Inner::Step2(blocks_tree::BodyVerifyStep2::StorageGet(mut req)) => {
                     // The underlying verification process is asking for a storage entry in the
@@ -626,6 +818,98 @@ impl<TRq, TSrc> ProcessOne<TRq, TSrc> {
             }
         }
     }
+
+    /// Resets the chain to the latest finalized block after a problem has happened.
+    fn reset(
+        mut chain: blocks_tree::NonFinalizedTree<Block>,
+        mut shared: ProcessOneShared<TRq, TSrc>,
+        reason: ResetCause,
+    ) -> Self {
+        // A block can be invalid only because of one of its ancestors. The source is only
+        // punished if the problematic block isn't the first one of the batch, in which case its
+        // ancestors have been provided by the same source.
+        let source_at_fault = match reason {
+            ResetCause::InvalidHeader(_)
+            | ResetCause::NonCanonical
+            | ResetCause::BlockVerification(_)
+            | ResetCause::JustificationError(_) => shared.num_blocks_started >= 2,
+            ResetCause::MissingRuntimeCode
+            | ResetCause::InvalidHeapPages
+            | ResetCause::InvalidRuntime(_) => false,
+        };
+
+        let banned_source = if source_at_fault {
+            shared.to_process.report.source_sent_invalid_data()
+        } else {
+            None
+        };
+
+        // All the non-finalized blocks are discarded, and the caches, which correspond to the
+        // best block, are thrown away as well.
+        debug_assert!(shared.finalized_blocks.is_empty());
+        chain.clear();
+        let sync = shared
+            .to_process
+            .report
+            .reset_to_finalized(chain.finalized_block_header().number);
+
+        ProcessOne::Reset {
+            sync: OptimisticFullSync {
+                chain,
+                best_to_finalized_storage_diff: BTreeMap::new(),
+                runtime_code_cache: None,
+                top_trie_root_calculation_cache: None,
+                future_block_verifiable_from: None,
+                sync: Some(sync),
+            },
+            reason,
+            banned_source,
+        }
+    }
+}
+
+/// Problem that happened and caused the reset.
+#[derive(Debug, derive_more::Display)]
+pub enum ResetCause {
+    /// Error while decoding the header of a block.
+    InvalidHeader(header::Error),
+    /// Received block isn't a child of the current best block.
+    NonCanonical,
+    /// Error while verifying a block.
+    BlockVerification(verify::header_body::Error),
+    /// Error while verifying a justification.
+    JustificationError(blocks_tree::JustificationVerifyError),
+    /// The runtime code of the parent block can't be found in its storage.
+    ///
+    /// > **Note**: This indicates that the storage of the finalized block provided by the user
+    /// >           is incomplete or that the chain is broken, rather than a misbehaving source.
+    #[display(fmt = "Runtime code can't be found in storage")]
+    MissingRuntimeCode,
+    /// The `:heappages` storage entry of the parent block has an invalid format.
+    #[display(fmt = "Invalid :heappages storage entry")]
+    InvalidHeapPages,
+    /// Error while compiling the runtime code of the parent block.
+    #[display(fmt = "Error while compiling the runtime: {}", _0)]
+    InvalidRuntime(executor::NewErr),
+}
+
+/// Decodes the value of the `:heappages` storage entry.
+fn decode_heap_pages(value: Option<&[u8]>) -> Result<u64, ResetCause> {
+    match value {
+        Some(value) => <[u8; 8]>::try_from(value)
+            .map(u64::from_le_bytes)
+            .map_err(|_| ResetCause::InvalidHeapPages),
+        None => Ok(executor::DEFAULT_HEAP_PAGES),
+    }
+}
+
+/// Compiles the runtime code found in the `:code` storage entry.
+fn build_runtime(
+    wasm_code: Option<&[u8]>,
+    heap_pages: u64,
+) -> Result<executor::WasmVmPrototype, ResetCause> {
+    let wasm_code = wasm_code.ok_or(ResetCause::MissingRuntimeCode)?;
+    executor::WasmVmPrototype::new(wasm_code, heap_pages).map_err(ResetCause::InvalidRuntime)
 }
 
 /// Loading a storage value is required in order to continue.
@@ -644,7 +928,7 @@ enum StorageGetTarget {
     ),
     HeapPages(
         blocks_tree::BodyVerifyRuntimeRequired<Block, vec::IntoIter<Vec<u8>>>,
-        Vec<u8>,
+        Option<Vec<u8>>,
     ),
 }
 
@@ -680,46 +964,39 @@ impl<TRq, TBl> StorageGet<TRq, TBl> {
     /// Injects the corresponding storage value.
     // TODO: change API, see execute_block::StorageGet
     pub fn inject_value(mut self, value: Option<&[u8]>) -> ProcessOne<TRq, TBl> {
-        // TODO: simplify code inside here
         match self.inner {
             StorageGetTarget::Storage(inner) => {
                 let inner = inner.inject_value(value);
                 ProcessOne::from(Inner::Step2(inner), self.shared)
             }
-            StorageGetTarget::HeapPagesAndRuntime(inner) => {
-                let heap_pages = if let Some(value) = value {
-                    u64::from_le_bytes(
-                        <[u8; 8]>::try_from(&value[..]).unwrap(), // TODO: don't unwrap
-                    )
-                } else {
-                    1024 // TODO: default heap pages
-                };
-                ProcessOne::FinalizedStorageGet(StorageGet {
+            StorageGetTarget::HeapPagesAndRuntime(inner) => match decode_heap_pages(value) {
+                Ok(heap_pages) => ProcessOne::FinalizedStorageGet(StorageGet {
                     inner: StorageGetTarget::Runtime(inner, heap_pages),
                     shared: self.shared,
-                })
-            }
+                }),
+                Err(err) => ProcessOne::reset(inner.abort(), self.shared, err),
+            },
             StorageGetTarget::Runtime(inner, heap_pages) => {
-                let wasm_code = value.expect("no runtime code in storage?"); // TODO: ?!?!
-                let wasm_vm = executor::WasmVmPrototype::new(wasm_code, heap_pages)
-                    .expect("invalid runtime code?!?!"); // TODO: ?!?!
-                let inner =
-                    inner.resume(wasm_vm, self.shared.top_trie_root_calculation_cache.take());
-                ProcessOne::from(Inner::Step2(inner), self.shared)
+                match build_runtime(value, heap_pages) {
+                    Ok(wasm_vm) => {
+                        let inner = inner
+                            .resume(wasm_vm, self.shared.top_trie_root_calculation_cache.take());
+                        ProcessOne::from(Inner::Step2(inner), self.shared)
+                    }
+                    Err(err) => ProcessOne::reset(inner.abort(), self.shared, err),
+                }
             }
             StorageGetTarget::HeapPages(inner, wasm_code) => {
-                let heap_pages = if let Some(value) = value {
-                    u64::from_le_bytes(
-                        <[u8; 8]>::try_from(&value[..]).unwrap(), // TODO: don't unwrap
-                    )
-                } else {
-                    1024 // TODO: default heap pages
-                };
-                let wasm_vm = executor::WasmVmPrototype::new(&wasm_code, heap_pages)
-                    .expect("invalid runtime code?!?!"); // TODO: ?!?!
-                let inner =
-                    inner.resume(wasm_vm, self.shared.top_trie_root_calculation_cache.take());
-                ProcessOne::from(Inner::Step2(inner), self.shared)
+                match decode_heap_pages(value)
+                    .and_then(|hp| build_runtime(wasm_code.as_deref(), hp))
+                {
+                    Ok(wasm_vm) => {
+                        let inner = inner
+                            .resume(wasm_vm, self.shared.top_trie_root_calculation_cache.take());
+                        ProcessOne::from(Inner::Step2(inner), self.shared)
+                    }
+                    Err(err) => ProcessOne::reset(inner.abort(), self.shared, err),
+                }
             }
         }
     }
@@ -27,16 +27,22 @@
 //!
 //! The [`OptimisticHeadersSync`] makes it possible to sync the finalized blocks of a chain, but
 //! not the non-finalized blocks.
+//!
+//! GrandPa commits received from the network, for example through the GrandPa gossip protocol,
+//! can be passed to [`OptimisticHeadersSync::grandpa_commit`] in order to finalize one of the
+//! latest blocks without waiting for a justification.
 
 // TODO: document usage
 
 use super::super::{blocks_tree, chain_information};
 use super::optimistic;
+use crate::{finality::grandpa, header};
 
-use core::{convert::TryFrom as _, num::NonZeroU32};
+use alloc::collections::VecDeque;
+use core::{convert::TryFrom as _, iter, num::NonZeroU32, time::Duration};
 
 pub use optimistic::{
-    FinishRequestOutcome, RequestAction, RequestFail, RequestId, SourceId, Start,
+    FinishRequestOutcome, RequestAction, RequestFail, RequestId, SourceId, SourceReputation, Start,
 };
 
 /// Configuration for the [`OptimisticHeadersSync`].
@@ -54,6 +60,14 @@ pub struct Config {
     /// >           protocol enforced limit.
     pub blocks_request_granularity: NonZeroU32,
 
+    /// Maximum number of requests that can be in progress at the same time towards a single
+    /// source.
+    ///
+    /// The blocks ahead of the best block are split between all the sources, and requests are
+    /// performed in parallel. Responses can arrive in any order, and are put back in order
+    /// before being verified.
+    pub max_requests_per_source: NonZeroU32,
+
     /// Number of blocks to download ahead of the best block.
     ///
     /// Whenever the latest best block is updated, the state machine will start block
@@ -71,6 +85,15 @@ pub struct Config {
     /// You are encouraged to use something like `rand::random()` to fill this field, except in
     /// situations where determinism/reproducibility is desired.
     pub source_selection_randomness_seed: u64,
+
+    /// Number of slots the Aura or BABE slot of a block is allowed to be ahead of the current slot.
+    /// See [`blocks_tree::Config::max_slot_drift`].
+    pub max_slot_drift: u64,
+
+    /// Number of blocks below the best block that are kept in memory, in order to be able to
+    /// verify the GrandPa commits that target them. See
+    /// [`OptimisticHeadersSync::grandpa_commit`].
+    pub recent_blocks_kept: usize,
 }
 
 /// Optimistic headers-only syncing.
@@ -82,13 +105,25 @@ pub struct OptimisticHeadersSync<TRq, TSrc> {
     /// Chain containing the state necessary to verify blocks.
     ///
     /// Important: the finalized block in this chain is not the actual finalized blocks. In order
-    /// to reduce memory consumption, every block that isn't the best block is discarded. This is
-    /// done by considering the best block as finalized even though it's not actually.
+    /// to reduce memory consumption, every block that isn't the best block or one of the
+    /// [`Config::recent_blocks_kept`] blocks below it is discarded. This is done by considering
+    /// the block below these as finalized even though it's not actually.
     chain: blocks_tree::NonFinalizedTree<()>,
 
+    /// Hashes of the non-finalized blocks of [`OptimisticHeadersSync::chain`], in increasing
+    /// block number. The last element is the best block.
+    recent_blocks: VecDeque<[u8; 32]>,
+
+    /// See [`Config::recent_blocks_kept`].
+    recent_blocks_kept: usize,
+
     /// Underlying helper. Manages sources and requests.
     /// Always `Some`, except during some temporary extractions.
     sync: Option<optimistic::OptimisticSync<TRq, TSrc, RequestSuccessBlock>>,
+
+    /// If `Some`, the next block to verify belongs to a future slot, and must not be verified
+    /// before the given time elapsed since the Unix epoch.
+    future_block_verifiable_from: Option<Duration>,
 }
 
 impl<TRq, TSrc> OptimisticHeadersSync<TRq, TSrc> {
@@ -98,6 +133,10 @@ impl<TRq, TSrc> OptimisticHeadersSync<TRq, TSrc> {
             chain_information_config: config.chain_information_config,
             blocks_capacity: usize::try_from(config.blocks_request_granularity.get())
                 .unwrap_or(usize::max_value()),
+            // Most of the blocks are discarded. See `chain` below.
+            max_non_finalized_blocks: None,
+            max_non_finalized_depth: None,
+            max_slot_drift: config.max_slot_drift,
         };
 
         let chain = blocks_tree::NonFinalizedTree::new(blocks_tree_config.clone());
@@ -106,13 +145,17 @@ impl<TRq, TSrc> OptimisticHeadersSync<TRq, TSrc> {
         OptimisticHeadersSync {
             finalized_chain_information: blocks_tree_config,
             chain,
+            recent_blocks: VecDeque::with_capacity(config.recent_blocks_kept + 1),
+            recent_blocks_kept: config.recent_blocks_kept,
             sync: Some(optimistic::OptimisticSync::new(optimistic::Config {
                 best_block_number,
                 sources_capacity: config.sources_capacity,
                 blocks_request_granularity: config.blocks_request_granularity,
+                max_requests_per_source: config.max_requests_per_source,
                 download_ahead_blocks: config.download_ahead_blocks,
                 source_selection_randomness_seed: config.source_selection_randomness_seed,
             })),
+            future_block_verifiable_from: None,
         }
     }
 
@@ -131,6 +174,48 @@ impl<TRq, TSrc> OptimisticHeadersSync<TRq, TSrc> {
         self.sync.as_mut().unwrap().add_source(source)
     }
 
+    /// Returns the current reputation of the given source.
+    ///
+    /// The network layer is encouraged to use this information to disconnect from or ban
+    /// sources that are misbehaving.
+    ///
+    /// # Panic
+    ///
+    /// Panics if the [`SourceId`] is invalid.
+    ///
+    pub fn source_reputation(&self, source: SourceId) -> SourceReputation {
+        self.sync.as_ref().unwrap().source_reputation(source)
+    }
+
+    /// Inform the [`OptimisticHeadersSync`] of the height of the best block of a source, for example
+    /// after this source has announced a new block.
+    ///
+    /// Blocks above this height will no longer be requested from this source. Call
+    /// [`OptimisticHeadersSync::next_request_action`] afterwards in order to immediately start requesting
+    /// the newly-announced blocks.
+    ///
+    /// # Panic
+    ///
+    /// Panics if the [`SourceId`] is invalid.
+    ///
+    pub fn source_best_block_update(&mut self, source: SourceId, best_block_number: u64) {
+        self.sync
+            .as_mut()
+            .unwrap()
+            .source_best_block_update(source, best_block_number)
+    }
+
+    /// Returns the height of the best block of the given source, as last reported with
+    /// [`OptimisticHeadersSync::source_best_block_update`], or `None` if unknown.
+    ///
+    /// # Panic
+    ///
+    /// Panics if the [`SourceId`] is invalid.
+    ///
+    pub fn source_best_block(&self, source: SourceId) -> Option<u64> {
+        self.sync.as_ref().unwrap().source_best_block(source)
+    }
+
     /// Inform the [`OptimisticHeadersSync`] that a source of blocks is no longer available.
     ///
     /// This automatically cancels all the requests that have been emitted for this source.
@@ -149,14 +234,24 @@ impl<TRq, TSrc> OptimisticHeadersSync<TRq, TSrc> {
 
     /// Returns an iterator that extracts all requests that need to be started and requests that
     /// need to be cancelled.
-    pub fn next_request_action(&mut self) -> Option<RequestAction<TRq, TSrc, RequestSuccessBlock>> {
-        self.sync.as_mut().unwrap().next_request_action()
+    ///
+    /// `now` must be the time elapsed since an arbitrary moment in the past, and that same
+    /// moment must be used for all the calls to [`OptimisticHeadersSync::next_request_action`] and
+    /// [`OptimisticHeadersSync::finish_request`]. It is used to measure the latency of requests and to
+    /// expire the bans of sources.
+    pub fn next_request_action(
+        &mut self,
+        now: Duration,
+    ) -> Option<RequestAction<TRq, TSrc, RequestSuccessBlock>> {
+        self.sync.as_mut().unwrap().next_request_action(now)
     }
 
     /// Update the [`OptimisticHeadersSync`] with the outcome of a request.
     ///
     /// Returns the user data that was associated to that request.
     ///
+    /// See [`OptimisticHeadersSync::next_request_action`] for the meaning of `now`.
+    ///
     /// # Panic
     ///
     /// Panics if the [`RequestId`] is invalid.
@@ -165,11 +260,46 @@ impl<TRq, TSrc> OptimisticHeadersSync<TRq, TSrc> {
         &'a mut self,
         request_id: RequestId,
         outcome: Result<impl Iterator<Item = RequestSuccessBlock>, RequestFail>,
+        now: Duration,
     ) -> (TRq, FinishRequestOutcome<'a, TSrc>) {
         self.sync
             .as_mut()
             .unwrap()
-            .finish_request(request_id, outcome)
+            .finish_request(request_id, outcome, now)
+    }
+
+    /// Verifies the given GrandPa commit and, if it is valid, finalizes the block it targets.
+    ///
+    /// The block targeted by the commit must be the best block or one of the
+    /// [`Config::recent_blocks_kept`] blocks below it, and must not be finalized yet. On
+    /// success, returns the number and hash of the new finalized block.
+    pub fn grandpa_commit(
+        &mut self,
+        commit: &grandpa::gossip::CommitMessage,
+    ) -> Result<(u64, [u8; 32]), blocks_tree::JustificationVerifyError> {
+        drop(self.chain.verify_grandpa_commit(commit)?.apply());
+        self.on_finalized(&commit.target_hash);
+        Ok((u64::from(commit.target_number), commit.target_hash))
+    }
+
+    /// Updates `self` after the given block of [`OptimisticHeadersSync::chain`] has been
+    /// finalized with a justification or a commit.
+    fn on_finalized(&mut self, block_hash: &[u8; 32]) {
+        if let Some(position) = self.recent_blocks.iter().position(|h| h == block_hash) {
+            self.recent_blocks.drain(..=position);
+        }
+
+        self.finalized_chain_information
+            .chain_information_config
+            .chain_information = self.chain.as_chain_information().into();
+    }
+
+    /// Returns the earliest time, since the Unix epoch, at which the next block to verify, which
+    /// has been found to belong to a future slot, can be verified again, if any.
+    ///
+    /// [`OptimisticHeadersSync::process_one`] should be called again once this time is reached.
+    pub fn next_future_block_time(&self) -> Option<Duration> {
+        self.future_block_verifiable_from
     }
 
     /// Process a batch of blocks in the queue of verification.
@@ -181,7 +311,20 @@ impl<TRq, TSrc> OptimisticHeadersSync<TRq, TSrc> {
     /// It is encouraged to call this method multiple times in a row until
     /// [`ProcessOneOutcome::Idle`] is returned, interleaving any necessary high-priority
     /// operations (e.g. processing network sockets) in-between two calls.
-    pub fn process_one(&mut self) -> ProcessOneOutcome {
+    ///
+    /// `now_from_unix_epoch` must be the time elapsed since the Unix epoch. Blocks whose Aura or
+    /// BABE slot is too far ahead of the current slot are kept in the queue, alongside with the
+    /// blocks that follow them, until their slot comes. See
+    /// [`OptimisticHeadersSync::next_future_block_time`].
+    pub fn process_one(&mut self, now_from_unix_epoch: Duration) -> ProcessOneOutcome {
+        if self
+            .future_block_verifiable_from
+            .map_or(false, |from| from > now_from_unix_epoch)
+        {
+            return ProcessOneOutcome::Idle;
+        }
+        self.future_block_verifiable_from = None;
+
         let mut to_process = match self.sync.take().unwrap().process_one() {
             Ok(tp) => tp,
             Err(sync) => {
@@ -196,8 +339,16 @@ impl<TRq, TSrc> OptimisticHeadersSync<TRq, TSrc> {
         // The loop stops whenever something unexpected (such as a verification error) happens.
         let mut finalized_update = false;
         let mut has_error = None;
-        for block in to_process.blocks {
-            match self.chain.verify_header(block.scale_encoded_header.into()) {
+        // `true` if the source of the blocks is certainly responsible for the error, if any.
+        let mut source_at_fault = false;
+        // Block of the batch that belongs to a future slot, if any.
+        let mut future_block = None;
+        let mut block_index = 0;
+        while let Some(block) = to_process.blocks.next() {
+            match self
+                .chain
+                .verify_header(block.scale_encoded_header.clone(), now_from_unix_epoch)
+            {
                 Ok(blocks_tree::HeaderVerifySuccess::Insert {
                     block_height,
                     is_new_best,
@@ -206,10 +357,12 @@ impl<TRq, TSrc> OptimisticHeadersSync<TRq, TSrc> {
                     if !is_new_best {
                         debug_assert!(has_error.is_none());
                         has_error = Some(ResetCause::NonCanonical);
+                        source_at_fault = block_index != 0;
                         break;
                     }
                     if block_height != to_process.expected_block_height {
                         debug_assert!(has_error.is_none());
+                        source_at_fault = true;
                         has_error = Some(ResetCause::UnexpectedBlockNumber {
                             expected: to_process.expected_block_height,
                             actual: block_height,
@@ -218,15 +371,29 @@ impl<TRq, TSrc> OptimisticHeadersSync<TRq, TSrc> {
                     }
 
                     insert.insert(());
+                    self.recent_blocks
+                        .push_back(header::hash_from_scale_encoded_header(
+                            &block.scale_encoded_header,
+                        ));
                 }
                 Ok(blocks_tree::HeaderVerifySuccess::Duplicate) => {
                     debug_assert!(has_error.is_none());
                     has_error = Some(ResetCause::NonCanonical);
+                    source_at_fault = block_index != 0;
+                    break;
+                }
+                Err(blocks_tree::HeaderVerifyError::FutureBlock { verifiable_from }) => {
+                    // The block isn't necessarily invalid. It is put back in the queue,
+                    // alongside with the blocks that follow it, and is verified again once its
+                    // slot has come.
+                    self.future_block_verifiable_from = Some(verifiable_from);
+                    future_block = Some(block);
                     break;
                 }
                 Err(err) => {
                     debug_assert!(has_error.is_none());
                     has_error = Some(ResetCause::HeaderError(err));
+                    source_at_fault = block_index != 0;
                     break;
                 }
             }
@@ -236,19 +403,20 @@ impl<TRq, TSrc> OptimisticHeadersSync<TRq, TSrc> {
                     Ok(apply) => {
                         apply.apply();
                         finalized_update = true;
-                        self.finalized_chain_information
-                            .chain_information_config
-                            .chain_information = self.chain.as_chain_information().into();
+                        let finalized_block_hash = self.chain.finalized_block_hash();
+                        self.on_finalized(&finalized_block_hash);
                     }
                     Err(err) => {
                         debug_assert!(has_error.is_none());
                         has_error = Some(ResetCause::JustificationError(err));
+                        source_at_fault = block_index != 0;
                         break;
                     }
                 }
             }
 
             to_process.expected_block_height += 1;
+            block_index += 1;
         }
 
         // In case something unexpected happens, such as an invalid block, there is unfortunately
@@ -263,35 +431,61 @@ impl<TRq, TSrc> OptimisticHeadersSync<TRq, TSrc> {
         //
         // Consequently, if something unexpected happens, the strategy employed is to clear any
         // non-finalized block, cancel all requests in progress, and restart from the finalized
-        // block. No attempt is made to punish nodes.
+        // block. The source is only punished if the problem can't be caused by a block provided
+        // by a different source, in other words if the problematic block isn't the first one of
+        // the batch, or if it doesn't have the number that was requested.
         if let Some(has_error) = has_error {
+            let banned_source = if source_at_fault {
+                to_process.report.source_sent_invalid_data()
+            } else {
+                None
+            };
+
             // As documented, the `chain` field does not contain the *actual* finalized block.
             // Instead, a new chain is recreated in order to reset to the actual finalized block.
             self.chain =
                 blocks_tree::NonFinalizedTree::new(self.finalized_chain_information.clone());
+            self.recent_blocks.clear();
             let sync = to_process
                 .report
                 .reset_to_finalized(self.chain.finalized_block_header().number);
             self.sync = Some(sync);
             return ProcessOneOutcome::Reset {
                 reason: has_error,
+                banned_source,
                 new_best_block_number: self.chain.best_block_header().number,
                 new_best_block_hash: self.chain.best_block_hash(),
             };
         }
 
-        let sync = to_process
-            .report
-            .update_block_height(self.chain.best_block_header().number);
+        // Nothing has been imported if the first block of the batch belongs to a future slot.
+        let nothing_imported = future_block.is_some() && block_index == 0;
+        let sync = match future_block {
+            Some(block) => to_process.report.process_later(
+                self.chain.best_block_header().number,
+                iter::once(block).chain(to_process.blocks),
+            ),
+            None => to_process
+                .report
+                .update_block_height(self.chain.best_block_header().number),
+        };
         self.sync = Some(sync);
 
+        if nothing_imported {
+            return ProcessOneOutcome::Idle;
+        }
+
         // As documented, the finalized block tracked by the `chain` field is not the actual
         // finalized block. The optimistic sync state machine tracks the actual finalized block
-        // separately, and the finalized block of `chain` is always set to the best block.
+        // separately, and the finalized block of `chain` is always set to the block below the
+        // ones that are kept.
+        if self.recent_blocks.len() > self.recent_blocks_kept + 1 {
+            let to_finalize = self.recent_blocks.len() - self.recent_blocks_kept - 2;
+            let to_finalize_hash = self.recent_blocks[to_finalize];
+            self.recent_blocks.drain(..=to_finalize);
+            drop(self.chain.set_finalized_block(&to_finalize_hash).unwrap());
+        }
         let best_block_hash = self.chain.best_block_hash();
-        // `set_finalized_block` will error if best block == finalized block, which will
-        // frequently and legitimately happen. As such, we ignore errors.
-        let _ = self.chain.set_finalized_block(&best_block_hash);
 
         // Success! 🎉
         ProcessOneOutcome::Updated {
@@ -331,7 +525,8 @@ pub struct RequestSuccessBlock {
 /// Outcome of calling [`OptimisticHeadersSync::process_one`].
 #[derive(Debug)]
 pub enum ProcessOneOutcome {
-    /// There was nothing to do.
+    /// There was nothing to do, or the next block to verify belongs to a future slot. See
+    /// [`OptimisticHeadersSync::next_future_block_time`].
     Idle,
 
     /// An issue happened when verifying a block or justification, resulting in resetting the
@@ -342,6 +537,9 @@ pub enum ProcessOneOutcome {
     Reset {
         /// Problem that happened and caused the reset.
         reason: ResetCause,
+        /// Source that has been banned because it is responsible for the problem, if it could
+        /// be determined. See also [`OptimisticHeadersSync::source_reputation`].
+        banned_source: Option<SourceId>,
         /// Number of the new best block. Identical to the number of the finalized block.
         new_best_block_number: u64,
         /// Hash of the new best block. Identical to the hash of the finalized block.
@@ -28,8 +28,27 @@ use core::{
     marker::PhantomData,
     mem,
     num::{NonZeroU32, NonZeroU64},
+    time::Duration,
 };
-use rand::{seq::IteratorRandom as _, SeedableRng as _};
+use rand::{Rng as _, SeedableRng as _};
+
+/// Reputation of a newly-added source, and of a source whose ban has expired.
+const INITIAL_REPUTATION: i32 = 0;
+/// Maximum reputation a source can reach.
+const MAX_REPUTATION: i32 = 1000;
+/// Sources whose reputation reaches this value or below get banned.
+const BAN_THRESHOLD: i32 = -200;
+/// Reputation change when a request fails.
+const REQUEST_FAILURE_REPUTATION_CHANGE: i32 = -50;
+/// Duration of the first ban of a source. Every subsequent ban of the same source lasts twice
+/// as long as the previous one, up to `MAX_BAN_DURATION`.
+const BASE_BAN_DURATION: Duration = Duration::from_secs(30);
+/// Maximum duration of a ban.
+const MAX_BAN_DURATION: Duration = Duration::from_secs(30 * 60);
+/// Duration that a request towards a source should ideally take. The number of blocks requested
+/// from each source is adjusted according to its measured throughput in order to match this
+/// duration.
+const TARGET_REQUEST_DURATION: Duration = Duration::from_secs(2);
 
 /// Configuration for the [`OptimisticSync`].
 #[derive(Debug)]
@@ -46,6 +65,14 @@ pub struct Config {
     /// >           protocol enforced limit.
     pub blocks_request_granularity: NonZeroU32,
 
+    /// Maximum number of requests that can be in progress at the same time towards a single
+    /// source.
+    ///
+    /// The blocks ahead of the best block are split between all the sources, and requests are
+    /// performed in parallel. Responses can arrive in any order, and are put back in order
+    /// before being verified.
+    pub max_requests_per_source: NonZeroU32,
+
     /// Number of blocks to download ahead of the best block.
     ///
     /// Whenever the latest best block is updated, the state machine will start block
@@ -80,6 +107,9 @@ pub struct OptimisticSync<TRq, TSrc, TBl> {
     /// Value passed by [`Config::blocks_request_granularity`].
     blocks_request_granularity: NonZeroU32,
 
+    /// Value passed by [`Config::max_requests_per_source`].
+    max_requests_per_source: NonZeroU32,
+
     /// Value passed by [`Config::download_ahead_blocks`].
     download_ahead_blocks: u32,
 
@@ -90,6 +120,29 @@ pub struct OptimisticSync<TRq, TSrc, TBl> {
     source_selection_rng: rand_chacha::ChaCha8Rng,
 }
 
+/// Reputation of a source, as returned by [`OptimisticSync::source_reputation`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceReputation {
+    /// Current score of the source. Increased when requests succeed quickly, decreased when
+    /// requests fail or take a long time. The higher the score, the more likely the source is to
+    /// be chosen for requests.
+    pub score: i32,
+    /// Number of requests towards this source that have succeeded.
+    pub successful_requests: u32,
+    /// Number of requests towards this source that have failed.
+    pub failed_requests: u32,
+    /// Number of times the source has sent back blocks that failed to verify.
+    pub invalid_data: u32,
+    /// Moving average of the time it took for the requests towards this source to finish.
+    /// `None` if no request has ever finished.
+    pub average_latency: Option<Duration>,
+    /// Number of times this source has been banned.
+    pub num_bans: u32,
+    /// `true` if no request is currently started towards this source because of its bad
+    /// behaviour. Bans expire after a cooldown period.
+    pub is_banned: bool,
+}
+
 struct VerificationQueueEntry<TRq, TBl> {
     block_height: NonZeroU64,
     ty: VerificationQueueEntryTy<TRq, TBl>,
@@ -97,19 +150,158 @@ struct VerificationQueueEntry<TRq, TBl> {
 
 struct Source<TSrc> {
     user_data: TSrc,
-    banned: bool, // TODO: ban shouldn't be held forever
+    reputation: SourceReputation,
+    ban: Ban,
+    /// Number of requests towards this source that are currently in progress.
+    num_ongoing_requests: u32,
+    /// Number of blocks to request from this source, adjusted according to its measured
+    /// throughput. Never larger than [`OptimisticSync::blocks_request_granularity`].
+    request_size: NonZeroU32,
+    /// Height of the best block of this source, as last reported with
+    /// [`OptimisticSync::source_best_block_update`]. `None` if unknown, in which case the source
+    /// is assumed to know all the blocks.
+    best_block_number: Option<u64>,
+}
+
+enum Ban {
+    NotBanned,
+    /// Source is banned for the given duration, but the moment when the ban expires will only be
+    /// determined the next time the current time is known.
+    Pending(Duration),
+    /// Source is banned until the given moment.
+    Until(Duration),
+}
+
+impl<TSrc> Source<TSrc> {
+    fn new(user_data: TSrc, request_size: NonZeroU32) -> Self {
+        Source {
+            user_data,
+            num_ongoing_requests: 0,
+            request_size,
+            best_block_number: None,
+            reputation: SourceReputation {
+                score: INITIAL_REPUTATION,
+                successful_requests: 0,
+                failed_requests: 0,
+                invalid_data: 0,
+                average_latency: None,
+                num_bans: 0,
+                is_banned: false,
+            },
+            ban: Ban::NotBanned,
+        }
+    }
+
+    /// Adjusts the reputation of this source by the given value, banning it if it reaches
+    /// [`BAN_THRESHOLD`]. Returns `true` if the source has been banned.
+    fn adjust_reputation(&mut self, change: i32, now: Option<Duration>) -> bool {
+        self.reputation.score =
+            cmp::min(self.reputation.score.saturating_add(change), MAX_REPUTATION);
+
+        if self.reputation.score > BAN_THRESHOLD || self.reputation.is_banned {
+            return false;
+        }
+
+        self.ban(now);
+        true
+    }
+
+    /// Bans the source. If `now` is `None`, the ban will only start the next time the current
+    /// time is known.
+    fn ban(&mut self, now: Option<Duration>) {
+        debug_assert!(!self.reputation.is_banned);
+        let duration = cmp::min(
+            BASE_BAN_DURATION
+                .checked_mul(1u32 << cmp::min(self.reputation.num_bans, 16))
+                .unwrap_or(MAX_BAN_DURATION),
+            MAX_BAN_DURATION,
+        );
+        self.reputation.num_bans = self.reputation.num_bans.saturating_add(1);
+        self.reputation.is_banned = true;
+        self.ban = match now {
+            Some(now) => Ban::Until(now + duration),
+            None => Ban::Pending(duration),
+        };
+    }
+
+    /// Updates [`Source::request_size`] after a request towards this source has succeeded.
+    fn update_request_size(
+        &mut self,
+        num_blocks: u32,
+        latency: Duration,
+        blocks_request_granularity: NonZeroU32,
+    ) {
+        // Number of blocks that the source would be capable of sending back in
+        // `TARGET_REQUEST_DURATION`, based on this request.
+        let ideal = u128::from(num_blocks) * TARGET_REQUEST_DURATION.as_millis()
+            / cmp::max(latency.as_millis(), 1);
+        // The new size is the average between the previous one and the ideal one, in order to
+        // smooth out variations.
+        let new_size = (u128::from(self.request_size.get()) + ideal) / 2;
+        let new_size = cmp::min(
+            cmp::max(new_size, 1),
+            u128::from(blocks_request_granularity.get()),
+        );
+        self.request_size = NonZeroU32::new(u32::try_from(new_size).unwrap()).unwrap();
+    }
+
+    /// Updates [`Source::request_size`] after a request towards this source has failed.
+    fn shrink_request_size(&mut self) {
+        self.request_size = NonZeroU32::new(cmp::max(self.request_size.get() / 2, 1)).unwrap();
+    }
+
+    /// Updates the state of the ban of this source according to the current time.
+    fn update_ban(&mut self, now: Duration) {
+        match self.ban {
+            Ban::NotBanned => {}
+            Ban::Pending(duration) => self.ban = Ban::Until(now + duration),
+            Ban::Until(until) if until <= now => {
+                self.ban = Ban::NotBanned;
+                self.reputation.is_banned = false;
+                self.reputation.score = INITIAL_REPUTATION;
+            }
+            Ban::Until(_) => {}
+        }
+    }
+}
+
+/// Returns the reputation change to apply to a source when a request has succeeded after the
+/// given duration.
+fn request_success_reputation_change(latency: Duration) -> i32 {
+    if latency < Duration::from_secs(2) {
+        20
+    } else if latency < Duration::from_secs(5) {
+        10
+    } else if latency < Duration::from_secs(15) {
+        0
+    } else {
+        -20
+    }
 }
 
 enum VerificationQueueEntryTy<TRq, TBl> {
-    Missing,
+    Missing {
+        /// Index within [`OptimisticSync::sources`] of a source that has failed to provide these
+        /// blocks in the past. This source is only chosen again if no other source is available.
+        failed_source: Option<usize>,
+    },
     Requested {
         id: RequestId,
         /// User-chosen data for this request.
         user_data: TRq,
         // Index of this source within [`OptimisticSync::sources`].
         source: usize,
+        /// Moment when the request has been started.
+        start_time: Duration,
+        /// Number of blocks that have been requested.
+        num_blocks: NonZeroU32,
+    },
+    Queued {
+        blocks: Vec<TBl>,
+        /// Index within [`OptimisticSync::sources`] of the source the blocks have been obtained
+        /// from, or `None` if this source has been removed since then.
+        source: Option<usize>,
     },
-    Queued(Vec<TBl>),
 }
 
 impl<TRq, TSrc, TBl> OptimisticSync<TRq, TSrc, TBl> {
@@ -127,6 +319,7 @@ impl<TRq, TSrc, TBl> OptimisticSync<TRq, TSrc, TBl> {
                 .saturating_add(1),
             ),
             blocks_request_granularity: config.blocks_request_granularity,
+            max_requests_per_source: config.max_requests_per_source,
             download_ahead_blocks: config.download_ahead_blocks,
             next_request_id: RequestId(0),
             source_selection_rng: rand_chacha::ChaCha8Rng::seed_from_u64(
@@ -137,10 +330,49 @@ impl<TRq, TSrc, TBl> OptimisticSync<TRq, TSrc, TBl> {
 
     /// Inform the [`OptimisticSync`] of a new potential source of blocks.
     pub fn add_source(&mut self, source: TSrc) -> SourceId {
-        SourceId(self.sources.insert(Source {
-            user_data: source,
-            banned: false,
-        }))
+        SourceId(
+            self.sources
+                .insert(Source::new(source, self.blocks_request_granularity)),
+        )
+    }
+
+    /// Returns the current reputation of the given source.
+    ///
+    /// The network layer is encouraged to use this information to disconnect from or ban
+    /// sources that are misbehaving.
+    ///
+    /// # Panic
+    ///
+    /// Panics if the [`SourceId`] is invalid.
+    ///
+    pub fn source_reputation(&self, source: SourceId) -> SourceReputation {
+        self.sources[source.0].reputation.clone()
+    }
+
+    /// Inform the [`OptimisticSync`] of the height of the best block of a source, for example
+    /// after this source has announced a new block.
+    ///
+    /// Blocks above this height will no longer be requested from this source. If the source was
+    /// the only one to know about blocks that are missing, a request for these blocks can
+    /// immediately be started by calling [`OptimisticSync::next_request_action`].
+    ///
+    /// # Panic
+    ///
+    /// Panics if the [`SourceId`] is invalid.
+    ///
+    pub fn source_best_block_update(&mut self, source: SourceId, best_block_number: u64) {
+        self.sources[source.0].best_block_number = Some(best_block_number);
+    }
+
+    /// Returns the height of the best block of the given source, as last reported with
+    /// [`OptimisticSync::source_best_block_update`], or `None` if unknown.
+    ///
+    /// # Panic
+    ///
+    /// Panics if the [`SourceId`] is invalid.
+    ///
+    pub fn source_best_block(&self, source: SourceId) -> Option<u64> {
+        self.sources[source.0].best_block_number
     }
 
     /// Inform the [`OptimisticSync`] that a source of blocks is no longer available.
@@ -166,7 +398,12 @@ impl<TRq, TSrc, TBl> OptimisticSync<TRq, TSrc, TBl> {
 
     /// Returns an iterator that extracts all requests that need to be started and requests that
     /// need to be cancelled.
-    pub fn next_request_action(&mut self) -> Option<RequestAction<TRq, TSrc, TBl>> {
+    ///
+    /// `now` must be the time elapsed since an arbitrary moment in the past, and that same
+    /// moment must be used for all the calls to [`OptimisticSync::next_request_action`] and
+    /// [`OptimisticSync::finish_request`]. It is used to measure the latency of requests and to
+    /// expire the bans of sources.
+    pub fn next_request_action(&mut self, now: Duration) -> Option<RequestAction<TRq, TSrc, TBl>> {
         if self.cancelling_requests {
             while let Some(queue_elem) = self.verification_queue.pop_back() {
                 match queue_elem.ty {
@@ -174,7 +411,9 @@ impl<TRq, TSrc, TBl> OptimisticSync<TRq, TSrc, TBl> {
                         id,
                         source,
                         user_data,
+                        ..
                     } => {
+                        self.sources[source].num_ongoing_requests -= 1;
                         return Some(RequestAction::Cancel {
                             request_id: id,
                             user_data,
@@ -203,51 +442,81 @@ impl<TRq, TSrc, TBl> OptimisticSync<TRq, TSrc, TBl> {
                 .unwrap_or(self.best_block_number + 1);
             self.verification_queue.push_back(VerificationQueueEntry {
                 block_height: NonZeroU64::new(block_height).unwrap(),
-                ty: VerificationQueueEntryTy::Missing,
+                ty: VerificationQueueEntryTy::Missing {
+                    failed_source: None,
+                },
             });
         }
 
-        if let Some((missing_pos, _)) = self
+        if let Some((missing_pos, failed_source)) = self
             .verification_queue
             .iter()
             .enumerate()
-            .find(|(_, e)| matches!(e.ty, VerificationQueueEntryTy::Missing))
+            .find_map(|(pos, e)| match e.ty {
+                VerificationQueueEntryTy::Missing { failed_source } => Some((pos, failed_source)),
+                _ => None,
+            })
         {
-            let source = self
-                .sources
-                .iter()
-                .filter(|(_, src)| !src.banned)
-                .choose(&mut self.source_selection_rng)?
-                .0;
-
+            // Sources are chosen randomly amongst the ones that aren't banned and that aren't
+            // already busy, with a probability proportional to how far above the ban threshold
+            // their reputation is. If these blocks have previously failed to be obtained from a
+            // certain source, a different source is preferred. Sources whose best block is known
+            // to be below the first missing block are never chosen.
+            for (_, src) in self.sources.iter_mut() {
+                src.update_ban(now);
+            }
             let block_height = self.verification_queue[missing_pos].block_height;
-
-            let num_blocks = if let Some(next) = self.verification_queue.get(missing_pos + 1) {
-                NonZeroU32::new(
-                    u32::try_from(cmp::min(
-                        u64::from(self.blocks_request_granularity.get()),
-                        next.block_height
-                            .get()
-                            .checked_sub(block_height.get())
-                            .unwrap(),
-                    ))
-                    .unwrap(),
-                )
-                .unwrap()
-            } else {
-                self.blocks_request_granularity
+            let source = self
+                .pick_source(block_height.get(), |index| Some(index) != failed_source)
+                .or_else(|| self.pick_source(block_height.get(), |_| true))?;
+
+            // If the source is only capable of delivering fewer blocks than the size of the
+            // missing entry, either because of its throughput or because it doesn't know all of
+            // them, the entry is split in two. The second half is only inserted in the queue
+            // once the request has been started.
+            let (num_blocks, split) = {
+                let entry_size = self.entry_num_blocks(missing_pos);
+                let request_size = match self.sources[source].best_block_number {
+                    Some(best) => {
+                        let known = best - block_height.get() + 1;
+                        let request_size = self.sources[source].request_size;
+                        if known < u64::from(request_size.get()) {
+                            NonZeroU32::new(u32::try_from(known).unwrap()).unwrap()
+                        } else {
+                            request_size
+                        }
+                    }
+                    None => self.sources[source].request_size,
+                };
+                if request_size < entry_size {
+                    let split = VerificationQueueEntry {
+                        block_height: NonZeroU64::new(
+                            block_height.get() + u64::from(request_size.get()),
+                        )
+                        .unwrap(),
+                        ty: VerificationQueueEntryTy::Missing { failed_source },
+                    };
+                    (request_size, Some(split))
+                } else {
+                    (entry_size, None)
+                }
             };
 
+            let source_entry = &mut self.sources[source];
             return Some(RequestAction::Start {
                 source_id: SourceId(source),
-                source: &mut self.sources[source].user_data,
+                source: &mut source_entry.user_data,
                 block_height,
                 num_blocks,
                 start: Start {
                     verification_queue: &mut self.verification_queue,
+                    source_num_ongoing_requests: &mut source_entry.num_ongoing_requests,
                     missing_pos,
+                    split,
                     next_request_id: &mut self.next_request_id,
                     source,
+                    num_blocks,
+                    now,
                     marker: PhantomData,
                 },
             });
@@ -260,6 +529,8 @@ impl<TRq, TSrc, TBl> OptimisticSync<TRq, TSrc, TBl> {
     ///
     /// Returns the user data that was associated to that request.
     ///
+    /// See [`OptimisticSync::next_request_action`] for the meaning of `now`.
+    ///
     /// # Panic
     ///
     /// Panics if the [`RequestId`] is invalid.
@@ -268,43 +539,121 @@ impl<TRq, TSrc, TBl> OptimisticSync<TRq, TSrc, TBl> {
         &'a mut self,
         request_id: RequestId,
         outcome: Result<impl Iterator<Item = TBl>, RequestFail>,
+        now: Duration,
     ) -> (TRq, FinishRequestOutcome<'a, TSrc>) {
-        let (verification_queue_entry, source_id) = self
+        let (verification_queue_entry, source_id, start_time, num_blocks) = self
             .verification_queue
             .iter()
             .enumerate()
             .filter_map(|(pos, entry)| match entry.ty {
-                VerificationQueueEntryTy::Requested { id, source, .. } if id == request_id => {
-                    Some((pos, source))
-                }
+                VerificationQueueEntryTy::Requested {
+                    id,
+                    source,
+                    start_time,
+                    num_blocks,
+                    ..
+                } if id == request_id => Some((pos, source, start_time, num_blocks)),
                 _ => None,
             })
             .next()
             .expect("invalid RequestId");
 
+        let source = &mut self.sources[source_id];
+        source.num_ongoing_requests -= 1;
+        let latency = now.checked_sub(start_time).unwrap_or_default();
+        source.reputation.average_latency = Some(match source.reputation.average_latency {
+            Some(avg) => avg * 7 / 8 + latency / 8,
+            None => latency,
+        });
+
+        // Any block beyond the number of blocks that have been requested is discarded.
+        // An empty response is treated the same way as a failed request, as it doesn't make
+        // any progress.
         let blocks = match outcome {
-            Ok(blocks) => blocks.collect(),
-            Err(_) => {
+            Ok(blocks) => {
+                let blocks = blocks
+                    .take(usize::try_from(num_blocks.get()).unwrap())
+                    .collect::<Vec<_>>();
+                if blocks.is_empty() {
+                    Err(RequestFail::BlocksUnavailable)
+                } else {
+                    Ok(blocks)
+                }
+            }
+            Err(err) => Err(err),
+        };
+
+        let blocks = match blocks {
+            Ok(blocks) => blocks,
+            Err(err) => {
+                // The blocks will be requested again, preferably from a different source.
                 let user_data = match mem::replace(
                     &mut self.verification_queue[verification_queue_entry].ty,
-                    VerificationQueueEntryTy::Missing,
+                    VerificationQueueEntryTy::Missing {
+                        failed_source: Some(source_id),
+                    },
                 ) {
                     VerificationQueueEntryTy::Requested { user_data, .. } => user_data,
                     _ => unreachable!(),
                 };
 
-                return (
-                    user_data,
-                    FinishRequestOutcome::SourcePunished(&mut self.sources[source_id].user_data),
-                );
+                source.reputation.failed_requests =
+                    source.reputation.failed_requests.saturating_add(1);
+                source.shrink_request_size();
+
+                let banned = match err {
+                    RequestFail::BlocksUnavailable => {
+                        source.adjust_reputation(REQUEST_FAILURE_REPUTATION_CHANGE, Some(now))
+                    }
+                    RequestFail::InvalidBlocks => {
+                        source.reputation.invalid_data =
+                            source.reputation.invalid_data.saturating_add(1);
+                        if !source.reputation.is_banned {
+                            source.ban(Some(now));
+                        }
+                        true
+                    }
+                };
+
+                let outcome = if banned {
+                    FinishRequestOutcome::SourceBanned(&mut source.user_data)
+                } else {
+                    FinishRequestOutcome::SourcePunished(&mut source.user_data)
+                };
+
+                return (user_data, outcome);
             }
         };
 
-        // TODO: handle if blocks.len() < expected_number_of_blocks
+        let num_received = u32::try_from(blocks.len()).unwrap();
+        source.reputation.successful_requests =
+            source.reputation.successful_requests.saturating_add(1);
+        source.adjust_reputation(request_success_reputation_change(latency), Some(now));
+        source.update_request_size(num_received, latency, self.blocks_request_granularity);
+
+        // If the source has sent back fewer blocks than requested, the blocks that are missing
+        // are put in a separate entry in order to be requested again.
+        if num_received < num_blocks.get() {
+            let block_height = self.verification_queue[verification_queue_entry]
+                .block_height
+                .get();
+            self.verification_queue.insert(
+                verification_queue_entry + 1,
+                VerificationQueueEntry {
+                    block_height: NonZeroU64::new(block_height + u64::from(num_received)).unwrap(),
+                    ty: VerificationQueueEntryTy::Missing {
+                        failed_source: None,
+                    },
+                },
+            );
+        }
 
         let user_data = match mem::replace(
             &mut self.verification_queue[verification_queue_entry].ty,
-            VerificationQueueEntryTy::Queued(blocks),
+            VerificationQueueEntryTy::Queued {
+                blocks,
+                source: Some(source_id),
+            },
         ) {
             VerificationQueueEntryTy::Requested { user_data, .. } => user_data,
             _ => unreachable!(),
@@ -313,8 +662,71 @@ impl<TRq, TSrc, TBl> OptimisticSync<TRq, TSrc, TBl> {
         (user_data, FinishRequestOutcome::Queued)
     }
 
+    /// Randomly picks a source amongst the ones that aren't banned, that aren't already busy,
+    /// that might know the block at the given height, and for which `filter` returns `true`.
+    /// Returns `None` if there isn't any such source.
+    fn pick_source(&mut self, block_height: u64, filter: impl Fn(usize) -> bool) -> Option<usize> {
+        let max_requests_per_source = self.max_requests_per_source.get();
+        let is_available = |index: usize, src: &Source<TSrc>| {
+            !src.reputation.is_banned
+                && src.num_ongoing_requests < max_requests_per_source
+                && src
+                    .best_block_number
+                    .map_or(true, |best| best >= block_height)
+                && filter(index)
+        };
+
+        let total_weight = self
+            .sources
+            .iter()
+            .filter(|(index, src)| is_available(*index, src))
+            .map(|(_, src)| source_weight(src))
+            .sum::<u64>();
+        if total_weight == 0 {
+            return None;
+        }
+
+        let mut pick = self.source_selection_rng.gen_range(0, total_weight);
+        self.sources
+            .iter()
+            .filter(|(index, src)| is_available(*index, src))
+            .find(|(_, src)| {
+                let weight = source_weight(src);
+                if pick < weight {
+                    true
+                } else {
+                    pick -= weight;
+                    false
+                }
+            })
+            .map(|(index, _)| index)
+    }
+
+    /// Returns the number of blocks covered by the entry of the verification queue at the given
+    /// position.
+    fn entry_num_blocks(&self, pos: usize) -> NonZeroU32 {
+        if let Some(next) = self.verification_queue.get(pos + 1) {
+            NonZeroU32::new(
+                u32::try_from(cmp::min(
+                    u64::from(self.blocks_request_granularity.get()),
+                    next.block_height
+                        .get()
+                        .checked_sub(self.verification_queue[pos].block_height.get())
+                        .unwrap(),
+                ))
+                .unwrap(),
+            )
+            .unwrap()
+        } else {
+            self.blocks_request_granularity
+        }
+    }
+
     /// Process a single block in the queue of verification.
     ///
+    /// Blocks are always processed in increasing order of block height, no matter the order in
+    /// which the responses to the requests have arrived.
+    ///
     /// Returns an error if the queue is empty.
     pub fn process_one(mut self) -> Result<ProcessOne<TRq, TSrc, TBl>, Self> {
         if self.cancelling_requests {
@@ -322,9 +734,9 @@ impl<TRq, TSrc, TBl> OptimisticSync<TRq, TSrc, TBl> {
         }
 
         // Extract the chunk of blocks to process next.
-        let blocks = match &mut self.verification_queue.get_mut(0).map(|b| &mut b.ty) {
-            Some(VerificationQueueEntryTy::Queued(blocks)) => {
-                mem::replace(blocks, Default::default())
+        let (blocks, source) = match &mut self.verification_queue.get_mut(0).map(|b| &mut b.ty) {
+            Some(VerificationQueueEntryTy::Queued { blocks, source }) => {
+                (mem::replace(blocks, Default::default()), *source)
             }
             _ => return Err(self),
         };
@@ -335,12 +747,22 @@ impl<TRq, TSrc, TBl> OptimisticSync<TRq, TSrc, TBl> {
 
         Ok(ProcessOne {
             expected_block_height,
+            source_id: source.map(SourceId),
             blocks: blocks.into_iter(),
-            report: ProcessOneReport { parent: self },
+            report: ProcessOneReport {
+                parent: self,
+                source,
+            },
         })
     }
 }
 
+/// Weight of the given source when randomly choosing a source to send a request to.
+fn source_weight<TSrc>(source: &Source<TSrc>) -> u64 {
+    debug_assert!(source.reputation.score > BAN_THRESHOLD);
+    u64::try_from(source.reputation.score - BAN_THRESHOLD).unwrap()
+}
+
 /// Identifier for an ongoing request in the [`OptimisticSync`].
 #[derive(Debug, Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash)]
 pub struct RequestId(u64);
@@ -366,7 +788,7 @@ pub enum RequestAction<'a, TRq, TSrc, TBl> {
         start: Start<'a, TRq, TSrc, TBl>,
         /// Height of the block to request.
         block_height: NonZeroU64,
-        /// Number of blocks to request. Always smaller than the value passed through
+        /// Number of blocks to request. Always smaller than or equal to the value passed through
         /// [`Config::blocks_request_granularity`].
         num_blocks: NonZeroU32,
     },
@@ -391,9 +813,15 @@ pub enum RequestAction<'a, TRq, TSrc, TBl> {
 #[must_use]
 pub struct Start<'a, TRq, TSrc, TBl> {
     verification_queue: &'a mut VecDeque<VerificationQueueEntry<TRq, TBl>>,
+    source_num_ongoing_requests: &'a mut u32,
     source: usize,
     missing_pos: usize,
+    /// Entry to insert right after the one at `missing_pos`, if the request covers only part of
+    /// the missing blocks.
+    split: Option<VerificationQueueEntry<TRq, TBl>>,
     next_request_id: &'a mut RequestId,
+    num_blocks: NonZeroU32,
+    now: Duration,
     marker: PhantomData<&'a TSrc>,
 }
 
@@ -404,13 +832,20 @@ impl<'a, TRq, TSrc, TBl> Start<'a, TRq, TSrc, TBl> {
     pub fn start(self, user_data: TRq) -> RequestId {
         let request_id = *self.next_request_id;
         self.next_request_id.0 += 1;
+        *self.source_num_ongoing_requests += 1;
 
         self.verification_queue[self.missing_pos].ty = VerificationQueueEntryTy::Requested {
             id: request_id,
             source: self.source,
             user_data,
+            start_time: self.now,
+            num_blocks: self.num_blocks,
         };
 
+        if let Some(split) = self.split {
+            self.verification_queue.insert(self.missing_pos + 1, split);
+        }
+
         request_id
     }
 }
@@ -421,15 +856,24 @@ impl<'a, TRq, TSrc, TBl> fmt::Debug for Start<'a, TRq, TSrc, TBl> {
     }
 }
 
+/// Outcome of calling [`OptimisticSync::finish_request`].
 pub enum FinishRequestOutcome<'a, TSrc> {
+    /// The blocks have been queued for verification.
     Queued,
+    /// The request has failed and the reputation of the source has been decreased.
     SourcePunished(&'a mut TSrc),
+    /// The request has failed and the reputation of the source has reached the point where it
+    /// is now banned. No request will be started towards this source until the ban expires.
+    SourceBanned(&'a mut TSrc),
 }
 
 /// Reason why a request has failed.
 pub enum RequestFail {
     /// Requested blocks aren't available from this source.
     BlocksUnavailable,
+    /// The source has sent back blocks that are provably invalid, for example a block body that
+    /// doesn't match its header. The source gets banned.
+    InvalidBlocks,
 }
 
 /// Iterator that drains requests after a source has been removed.
@@ -444,11 +888,23 @@ impl<'a, TRq, TBl> Iterator for RequestsDrain<'a, TRq, TBl> {
     fn next(&mut self) -> Option<Self::Item> {
         loop {
             let entry = self.iter.next()?;
-            match entry.ty {
+            match &mut entry.ty {
+                // Blocks that have already been received are kept, but are no longer associated
+                // with the source, as its index might get reused.
+                VerificationQueueEntryTy::Queued { source, .. }
+                    if *source == Some(self.source_index) =>
+                {
+                    *source = None;
+                }
                 VerificationQueueEntryTy::Requested { source, .. }
-                    if source == self.source_index =>
+                    if *source == self.source_index =>
                 {
-                    match mem::replace(&mut entry.ty, VerificationQueueEntryTy::Missing) {
+                    match mem::replace(
+                        &mut entry.ty,
+                        VerificationQueueEntryTy::Missing {
+                            failed_source: None,
+                        },
+                    ) {
                         VerificationQueueEntryTy::Requested { id, user_data, .. } => {
                             return Some((id, user_data));
                         }
@@ -481,6 +937,8 @@ impl<'a, TRq, TBl> Drop for RequestsDrain<'a, TRq, TBl> {
 
 pub struct ProcessOne<TRq, TSrc, TBl> {
     pub expected_block_height: u64,
+    /// Source the blocks have been obtained from, or `None` if it has been removed since then.
+    pub source_id: Option<SourceId>,
     pub blocks: vec::IntoIter<TBl>,
     pub report: ProcessOneReport<TRq, TSrc, TBl>,
 }
@@ -488,9 +946,32 @@ pub struct ProcessOne<TRq, TSrc, TBl> {
 #[must_use]
 pub struct ProcessOneReport<TRq, TSrc, TBl> {
     parent: OptimisticSync<TRq, TSrc, TBl>,
+    /// Index within [`OptimisticSync::sources`] of the source the blocks have been obtained
+    /// from.
+    source: Option<usize>,
 }
 
 impl<TRq, TSrc, TBl> ProcessOneReport<TRq, TSrc, TBl> {
+    /// Reports that the source the blocks have been obtained from has sent back blocks that are
+    /// invalid, and bans it.
+    ///
+    /// Returns the [`SourceId`] of the banned source, or `None` if the source has been removed
+    /// or is already banned.
+    ///
+    /// > **Note**: Keep in mind that a block can be invalid only because of one of its ancestors
+    /// >           that was provided by a different source. Only call this method if the blame
+    /// >           can be attributed with certainty.
+    pub fn source_sent_invalid_data(&mut self) -> Option<SourceId> {
+        let source_index = self.source?;
+        let source = &mut self.parent.sources[source_index];
+        source.reputation.invalid_data = source.reputation.invalid_data.saturating_add(1);
+        if source.reputation.is_banned {
+            return None;
+        }
+        source.ban(None);
+        Some(SourceId(source_index))
+    }
+
     pub fn reset_to_finalized(
         mut self,
         finalized_block_number: u64,
@@ -507,4 +988,29 @@ impl<TRq, TSrc, TBl> ProcessOneReport<TRq, TSrc, TBl> {
         self.parent.best_block_number = new_best_block_number;
         self.parent
     }
+
+    /// Puts back the given blocks at the head of the verification queue, in order for them to be
+    /// processed again by a later call to [`OptimisticSync::process_one`].
+    ///
+    /// `blocks` must be the blocks that haven't been processed, the first of them being the
+    /// child of the block whose number is `new_best_block_number`.
+    pub fn process_later(
+        mut self,
+        new_best_block_number: u64,
+        blocks: impl Iterator<Item = TBl>,
+    ) -> OptimisticSync<TRq, TSrc, TBl> {
+        let blocks = blocks.collect::<Vec<_>>();
+        if !blocks.is_empty() {
+            self.parent
+                .verification_queue
+                .push_front(VerificationQueueEntry {
+                    block_height: NonZeroU64::new(new_best_block_number + 1).unwrap(),
+                    ty: VerificationQueueEntryTy::Queued {
+                        blocks,
+                        source: self.source,
+                    },
+                });
+        }
+        self.update_block_height(new_best_block_number)
+    }
 }
@@ -0,0 +1,919 @@
+// Substrate-lite
+// Copyright (C) 2019-2020  Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! State syncing.
+//!
+//! State syncing consists in downloading, from a source, all the storage entries of a specific
+//! block, rather than rebuilding this storage by executing every single block since the genesis
+//! block.
+//!
+//! The storage is downloaded using the state request protocol of Substrate. Each request
+//! indicates the key after which to start, and each response consists of a proof (see the
+//! [`compact_proof`](crate::trie::compact_proof) module) of the storage entries that follow
+//! this key, up to a size chosen by the source. The proof is verified against the state root
+//! found in the header of the block. A source can therefore not provide storage entries that
+//! don't belong to the block, nor omit entries in the middle of a range.
+//!
+//! # Child tries
+//!
+//! The storage value of each key of the main trie starting with `:child_storage:default:` is the
+//! Merkle value of the root node of a child trie. The entries of a child trie are downloaded
+//! right after the key of the main trie that contains its root, and are then followed with the
+//! rest of the entries of the main trie. The compact proofs of the child tries are found in the
+//! response after the compact proof of the main trie.
+//!
+//! A request can start in the middle of a child trie, in which case it indicates both the key of
+//! the child trie in the main trie and the key after which to start within the child trie.
+//!
+//! # Usage
+//!
+//! Sources of storage entries must be registered using [`StateSync::add_source`] and
+//! unregistered using [`StateSync::remove_source`].
+//!
+//! Call [`StateSync::next_request_action`] in order to know which request to start, then pass
+//! the response to [`StateSync::finish_request`]. On success, this method returns a list of
+//! verified storage entries that must be inserted in the storage of the block.
+//!
+//! Once [`StateSync::is_finished`] returns `true`, the storage of the block is complete, and
+//! blocks can be executed on top of this block. This block is typically the latest finalized
+//! block obtained through [warp syncing](super::warp_sync).
+
+use crate::{
+    header,
+    trie::{self, compact_proof, proof_verify},
+};
+
+use alloc::vec::Vec;
+use core::{convert::TryFrom as _, fmt};
+
+/// Prefix of the keys of the main trie whose storage value is the Merkle value of the root node
+/// of a child trie.
+const DEFAULT_CHILD_STORAGE_PREFIX: &[u8] = b":child_storage:default:";
+
+/// Configuration for the [`StateSync`].
+#[derive(Debug)]
+pub struct Config {
+    /// Header of the block whose storage must be downloaded.
+    pub block_header: header::Header,
+
+    /// Layout of the storage tries of the block.
+    pub trie_layout: trie::Layout,
+
+    /// Pre-allocated capacity for the number of sources.
+    pub sources_capacity: usize,
+}
+
+/// Download of the storage of a block.
+pub struct StateSync<TSrc> {
+    /// Hash of the block whose storage is downloaded.
+    block_hash: [u8; 32],
+
+    /// Number of the block whose storage is downloaded.
+    block_number: u64,
+
+    /// State trie root found in the header of the block.
+    state_root: [u8; 32],
+
+    /// See [`Config::trie_layout`].
+    trie_layout: trie::Layout,
+
+    /// List of sources of storage entries.
+    sources: slab::Slab<Source<TSrc>>,
+
+    /// Request in progress, if any.
+    ongoing_request: Option<(RequestId, SourceId)>,
+
+    /// Identifier to assign to the next request.
+    next_request_id: RequestId,
+
+    /// Position in the storage of the next storage entry to download.
+    cursor: Cursor,
+
+    /// Number of storage entries that have been returned by [`StateSync::finish_request`] so
+    /// far.
+    num_entries: u64,
+}
+
+struct Source<TSrc> {
+    user_data: TSrc,
+    /// `true` if this source has sent back an invalid response. No further request will be
+    /// started towards this source.
+    banned: bool,
+}
+
+/// Position in the storage of the next storage entry to download.
+#[derive(Debug, Clone)]
+enum Cursor {
+    /// The next entries are found in the main trie, strictly after the given key, or from the
+    /// start of the main trie if `None`.
+    MainTrie { after: Option<Vec<u8>> },
+    /// The next entries are found in a child trie, strictly after the given key.
+    ChildTrie {
+        /// Key in the main trie whose storage value is the root of the child trie.
+        child_trie: Vec<u8>,
+        /// Merkle value of the root node of the child trie.
+        root: [u8; 32],
+        /// Last key of the child trie that has been downloaded.
+        after: Vec<u8>,
+    },
+    /// All the entries have been downloaded.
+    Finished,
+}
+
+impl<TSrc> StateSync<TSrc> {
+    /// Builds a new [`StateSync`].
+    pub fn new(config: Config) -> Self {
+        StateSync {
+            block_hash: config.block_header.hash(),
+            block_number: config.block_header.number,
+            state_root: config.block_header.state_root,
+            trie_layout: config.trie_layout,
+            sources: slab::Slab::with_capacity(config.sources_capacity),
+            ongoing_request: None,
+            next_request_id: RequestId(0),
+            cursor: Cursor::MainTrie { after: None },
+            num_entries: 0,
+        }
+    }
+
+    /// Returns the hash of the block whose storage is downloaded.
+    pub fn block_hash(&self) -> [u8; 32] {
+        self.block_hash
+    }
+
+    /// Returns the number of the block whose storage is downloaded.
+    pub fn block_number(&self) -> u64 {
+        self.block_number
+    }
+
+    /// Returns the number of storage entries that have been downloaded and verified so far.
+    pub fn num_entries_downloaded(&self) -> u64 {
+        self.num_entries
+    }
+
+    /// Returns `true` if all the storage entries of the block have been downloaded.
+    pub fn is_finished(&self) -> bool {
+        matches!(self.cursor, Cursor::Finished)
+    }
+
+    /// Inform the [`StateSync`] of a new potential source of storage entries.
+    pub fn add_source(&mut self, source: TSrc) -> SourceId {
+        SourceId(self.sources.insert(Source {
+            user_data: source,
+            banned: false,
+        }))
+    }
+
+    /// Inform the [`StateSync`] that a source is no longer available.
+    ///
+    /// Returns the user data of the source, and the identifier of the request that was in
+    /// progress towards this source, if any. This request must no longer be passed to
+    /// [`StateSync::finish_request`].
+    ///
+    /// # Panic
+    ///
+    /// Panics if the [`SourceId`] is invalid.
+    ///
+    pub fn remove_source(&mut self, source_id: SourceId) -> (TSrc, Option<RequestId>) {
+        let user_data = self.sources.remove(source_id.0).user_data;
+
+        let request = match self.ongoing_request {
+            Some((request_id, s)) if s == source_id => {
+                self.ongoing_request = None;
+                Some(request_id)
+            }
+            _ => None,
+        };
+
+        (user_data, request)
+    }
+
+    /// Returns the request that should be started, if any.
+    ///
+    /// At most one request is in progress at any given time. The request must be started
+    /// immediately, and [`StateSync::finish_request`] must later be called with the
+    /// [`RequestId`] returned here.
+    pub fn next_request_action(&mut self) -> Option<RequestAction<TSrc>> {
+        if self.ongoing_request.is_some() {
+            return None;
+        }
+
+        let start = match &self.cursor {
+            Cursor::MainTrie { after: None } => Vec::new(),
+            Cursor::MainTrie { after: Some(after) } => vec![after.clone()],
+            Cursor::ChildTrie {
+                child_trie, after, ..
+            } => vec![child_trie.clone(), after.clone()],
+            Cursor::Finished => return None,
+        };
+
+        let source_id = SourceId(self.sources.iter().find(|(_, s)| !s.banned)?.0);
+
+        let request_id = self.next_request_id;
+        self.next_request_id.0 += 1;
+        self.ongoing_request = Some((request_id, source_id));
+
+        Some(RequestAction {
+            request_id,
+            source_id,
+            source: &mut self.sources[source_id.0].user_data,
+            block_hash: self.block_hash,
+            start,
+        })
+    }
+
+    /// Update the [`StateSync`] with the outcome of a request.
+    ///
+    /// On success, must be passed the entries of the proof sent back by the source, which
+    /// consists of the compact proof of the main trie followed with the compact proofs of the
+    /// child tries. If the proof is valid, returns the list of storage entries that it contains.
+    /// These entries must be inserted in the storage of the block.
+    ///
+    /// On error, no further request will be started towards this source.
+    ///
+    /// # Panic
+    ///
+    /// Panics if the [`RequestId`] is invalid.
+    ///
+    pub fn finish_request(
+        &mut self,
+        request_id: RequestId,
+        outcome: Result<Vec<Vec<u8>>, RequestFail>,
+    ) -> Result<Vec<StorageEntry>, Error> {
+        let source_id = match self.ongoing_request.take() {
+            Some((id, source_id)) if id == request_id => source_id,
+            _ => panic!("invalid RequestId"),
+        };
+
+        let result = match outcome {
+            Ok(proof) => self.verify_response(&proof),
+            Err(RequestFail::Unavailable) => Err(Error::Unavailable),
+        };
+
+        if result.is_err() {
+            // Note that the source is not necessarily malicious in case of `Unavailable`, but
+            // there's no point in asking it again.
+            self.sources[source_id.0].banned = true;
+        }
+
+        result
+    }
+
+    /// Verifies the given proof and, on success, updates the state of `self`.
+    fn verify_response(&mut self, proof: &[Vec<u8>]) -> Result<Vec<StorageEntry>, Error> {
+        let decoded =
+            compact_proof::decode_concatenated(self.trie_layout, proof.iter().map(|e| &e[..]))
+                .map_err(Error::InvalidProof)?;
+
+        if decoded[0].trie_root_hash != self.state_root {
+            return Err(Error::StateRootMismatch);
+        }
+
+        // The node values of all the tries are merged together. Each trie is then traversed
+        // starting from its root.
+        let proof = decoded
+            .iter()
+            .flat_map(|d| d.proof.iter().map(|e| &e[..]))
+            .collect::<Vec<_>>();
+
+        let mut entries = Vec::new();
+
+        // If the request started in the middle of a child trie, the rest of this child trie is
+        // found first.
+        let mut main_trie_after = match &self.cursor {
+            Cursor::MainTrie { after } => after.clone(),
+            Cursor::ChildTrie {
+                child_trie,
+                root,
+                after,
+            } => {
+                let (child_entries, complete) =
+                    self.verify_child_trie(&proof, child_trie, root, Some(after))?;
+                if !complete {
+                    if child_entries.is_empty() {
+                        return Err(Error::NoProgress);
+                    }
+
+                    self.cursor = Cursor::ChildTrie {
+                        child_trie: child_trie.clone(),
+                        root: *root,
+                        after: child_entries.last().unwrap().key.clone(),
+                    };
+                    self.num_entries += u64::try_from(child_entries.len()).unwrap();
+                    return Ok(child_entries);
+                }
+
+                entries.extend(child_entries);
+                Some(child_trie.clone())
+            }
+            Cursor::Finished => unreachable!(),
+        };
+
+        let next_cursor = loop {
+            let start_key = main_trie_after
+                .as_ref()
+                .map_or(Vec::new(), |k| key_after(k));
+            let outcome = proof_verify::verify_range_proof(proof_verify::RangeConfig {
+                start_key: &start_key,
+                trie_root_hash: &self.state_root,
+                proof: proof.iter().copied(),
+                layout: self.trie_layout,
+            })
+            .map_err(Error::Verify)?;
+
+            let mut child_trie = None;
+            for (key, value) in outcome.entries {
+                if key.starts_with(DEFAULT_CHILD_STORAGE_PREFIX) {
+                    let root =
+                        <[u8; 32]>::try_from(value).map_err(|_| Error::InvalidChildTrieRoot)?;
+                    child_trie = Some((key, root));
+                    break;
+                }
+
+                main_trie_after = Some(key.clone());
+                entries.push(StorageEntry {
+                    child_trie: None,
+                    key,
+                    value: value.to_vec(),
+                });
+            }
+
+            let (child_trie, root) = match child_trie {
+                Some(c) => c,
+                None if outcome.complete => break Cursor::Finished,
+                None => {
+                    break Cursor::MainTrie {
+                        after: main_trie_after,
+                    }
+                }
+            };
+
+            let (child_entries, complete) =
+                self.verify_child_trie(&proof, &child_trie, &root, None)?;
+
+            // If none of the entries of the child trie are in the proof, the next request
+            // starts again before the key of the child trie in the main trie.
+            if !complete && child_entries.is_empty() {
+                break Cursor::MainTrie {
+                    after: main_trie_after,
+                };
+            }
+
+            entries.push(StorageEntry {
+                child_trie: None,
+                key: child_trie.clone(),
+                value: root.to_vec(),
+            });
+            entries.extend(child_entries);
+
+            if !complete {
+                break Cursor::ChildTrie {
+                    after: entries.last().unwrap().key.clone(),
+                    child_trie,
+                    root,
+                };
+            }
+
+            main_trie_after = Some(child_trie);
+        };
+
+        // A response that neither contains any entry nor finishes the download would make it
+        // possible for a source to stall the syncing forever.
+        if entries.is_empty() && !matches!(next_cursor, Cursor::Finished) {
+            return Err(Error::NoProgress);
+        }
+
+        self.cursor = next_cursor;
+        self.num_entries += u64::try_from(entries.len()).unwrap();
+        Ok(entries)
+    }
+
+    /// Verifies the entries of the given child trie found in `proof`, starting strictly after
+    /// `after` or at the start of the child trie if `None`.
+    ///
+    /// Returns the entries and whether all the entries of the child trie have been found.
+    fn verify_child_trie(
+        &self,
+        proof: &[&[u8]],
+        child_trie: &[u8],
+        root: &[u8; 32],
+        after: Option<&Vec<u8>>,
+    ) -> Result<(Vec<StorageEntry>, bool), Error> {
+        let start_key = after.map_or(Vec::new(), |k| key_after(k));
+        let outcome = proof_verify::verify_range_proof(proof_verify::RangeConfig {
+            start_key: &start_key,
+            trie_root_hash: root,
+            proof: proof.iter().copied(),
+            layout: self.trie_layout,
+        });
+
+        let outcome = match outcome {
+            Ok(outcome) => outcome,
+            // The child trie isn't covered by the proof at all.
+            Err(proof_verify::Error::TrieRootNotFound) => return Ok((Vec::new(), false)),
+            Err(err) => return Err(Error::Verify(err)),
+        };
+
+        let entries = outcome
+            .entries
+            .into_iter()
+            .map(|(key, value)| StorageEntry {
+                child_trie: Some(child_trie.to_vec()),
+                key,
+                value: value.to_vec(),
+            })
+            .collect();
+        Ok((entries, outcome.complete))
+    }
+}
+
+/// Returns the smallest key strictly superior to the given key.
+fn key_after(key: &[u8]) -> Vec<u8> {
+    let mut key = key.to_vec();
+    key.push(0);
+    key
+}
+
+impl<TSrc> fmt::Debug for StateSync<TSrc> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("StateSync")
+            .field("block_hash", &self.block_hash)
+            .field("num_entries", &self.num_entries)
+            .field("finished", &self.is_finished())
+            .finish()
+    }
+}
+
+/// Identifier for an ongoing request in the [`StateSync`].
+#[derive(Debug, Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash)]
+pub struct RequestId(u64);
+
+/// Identifier for a source in the [`StateSync`].
+#[derive(Debug, Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash)]
+pub struct SourceId(usize);
+
+/// Request that must be started towards a source.
+#[derive(Debug)]
+pub struct RequestAction<'a, TSrc> {
+    /// Identifier of the request, to later pass to [`StateSync::finish_request`].
+    pub request_id: RequestId,
+    /// Source where to request storage entries from.
+    pub source_id: SourceId,
+    /// User data of source where to request storage entries from.
+    pub source: &'a mut TSrc,
+    /// Hash of the block whose storage entries are requested.
+    pub block_hash: [u8; 32],
+    /// Keys after which the storage entries are requested, as found in the `start` field of a
+    /// state request. Empty if the storage entries are requested from the start of the main
+    /// trie. Contains one key of the main trie, or the key of a child trie in the main trie
+    /// followed with a key of this child trie.
+    pub start: Vec<Vec<u8>>,
+}
+
+/// Storage entry returned by [`StateSync::finish_request`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StorageEntry {
+    /// If `Some`, key in the main trie of the child trie the entry belongs to, starting with
+    /// `:child_storage:default:`. If `None`, the entry belongs to the main trie.
+    pub child_trie: Option<Vec<u8>>,
+    /// Key of the entry within its trie.
+    pub key: Vec<u8>,
+    /// Storage value of the entry.
+    pub value: Vec<u8>,
+}
+
+/// Reason why a request has failed.
+#[derive(Debug)]
+pub enum RequestFail {
+    /// The source couldn't provide the storage entries.
+    Unavailable,
+}
+
+/// Error that can happen when processing the outcome of a request.
+#[derive(Debug, derive_more::Display)]
+pub enum Error {
+    /// The source couldn't provide the storage entries.
+    Unavailable,
+    /// Error while decoding the compact proofs.
+    #[display(fmt = "Invalid compact proof: {}", _0)]
+    InvalidProof(compact_proof::DecodeError),
+    /// The proof doesn't match the state root found in the header of the block.
+    StateRootMismatch,
+    /// Error while verifying the proof.
+    Verify(proof_verify::Error),
+    /// The storage value of a child trie key of the main trie isn't a Merkle value.
+    InvalidChildTrieRoot,
+    /// The proof doesn't contain any storage entry, yet doesn't cover the rest of the storage.
+    NoProgress,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        Config, Error, RequestFail, StateSync, StorageEntry, DEFAULT_CHILD_STORAGE_PREFIX,
+    };
+    use crate::{
+        header,
+        trie::{self, compact_proof, node_codec},
+    };
+    use core::iter;
+    use std::collections::BTreeMap;
+
+    /// Builds the node values of the trie containing the given entries. Returns the node values
+    /// and the Merkle value of the root node.
+    fn trie_nodes(entries: &BTreeMap<Vec<u8>, Vec<u8>>) -> (Vec<Vec<u8>>, [u8; 32]) {
+        let entries = entries
+            .iter()
+            .map(|(key, value)| {
+                let nibbles = trie::bytes_to_nibbles(key.iter().copied()).collect::<Vec<_>>();
+                (nibbles, &value[..])
+            })
+            .collect::<Vec<_>>();
+
+        let mut nodes = Vec::new();
+        let root = node_value(&entries, &mut nodes);
+        (nodes, trie::HashFunction::Blake2.hash(&root))
+    }
+
+    /// Builds the node value of the node whose descendants are the given entries, whose keys
+    /// are relative to the node. Pushes the node values of the node and its descendants to
+    /// `nodes`.
+    fn node_value(entries: &[(Vec<trie::Nibble>, &[u8])], nodes: &mut Vec<Vec<u8>>) -> Vec<u8> {
+        let first_key = &entries[0].0;
+        let partial_key_len = entries
+            .iter()
+            .map(|(key, _)| {
+                key.iter()
+                    .zip(first_key)
+                    .take_while(|(a, b)| a == b)
+                    .count()
+            })
+            .min()
+            .unwrap();
+
+        let children_merkle_values = (0..16u8)
+            .map(|nibble| {
+                let child_entries = entries
+                    .iter()
+                    .filter(|(key, _)| {
+                        key.len() > partial_key_len && u8::from(key[partial_key_len]) == nibble
+                    })
+                    .map(|(key, value)| (key[partial_key_len + 1..].to_vec(), *value))
+                    .collect::<Vec<_>>();
+                if child_entries.is_empty() {
+                    return None;
+                }
+
+                let node_value = node_value(&child_entries, nodes);
+                if node_value.len() < 32 {
+                    Some(node_value)
+                } else {
+                    Some(trie::HashFunction::Blake2.hash(&node_value).to_vec())
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let mut children = [None; 16];
+        for (child, merkle_value) in children.iter_mut().zip(&children_merkle_values) {
+            *child = merkle_value.as_deref();
+        }
+
+        let storage_value = entries
+            .iter()
+            .find(|(key, _)| key.len() == partial_key_len)
+            .map_or(node_codec::StorageValue::None, |(_, value)| {
+                node_codec::StorageValue::Unhashed(value)
+            });
+
+        let node_value = node_codec::encode(node_codec::Decoded {
+            partial_key: first_key[..partial_key_len].iter().copied(),
+            children,
+            storage_value,
+        })
+        .unwrap();
+        nodes.push(node_value.clone());
+        node_value
+    }
+
+    /// Storage of the test block, made of a main trie and of a single child trie.
+    struct TestStorage {
+        main_trie: BTreeMap<Vec<u8>, Vec<u8>>,
+        main_trie_nodes: Vec<Vec<u8>>,
+        state_root: [u8; 32],
+        child_trie_key: Vec<u8>,
+        child_trie: BTreeMap<Vec<u8>, Vec<u8>>,
+        child_trie_nodes: Vec<Vec<u8>>,
+        child_trie_root: [u8; 32],
+    }
+
+    impl TestStorage {
+        fn new() -> Self {
+            // The storage values are long enough for the leaves to not be inlined in their
+            // parent.
+            let child_trie = [(&b"k1"[..], [3; 40]), (&b"k2"[..], [4; 40])]
+                .iter()
+                .map(|(k, v)| (k.to_vec(), v.to_vec()))
+                .collect::<BTreeMap<_, _>>();
+            let (child_trie_nodes, child_trie_root) = trie_nodes(&child_trie);
+
+            let mut child_trie_key = DEFAULT_CHILD_STORAGE_PREFIX.to_vec();
+            child_trie_key.extend_from_slice(b"foo");
+
+            let main_trie = [
+                (&b"0"[..], vec![1; 40]),
+                (&child_trie_key[..], child_trie_root.to_vec()),
+                (&b"a"[..], vec![2; 40]),
+                (&b"z"[..], vec![5; 40]),
+            ]
+            .iter()
+            .map(|(k, v)| (k.to_vec(), v.clone()))
+            .collect::<BTreeMap<_, _>>();
+            let (main_trie_nodes, state_root) = trie_nodes(&main_trie);
+
+            // Make sure that the test helpers calculate the same root as the rest of the code.
+            let mut trie = trie::Trie::new();
+            for (key, value) in &main_trie {
+                trie.insert(key, value.clone());
+            }
+            assert_eq!(trie.root_merkle_value(None), state_root);
+
+            TestStorage {
+                main_trie,
+                main_trie_nodes,
+                state_root,
+                child_trie_key,
+                child_trie,
+                child_trie_nodes,
+                child_trie_root,
+            }
+        }
+
+        /// Header of the block whose storage is the test storage.
+        fn state_sync_header(&self) -> header::Header {
+            header::Header {
+                parent_hash: [0; 32],
+                number: 1,
+                state_root: self.state_root,
+                extrinsics_root: [0; 32],
+                digest: header::DigestRef::empty().into(),
+            }
+        }
+
+        fn state_sync(&self) -> StateSync<()> {
+            StateSync::new(Config {
+                block_header: self.state_sync_header(),
+                trie_layout: trie::Layout::default(),
+                sources_capacity: 4,
+            })
+        }
+
+        /// Builds a response to a state request, containing the nodes of the main trie and of
+        /// the child trie except for the leaves whose storage value is in `omitted_values`.
+        ///
+        /// If `with_child_trie` is `false`, the compact proof of the child trie is omitted.
+        fn response(&self, omitted_values: &[&[u8]], with_child_trie: bool) -> Vec<Vec<u8>> {
+            let filter = |nodes: &[Vec<u8>]| {
+                nodes
+                    .iter()
+                    .filter(|node| {
+                        !omitted_values
+                            .iter()
+                            .any(|value| node.windows(value.len()).any(|w| w == *value))
+                    })
+                    .cloned()
+                    .collect::<Vec<_>>()
+            };
+
+            let layout = trie::Layout::default();
+            let main_trie_nodes = filter(&self.main_trie_nodes);
+            let mut response = compact_proof::encode(
+                layout,
+                &self.state_root,
+                main_trie_nodes.iter().map(|n| &n[..]),
+            )
+            .unwrap();
+
+            if with_child_trie {
+                let child_trie_nodes = filter(&self.child_trie_nodes);
+                response.extend(
+                    compact_proof::encode(
+                        layout,
+                        &self.child_trie_root,
+                        child_trie_nodes.iter().map(|n| &n[..]),
+                    )
+                    .unwrap(),
+                );
+            }
+
+            response
+        }
+
+        fn main_entry(&self, key: &[u8]) -> StorageEntry {
+            StorageEntry {
+                child_trie: None,
+                key: key.to_vec(),
+                value: self.main_trie[key].clone(),
+            }
+        }
+
+        fn child_entry(&self, key: &[u8]) -> StorageEntry {
+            StorageEntry {
+                child_trie: Some(self.child_trie_key.clone()),
+                key: key.to_vec(),
+                value: self.child_trie[key].clone(),
+            }
+        }
+    }
+
+    #[test]
+    fn full_storage_in_one_response() {
+        let storage = TestStorage::new();
+        let mut sync = storage.state_sync();
+        sync.add_source(());
+
+        let request = sync.next_request_action().unwrap();
+        assert!(request.start.is_empty());
+        let request_id = request.request_id;
+        assert!(sync.next_request_action().is_none());
+
+        let entries = sync
+            .finish_request(request_id, Ok(storage.response(&[], true)))
+            .unwrap();
+
+        // The entries of the child trie are found right after its key in the main trie.
+        assert_eq!(
+            entries,
+            vec![
+                storage.main_entry(b"0"),
+                storage.main_entry(&storage.child_trie_key),
+                storage.child_entry(b"k1"),
+                storage.child_entry(b"k2"),
+                storage.main_entry(b"a"),
+                storage.main_entry(b"z"),
+            ]
+        );
+        assert!(sync.is_finished());
+        assert_eq!(sync.num_entries_downloaded(), 6);
+        assert!(sync.next_request_action().is_none());
+    }
+
+    #[test]
+    fn resume_in_child_trie() {
+        let storage = TestStorage::new();
+        let mut sync = storage.state_sync();
+        sync.add_source(());
+
+        let request_id = sync.next_request_action().unwrap().request_id;
+        let entries = sync
+            .finish_request(
+                request_id,
+                Ok(storage.response(&[&[2; 40], &[4; 40], &[5; 40]], true)),
+            )
+            .unwrap();
+        assert_eq!(
+            entries,
+            vec![
+                storage.main_entry(b"0"),
+                storage.main_entry(&storage.child_trie_key),
+                storage.child_entry(b"k1"),
+            ]
+        );
+        assert!(!sync.is_finished());
+
+        // The next request starts after the last downloaded key of the child trie.
+        let request = sync.next_request_action().unwrap();
+        assert_eq!(
+            request.start,
+            vec![storage.child_trie_key.clone(), b"k1".to_vec()]
+        );
+        let request_id = request.request_id;
+        let entries = sync
+            .finish_request(request_id, Ok(storage.response(&[&[1; 40]], true)))
+            .unwrap();
+        assert_eq!(
+            entries,
+            vec![
+                storage.child_entry(b"k2"),
+                storage.main_entry(b"a"),
+                storage.main_entry(b"z"),
+            ]
+        );
+        assert!(sync.is_finished());
+        assert_eq!(sync.num_entries_downloaded(), 6);
+    }
+
+    #[test]
+    fn child_trie_missing_from_proof() {
+        let storage = TestStorage::new();
+        let mut sync = storage.state_sync();
+        sync.add_source(());
+
+        // The key of the child trie in the main trie is only returned once the child trie itself
+        // can be downloaded.
+        let request_id = sync.next_request_action().unwrap().request_id;
+        let entries = sync
+            .finish_request(request_id, Ok(storage.response(&[], false)))
+            .unwrap();
+        assert_eq!(entries, vec![storage.main_entry(b"0")]);
+
+        let request = sync.next_request_action().unwrap();
+        assert_eq!(request.start, vec![b"0".to_vec()]);
+        let request_id = request.request_id;
+
+        // A response that doesn't make the download progress is refused.
+        assert!(matches!(
+            sync.finish_request(request_id, Ok(storage.response(&[], false))),
+            Err(Error::NoProgress)
+        ));
+        assert!(sync.next_request_action().is_none());
+    }
+
+    /// Builds the compact proof of a trie made of a single entry, and returns it together with
+    /// the Merkle value of the root node.
+    fn single_entry_proof(key: Vec<u8>, value: Vec<u8>) -> (Vec<Vec<u8>>, [u8; 32]) {
+        let (nodes, root) = trie_nodes(&iter::once((key, value)).collect());
+        let proof =
+            compact_proof::encode(trie::Layout::default(), &root, nodes.iter().map(|n| &n[..]))
+                .unwrap();
+        (proof, root)
+    }
+
+    #[test]
+    fn invalid_child_trie_root() {
+        let mut child_trie_key = DEFAULT_CHILD_STORAGE_PREFIX.to_vec();
+        child_trie_key.extend_from_slice(b"foo");
+        let (proof, state_root) = single_entry_proof(child_trie_key, vec![1, 2, 3]);
+
+        let mut sync = StateSync::new(Config {
+            block_header: header::Header {
+                state_root,
+                ..TestStorage::new().state_sync_header()
+            },
+            trie_layout: trie::Layout::default(),
+            sources_capacity: 4,
+        });
+        sync.add_source(());
+
+        let request_id = sync.next_request_action().unwrap().request_id;
+        assert!(matches!(
+            sync.finish_request(request_id, Ok(proof)),
+            Err(Error::InvalidChildTrieRoot)
+        ));
+    }
+
+    #[test]
+    fn invalid_responses_ban_source() {
+        let storage = TestStorage::new();
+        let mut sync = storage.state_sync();
+        let source1 = sync.add_source(());
+        let source2 = sync.add_source(());
+
+        // Proof of a trie that isn't the one of the block.
+        let other_proof = single_entry_proof(b"a".to_vec(), vec![2; 40]).0;
+
+        let request = sync.next_request_action().unwrap();
+        assert_eq!(request.source_id, source1);
+        let request_id = request.request_id;
+        assert!(matches!(
+            sync.finish_request(request_id, Ok(other_proof)),
+            Err(Error::StateRootMismatch)
+        ));
+
+        // Truncated compact proof.
+        let request = sync.next_request_action().unwrap();
+        assert_eq!(request.source_id, source2);
+        let request_id = request.request_id;
+        let mut invalid = storage.response(&[], true);
+        invalid.truncate(invalid.len() - 1);
+        assert!(matches!(
+            sync.finish_request(request_id, Ok(invalid)),
+            Err(Error::InvalidProof(_))
+        ));
+
+        assert!(sync.next_request_action().is_none());
+        let source3 = sync.add_source(());
+        let request = sync.next_request_action().unwrap();
+        assert_eq!(request.source_id, source3);
+        let request_id = request.request_id;
+        assert!(matches!(
+            sync.finish_request(request_id, Err(RequestFail::Unavailable)),
+            Err(Error::Unavailable)
+        ));
+        assert!(sync.next_request_action().is_none());
+        assert_eq!(sync.num_entries_downloaded(), 0);
+    }
+}
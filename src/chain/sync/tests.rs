@@ -0,0 +1,1420 @@
+// Substrate-lite
+// Copyright (C) 2019-2020  Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Simulation of syncing from multiple sources.
+//!
+//! The tests in this module build a synthetic chain of headers that are valid according to the
+//! BABE and GrandPa algorithms, using test keys, and serve it from simulated sources. Each source
+//! can be slow, send back fewer blocks than requested, lie, be unavailable, or be on a fork.
+//!
+//! Time is simulated as well: a virtual clock is advanced to the moment when the next response
+//! arrives, which makes the tests fully deterministic.
+//!
+//! > **Note**: Only [`headers_optimistic`], [`all_forks`] and [`warp_sync`] are covered. Full
+//! >           syncing requires executing a runtime, which synthetic blocks don't have.
+
+#![cfg(test)]
+
+use super::{all_forks, headers_optimistic, warp_sync};
+use crate::{chain::chain_information, finality::grandpa, header, test_utils};
+
+use core::{
+    convert::TryFrom as _,
+    num::{NonZeroU32, NonZeroUsize},
+    time::Duration,
+};
+use rand::SeedableRng as _;
+use std::collections::BTreeMap;
+
+/// Number of slots in each BABE epoch of the test chain.
+const SLOTS_PER_EPOCH: u64 = 8;
+/// Slot number of block #1 of the test chain. Each block is in the slot following its parent's.
+const BLOCK1_SLOT_NUMBER: u64 = 1000;
+/// Duration of a BABE slot of the test chain, in milliseconds.
+const SLOT_DURATION_MS: u64 = 6000;
+/// Time since the Unix epoch when the simulation starts. The slots of all the blocks of the test
+/// chains have started by then.
+const SIMULATION_START: Duration =
+    Duration::from_millis((BLOCK1_SLOT_NUMBER + 10_000) * SLOT_DURATION_MS);
+
+/// Keys of the single BABE and GrandPa authority of the test chain.
+struct Keys {
+    babe: schnorrkel::Keypair,
+    grandpa: ed25519_dalek::Keypair,
+}
+
+impl Keys {
+    fn new() -> Self {
+        Keys {
+            babe: test_utils::sr25519_keypair(1),
+            grandpa: test_utils::ed25519_keypair(2),
+        }
+    }
+
+    /// Information about the given BABE epoch, as found in the header of the first block of the
+    /// previous epoch.
+    ///
+    /// Since the test chain only contains secondary slot claims, the randomness of each epoch is
+    /// derived from the randomness of the previous epoch alone.
+    fn epoch_information(&self, epoch_number: u64) -> header::BabeNextEpoch {
+        let mut randomness = [0; 32];
+        for n in 1..=epoch_number {
+            let mut hash = blake2_rfc::blake2b::Blake2b::new(32);
+            hash.update(&randomness);
+            hash.update(&n.to_le_bytes());
+            randomness.copy_from_slice(hash.finalize().as_bytes());
+        }
+
+        header::BabeNextEpoch {
+            authorities: vec![header::BabeAuthority {
+                public_key: self.babe.public.to_bytes(),
+                weight: 1,
+            }],
+            randomness,
+        }
+    }
+
+    /// Builds the chain information of the genesis block of the test chain.
+    fn genesis_chain_information(&self) -> chain_information::ChainInformationConfig {
+        let genesis = test_utils::encode_header(&[0; 32], 0, &[]);
+
+        chain_information::ChainInformationConfig {
+            chain_information: chain_information::ChainInformation {
+                finalized_block_header: header::decode(&genesis).unwrap().into(),
+                babe_finalized_block1_slot_number: None,
+                babe_finalized_block_weight: 0,
+                babe_finalized_epoch_randomness_contributions: Some(Vec::new()),
+                babe_finalized_disabled_authorities: Vec::new(),
+                babe_finalized_block_epoch_information: None,
+                babe_finalized_next_epoch_transition: None,
+                aura_finalized_authorities_list: None,
+                grandpa_after_finalized_block_authorities_set_id: 0,
+                grandpa_finalized_triggered_authorities: vec![header::GrandpaAuthority {
+                    public_key: self.grandpa.public.to_bytes(),
+                    weight: 1,
+                }],
+                grandpa_finalized_scheduled_change: None,
+                grandpa_finalized_forced_change: None,
+                grandpa_finalized_disabled_authorities: Vec::new(),
+                grandpa_finalized_pause_state: chain_information::GrandpaPauseState::Live,
+            },
+            consensus_genesis_config: chain_information::ConsensusGenesisConfiguration::Babe(
+                chain_information::babe::BabeGenesisConfiguration::from_parts(
+                    SLOT_DURATION_MS,
+                    SLOTS_PER_EPOCH,
+                    (1, 4),
+                    header::BabeAllowedSlots::PrimaryAndSecondaryPlainSlots,
+                    self.epoch_information(0),
+                ),
+            ),
+        }
+    }
+}
+
+/// Chain of blocks built for the purpose of the tests.
+#[derive(Clone)]
+struct TestChain {
+    /// SCALE-encoded headers, indexed by block number. Index 0 is the genesis block.
+    headers: Vec<Vec<u8>>,
+    /// SCALE-encoded justifications, indexed by block number.
+    justifications: BTreeMap<u64, Vec<u8>>,
+}
+
+impl TestChain {
+    /// Builds a chain containing only the genesis block.
+    fn genesis() -> Self {
+        TestChain {
+            headers: vec![test_utils::encode_header(&[0; 32], 0, &[])],
+            justifications: BTreeMap::new(),
+        }
+    }
+
+    /// Returns the number of the highest block of the chain.
+    fn best_block_number(&self) -> u64 {
+        u64::try_from(self.headers.len() - 1).unwrap()
+    }
+
+    /// Returns the hash of the block with the given number.
+    fn block_hash(&self, number: u64) -> [u8; 32] {
+        header::hash_from_scale_encoded_header(&self.headers[usize::try_from(number).unwrap()])
+    }
+
+    /// Returns a copy of this chain, with all the blocks above `number` removed.
+    fn truncated(&self, number: u64) -> Self {
+        let mut chain = self.clone();
+        chain.headers.truncate(usize::try_from(number + 1).unwrap());
+        chain.justifications = self
+            .justifications
+            .range(..=number)
+            .map(|(n, j)| (*n, j.clone()))
+            .collect();
+        chain
+    }
+
+    /// Adds `num_blocks` blocks on top of the chain.
+    ///
+    /// A justification is added every `justification_period` block, and to the last block.
+    /// If `justification_period` is `None`, no justification is added.
+    ///
+    /// `state_root_byte` is used to fill the state root of the blocks, making it possible to
+    /// build forks.
+    fn extend(
+        &mut self,
+        keys: &Keys,
+        num_blocks: u64,
+        justification_period: Option<u64>,
+        state_root_byte: u8,
+    ) {
+        let target = self.best_block_number() + num_blocks;
+
+        while self.best_block_number() < target {
+            let number = self.best_block_number() + 1;
+            let parent_hash = self.block_hash(number - 1);
+            let slot_number = BLOCK1_SLOT_NUMBER + number - 1;
+
+            let pre_digest =
+                header::BabePreDigest::SecondaryPlain(header::BabeSecondaryPlainPreDigest {
+                    authority_index: 0,
+                    slot_number,
+                });
+
+            let mut digest = vec![header::DigestItem::BabePreDigest(pre_digest)];
+
+            // The first block of each epoch announces the information about the next epoch.
+            if (number - 1) % SLOTS_PER_EPOCH == 0 {
+                let epoch_number = (number - 1) / SLOTS_PER_EPOCH;
+                digest.push(header::DigestItem::BabeConsensus(
+                    header::BabeConsensusLog::NextEpochData(
+                        keys.epoch_information(epoch_number + 1),
+                    ),
+                ));
+            }
+
+            let pre_seal_hash =
+                header::hash_from_scale_encoded_header(test_utils::encode_header_with_state_root(
+                    &parent_hash,
+                    number,
+                    &[state_root_byte; 32],
+                    &digest,
+                ));
+            let signature = keys.babe.sign(schnorrkel::context::attach_rng(
+                schnorrkel::signing_context(b"substrate").bytes(&pre_seal_hash),
+                rand_chacha::ChaCha20Rng::seed_from_u64(number),
+            ));
+            digest.push(header::DigestItem::BabeSeal(signature.to_bytes()));
+
+            let scale_encoded_header = test_utils::encode_header_with_state_root(
+                &parent_hash,
+                number,
+                &[state_root_byte; 32],
+                &digest,
+            );
+            let hash = header::hash_from_scale_encoded_header(&scale_encoded_header);
+            self.headers.push(scale_encoded_header);
+
+            if justification_period.map_or(false, |p| number % p == 0 || number == target) {
+                self.justifications
+                    .insert(number, encode_justification(keys, number, &hash));
+            }
+        }
+    }
+}
+
+/// Builds the SCALE encoding of a justification, signed by the GrandPa authority, for the given
+/// block.
+fn encode_justification(keys: &Keys, number: u64, hash: &[u8; 32]) -> Vec<u8> {
+    test_utils::justification_signed_by(&keys.grandpa, 0, number, hash)
+}
+
+/// Builds a GrandPa commit for the block with the given number and hash, containing a single
+/// pre-commit signed by the GrandPa authority.
+fn grandpa_commit(keys: &Keys, number: u64, hash: &[u8; 32]) -> grandpa::gossip::CommitMessage {
+    let precommit = test_utils::precommit(&keys.grandpa, number, 0, hash, number);
+    test_utils::grandpa_commit(number, 0, hash, number, vec![precommit])
+}
+
+/// Simulated source of blocks.
+struct Source {
+    /// Chain known by the source.
+    chain: TestChain,
+    /// Time it takes for the source to answer a request.
+    latency: Duration,
+    /// Maximum number of blocks that the source sends back in a response.
+    max_blocks_per_response: u32,
+    /// If `true`, the source corrupts the last block of every response containing more than
+    /// one block.
+    lies: bool,
+    /// If `true`, all requests towards this source fail.
+    unavailable: bool,
+    /// If `true`, the source announces its best block when syncing starts.
+    announces_best_block: bool,
+}
+
+impl Source {
+    /// Honest source knowing the given chain.
+    fn honest(chain: &TestChain) -> Self {
+        Source {
+            chain: chain.clone(),
+            latency: Duration::from_millis(100),
+            max_blocks_per_response: u32::max_value(),
+            lies: false,
+            unavailable: false,
+            announces_best_block: false,
+        }
+    }
+
+    /// Builds the response to a request for the given blocks.
+    fn respond(
+        &self,
+        first_block_height: u64,
+        num_blocks: u32,
+    ) -> Result<Vec<headers_optimistic::RequestSuccessBlock>, headers_optimistic::RequestFail> {
+        if self.unavailable || first_block_height > self.chain.best_block_number() {
+            return Err(headers_optimistic::RequestFail::BlocksUnavailable);
+        }
+
+        let num_blocks = u64::from(core::cmp::min(num_blocks, self.max_blocks_per_response));
+        let mut blocks = (first_block_height..first_block_height + num_blocks)
+            .take_while(|n| *n <= self.chain.best_block_number())
+            .map(|n| headers_optimistic::RequestSuccessBlock {
+                scale_encoded_header: self.chain.headers[usize::try_from(n).unwrap()].clone(),
+                scale_encoded_justification: self.chain.justifications.get(&n).cloned(),
+            })
+            .collect::<Vec<_>>();
+
+        if self.lies && blocks.len() >= 2 {
+            blocks.last_mut().unwrap().scale_encoded_header = vec![0xff; 16];
+        }
+
+        Ok(blocks)
+    }
+}
+
+/// Syncs the chain from the given sources until the block `target` is finalized, and returns
+/// the state machine.
+///
+/// # Panic
+///
+/// Panics if the syncing doesn't finish in a reasonable amount of simulated time.
+///
+fn run_sync(
+    keys: &Keys,
+    sources: &[Source],
+    target: u64,
+    seed: u64,
+) -> (
+    headers_optimistic::OptimisticHeadersSync<(), usize>,
+    Vec<headers_optimistic::SourceId>,
+) {
+    let mut sync = headers_optimistic::OptimisticHeadersSync::new(headers_optimistic::Config {
+        chain_information_config: keys.genesis_chain_information(),
+        sources_capacity: sources.len(),
+        blocks_request_granularity: NonZeroU32::new(16).unwrap(),
+        max_requests_per_source: NonZeroU32::new(2).unwrap(),
+        download_ahead_blocks: 64,
+        source_selection_randomness_seed: seed,
+        max_slot_drift: 0,
+        recent_blocks_kept: 0,
+    });
+
+    let source_ids = (0..sources.len())
+        .map(|n| sync.add_source(n))
+        .collect::<Vec<_>>();
+    for (source, source_id) in sources.iter().zip(&source_ids) {
+        if source.announces_best_block {
+            sync.source_best_block_update(*source_id, source.chain.best_block_number());
+        }
+    }
+
+    // List of requests in progress, with the moment when their response arrives.
+    let mut pending = Vec::new();
+    let mut now = Duration::new(0, 0);
+
+    while now < Duration::from_secs(3600) {
+        while let Some(action) = sync.next_request_action(now) {
+            match action {
+                headers_optimistic::RequestAction::Start {
+                    start,
+                    source,
+                    block_height,
+                    num_blocks,
+                    ..
+                } => {
+                    let source = &sources[*source];
+                    let response = source.respond(block_height.get(), num_blocks.get());
+                    let request_id = start.start(());
+                    pending.push((now + source.latency, request_id, response));
+                }
+                headers_optimistic::RequestAction::Cancel { request_id, .. } => {
+                    pending.retain(|(_, id, _)| *id != request_id);
+                }
+            }
+        }
+
+        while !matches!(
+            sync.process_one(SIMULATION_START + now),
+            headers_optimistic::ProcessOneOutcome::Idle
+        ) {}
+
+        if sync.as_chain_information().finalized_block_header.number >= target {
+            return (sync, source_ids);
+        }
+
+        // If no request is in progress, for example because all the sources are banned, wait
+        // for a bit.
+        if pending.is_empty() {
+            now += Duration::from_secs(1);
+            continue;
+        }
+
+        let next = pending
+            .iter()
+            .enumerate()
+            .min_by_key(|(_, (when, _, _))| *when)
+            .unwrap()
+            .0;
+        let (when, request_id, response) = pending.remove(next);
+        now = core::cmp::max(now, when);
+        let _ = sync.finish_request(request_id, response.map(|b| b.into_iter()), now);
+    }
+
+    panic!("syncing didn't finish")
+}
+
+/// Checks that the finalized state of `sync` corresponds to the given block of `chain`.
+fn assert_finalized(
+    keys: &Keys,
+    sync: &headers_optimistic::OptimisticHeadersSync<(), usize>,
+    chain: &TestChain,
+    number: u64,
+) {
+    let info = chain_information::ChainInformation::from(sync.as_chain_information());
+    assert_eq!(info.finalized_block_header.number, number);
+    assert_eq!(info.finalized_block_header.hash(), chain.block_hash(number));
+    assert_eq!(
+        info.babe_finalized_block1_slot_number,
+        Some(BLOCK1_SLOT_NUMBER)
+    );
+
+    let epoch_number = (number - 1) / SLOTS_PER_EPOCH;
+    assert_eq!(
+        info.babe_finalized_block_epoch_information.map(|(e, _)| e),
+        if epoch_number == 0 {
+            None
+        } else {
+            Some(keys.epoch_information(epoch_number))
+        }
+    );
+    assert_eq!(
+        info.babe_finalized_next_epoch_transition.map(|(e, _)| e),
+        Some(keys.epoch_information(epoch_number + 1))
+    );
+    assert_eq!(info.grandpa_after_finalized_block_authorities_set_id, 0);
+    assert!(info.grandpa_finalized_scheduled_change.is_none());
+}
+
+#[test]
+fn honest_sources() {
+    let keys = Keys::new();
+    let mut chain = TestChain::genesis();
+    chain.extend(&keys, 100, Some(16), 0);
+
+    let sources = (0..3).map(|_| Source::honest(&chain)).collect::<Vec<_>>();
+    let (sync, _) = run_sync(&keys, &sources, 100, 0);
+    assert_finalized(&keys, &sync, &chain, 100);
+}
+
+#[test]
+fn slow_and_limited_sources() {
+    let keys = Keys::new();
+    let mut chain = TestChain::genesis();
+    chain.extend(&keys, 100, Some(16), 0);
+
+    let sources = vec![
+        Source {
+            latency: Duration::from_secs(20),
+            ..Source::honest(&chain)
+        },
+        Source {
+            max_blocks_per_response: 3,
+            ..Source::honest(&chain)
+        },
+    ];
+
+    let (sync, source_ids) = run_sync(&keys, &sources, 100, 1);
+    assert_finalized(&keys, &sync, &chain, 100);
+
+    // Most of the blocks have been obtained from the fast source.
+    assert!(
+        sync.source_reputation(source_ids[1]).successful_requests
+            > sync.source_reputation(source_ids[0]).successful_requests
+    );
+}
+
+#[test]
+fn lying_source_is_banned() {
+    let keys = Keys::new();
+    let mut chain = TestChain::genesis();
+    chain.extend(&keys, 100, Some(16), 0);
+
+    let sources = vec![
+        Source::honest(&chain),
+        Source {
+            lies: true,
+            ..Source::honest(&chain)
+        },
+    ];
+
+    let (sync, source_ids) = run_sync(&keys, &sources, 100, 2);
+    assert_finalized(&keys, &sync, &chain, 100);
+
+    let liar_reputation = sync.source_reputation(source_ids[1]);
+    assert!(liar_reputation.invalid_data >= 1);
+    assert!(liar_reputation.num_bans >= 1);
+    assert_eq!(sync.source_reputation(source_ids[0]).invalid_data, 0);
+}
+
+#[test]
+fn unavailable_source() {
+    let keys = Keys::new();
+    let mut chain = TestChain::genesis();
+    chain.extend(&keys, 100, Some(16), 0);
+
+    let sources = vec![
+        Source {
+            unavailable: true,
+            ..Source::honest(&chain)
+        },
+        Source::honest(&chain),
+    ];
+
+    let (sync, source_ids) = run_sync(&keys, &sources, 100, 3);
+    assert_finalized(&keys, &sync, &chain, 100);
+    assert_eq!(sync.source_reputation(source_ids[0]).successful_requests, 0);
+    assert!(sync.source_reputation(source_ids[0]).failed_requests >= 1);
+}
+
+#[test]
+fn forked_source() {
+    let keys = Keys::new();
+    let mut chain = TestChain::genesis();
+    chain.extend(&keys, 100, Some(16), 0);
+
+    // The fork diverges after block 40 and has no justification after that point.
+    let mut fork = chain.truncated(40);
+    fork.extend(&keys, 70, None, 1);
+    assert_ne!(fork.block_hash(41), chain.block_hash(41));
+
+    let sources = vec![Source::honest(&fork), Source::honest(&chain)];
+    let (sync, _) = run_sync(&keys, &sources, 100, 4);
+    assert_finalized(&keys, &sync, &chain, 100);
+}
+
+#[test]
+fn announced_best_block_is_respected() {
+    let keys = Keys::new();
+    let mut chain = TestChain::genesis();
+    chain.extend(&keys, 100, Some(16), 0);
+
+    let sources = vec![
+        Source {
+            announces_best_block: true,
+            ..Source::honest(&chain.truncated(30))
+        },
+        Source::honest(&chain),
+    ];
+
+    let (sync, source_ids) = run_sync(&keys, &sources, 100, 5);
+    assert_finalized(&keys, &sync, &chain, 100);
+
+    // Blocks above its best block are never requested from the source that is behind.
+    assert_eq!(sync.source_reputation(source_ids[0]).failed_requests, 0);
+    assert_eq!(sync.source_best_block(source_ids[0]), Some(30));
+}
+
+#[test]
+fn request_not_started_is_not_split() {
+    let keys = Keys::new();
+    let mut sync =
+        headers_optimistic::OptimisticHeadersSync::<(), ()>::new(headers_optimistic::Config {
+            chain_information_config: keys.genesis_chain_information(),
+            sources_capacity: 1,
+            blocks_request_granularity: NonZeroU32::new(16).unwrap(),
+            max_requests_per_source: NonZeroU32::new(1).unwrap(),
+            download_ahead_blocks: 64,
+            source_selection_randomness_seed: 0,
+            max_slot_drift: 0,
+            recent_blocks_kept: 0,
+        });
+
+    let source_id = sync.add_source(());
+    sync.source_best_block_update(source_id, 5);
+
+    // The source only knows 5 of the 16 blocks of the first entry. The request isn't started.
+    match sync.next_request_action(Duration::new(0, 0)) {
+        Some(headers_optimistic::RequestAction::Start {
+            block_height,
+            num_blocks,
+            ..
+        }) => {
+            assert_eq!(block_height.get(), 1);
+            assert_eq!(num_blocks.get(), 5);
+        }
+        _ => panic!(),
+    }
+
+    // Now that the source knows more blocks, the whole entry is requested.
+    sync.source_best_block_update(source_id, 100);
+    match sync.next_request_action(Duration::new(0, 0)) {
+        Some(headers_optimistic::RequestAction::Start {
+            block_height,
+            num_blocks,
+            ..
+        }) => {
+            assert_eq!(block_height.get(), 1);
+            assert_eq!(num_blocks.get(), 16);
+        }
+        _ => panic!(),
+    }
+}
+
+#[test]
+fn future_blocks_are_verified_later() {
+    let keys = Keys::new();
+    let mut chain = TestChain::genesis();
+    chain.extend(&keys, 16, Some(16), 0);
+
+    let mut sync =
+        headers_optimistic::OptimisticHeadersSync::<(), ()>::new(headers_optimistic::Config {
+            chain_information_config: keys.genesis_chain_information(),
+            sources_capacity: 1,
+            blocks_request_granularity: NonZeroU32::new(16).unwrap(),
+            max_requests_per_source: NonZeroU32::new(1).unwrap(),
+            download_ahead_blocks: 16,
+            source_selection_randomness_seed: 0,
+            max_slot_drift: 0,
+            recent_blocks_kept: 0,
+        });
+
+    let source_id = sync.add_source(());
+    match sync.next_request_action(Duration::new(0, 0)) {
+        Some(headers_optimistic::RequestAction::Start {
+            start,
+            block_height,
+            num_blocks,
+            ..
+        }) => {
+            let response = Source::honest(&chain).respond(block_height.get(), num_blocks.get());
+            let request_id = start.start(());
+            let _ = sync.finish_request(
+                request_id,
+                response.map(|b| b.into_iter()),
+                Duration::new(0, 0),
+            );
+        }
+        _ => panic!(),
+    }
+
+    // Time since the Unix epoch when the slot of the block with the given number starts.
+    let slot_start =
+        |number: u64| Duration::from_millis((BLOCK1_SLOT_NUMBER + number - 1) * SLOT_DURATION_MS);
+
+    // The blocks above the current slot are kept aside rather than considered invalid.
+    match sync.process_one(slot_start(10)) {
+        headers_optimistic::ProcessOneOutcome::Updated {
+            best_block_number,
+            finalized_block,
+            ..
+        } => {
+            assert_eq!(best_block_number, 10);
+            assert!(finalized_block.is_none());
+        }
+        _ => panic!(),
+    }
+    assert_eq!(sync.next_future_block_time(), Some(slot_start(11)));
+    assert!(matches!(
+        sync.process_one(slot_start(11) - Duration::from_millis(1)),
+        headers_optimistic::ProcessOneOutcome::Idle
+    ));
+
+    match sync.process_one(slot_start(16)) {
+        headers_optimistic::ProcessOneOutcome::Updated {
+            best_block_number,
+            finalized_block,
+            ..
+        } => {
+            assert_eq!(best_block_number, 16);
+            assert_eq!(finalized_block, Some((16, chain.block_hash(16))));
+        }
+        _ => panic!(),
+    }
+    assert!(sync.next_future_block_time().is_none());
+
+    let reputation = sync.source_reputation(source_id);
+    assert_eq!(reputation.invalid_data, 0);
+    assert_eq!(reputation.num_bans, 0);
+}
+
+#[test]
+fn grandpa_commit_finalizes_recent_block() {
+    let keys = Keys::new();
+    let mut chain = TestChain::genesis();
+    chain.extend(&keys, 16, None, 0);
+
+    let mut sync =
+        headers_optimistic::OptimisticHeadersSync::<(), ()>::new(headers_optimistic::Config {
+            chain_information_config: keys.genesis_chain_information(),
+            sources_capacity: 1,
+            blocks_request_granularity: NonZeroU32::new(16).unwrap(),
+            max_requests_per_source: NonZeroU32::new(1).unwrap(),
+            download_ahead_blocks: 16,
+            source_selection_randomness_seed: 0,
+            max_slot_drift: 0,
+            recent_blocks_kept: 4,
+        });
+
+    sync.add_source(());
+    match sync.next_request_action(Duration::new(0, 0)) {
+        Some(headers_optimistic::RequestAction::Start {
+            start,
+            block_height,
+            num_blocks,
+            ..
+        }) => {
+            let response = Source::honest(&chain).respond(block_height.get(), num_blocks.get());
+            let request_id = start.start(());
+            let _ = sync.finish_request(
+                request_id,
+                response.map(|b| b.into_iter()),
+                Duration::new(0, 0),
+            );
+        }
+        _ => panic!(),
+    }
+    while !matches!(
+        sync.process_one(SIMULATION_START),
+        headers_optimistic::ProcessOneOutcome::Idle
+    ) {}
+
+    // Only the best block and the 4 blocks below it can be finalized with a commit.
+    assert!(sync
+        .grandpa_commit(&grandpa_commit(&keys, 11, &chain.block_hash(11)))
+        .is_err());
+    assert_eq!(
+        sync.grandpa_commit(&grandpa_commit(&keys, 13, &chain.block_hash(13)))
+            .unwrap(),
+        (13, chain.block_hash(13))
+    );
+    let info = chain_information::ChainInformation::from(sync.as_chain_information());
+    assert_eq!(info.finalized_block_header.hash(), chain.block_hash(13));
+
+    // Blocks that are already finalized can't be finalized again.
+    assert!(sync
+        .grandpa_commit(&grandpa_commit(&keys, 12, &chain.block_hash(12)))
+        .is_err());
+    assert_eq!(
+        sync.grandpa_commit(&grandpa_commit(&keys, 16, &chain.block_hash(16)))
+            .unwrap(),
+        (16, chain.block_hash(16))
+    );
+}
+
+/// Builds a warp sync fragment for a block with the given number and GrandPa digest log items,
+/// justified by the given authority of the given authorities set.
+fn warp_sync_fragment(
+    number: u64,
+    logs: Vec<header::GrandpaConsensusLog>,
+    signer: &ed25519_dalek::Keypair,
+    authorities_set_id: u64,
+) -> warp_sync::Fragment {
+    let digest = logs
+        .into_iter()
+        .map(header::DigestItem::GrandpaConsensus)
+        .collect::<Vec<_>>();
+    let scale_encoded_header = test_utils::encode_header(&[0; 32], number, &digest);
+    let hash = header::hash_from_scale_encoded_header(&scale_encoded_header);
+    warp_sync::Fragment {
+        scale_encoded_justification: test_utils::justification_signed_by(
+            signer,
+            authorities_set_id,
+            number,
+            &hash,
+        ),
+        scale_encoded_header,
+    }
+}
+
+/// Starts warp syncing from the genesis block of the test chain, passes the given fragments as
+/// the response of a single source, then verifies them.
+///
+/// Returns the authorities set ID after each successfully verified fragment, or the error.
+fn warp_sync_process(
+    keys: &Keys,
+    fragments: Vec<warp_sync::Fragment>,
+    is_finished: bool,
+) -> (
+    warp_sync::WarpSync<()>,
+    Vec<Result<u64, warp_sync::VerifyError>>,
+) {
+    let mut sync = warp_sync::WarpSync::new(warp_sync::Config {
+        start_chain_information: keys.genesis_chain_information().chain_information,
+        sources_capacity: 1,
+    });
+
+    sync.add_source(());
+    let request_id = sync.next_request_action().unwrap().request_id;
+    sync.finish_request(request_id, Ok((fragments.into_iter(), is_finished)));
+
+    let mut outcomes = Vec::new();
+    loop {
+        match sync.process_one() {
+            warp_sync::ProcessOne::Idle => break,
+            warp_sync::ProcessOne::FragmentVerified {
+                authorities_set_id, ..
+            } => outcomes.push(Ok(authorities_set_id)),
+            warp_sync::ProcessOne::VerifyError { error, .. } => outcomes.push(Err(error)),
+        }
+    }
+
+    (sync, outcomes)
+}
+
+/// BABE information to pass to [`warp_sync::WarpSync::into_chain_information`].
+fn warp_sync_babe_information(keys: &Keys) -> warp_sync::BabeInformation {
+    warp_sync::BabeInformation {
+        finalized_block1_slot_number: BLOCK1_SLOT_NUMBER,
+        finalized_block_weight: 0,
+        finalized_block_epoch_information: None,
+        finalized_next_epoch_transition: (
+            keys.epoch_information(1),
+            header::BabeNextConfig {
+                c: (1, 4),
+                allowed_slots: header::BabeAllowedSlots::PrimaryAndSecondaryPlainSlots,
+            },
+        ),
+        finalized_disabled_authorities: Vec::new(),
+    }
+}
+
+/// GrandPa information to pass to [`warp_sync::WarpSync::into_chain_information`].
+fn warp_sync_grandpa_information() -> warp_sync::GrandpaInformation {
+    warp_sync::GrandpaInformation {
+        finalized_disabled_authorities: Vec::new(),
+        finalized_pause_state: chain_information::GrandpaPauseState::Live,
+    }
+}
+
+#[test]
+fn warp_sync_scheduled_changes() {
+    let keys = Keys::new();
+    let authority1 = test_utils::ed25519_keypair(3);
+    let authority2 = test_utils::ed25519_keypair(4);
+
+    // Block #10 schedules a change triggered immediately, block #20 schedules a change triggered
+    // at block #25, which must be finalized by the previous authorities.
+    let fragments = vec![
+        warp_sync_fragment(
+            10,
+            vec![header::GrandpaConsensusLog::ScheduledChange(
+                test_utils::grandpa_change_to(&authority1, 0),
+            )],
+            &keys.grandpa,
+            0,
+        ),
+        warp_sync_fragment(
+            20,
+            vec![header::GrandpaConsensusLog::ScheduledChange(
+                test_utils::grandpa_change_to(&authority2, 5),
+            )],
+            &authority1,
+            1,
+        ),
+        warp_sync_fragment(25, Vec::new(), &authority1, 1),
+        warp_sync_fragment(40, Vec::new(), &authority2, 2),
+    ];
+
+    let (sync, outcomes) = warp_sync_process(&keys, fragments, true);
+    assert_eq!(outcomes.len(), 4);
+    assert!(outcomes.iter().all(|o| o.is_ok()));
+    assert_eq!(
+        outcomes.into_iter().map(Result::unwrap).collect::<Vec<_>>(),
+        vec![1, 1, 2, 2]
+    );
+    assert!(sync.is_finished());
+    assert_eq!(sync.finalized_block_header().number, 40);
+
+    let info = sync.into_chain_information(
+        warp_sync::ConsensusInformation::Babe(warp_sync_babe_information(&keys)),
+        warp_sync_grandpa_information(),
+    );
+    assert_eq!(info.finalized_block_header.number, 40);
+    assert_eq!(info.grandpa_after_finalized_block_authorities_set_id, 2);
+    assert_eq!(
+        info.grandpa_finalized_triggered_authorities,
+        test_utils::grandpa_change_to(&authority2, 0).next_authorities
+    );
+    assert!(info.grandpa_finalized_scheduled_change.is_none());
+}
+
+#[test]
+fn warp_sync_grandpa_state_is_provided() {
+    let keys = Keys::new();
+    let authority1 = test_utils::ed25519_keypair(3);
+
+    // The pause and disabling signals of the fragments are ignored in favour of the state
+    // passed when building the chain information.
+    let fragments = vec![warp_sync_fragment(
+        10,
+        vec![
+            header::GrandpaConsensusLog::ScheduledChange(test_utils::grandpa_change_to(
+                &authority1,
+                0,
+            )),
+            header::GrandpaConsensusLog::Pause(0),
+        ],
+        &keys.grandpa,
+        0,
+    )];
+
+    let (sync, outcomes) = warp_sync_process(&keys, fragments, true);
+    assert!(matches!(&outcomes[..], [Ok(1)]));
+
+    let info = sync.into_chain_information(
+        warp_sync::ConsensusInformation::Babe(warp_sync_babe_information(&keys)),
+        warp_sync::GrandpaInformation {
+            finalized_disabled_authorities: vec![0],
+            finalized_pause_state: chain_information::GrandpaPauseState::PendingResume {
+                trigger_block_height: 12,
+            },
+        },
+    );
+    assert_eq!(info.grandpa_finalized_disabled_authorities, vec![0]);
+    assert_eq!(
+        info.grandpa_finalized_pause_state,
+        chain_information::GrandpaPauseState::PendingResume {
+            trigger_block_height: 12
+        }
+    );
+}
+
+#[test]
+fn warp_sync_consensus_state_is_provided() {
+    let keys = Keys::new();
+    let authority1 = test_utils::ed25519_keypair(3);
+    let fragments = || {
+        vec![warp_sync_fragment(
+            10,
+            vec![header::GrandpaConsensusLog::ScheduledChange(
+                test_utils::grandpa_change_to(&authority1, 0),
+            )],
+            &keys.grandpa,
+            0,
+        )]
+    };
+
+    // The BABE authorities disabled in the epoch of the finalized block are taken as is.
+    let (sync, _) = warp_sync_process(&keys, fragments(), true);
+    let info = sync.into_chain_information(
+        warp_sync::ConsensusInformation::Babe(warp_sync::BabeInformation {
+            finalized_disabled_authorities: vec![0],
+            ..warp_sync_babe_information(&keys)
+        }),
+        warp_sync_grandpa_information(),
+    );
+    assert_eq!(info.babe_finalized_disabled_authorities, vec![0]);
+    assert_eq!(
+        info.babe_finalized_block1_slot_number,
+        Some(BLOCK1_SLOT_NUMBER)
+    );
+    assert!(info.aura_finalized_authorities_list.is_none());
+
+    // On Aura chains, the chain information doesn't contain any BABE-related state.
+    let aura_authorities = vec![header::AuraAuthority {
+        public_key: keys.grandpa.public.to_bytes(),
+    }];
+    let (sync, _) = warp_sync_process(&keys, fragments(), true);
+    let info = sync.into_chain_information(
+        warp_sync::ConsensusInformation::Aura(warp_sync::AuraInformation {
+            finalized_authorities_list: aura_authorities.clone(),
+        }),
+        warp_sync_grandpa_information(),
+    );
+    assert_eq!(info.aura_finalized_authorities_list, Some(aura_authorities));
+    assert!(info.babe_finalized_block1_slot_number.is_none());
+    assert!(info.babe_finalized_next_epoch_transition.is_none());
+}
+
+#[test]
+fn warp_sync_scheduled_change_skipped() {
+    let keys = Keys::new();
+    let authority1 = test_utils::ed25519_keypair(3);
+
+    // The block that triggers the change, #15, is missing.
+    let fragments = vec![
+        warp_sync_fragment(
+            10,
+            vec![header::GrandpaConsensusLog::ScheduledChange(
+                test_utils::grandpa_change_to(&authority1, 5),
+            )],
+            &keys.grandpa,
+            0,
+        ),
+        warp_sync_fragment(20, Vec::new(), &authority1, 1),
+    ];
+
+    let (sync, outcomes) = warp_sync_process(&keys, fragments, true);
+    assert!(matches!(
+        &outcomes[..],
+        [Ok(0), Err(warp_sync::VerifyError::ScheduledChangeSkipped)]
+    ));
+    assert!(!sync.is_finished());
+}
+
+#[test]
+fn warp_sync_forced_changes() {
+    let keys = Keys::new();
+    let authority1 = test_utils::ed25519_keypair(3);
+    let authority2 = test_utils::ed25519_keypair(4);
+
+    // Block #10 forces a change triggered at block #15. Since forced changes are triggered by
+    // the import of a block, block #15 doesn't need to be part of the fragments, and block #20
+    // is finalized by the new authorities.
+    let forced_change = |authority, delay| header::GrandpaConsensusLog::ForcedChange {
+        reset_block_height: 0,
+        change: test_utils::grandpa_change_to(authority, delay),
+    };
+    let fragments = vec![
+        warp_sync_fragment(10, vec![forced_change(&authority1, 5)], &keys.grandpa, 0),
+        warp_sync_fragment(20, vec![forced_change(&authority2, 10)], &authority1, 1),
+    ];
+
+    let (sync, outcomes) = warp_sync_process(&keys, fragments, true);
+    assert!(matches!(&outcomes[..], [Ok(0), Ok(1)]));
+
+    // The change forced by block #20 is still pending.
+    let info = sync.into_chain_information(
+        warp_sync::ConsensusInformation::Babe(warp_sync_babe_information(&keys)),
+        warp_sync_grandpa_information(),
+    );
+    assert_eq!(info.grandpa_after_finalized_block_authorities_set_id, 1);
+    assert_eq!(
+        info.grandpa_finalized_triggered_authorities,
+        test_utils::grandpa_change_to(&authority1, 0).next_authorities
+    );
+    assert_eq!(
+        info.grandpa_finalized_forced_change,
+        Some((
+            30,
+            test_utils::grandpa_change_to(&authority2, 0).next_authorities
+        ))
+    );
+
+    // A change forced with no delay applies to the block that forces it.
+    let fragments = vec![warp_sync_fragment(
+        10,
+        vec![forced_change(&authority1, 0)],
+        &authority1,
+        1,
+    )];
+    let (_, outcomes) = warp_sync_process(&keys, fragments, true);
+    assert!(matches!(&outcomes[..], [Ok(1)]));
+
+    // The old authorities can't finalize blocks after the forced change is triggered.
+    let fragments = vec![
+        warp_sync_fragment(10, vec![forced_change(&authority1, 5)], &keys.grandpa, 0),
+        warp_sync_fragment(20, Vec::new(), &keys.grandpa, 0),
+    ];
+    let (_, outcomes) = warp_sync_process(&keys, fragments, true);
+    assert!(matches!(
+        &outcomes[..],
+        [Ok(0), Err(warp_sync::VerifyError::JustificationVerify(_))]
+    ));
+}
+
+#[test]
+fn warp_sync_invalid_fragments() {
+    let keys = Keys::new();
+    let authority1 = test_utils::ed25519_keypair(3);
+    let change = || {
+        vec![header::GrandpaConsensusLog::ScheduledChange(
+            test_utils::grandpa_change_to(&authority1, 0),
+        )]
+    };
+
+    // Only the last fragment is allowed not to change the list of authorities.
+    let fragments = vec![
+        warp_sync_fragment(10, Vec::new(), &keys.grandpa, 0),
+        warp_sync_fragment(20, Vec::new(), &keys.grandpa, 0),
+    ];
+    let (_, outcomes) = warp_sync_process(&keys, fragments, true);
+    assert!(matches!(
+        &outcomes[..],
+        [Err(warp_sync::VerifyError::MissingAuthoritiesChange)]
+    ));
+
+    // Block numbers must increase.
+    let fragments = vec![
+        warp_sync_fragment(10, change(), &keys.grandpa, 0),
+        warp_sync_fragment(10, Vec::new(), &authority1, 1),
+    ];
+    let (_, outcomes) = warp_sync_process(&keys, fragments, true);
+    assert!(matches!(
+        &outcomes[..],
+        [Ok(1), Err(warp_sync::VerifyError::BlockNumberNotIncreasing)]
+    ));
+
+    // The justification must target the header of the fragment.
+    let mut fragment = warp_sync_fragment(10, change(), &keys.grandpa, 0);
+    fragment.scale_encoded_justification =
+        warp_sync_fragment(10, Vec::new(), &keys.grandpa, 0).scale_encoded_justification;
+    let (_, outcomes) = warp_sync_process(&keys, vec![fragment], true);
+    assert!(matches!(
+        &outcomes[..],
+        [Err(warp_sync::VerifyError::TargetMismatch)]
+    ));
+
+    // The justification must be signed by the current authorities.
+    let fragments = vec![warp_sync_fragment(10, change(), &authority1, 0)];
+    let (mut sync, outcomes) = warp_sync_process(&keys, fragments, true);
+    assert!(matches!(
+        &outcomes[..],
+        [Err(warp_sync::VerifyError::JustificationVerify(_))]
+    ));
+
+    // The source that has sent an invalid fragment is no longer used.
+    assert!(!sync.is_finished());
+    assert!(sync.next_request_action().is_none());
+}
+
+/// Builds an [`all_forks::AllForksSync`] on top of the genesis block of the test chain.
+fn all_forks_sync(
+    keys: &Keys,
+    granularity: u32,
+    max_non_finalized_blocks: Option<usize>,
+) -> all_forks::AllForksSync<(), ()> {
+    all_forks::AllForksSync::new(all_forks::Config {
+        chain_information_config: keys.genesis_chain_information(),
+        sources_capacity: 4,
+        blocks_capacity: 32,
+        max_non_finalized_blocks: max_non_finalized_blocks.map(|n| NonZeroUsize::new(n).unwrap()),
+        max_non_finalized_depth: None,
+        max_slot_drift: 0,
+        max_disjoint_headers: 64,
+        blocks_request_granularity: NonZeroU32::new(granularity).unwrap(),
+    })
+}
+
+/// Builds the response to an ancestry search, in other words the given number of blocks of
+/// `chain` in decreasing block numbers starting from `first_block_number`.
+fn all_forks_respond(
+    chain: &TestChain,
+    first_block_number: u64,
+    num_blocks: u32,
+) -> Vec<all_forks::RequestSuccessBlock> {
+    (0..u64::from(num_blocks))
+        .take_while(|n| *n < first_block_number)
+        .map(|n| first_block_number - n)
+        .map(|n| all_forks::RequestSuccessBlock {
+            scale_encoded_header: chain.headers[usize::try_from(n).unwrap()].clone(),
+            scale_encoded_justification: chain.justifications.get(&n).cloned(),
+        })
+        .collect()
+}
+
+/// Verifies blocks with the given [`all_forks::AllForksSync`] until there is nothing to do.
+fn all_forks_process(sync: &mut all_forks::AllForksSync<(), ()>) {
+    loop {
+        match sync.process_one(SIMULATION_START) {
+            all_forks::ProcessOne::Idle => break,
+            all_forks::ProcessOne::HeaderVerified { .. } => {}
+            other => panic!("{:?}", other),
+        }
+    }
+}
+
+#[test]
+fn all_forks_ancestry_search() {
+    let keys = Keys::new();
+    let mut chain = TestChain::genesis();
+    chain.extend(&keys, 10, None, 0);
+
+    let mut sync = all_forks_sync(&keys, 4, None);
+    let source = sync.add_source((), 10, chain.block_hash(10));
+
+    // The ancestors of the best block of the source are requested by batches of at most 4
+    // blocks, and never below the finalized block.
+    for (first_block_number, num_blocks) in [(10, 4), (6, 4), (2, 2)].iter().cloned() {
+        let search = sync.next_request_action().unwrap();
+        assert_eq!(search.source_id, source);
+        assert_eq!(search.first_block_number, first_block_number);
+        assert_eq!(
+            search.first_block_hash,
+            chain.block_hash(first_block_number)
+        );
+        assert_eq!(search.num_blocks.get(), num_blocks);
+        let request_id = search.start(());
+
+        // Only one request at a time is started towards each source.
+        assert!(sync.next_request_action().is_none());
+
+        let response = all_forks_respond(&chain, first_block_number, num_blocks);
+        let (_, outcome) = sync.finish_ancestry_search(request_id, Ok(response.into_iter()));
+        assert!(matches!(outcome, all_forks::AncestrySearchOutcome::Success));
+    }
+
+    assert!(sync.next_request_action().is_none());
+    all_forks_process(&mut sync);
+    assert_eq!(sync.best_block_hash(), chain.block_hash(10));
+    assert_eq!(sync.finalized_block_header().number, 0);
+}
+
+#[test]
+fn all_forks_ancestry_search_misbehaving_source() {
+    let keys = Keys::new();
+    let mut chain = TestChain::genesis();
+    chain.extend(&keys, 10, None, 0);
+
+    let mut sync = all_forks_sync(&keys, 4, None);
+    sync.add_source((), 10, chain.block_hash(10));
+
+    // The source sends back blocks other than the requested ones.
+    let search = sync.next_request_action().unwrap();
+    let request_id = search.start(());
+    let response = all_forks_respond(&chain, 9, 4);
+    let (_, outcome) = sync.finish_ancestry_search(request_id, Ok(response.into_iter()));
+    assert!(matches!(
+        outcome,
+        all_forks::AncestrySearchOutcome::SourceMisbehaved
+    ));
+
+    // No other source is known to have the block, which is therefore forgotten.
+    assert!(sync.next_request_action().is_none());
+}
+
+#[test]
+fn all_forks_disjoint_blocks() {
+    let keys = Keys::new();
+    let mut chain = TestChain::genesis();
+    chain.extend(&keys, 3, None, 0);
+
+    let mut sync = all_forks_sync(&keys, 4, None);
+    let source = sync.add_source((), 0, chain.block_hash(0));
+    assert!(sync.next_request_action().is_none());
+
+    // The parent of block #3 is unknown, and must be requested from the source that has
+    // announced it.
+    assert!(matches!(
+        sync.block_announce(source, chain.headers[3].clone(), true),
+        all_forks::BlockAnnounceOutcome::Disjoint
+    ));
+    assert_eq!(sync.source_best_block(source), (3, &chain.block_hash(3)));
+    let search = sync.next_request_action().unwrap();
+    assert_eq!(search.first_block_number, 2);
+    assert_eq!(search.first_block_hash, chain.block_hash(2));
+    let request_id = search.start(());
+
+    // Block #3 can't be verified before its parent.
+    all_forks_process(&mut sync);
+    assert_eq!(sync.best_block_header().number, 0);
+
+    // Block #1 can immediately be verified.
+    assert!(matches!(
+        sync.block_announce(source, chain.headers[1].clone(), false),
+        all_forks::BlockAnnounceOutcome::Queued
+    ));
+    assert!(matches!(
+        sync.block_announce(source, chain.headers[0].clone(), false),
+        all_forks::BlockAnnounceOutcome::TooOld
+    ));
+    all_forks_process(&mut sync);
+    assert_eq!(sync.best_block_hash(), chain.block_hash(1));
+    assert!(matches!(
+        sync.block_announce(source, chain.headers[1].clone(), false),
+        all_forks::BlockAnnounceOutcome::AlreadyInChain
+    ));
+
+    // The response links block #3 to block #1.
+    let response = all_forks_respond(&chain, 2, 1);
+    let (_, outcome) = sync.finish_ancestry_search(request_id, Ok(response.into_iter()));
+    assert!(matches!(outcome, all_forks::AncestrySearchOutcome::Success));
+    all_forks_process(&mut sync);
+    assert_eq!(sync.best_block_hash(), chain.block_hash(3));
+}
+
+#[test]
+fn all_forks_prunes_blocks_not_descending_from_finalized() {
+    let keys = Keys::new();
+    let mut chain = TestChain::genesis();
+    chain.extend(&keys, 4, Some(4), 0);
+    let mut fork = chain.truncated(2);
+    fork.extend(&keys, 3, None, 1);
+
+    let mut sync = all_forks_sync(&keys, 4, None);
+
+    // Two sources announce the blocks #5 and #4 of a fork starting after block #2, whose block
+    // #3 is unknown. It is requested from the first source.
+    let fork_source1 = sync.add_source((), 0, chain.block_hash(0));
+    let fork_source2 = sync.add_source((), 0, chain.block_hash(0));
+    for source in [fork_source1, fork_source2].iter() {
+        for number in [5, 4].iter() {
+            assert!(matches!(
+                sync.block_announce(*source, fork.headers[*number].clone(), true),
+                all_forks::BlockAnnounceOutcome::Disjoint
+            ));
+        }
+    }
+    let search = sync.next_request_action().unwrap();
+    assert_eq!(search.first_block_hash, fork.block_hash(3));
+    let fork_request_id = search.start(());
+
+    // An other source provides the main chain, where block #4 has a justification.
+    let source = sync.add_source((), 4, chain.block_hash(4));
+    let search = sync.next_request_action().unwrap();
+    assert_eq!(search.source_id, source);
+    let request_id = search.start(());
+    let response = all_forks_respond(&chain, 4, 4);
+    let (_, outcome) = sync.finish_ancestry_search(request_id, Ok(response.into_iter()));
+    assert!(matches!(outcome, all_forks::AncestrySearchOutcome::Success));
+
+    all_forks_process(&mut sync);
+    assert_eq!(sync.finalized_block_header().number, 4);
+
+    // The blocks of the fork can no longer be finalized and have been discarded, including the
+    // block #5, which is above the finalized block. The block #3 of the fork would otherwise
+    // be requested from the second source.
+    let (_, outcome) = sync.finish_ancestry_search(
+        fork_request_id,
+        Err::<core::iter::Empty<_>, _>(all_forks::RequestFail::BlocksUnavailable),
+    );
+    assert!(matches!(outcome, all_forks::AncestrySearchOutcome::Failed));
+    assert!(sync.next_request_action().is_none());
+    assert!(matches!(
+        sync.block_announce(fork_source2, fork.headers[5].clone(), false),
+        all_forks::BlockAnnounceOutcome::NotFinalizedChain
+    ));
+}
+
+#[test]
+fn all_forks_evicted_block_descendants_discarded() {
+    let keys = Keys::new();
+
+    // Four forks of two blocks starting after the genesis block, sorted by the hash of their
+    // block #1.
+    let mut forks = (1..=4)
+        .map(|state_root_byte| {
+            let mut fork = TestChain::genesis();
+            fork.extend(&keys, 2, None, state_root_byte);
+            fork
+        })
+        .collect::<Vec<_>>();
+    forks.sort_by_key(|fork| fork.block_hash(1));
+
+    let mut sync = all_forks::AllForksSync::<(), ()>::new(all_forks::Config {
+        chain_information_config: keys.genesis_chain_information(),
+        sources_capacity: 1,
+        blocks_capacity: 3,
+        max_non_finalized_blocks: NonZeroUsize::new(3),
+        max_non_finalized_depth: None,
+        max_slot_drift: 0,
+        max_disjoint_headers: 2,
+        blocks_request_granularity: NonZeroU32::new(4).unwrap(),
+    });
+    let source = sync.add_source((), 0, forks[0].block_hash(0));
+
+    // The chain is full after the block #1 of the first three forks. The one with the highest
+    // hash is the best.
+    for fork in &forks[..3] {
+        assert!(matches!(
+            sync.block_announce(source, fork.headers[1].clone(), false),
+            all_forks::BlockAnnounceOutcome::Queued
+        ));
+        all_forks_process(&mut sync);
+    }
+    assert_eq!(sync.best_block_hash(), forks[2].block_hash(1));
+
+    // The block #2 of the first fork would become the new best block, and the block #1 of the
+    // fourth fork is better than the block #1 of the first fork. Both fit in the chain.
+    for header in &[&forks[0].headers[2], &forks[3].headers[1]] {
+        assert!(matches!(
+            sync.block_announce(source, (*header).clone(), false),
+            all_forks::BlockAnnounceOutcome::Queued
+        ));
+    }
+
+    // Inserting the block #1 of the fourth fork evicts the block #1 of the first fork, whose
+    // child can no longer be verified and is discarded.
+    all_forks_process(&mut sync);
+    assert_eq!(sync.best_block_hash(), forks[3].block_hash(1));
+
+    // The discarded child doesn't count towards `max_disjoint_headers`.
+    for fork in &[&forks[1], &forks[2]] {
+        assert!(matches!(
+            sync.block_announce(source, fork.headers[2].clone(), false),
+            all_forks::BlockAnnounceOutcome::Queued
+        ));
+    }
+}
+
+#[test]
+fn all_forks_limit_reached_keeps_descendants() {
+    let keys = Keys::new();
+    let mut chain = TestChain::genesis();
+    chain.extend(&keys, 2, None, 0);
+    let mut fork = TestChain::genesis();
+    fork.extend(&keys, 3, None, 1);
+
+    let mut sync = all_forks_sync(&keys, 4, Some(3));
+    let source = sync.add_source((), 0, chain.block_hash(0));
+    for number in 1..=2 {
+        assert!(matches!(
+            sync.block_announce(source, chain.headers[number].clone(), false),
+            all_forks::BlockAnnounceOutcome::Queued
+        ));
+        all_forks_process(&mut sync);
+    }
+
+    // The block #2 of the fork doesn't fit in the chain once its parent has been inserted.
+    for number in 1..=3 {
+        sync.block_announce(source, fork.headers[number].clone(), false);
+    }
+    assert!(matches!(
+        sync.process_one(SIMULATION_START),
+        all_forks::ProcessOne::HeaderVerified { number: 1, .. }
+    ));
+    match sync.process_one(SIMULATION_START) {
+        all_forks::ProcessOne::LimitReached { number, hash, .. } => {
+            assert_eq!(number, 2);
+            assert_eq!(hash, fork.block_hash(2));
+        }
+        other => panic!("{:?}", other),
+    }
+    assert!(matches!(
+        sync.process_one(SIMULATION_START),
+        all_forks::ProcessOne::Idle
+    ));
+
+    // Once the block #1 of the fork is finalized, the chain has room for the block #2. Its
+    // descendant has been kept and is verified as well.
+    sync.grandpa_commit(&grandpa_commit(&keys, 1, &fork.block_hash(1)))
+        .unwrap();
+    assert!(matches!(
+        sync.block_announce(source, fork.headers[2].clone(), false),
+        all_forks::BlockAnnounceOutcome::Queued
+    ));
+    all_forks_process(&mut sync);
+    assert_eq!(sync.best_block_hash(), fork.block_hash(3));
+}
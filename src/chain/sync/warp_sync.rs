@@ -0,0 +1,584 @@
+// Substrate-lite
+// Copyright (C) 2019-2020  Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! GrandPa warp syncing.
+//!
+//! Warp syncing consists in downloading, from a source, the list of blocks that change the list
+//! of GrandPa authorities, alongside with their justifications. Each justification is verified
+//! using the list of authorities of the previous block of the list, and, once verified, the list
+//! of authorities is updated from the header of that block.
+//!
+//! Contrary to the optimistic syncing strategies, the headers of all the blocks in between are
+//! never downloaded. This makes it possible to reach the head of the chain in a few requests,
+//! rather than having to verify every single header since the genesis block.
+//!
+//! Forced changes of the list of authorities are triggered when a block is imported rather than
+//! finalized. Since the blocks in between two fragments are never imported, a forced change is
+//! applied when verifying the first fragment at or above the block where it is triggered.
+//!
+//! # Usage
+//!
+//! Sources of fragments must be registered using [`WarpSync::add_source`] and unregistered
+//! using [`WarpSync::remove_source`].
+//!
+//! Call [`WarpSync::next_request_action`] in order to know which request to start, then pass
+//! the response to [`WarpSync::finish_request`]. The fragments in the response are then
+//! verified one by one by calling [`WarpSync::process_one`].
+//!
+//! Once [`WarpSync::is_finished`] returns `true`, the list of GrandPa authorities of the chain is
+//! known. However, since the BABE or Aura-related state of the chain, the GrandPa authorities
+//! that have been disabled, and whether GrandPa is paused can't be found in the headers of the
+//! blocks that change the list of GrandPa authorities, they have to be provided separately (for
+//! example by reading the storage of the finalized block) when calling
+//! [`WarpSync::into_chain_information`].
+//!
+//! > **Note**: The nodes don't use warp syncing yet, as reading the consensus-related state from
+//! >           the storage of the finalized block requires a storage proof request, which the
+//! >           networking doesn't support.
+
+use super::super::{blocks_tree, chain_information};
+use crate::{finality::justification, header};
+
+use alloc::{collections::VecDeque, vec::Vec};
+use core::fmt;
+
+/// Configuration for the [`WarpSync`].
+#[derive(Debug)]
+pub struct Config {
+    /// Information about the latest finalized block and its ancestors. Warp syncing starts from
+    /// this block.
+    pub start_chain_information: chain_information::ChainInformation,
+
+    /// Pre-allocated capacity for the number of sources.
+    pub sources_capacity: usize,
+}
+
+/// GrandPa warp syncing.
+pub struct WarpSync<TSrc> {
+    /// Information about the chain we started syncing from. Used in order to build the final
+    /// chain information if no fragment has been verified.
+    start_chain_information: chain_information::ChainInformation,
+
+    /// Header of the latest block whose finality has been verified.
+    finalized_block_header: header::Header,
+
+    /// Hash of [`WarpSync::finalized_block_header`].
+    finalized_block_hash: [u8; 32],
+
+    /// State of GrandPa as of the finalized block, updated from the headers of the fragments.
+    grandpa: blocks_tree::GrandpaState,
+
+    /// List of sources of fragments.
+    sources: slab::Slab<Source<TSrc>>,
+
+    /// Request in progress, if any.
+    ongoing_request: Option<(RequestId, SourceId)>,
+
+    /// Identifier to assign to the next request.
+    next_request_id: RequestId,
+
+    /// Fragments that have been received but not verified yet, and the source that sent them.
+    to_verify: VecDeque<(SourceId, Fragment)>,
+
+    /// `true` if the last element of [`WarpSync::to_verify`] is the last fragment that a source
+    /// has to provide.
+    to_verify_is_finished: bool,
+
+    /// `true` if all the fragments have been verified.
+    finished: bool,
+}
+
+struct Source<TSrc> {
+    user_data: TSrc,
+    /// `true` if this source has sent back an invalid fragment. No further request will be
+    /// started towards this source.
+    banned: bool,
+}
+
+impl<TSrc> WarpSync<TSrc> {
+    /// Builds a new [`WarpSync`].
+    pub fn new(config: Config) -> Self {
+        let start = config.start_chain_information;
+        WarpSync {
+            finalized_block_header: start.finalized_block_header.clone(),
+            finalized_block_hash: start.finalized_block_header.hash(),
+            grandpa: blocks_tree::GrandpaState {
+                authorities_set_id: start.grandpa_after_finalized_block_authorities_set_id,
+                triggered_authorities: start.grandpa_finalized_triggered_authorities.clone(),
+                scheduled_change: start.grandpa_finalized_scheduled_change.clone(),
+                forced_change: start.grandpa_finalized_forced_change.clone(),
+                disabled_authorities: start.grandpa_finalized_disabled_authorities.clone(),
+                pause_state: start.grandpa_finalized_pause_state,
+            },
+            start_chain_information: start,
+            sources: slab::Slab::with_capacity(config.sources_capacity),
+            ongoing_request: None,
+            next_request_id: RequestId(0),
+            to_verify: VecDeque::new(),
+            to_verify_is_finished: false,
+            finished: false,
+        }
+    }
+
+    /// Returns the header of the latest block whose finality has been verified.
+    pub fn finalized_block_header(&self) -> header::HeaderRef {
+        (&self.finalized_block_header).into()
+    }
+
+    /// Returns the hash of the latest block whose finality has been verified.
+    pub fn finalized_block_hash(&self) -> [u8; 32] {
+        self.finalized_block_hash
+    }
+
+    /// Returns `true` if a source has indicated that there is no more fragment after the one
+    /// returned by [`WarpSync::finalized_block_header`].
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// Inform the [`WarpSync`] of a new potential source of fragments.
+    pub fn add_source(&mut self, source: TSrc) -> SourceId {
+        SourceId(self.sources.insert(Source {
+            user_data: source,
+            banned: false,
+        }))
+    }
+
+    /// Inform the [`WarpSync`] that a source is no longer available.
+    ///
+    /// Returns the user data of the source, and the identifier of the request that was in
+    /// progress towards this source, if any. This request must no longer be passed to
+    /// [`WarpSync::finish_request`].
+    ///
+    /// # Panic
+    ///
+    /// Panics if the [`SourceId`] is invalid.
+    ///
+    pub fn remove_source(&mut self, source_id: SourceId) -> (TSrc, Option<RequestId>) {
+        let user_data = self.sources.remove(source_id.0).user_data;
+
+        let request = match self.ongoing_request {
+            Some((request_id, s)) if s == source_id => {
+                self.ongoing_request = None;
+                Some(request_id)
+            }
+            _ => None,
+        };
+
+        (user_data, request)
+    }
+
+    /// Returns the request that should be started, if any.
+    ///
+    /// At most one request is in progress at any given time. The request must be started
+    /// immediately, and [`WarpSync::finish_request`] must later be called with the
+    /// [`RequestId`] returned here.
+    pub fn next_request_action(&mut self) -> Option<RequestAction<TSrc>> {
+        if self.finished || self.ongoing_request.is_some() || !self.to_verify.is_empty() {
+            return None;
+        }
+
+        let source_id = SourceId(self.sources.iter().find(|(_, s)| !s.banned)?.0);
+
+        let request_id = self.next_request_id;
+        self.next_request_id.0 += 1;
+        self.ongoing_request = Some((request_id, source_id));
+
+        Some(RequestAction {
+            request_id,
+            source_id,
+            source: &mut self.sources[source_id.0].user_data,
+            begin_block_hash: self.finalized_block_hash,
+        })
+    }
+
+    /// Update the [`WarpSync`] with the outcome of a request.
+    ///
+    /// On success, must be passed the list of fragments in increasing block number, and a
+    /// boolean indicating whether the source has indicated that the last fragment is the latest
+    /// finalized block it knows about.
+    ///
+    /// # Panic
+    ///
+    /// Panics if the [`RequestId`] is invalid.
+    ///
+    pub fn finish_request(
+        &mut self,
+        request_id: RequestId,
+        outcome: Result<(impl Iterator<Item = Fragment>, bool), RequestFail>,
+    ) {
+        let source_id = match self.ongoing_request.take() {
+            Some((id, source_id)) if id == request_id => source_id,
+            _ => panic!("invalid RequestId"),
+        };
+
+        match outcome {
+            Ok((fragments, is_finished)) => {
+                debug_assert!(self.to_verify.is_empty());
+                self.to_verify
+                    .extend(fragments.map(|fragment| (source_id, fragment)));
+                self.to_verify_is_finished = is_finished;
+                if self.to_verify.is_empty() {
+                    if is_finished {
+                        self.finished = true;
+                    } else {
+                        // The source claims that there are more fragments but doesn't provide
+                        // any.
+                        self.sources[source_id.0].banned = true;
+                    }
+                }
+            }
+            Err(RequestFail::Unavailable) => {
+                // The source is not necessarily malicious, but there's no point in asking it
+                // again.
+                self.sources[source_id.0].banned = true;
+            }
+        }
+    }
+
+    /// Verifies the next fragment in the queue of verification.
+    ///
+    /// It is encouraged to call this method multiple times in a row until
+    /// [`ProcessOne::Idle`] is returned, interleaving any necessary high-priority operations
+    /// (e.g. processing network sockets) in-between two calls.
+    pub fn process_one(&mut self) -> ProcessOne {
+        let (source_id, fragment) = match self.to_verify.pop_front() {
+            Some(f) => f,
+            None => return ProcessOne::Idle,
+        };
+
+        let is_last = self.to_verify.is_empty() && self.to_verify_is_finished;
+
+        match self.verify_fragment(&fragment, is_last) {
+            Ok(()) => {
+                if is_last {
+                    self.finished = true;
+                }
+
+                ProcessOne::FragmentVerified {
+                    number: self.finalized_block_header.number,
+                    hash: self.finalized_block_hash,
+                    authorities_set_id: self.grandpa.authorities_set_id,
+                }
+            }
+            Err(error) => {
+                // The other fragments sent by this source are discarded as well.
+                self.to_verify.clear();
+                self.to_verify_is_finished = false;
+                if let Some(source) = self.sources.get_mut(source_id.0) {
+                    source.banned = true;
+                }
+
+                ProcessOne::VerifyError { source_id, error }
+            }
+        }
+    }
+
+    /// Verifies the given fragment and, on success, updates the state of `self`.
+    fn verify_fragment(&mut self, fragment: &Fragment, is_last: bool) -> Result<(), VerifyError> {
+        let decoded_header =
+            header::decode(&fragment.scale_encoded_header).map_err(VerifyError::InvalidHeader)?;
+        let hash = header::hash_from_scale_encoded_header(&fragment.scale_encoded_header);
+
+        if decoded_header.number <= self.finalized_block_header.number {
+            return Err(VerifyError::BlockNumberNotIncreasing);
+        }
+
+        let decoded_justification =
+            justification::decode::decode(&fragment.scale_encoded_justification)
+                .map_err(VerifyError::InvalidJustification)?;
+
+        if *decoded_justification.target_hash != hash
+            || u64::from(decoded_justification.target_number) != decoded_header.number
+        {
+            return Err(VerifyError::TargetMismatch);
+        }
+
+        // A scheduled change must be triggered by finalizing the block where it is triggered,
+        // which is necessarily part of the fragments.
+        let triggers_scheduled_change = match &self.grandpa.scheduled_change {
+            Some((trigger_block_height, _)) if decoded_header.number > *trigger_block_height => {
+                return Err(VerifyError::ScheduledChangeSkipped);
+            }
+            Some((trigger_block_height, _)) => *trigger_block_height == decoded_header.number,
+            None => false,
+        };
+
+        // Contrary to scheduled changes, a forced change is triggered by importing the block
+        // where it is triggered. Since the blocks in between two fragments are never imported,
+        // a pending forced change is applied when importing the first fragment at or above the
+        // block where it is triggered.
+        let triggers_forced_change = self
+            .grandpa
+            .forced_change
+            .as_ref()
+            .map_or(false, |(n, _)| *n <= decoded_header.number);
+
+        // Every fragment, except for the very last one, is expected to change the list of
+        // authorities. This guarantees that sources can't send back an unnecessarily long list
+        // of fragments.
+        if !is_last
+            && !triggers_scheduled_change
+            && !triggers_forced_change
+            && !decoded_header.digest.logs().any(|d| {
+                matches!(
+                    d,
+                    header::DigestItemRef::GrandpaConsensus(
+                        header::GrandpaConsensusLogRef::ScheduledChange(_)
+                    ) | header::DigestItemRef::GrandpaConsensus(
+                        header::GrandpaConsensusLogRef::ForcedChange { .. }
+                    )
+                )
+            })
+        {
+            return Err(VerifyError::MissingAuthoritiesChange);
+        }
+
+        // Import the block, which applies the forced changes, then verify its justification
+        // with the authorities that are supposed to finalize it.
+        let mut grandpa = self.grandpa.clone();
+        grandpa.import_block(decoded_header.clone());
+
+        // Equivocations found in the justification aren't reported, as there isn't any way to
+        // do so from within the warp syncing.
+        let _equivocations = justification::verify::verify(justification::verify::Config {
+            justification: decoded_justification,
+            authorities_set_id: grandpa.authorities_set_id,
+            authorities_list: grandpa
+                .triggered_authorities
+                .iter()
+                .map(header::GrandpaAuthorityRef::from),
+        })
+        .map_err(VerifyError::JustificationVerify)?;
+
+        // The fragment is valid. Update the state.
+        grandpa.finalize_block(decoded_header.number);
+        self.grandpa = grandpa;
+        self.finalized_block_header = decoded_header.into();
+        self.finalized_block_hash = hash;
+        Ok(())
+    }
+
+    /// Turns the [`WarpSync`] into a [`chain_information::ChainInformation`].
+    ///
+    /// Since the BABE or Aura-related information, the disabled GrandPa authorities and the
+    /// GrandPa pause state can't be found in the fragments, they must be passed as parameter.
+    /// This information must correspond to the block returned by
+    /// [`WarpSync::finalized_block_header`], and `consensus` must match the consensus algorithm
+    /// of the chain.
+    ///
+    /// The pause and disabling signals found in the headers of the fragments are ignored, as the
+    /// blocks in between two fragments, which might contain other such signals, are never
+    /// downloaded.
+    ///
+    /// # Panic
+    ///
+    /// Panics if [`WarpSync::is_finished`] returns `false`.
+    ///
+    pub fn into_chain_information(
+        self,
+        consensus: ConsensusInformation,
+        grandpa: GrandpaInformation,
+    ) -> chain_information::ChainInformation {
+        assert!(self.finished);
+
+        if self.finalized_block_hash == self.start_chain_information.finalized_block_header.hash() {
+            return self.start_chain_information;
+        }
+
+        let mut info = chain_information::ChainInformation {
+            finalized_block_header: self.finalized_block_header,
+            babe_finalized_block1_slot_number: None,
+            babe_finalized_block_weight: 0,
+            // The blocks of the epoch of the finalized block have been skipped.
+            babe_finalized_epoch_randomness_contributions: None,
+            babe_finalized_disabled_authorities: Vec::new(),
+            babe_finalized_block_epoch_information: None,
+            babe_finalized_next_epoch_transition: None,
+            aura_finalized_authorities_list: None,
+            grandpa_after_finalized_block_authorities_set_id: self.grandpa.authorities_set_id,
+            grandpa_finalized_triggered_authorities: self.grandpa.triggered_authorities,
+            grandpa_finalized_scheduled_change: self.grandpa.scheduled_change,
+            grandpa_finalized_forced_change: self.grandpa.forced_change,
+            grandpa_finalized_disabled_authorities: grandpa.finalized_disabled_authorities,
+            grandpa_finalized_pause_state: grandpa.finalized_pause_state,
+        };
+
+        match consensus {
+            ConsensusInformation::Babe(babe) => {
+                info.babe_finalized_block1_slot_number = Some(babe.finalized_block1_slot_number);
+                info.babe_finalized_block_weight = babe.finalized_block_weight;
+                info.babe_finalized_disabled_authorities = babe.finalized_disabled_authorities;
+                info.babe_finalized_block_epoch_information =
+                    babe.finalized_block_epoch_information;
+                info.babe_finalized_next_epoch_transition =
+                    Some(babe.finalized_next_epoch_transition);
+            }
+            ConsensusInformation::Aura(aura) => {
+                info.aura_finalized_authorities_list = Some(aura.finalized_authorities_list);
+            }
+        }
+
+        info
+    }
+}
+
+impl<TSrc> fmt::Debug for WarpSync<TSrc> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("WarpSync")
+            .field("finalized_block_hash", &self.finalized_block_hash)
+            .field("authorities_set_id", &self.grandpa.authorities_set_id)
+            .field("finished", &self.finished)
+            .finish()
+    }
+}
+
+/// Identifier for an ongoing request in the [`WarpSync`].
+#[derive(Debug, Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash)]
+pub struct RequestId(u64);
+
+/// Identifier for a source in the [`WarpSync`].
+#[derive(Debug, Copy, Clone, Ord, PartialOrd, Eq, PartialEq, Hash)]
+pub struct SourceId(usize);
+
+/// Request that must be started towards a source.
+#[derive(Debug)]
+pub struct RequestAction<'a, TSrc> {
+    /// Identifier of the request, to later pass to [`WarpSync::finish_request`].
+    pub request_id: RequestId,
+    /// Source where to request fragments from.
+    pub source_id: SourceId,
+    /// User data of source where to request fragments from.
+    pub source: &'a mut TSrc,
+    /// Hash of the block to start from. The source must return the blocks that change the list
+    /// of authorities and are descendants of this block.
+    pub begin_block_hash: [u8; 32],
+}
+
+/// Block that changes the list of GrandPa authorities, as found in a response.
+#[derive(Debug, Clone)]
+pub struct Fragment {
+    /// SCALE-encoded header of the block.
+    pub scale_encoded_header: Vec<u8>,
+    /// SCALE-encoded GrandPa justification of the block.
+    pub scale_encoded_justification: Vec<u8>,
+}
+
+/// Reason why a request has failed.
+#[derive(Debug)]
+pub enum RequestFail {
+    /// The source couldn't provide the fragments.
+    Unavailable,
+}
+
+/// Consensus-related information to pass to [`WarpSync::into_chain_information`].
+#[derive(Debug, Clone)]
+pub enum ConsensusInformation {
+    /// The chain uses BABE.
+    Babe(BabeInformation),
+    /// The chain uses Aura.
+    Aura(AuraInformation),
+}
+
+/// BABE-related information to pass to [`WarpSync::into_chain_information`].
+///
+/// See the equivalent fields in [`chain_information::ChainInformation`].
+#[derive(Debug, Clone)]
+pub struct BabeInformation {
+    /// Slot number of block #1.
+    pub finalized_block1_slot_number: u64,
+    /// Number of blocks authored using a BABE primary slot claim in the ancestry of the
+    /// finalized block.
+    ///
+    /// > **Note**: This value can't be verified. Since the fork choice rule only ever compares
+    /// >           blocks that descend from the finalized block, passing `0` if it is unknown is
+    /// >           harmless.
+    pub finalized_block_weight: u64,
+    /// Information about the epoch the finalized block belongs to. `None` if the finalized block
+    /// belongs to epoch #0.
+    pub finalized_block_epoch_information: Option<(header::BabeNextEpoch, header::BabeNextConfig)>,
+    /// Information about the epoch right after the one the finalized block belongs to.
+    pub finalized_next_epoch_transition: (header::BabeNextEpoch, header::BabeNextConfig),
+    /// Indices of the authorities of the epoch of the finalized block that have been disabled
+    /// by the finalized block or by its ancestors belonging to the same epoch.
+    pub finalized_disabled_authorities: Vec<u32>,
+}
+
+/// Aura-related information to pass to [`WarpSync::into_chain_information`].
+///
+/// See the equivalent fields in [`chain_information::ChainInformation`].
+#[derive(Debug, Clone)]
+pub struct AuraInformation {
+    /// List of Aura authorities that must author the block right after the finalized block.
+    pub finalized_authorities_list: Vec<header::AuraAuthority>,
+}
+
+/// GrandPa-related information to pass to [`WarpSync::into_chain_information`].
+///
+/// See the equivalent fields in [`chain_information::ChainInformation`].
+#[derive(Debug, Clone)]
+pub struct GrandpaInformation {
+    /// List of indices of the GrandPa authorities of the finalized block that have been disabled.
+    pub finalized_disabled_authorities: Vec<u64>,
+    /// State of the GrandPa pauses and resumes as of the finalized block.
+    pub finalized_pause_state: chain_information::GrandpaPauseState,
+}
+
+/// Outcome of calling [`WarpSync::process_one`].
+#[derive(Debug)]
+pub enum ProcessOne {
+    /// There was nothing to do.
+    Idle,
+
+    /// A fragment has been successfully verified. The finalized block has been updated.
+    FragmentVerified {
+        /// Number of the new finalized block.
+        number: u64,
+        /// Hash of the new finalized block.
+        hash: [u8; 32],
+        /// Authorities set ID of the block right after the new finalized block.
+        authorities_set_id: u64,
+    },
+
+    /// A fragment has failed to verify. The remaining fragments sent by this source have been
+    /// discarded, and no further request will be sent to this source.
+    VerifyError {
+        /// Source that has sent the invalid fragment.
+        source_id: SourceId,
+        /// Problem that happened.
+        error: VerifyError,
+    },
+}
+
+/// Error that can happen when verifying a fragment.
+#[derive(Debug, derive_more::Display)]
+pub enum VerifyError {
+    /// Error while decoding the header.
+    InvalidHeader(header::Error),
+    /// Error while decoding the justification.
+    InvalidJustification(justification::decode::Error),
+    /// The block number of the fragment isn't superior to the current finalized block.
+    BlockNumberNotIncreasing,
+    /// The justification doesn't target the header of the fragment.
+    TargetMismatch,
+    /// The fragment is after a block where a change in the list of authorities is triggered.
+    ScheduledChangeSkipped,
+    /// The justification verification has failed.
+    JustificationVerify(justification::verify::Error),
+    /// The fragment isn't the last one but doesn't change the list of authorities.
+    MissingAuthoritiesChange,
+}
@@ -47,17 +47,35 @@ pub(super) struct SerializedChainInformationV1 {
     finalized_block_header: Vec<u8>,
     #[serde(default, skip_serializing_if = "Option::is_none")]
     babe_finalized_block1_slot_number: Option<u64>,
+    // Not written by older versions of this code, hence the default.
+    #[serde(default)]
+    babe_finalized_block_weight: u64,
+    // Not written by older versions of this code, in which case the value is unknown.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    babe_finalized_epoch_randomness_contributions: Option<Vec<SerializedHash32V1>>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    babe_finalized_disabled_authorities: Vec<u32>,
     #[serde(default, skip_serializing_if = "Option::is_none")]
     babe_finalized_block_epoch_information:
         Option<(SerializedBabeNextEpochV1, SerializedBabeNextConfigV1)>,
     #[serde(default, skip_serializing_if = "Option::is_none")]
     babe_finalized_next_epoch_transition:
         Option<(SerializedBabeNextEpochV1, SerializedBabeNextConfigV1)>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    aura_finalized_authorities_list: Option<Vec<SerializedAuraAuthorityV1>>,
     grandpa_after_finalized_block_authorities_set_id: u64,
     #[serde(default, skip_serializing_if = "Vec::is_empty")]
     grandpa_finalized_triggered_authorities: Vec<SerializedGrandpaAuthorityV1>,
     #[serde(default, skip_serializing_if = "Option::is_none")]
     grandpa_finalized_scheduled_change: Option<SerializedFinalizedScheduledChangeV1>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    grandpa_finalized_forced_change: Option<SerializedFinalizedScheduledChangeV1>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    grandpa_finalized_disabled_authorities: Vec<u64>,
+    // `None` if the authorities are live, which is also the case when reading the output of older
+    // versions of this code.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    grandpa_finalized_pause_state: Option<SerializedGrandpaPauseStateV1>,
 }
 
 impl<'a> From<chain_information::ChainInformationRef<'a>> for SerializedChainInformationV1 {
@@ -71,12 +89,20 @@ impl<'a> From<chain_information::ChainInformationRef<'a>> for SerializedChainInf
                 },
             ),
             babe_finalized_block1_slot_number: from.babe_finalized_block1_slot_number,
+            babe_finalized_block_weight: from.babe_finalized_block_weight,
+            babe_finalized_epoch_randomness_contributions: from
+                .babe_finalized_epoch_randomness_contributions
+                .map(|list| list.iter().map(|c| SerializedHash32V1(*c)).collect()),
+            babe_finalized_disabled_authorities: from.babe_finalized_disabled_authorities.to_vec(),
             babe_finalized_block_epoch_information: from
                 .babe_finalized_block_epoch_information
                 .map(|(e, i)| (e.into(), i.into())),
             babe_finalized_next_epoch_transition: from
                 .babe_finalized_next_epoch_transition
                 .map(|(e, i)| (e.into(), i.into())),
+            aura_finalized_authorities_list: from
+                .aura_finalized_authorities_list
+                .map(|list| list.iter().map(Into::into).collect()),
             grandpa_after_finalized_block_authorities_set_id: from
                 .grandpa_after_finalized_block_authorities_set_id,
             grandpa_finalized_triggered_authorities: from
@@ -91,6 +117,19 @@ impl<'a> From<chain_information::ChainInformationRef<'a>> for SerializedChainInf
                     new_authorities_list: l.iter().map(Into::into).collect(),
                 },
             ),
+            grandpa_finalized_forced_change: from.grandpa_finalized_forced_change.map(|(n, l)| {
+                SerializedFinalizedScheduledChangeV1 {
+                    trigger_block_height: n,
+                    new_authorities_list: l.iter().map(Into::into).collect(),
+                }
+            }),
+            grandpa_finalized_disabled_authorities: from
+                .grandpa_finalized_disabled_authorities
+                .to_vec(),
+            grandpa_finalized_pause_state: match from.grandpa_finalized_pause_state {
+                chain_information::GrandpaPauseState::Live => None,
+                state => Some(state.into()),
+            },
         }
     }
 }
@@ -102,12 +141,20 @@ impl TryFrom<SerializedChainInformationV1> for chain_information::ChainInformati
         Ok(chain_information::ChainInformation {
             finalized_block_header: header::decode(&from.finalized_block_header)?.into(),
             babe_finalized_block1_slot_number: from.babe_finalized_block1_slot_number,
+            babe_finalized_block_weight: from.babe_finalized_block_weight,
+            babe_finalized_epoch_randomness_contributions: from
+                .babe_finalized_epoch_randomness_contributions
+                .map(|list| list.into_iter().map(|c| c.0).collect()),
+            babe_finalized_disabled_authorities: from.babe_finalized_disabled_authorities,
             babe_finalized_block_epoch_information: from
                 .babe_finalized_block_epoch_information
                 .map(|(e, i)| (e.into(), i.into())),
             babe_finalized_next_epoch_transition: from
                 .babe_finalized_next_epoch_transition
                 .map(|(e, i)| (e.into(), i.into())),
+            aura_finalized_authorities_list: from
+                .aura_finalized_authorities_list
+                .map(|list| list.into_iter().map(Into::into).collect()),
             grandpa_after_finalized_block_authorities_set_id: from
                 .grandpa_after_finalized_block_authorities_set_id,
             grandpa_finalized_triggered_authorities: from
@@ -127,6 +174,20 @@ impl TryFrom<SerializedChainInformationV1> for chain_information::ChainInformati
                     )
                 },
             ),
+            grandpa_finalized_forced_change: from.grandpa_finalized_forced_change.map(|change| {
+                (
+                    change.trigger_block_height,
+                    change
+                        .new_authorities_list
+                        .into_iter()
+                        .map(Into::into)
+                        .collect(),
+                )
+            }),
+            grandpa_finalized_disabled_authorities: from.grandpa_finalized_disabled_authorities,
+            grandpa_finalized_pause_state: from
+                .grandpa_finalized_pause_state
+                .map_or(chain_information::GrandpaPauseState::Live, Into::into),
         })
     }
 }
@@ -160,6 +221,32 @@ impl From<SerializedBabeNextEpochV1> for header::BabeNextEpoch {
     }
 }
 
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SerializedAuraAuthorityV1 {
+    #[serde(
+        serialize_with = "serialize_bytes",
+        deserialize_with = "deserialize_hash32"
+    )]
+    public_key: [u8; 32],
+}
+
+impl<'a> From<&'a header::AuraAuthority> for SerializedAuraAuthorityV1 {
+    fn from(from: &'a header::AuraAuthority) -> Self {
+        SerializedAuraAuthorityV1 {
+            public_key: from.public_key,
+        }
+    }
+}
+
+impl From<SerializedAuraAuthorityV1> for header::AuraAuthority {
+    fn from(from: SerializedAuraAuthorityV1) -> Self {
+        header::AuraAuthority {
+            public_key: from.public_key,
+        }
+    }
+}
+
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
 #[serde(deny_unknown_fields)]
 struct SerializedBabeAuthorityV1 {
@@ -264,6 +351,15 @@ impl From<SerializedBabeAllowedSlotsV1> for header::BabeAllowedSlots {
     }
 }
 
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+struct SerializedHash32V1(
+    #[serde(
+        serialize_with = "serialize_bytes",
+        deserialize_with = "deserialize_hash32"
+    )]
+    [u8; 32],
+);
+
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
 #[serde(deny_unknown_fields)]
 struct SerializedFinalizedScheduledChangeV1 {
@@ -271,6 +367,56 @@ struct SerializedFinalizedScheduledChangeV1 {
     new_authorities_list: Vec<SerializedGrandpaAuthorityV1>,
 }
 
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+enum SerializedGrandpaPauseStateV1 {
+    #[serde(rename = "live")]
+    Live,
+    #[serde(rename = "pending-pause")]
+    PendingPause { trigger_block_height: u64 },
+    #[serde(rename = "paused")]
+    Paused,
+    #[serde(rename = "pending-resume")]
+    PendingResume { trigger_block_height: u64 },
+}
+
+impl From<chain_information::GrandpaPauseState> for SerializedGrandpaPauseStateV1 {
+    fn from(from: chain_information::GrandpaPauseState) -> Self {
+        match from {
+            chain_information::GrandpaPauseState::Live => SerializedGrandpaPauseStateV1::Live,
+            chain_information::GrandpaPauseState::PendingPause {
+                trigger_block_height,
+            } => SerializedGrandpaPauseStateV1::PendingPause {
+                trigger_block_height,
+            },
+            chain_information::GrandpaPauseState::Paused => SerializedGrandpaPauseStateV1::Paused,
+            chain_information::GrandpaPauseState::PendingResume {
+                trigger_block_height,
+            } => SerializedGrandpaPauseStateV1::PendingResume {
+                trigger_block_height,
+            },
+        }
+    }
+}
+
+impl From<SerializedGrandpaPauseStateV1> for chain_information::GrandpaPauseState {
+    fn from(from: SerializedGrandpaPauseStateV1) -> Self {
+        match from {
+            SerializedGrandpaPauseStateV1::Live => chain_information::GrandpaPauseState::Live,
+            SerializedGrandpaPauseStateV1::PendingPause {
+                trigger_block_height,
+            } => chain_information::GrandpaPauseState::PendingPause {
+                trigger_block_height,
+            },
+            SerializedGrandpaPauseStateV1::Paused => chain_information::GrandpaPauseState::Paused,
+            SerializedGrandpaPauseStateV1::PendingResume {
+                trigger_block_height,
+            } => chain_information::GrandpaPauseState::PendingResume {
+                trigger_block_height,
+            },
+        }
+    }
+}
+
 #[derive(Debug, serde::Serialize, serde::Deserialize)]
 #[serde(deny_unknown_fields)]
 struct SerializedGrandpaAuthorityV1 {
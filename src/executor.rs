@@ -113,6 +113,13 @@ pub use externals::{
 };
 // TODO: reexports ^ ? shouldn't we just make the module public?
 
+/// Number of heap pages to pass to [`WasmVmPrototype::new`] when the storage of the block
+/// doesn't contain any `:heappages` entry.
+///
+/// The value of the `:heappages` entry, if any, is a little endian 64 bits number that must be
+/// used instead.
+pub const DEFAULT_HEAP_PAGES: u64 = 1024;
+
 /// Runs the `Core_version` function using the given virtual machine prototype, and returns
 /// the output.
 ///
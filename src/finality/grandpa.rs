@@ -16,3 +16,5 @@
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
 pub mod chain_config;
+pub mod gossip;
+pub mod voter;
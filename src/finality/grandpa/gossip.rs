@@ -0,0 +1,603 @@
+// Substrate-lite
+// Copyright (C) 2019-2020  Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! GrandPa gossip messages.
+//!
+//! Nodes exchange GrandPa messages through the [`PROTOCOL_NAME`] notifications protocol. Each
+//! notification contains one SCALE-encoded [`GossipMessage`].
+//!
+//! Nodes are expected to send a [`NeighborPacket`] to their peers when the substream opens and
+//! whenever their view changes. Peers only send votes and commits that concern the authorities
+//! set indicated in the neighbor packet.
+//!
+//! A [`CommitMessage`] is similar to a justification, except that it doesn't contain the headers
+//! of the blocks targeted by the pre-commits, as the receiver is expected to know them already.
+//! See [`CommitMessage::scale_encoded_justification`].
+
+use super::voter;
+
+use alloc::vec::Vec;
+use core::convert::TryFrom as _;
+
+/// Name of the notifications protocol used to gossip GrandPa messages.
+pub const PROTOCOL_NAME: &str = "/paritytech/grandpa/1";
+
+/// Attempt to decode the given SCALE-encoded GrandPa gossip message.
+pub fn decode(scale_encoded: &[u8]) -> Result<GossipMessage, Error> {
+    match nom::combinator::all_consuming(gossip_message)(scale_encoded) {
+        Ok((_, message)) => Ok(message),
+        Err(nom::Err::Error((_, kind))) | Err(nom::Err::Failure((_, kind))) => Err(Error(kind)),
+        Err(nom::Err::Incomplete(_)) => unreachable!(),
+    }
+}
+
+/// Message gossiped between the nodes participating in GrandPa.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GossipMessage {
+    /// Vote emitted by an authority during a round.
+    Vote(voter::SignedMessage),
+    /// Proof that a block has been finalized.
+    Commit(CommitMessage),
+    /// View of the sender.
+    Neighbor(NeighborPacket),
+    /// Request for the votes of a more recent round. See [`voter::CatchUpRequest`].
+    CatchUpRequest(voter::CatchUpRequest),
+    /// Response to a catch-up request. See [`voter::CatchUp`].
+    CatchUp(voter::CatchUp),
+}
+
+impl GossipMessage {
+    /// Returns the SCALE encoding of the message.
+    pub fn scale_encoding(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+
+        match self {
+            GossipMessage::Vote(vote) => {
+                out.push(0);
+                out.extend_from_slice(&vote.round.to_le_bytes());
+                out.extend_from_slice(&vote.authorities_set_id.to_le_bytes());
+                out.push(match vote.message {
+                    voter::Message::Prevote(_) => 0,
+                    voter::Message::Precommit(_) => 1,
+                    voter::Message::PrimaryPropose(_) => 2,
+                });
+                encode_vote(&mut out, vote.message.vote());
+                out.extend_from_slice(&vote.signature);
+                out.extend_from_slice(&vote.authority_public_key);
+            }
+            GossipMessage::Commit(commit) => {
+                out.push(1);
+                out.extend_from_slice(&commit.round.to_le_bytes());
+                out.extend_from_slice(&commit.authorities_set_id.to_le_bytes());
+                out.extend_from_slice(&commit.target_hash);
+                out.extend_from_slice(&commit.target_number.to_le_bytes());
+                encode_length(&mut out, commit.precommits.len());
+                for precommit in &commit.precommits {
+                    encode_vote(&mut out, &precommit.vote);
+                }
+                encode_length(&mut out, commit.precommits.len());
+                for precommit in &commit.precommits {
+                    out.extend_from_slice(&precommit.signature);
+                    out.extend_from_slice(&precommit.authority_public_key);
+                }
+            }
+            GossipMessage::Neighbor(packet) => {
+                out.push(2);
+                // Version of the neighbor packet. Only version 1 exists.
+                out.push(1);
+                out.extend_from_slice(&packet.round.to_le_bytes());
+                out.extend_from_slice(&packet.authorities_set_id.to_le_bytes());
+                out.extend_from_slice(&packet.commit_finalized_height.to_le_bytes());
+            }
+            GossipMessage::CatchUpRequest(request) => {
+                out.push(3);
+                out.extend_from_slice(&request.round.to_le_bytes());
+                out.extend_from_slice(&request.authorities_set_id.to_le_bytes());
+            }
+            GossipMessage::CatchUp(catch_up) => {
+                out.push(4);
+                out.extend_from_slice(&catch_up.authorities_set_id.to_le_bytes());
+                out.extend_from_slice(&catch_up.round.to_le_bytes());
+                for votes in &[&catch_up.prevotes, &catch_up.precommits] {
+                    encode_length(&mut out, votes.len());
+                    for vote in votes.iter() {
+                        encode_vote(&mut out, &vote.vote);
+                        out.extend_from_slice(&vote.signature);
+                        out.extend_from_slice(&vote.authority_public_key);
+                    }
+                }
+                out.extend_from_slice(&catch_up.base_hash);
+                out.extend_from_slice(&catch_up.base_number.to_le_bytes());
+            }
+        }
+
+        out
+    }
+}
+
+/// Proof that a block has been finalized, as gossiped over the network.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommitMessage {
+    /// Round that has finalized the block.
+    pub round: u64,
+    /// Identifier of the authorities set that has finalized the block.
+    pub authorities_set_id: u64,
+    /// Hash of the finalized block.
+    pub target_hash: [u8; 32],
+    /// Number of the finalized block.
+    pub target_number: u32,
+    /// Precommits targeting the finalized block or one of its descendants.
+    pub precommits: Vec<voter::SignedVote>,
+}
+
+impl CommitMessage {
+    /// Returns the SCALE encoding of the justification corresponding to this commit.
+    ///
+    /// `votes_ancestries` must contain the SCALE-encoded headers of the blocks between the
+    /// finalized block (excluded) and the targets of the precommits (included).
+    pub fn scale_encoded_justification(
+        &self,
+        votes_ancestries: impl ExactSizeIterator<Item = impl AsRef<[u8]>>,
+    ) -> Vec<u8> {
+        encode_justification(
+            self.round,
+            &self.target_hash,
+            self.target_number,
+            &self.precommits,
+            votes_ancestries,
+        )
+    }
+}
+
+impl From<voter::Commit> for CommitMessage {
+    fn from(commit: voter::Commit) -> Self {
+        CommitMessage {
+            round: commit.round,
+            authorities_set_id: commit.authorities_set_id,
+            target_hash: commit.target_hash,
+            target_number: commit.target_number,
+            precommits: commit.precommits,
+        }
+    }
+}
+
+/// View of a node, sent to its peers.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct NeighborPacket {
+    /// Current round of the node.
+    pub round: u64,
+    /// Current authorities set of the node.
+    pub authorities_set_id: u64,
+    /// Number of the latest block finalized by the node.
+    pub commit_finalized_height: u32,
+}
+
+/// Potential error when decoding a gossip message.
+#[derive(Debug, derive_more::Display)]
+#[display(fmt = "GrandPa gossip message parsing error: {:?}", _0)]
+pub struct Error(nom::error::ErrorKind);
+
+/// Builds the SCALE encoding of a justification.
+pub(super) fn encode_justification(
+    round: u64,
+    target_hash: &[u8; 32],
+    target_number: u32,
+    precommits: &[voter::SignedVote],
+    votes_ancestries: impl ExactSizeIterator<Item = impl AsRef<[u8]>>,
+) -> Vec<u8> {
+    let mut out = round.to_le_bytes().to_vec();
+    out.extend_from_slice(target_hash);
+    out.extend_from_slice(&target_number.to_le_bytes());
+
+    encode_length(&mut out, precommits.len());
+    for precommit in precommits {
+        encode_vote(&mut out, &precommit.vote);
+        out.extend_from_slice(&precommit.signature);
+        out.extend_from_slice(&precommit.authority_public_key);
+    }
+
+    encode_length(&mut out, votes_ancestries.len());
+    for header in votes_ancestries {
+        out.extend_from_slice(header.as_ref());
+    }
+
+    out
+}
+
+/// Appends the SCALE encoding of the given length to `out`.
+fn encode_length(out: &mut Vec<u8>, len: usize) {
+    out.extend_from_slice(&parity_scale_codec::Encode::encode(
+        &parity_scale_codec::Compact(u64::try_from(len).unwrap()),
+    ));
+}
+
+/// Appends the SCALE encoding of the given vote to `out`.
+fn encode_vote(out: &mut Vec<u8>, vote: &voter::Vote) {
+    out.extend_from_slice(&vote.target_hash);
+    out.extend_from_slice(&vote.target_number.to_le_bytes());
+}
+
+/// Nom combinator that parses a gossip message.
+fn gossip_message(bytes: &[u8]) -> nom::IResult<&[u8], GossipMessage> {
+    nom::error::context(
+        "gossip message",
+        nom::branch::alt((
+            nom::combinator::map(
+                nom::sequence::preceded(nom::bytes::complete::tag(&[0][..]), vote_message),
+                GossipMessage::Vote,
+            ),
+            nom::combinator::map(
+                nom::sequence::preceded(nom::bytes::complete::tag(&[1][..]), commit_message),
+                GossipMessage::Commit,
+            ),
+            nom::combinator::map(
+                nom::sequence::preceded(nom::bytes::complete::tag(&[2][..]), neighbor_packet),
+                GossipMessage::Neighbor,
+            ),
+            nom::combinator::map(
+                nom::sequence::preceded(nom::bytes::complete::tag(&[3][..]), catch_up_request),
+                GossipMessage::CatchUpRequest,
+            ),
+            nom::combinator::map(
+                nom::sequence::preceded(nom::bytes::complete::tag(&[4][..]), catch_up),
+                GossipMessage::CatchUp,
+            ),
+        )),
+    )(bytes)
+}
+
+/// Nom combinator that parses a vote message.
+fn vote_message(bytes: &[u8]) -> nom::IResult<&[u8], voter::SignedMessage> {
+    nom::error::context(
+        "vote message",
+        nom::combinator::map(
+            nom::sequence::tuple((
+                nom::number::complete::le_u64,
+                nom::number::complete::le_u64,
+                nom::branch::alt((
+                    nom::combinator::map(
+                        nom::sequence::preceded(nom::bytes::complete::tag(&[0][..]), vote),
+                        voter::Message::Prevote,
+                    ),
+                    nom::combinator::map(
+                        nom::sequence::preceded(nom::bytes::complete::tag(&[1][..]), vote),
+                        voter::Message::Precommit,
+                    ),
+                    nom::combinator::map(
+                        nom::sequence::preceded(nom::bytes::complete::tag(&[2][..]), vote),
+                        voter::Message::PrimaryPropose,
+                    ),
+                )),
+                signature,
+                public_key,
+            )),
+            |(round, authorities_set_id, message, signature, authority_public_key)| {
+                voter::SignedMessage {
+                    round,
+                    authorities_set_id,
+                    message,
+                    signature,
+                    authority_public_key,
+                }
+            },
+        ),
+    )(bytes)
+}
+
+/// Nom combinator that parses a commit message.
+fn commit_message(bytes: &[u8]) -> nom::IResult<&[u8], CommitMessage> {
+    nom::error::context(
+        "commit message",
+        nom::combinator::map_opt(
+            nom::sequence::tuple((
+                nom::number::complete::le_u64,
+                nom::number::complete::le_u64,
+                hash,
+                nom::number::complete::le_u32,
+                list(32 + 4, vote),
+                list(64 + 32, signature_and_public_key),
+            )),
+            |(round, authorities_set_id, target_hash, target_number, votes, auth_data)| {
+                // The list of votes and the list of signatures must match.
+                if votes.len() != auth_data.len() {
+                    return None;
+                }
+
+                Some(CommitMessage {
+                    round,
+                    authorities_set_id,
+                    target_hash,
+                    target_number,
+                    precommits: votes
+                        .into_iter()
+                        .zip(auth_data)
+                        .map(
+                            |(vote, (signature, authority_public_key))| voter::SignedVote {
+                                vote,
+                                signature,
+                                authority_public_key,
+                            },
+                        )
+                        .collect(),
+                })
+            },
+        ),
+    )(bytes)
+}
+
+/// Nom combinator that parses a neighbor packet.
+fn neighbor_packet(bytes: &[u8]) -> nom::IResult<&[u8], NeighborPacket> {
+    nom::error::context(
+        "neighbor packet",
+        nom::combinator::map(
+            nom::sequence::preceded(
+                // Only version 1 of neighbor packets exists.
+                nom::bytes::complete::tag(&[1][..]),
+                nom::sequence::tuple((
+                    nom::number::complete::le_u64,
+                    nom::number::complete::le_u64,
+                    nom::number::complete::le_u32,
+                )),
+            ),
+            |(round, authorities_set_id, commit_finalized_height)| NeighborPacket {
+                round,
+                authorities_set_id,
+                commit_finalized_height,
+            },
+        ),
+    )(bytes)
+}
+
+/// Nom combinator that parses a catch-up request.
+fn catch_up_request(bytes: &[u8]) -> nom::IResult<&[u8], voter::CatchUpRequest> {
+    nom::error::context(
+        "catch-up request",
+        nom::combinator::map(
+            nom::sequence::tuple((nom::number::complete::le_u64, nom::number::complete::le_u64)),
+            |(round, authorities_set_id)| voter::CatchUpRequest {
+                authorities_set_id,
+                round,
+            },
+        ),
+    )(bytes)
+}
+
+/// Nom combinator that parses a catch-up response.
+fn catch_up(bytes: &[u8]) -> nom::IResult<&[u8], voter::CatchUp> {
+    nom::error::context(
+        "catch-up",
+        nom::combinator::map(
+            nom::sequence::tuple((
+                nom::number::complete::le_u64,
+                nom::number::complete::le_u64,
+                list(32 + 4 + 64 + 32, signed_vote),
+                list(32 + 4 + 64 + 32, signed_vote),
+                hash,
+                nom::number::complete::le_u32,
+            )),
+            |(authorities_set_id, round, prevotes, precommits, base_hash, base_number)| {
+                voter::CatchUp {
+                    authorities_set_id,
+                    round,
+                    prevotes,
+                    precommits,
+                    base_hash,
+                    base_number,
+                }
+            },
+        ),
+    )(bytes)
+}
+
+/// Nom combinator that parses a vote and its signature.
+fn signed_vote(bytes: &[u8]) -> nom::IResult<&[u8], voter::SignedVote> {
+    nom::combinator::map(
+        nom::sequence::tuple((vote, signature, public_key)),
+        |(vote, signature, authority_public_key)| voter::SignedVote {
+            vote,
+            signature,
+            authority_public_key,
+        },
+    )(bytes)
+}
+
+/// Nom combinator that parses a signature followed with the public key of its author.
+fn signature_and_public_key(bytes: &[u8]) -> nom::IResult<&[u8], ([u8; 64], [u8; 32])> {
+    nom::sequence::tuple((signature, public_key))(bytes)
+}
+
+/// Nom combinator that parses the hash and number of a block.
+fn vote(bytes: &[u8]) -> nom::IResult<&[u8], voter::Vote> {
+    nom::combinator::map(
+        nom::sequence::tuple((hash, nom::number::complete::le_u32)),
+        |(target_hash, target_number)| voter::Vote {
+            target_hash,
+            target_number,
+        },
+    )(bytes)
+}
+
+/// Nom combinator that parses a 32 bytes hash.
+fn hash(bytes: &[u8]) -> nom::IResult<&[u8], [u8; 32]> {
+    nom::combinator::map(nom::bytes::complete::take(32u32), |h: &[u8]| {
+        <[u8; 32]>::try_from(h).unwrap()
+    })(bytes)
+}
+
+/// Nom combinator that parses an Ed25519 public key.
+fn public_key(bytes: &[u8]) -> nom::IResult<&[u8], [u8; 32]> {
+    hash(bytes)
+}
+
+/// Nom combinator that parses an Ed25519 signature.
+fn signature(bytes: &[u8]) -> nom::IResult<&[u8], [u8; 64]> {
+    nom::combinator::map(nom::bytes::complete::take(64u32), |s: &[u8]| {
+        let mut signature = [0; 64];
+        signature.copy_from_slice(s);
+        signature
+    })(bytes)
+}
+
+/// Builds a nom combinator that parses a SCALE-encoded list of items, each of which is encoded
+/// in exactly `item_len` bytes.
+fn list<'a, T>(
+    item_len: usize,
+    item: impl Fn(&'a [u8]) -> nom::IResult<&'a [u8], T> + Copy,
+) -> impl Fn(&'a [u8]) -> nom::IResult<&'a [u8], Vec<T>> {
+    move |bytes| {
+        let (bytes, num_items) =
+            crate::util::nom_scale_compact_usize::<(&[u8], nom::error::ErrorKind)>(bytes)?;
+
+        // The number of items is checked against the remaining data in order to not allocate a
+        // huge list because of a malicious length prefix.
+        if num_items > bytes.len() / item_len {
+            return Err(nom::Err::Error(nom::error::make_error(
+                bytes,
+                nom::error::ErrorKind::Count,
+            )));
+        }
+
+        nom::multi::many_m_n(num_items, num_items, item)(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, CommitMessage, GossipMessage, NeighborPacket};
+    use crate::finality::grandpa::voter;
+
+    fn signed_vote(seed: u8) -> voter::SignedVote {
+        voter::SignedVote {
+            vote: voter::Vote {
+                target_hash: [seed; 32],
+                target_number: u32::from(seed) * 1000,
+            },
+            signature: [seed.wrapping_add(1); 64],
+            authority_public_key: [seed.wrapping_add(2); 32],
+        }
+    }
+
+    fn round_trip(message: GossipMessage) {
+        let encoded = message.scale_encoding();
+        assert_eq!(decode(&encoded).unwrap(), message);
+
+        // Any truncation of the message must be detected.
+        for len in 0..encoded.len() {
+            assert!(decode(&encoded[..len]).is_err());
+        }
+    }
+
+    #[test]
+    fn vote() {
+        for message in &[
+            voter::Message::Prevote(signed_vote(1).vote),
+            voter::Message::Precommit(signed_vote(2).vote),
+            voter::Message::PrimaryPropose(signed_vote(3).vote),
+        ] {
+            round_trip(GossipMessage::Vote(voter::SignedMessage {
+                round: 12,
+                authorities_set_id: 3,
+                message: *message,
+                signature: [5; 64],
+                authority_public_key: [6; 32],
+            }));
+        }
+    }
+
+    #[test]
+    fn commit() {
+        round_trip(GossipMessage::Commit(CommitMessage {
+            round: 7,
+            authorities_set_id: 1,
+            target_hash: [9; 32],
+            target_number: 900,
+            precommits: vec![signed_vote(1), signed_vote(2), signed_vote(3)],
+        }));
+    }
+
+    #[test]
+    fn commit_signatures_mismatch() {
+        let mut encoded = GossipMessage::Commit(CommitMessage {
+            round: 7,
+            authorities_set_id: 1,
+            target_hash: [9; 32],
+            target_number: 900,
+            precommits: vec![signed_vote(1), signed_vote(2)],
+        })
+        .scale_encoding();
+
+        // Remove the last signature and public key, and decrement the number of signatures.
+        encoded.truncate(encoded.len() - 96);
+        let signatures_len_offset = encoded.len() - 96 - 1;
+        assert_eq!(encoded[signatures_len_offset], 2 << 2);
+        encoded[signatures_len_offset] = 1 << 2;
+
+        assert!(decode(&encoded).is_err());
+    }
+
+    #[test]
+    fn neighbor_packet() {
+        let packet = NeighborPacket {
+            round: 5,
+            authorities_set_id: 2,
+            commit_finalized_height: 1234,
+        };
+
+        let encoded = GossipMessage::Neighbor(packet).scale_encoding();
+        assert_eq!(
+            encoded,
+            vec![2, 1, 5, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 0, 0, 0, 0, 0xd2, 0x04, 0, 0]
+        );
+
+        round_trip(GossipMessage::Neighbor(packet));
+    }
+
+    #[test]
+    fn catch_up_request() {
+        round_trip(GossipMessage::CatchUpRequest(voter::CatchUpRequest {
+            authorities_set_id: 4,
+            round: 18,
+        }));
+    }
+
+    #[test]
+    fn catch_up() {
+        round_trip(GossipMessage::CatchUp(voter::CatchUp {
+            authorities_set_id: 4,
+            round: 18,
+            prevotes: vec![signed_vote(1), signed_vote(2)],
+            precommits: vec![signed_vote(3)],
+            base_hash: [8; 32],
+            base_number: 800,
+        }));
+    }
+
+    #[test]
+    fn unknown_message_type() {
+        assert!(decode(&[5, 0, 0, 0]).is_err());
+        assert!(decode(&[]).is_err());
+    }
+
+    #[test]
+    fn huge_list_length() {
+        // Commit message announcing a huge number of precommits.
+        let mut encoded = vec![1];
+        encoded.extend_from_slice(&[0; 8 + 8 + 32 + 4]);
+        encoded.extend_from_slice(&[0xfe, 0xff, 0xff, 0xff]);
+        assert!(decode(&encoded).is_err());
+    }
+}
@@ -0,0 +1,1427 @@
+// Substrate-lite
+// Copyright (C) 2019-2020  Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! GrandPa voter.
+//!
+//! The GrandPa voter participates in the finalization of blocks. It is organized in rounds,
+//! numbered incrementally. During each round, each authority emits a **prevote** for the block
+//! it thinks is the best, then a **precommit** for the highest block that more than two thirds
+//! of the authorities have prevoted for (the prevote GHOST). A block is finalized once more than
+//! two thirds of the authorities have precommitted for it or one of its descendants.
+//!
+//! At the beginning of each round, a **primary** authority (chosen in a round-robin way) can
+//! emit a primary proposal in order to help the other authorities agree on a block.
+//!
+//! # Usage
+//!
+//! A [`GrandpaVoter`] is specific to a single authorities set. When the authorities set changes,
+//! a new voter must be created.
+//!
+//! The voter needs to be informed of the blocks of the chain, by calling
+//! [`GrandpaVoter::block_imported`] and [`GrandpaVoter::set_best_block`], and of the messages
+//! received from the network, by calling [`GrandpaVoter::inject_message`].
+//!
+//! [`GrandpaVoter::next_action`] must then be called repeatedly in order to drive the voter,
+//! until it returns `None`. It must be called again after any of the methods mentioned above
+//! has been called, or when the moment returned by [`GrandpaVoter::next_wake_up`] is reached.
+//!
+//! The voter is driven by the keys found in [`Config::keystore`]. If one of these keys is part of
+//! the authorities, the voter emits votes signed with this key. Otherwise, the voter only
+//! observes the other authorities in order to detect which blocks are finalized. Since the
+//! voter is specific to an authorities set, the same keystore can be passed to the voters of
+//! all the successive sets.
+//!
+//! The messages to broadcast and the messages received from the network are exchanged through
+//! the GrandPa gossip protocol. See the [`gossip`](super::gossip) module.
+//!
+//! > **Note**: The nodes don't run a voter yet, as they don't participate in the production of
+//! >           blocks and can't be authorities.
+//!
+//! # Catch-up
+//!
+//! When a voter is behind the other authorities, for example after having been offline, it can
+//! catch up with them by requesting the votes of a more recent round that is completable. The
+//! voter generates a [`CatchUpRequest`] when it receives a message from a round that is too far
+//! ahead. This request should be sent to the source of the message, and the response passed to
+//! [`GrandpaVoter::inject_catch_up`]. Requests received from other nodes can be answered with
+//! [`GrandpaVoter::answer_catch_up_request`].
+
+use crate::header;
+
+use alloc::{
+    collections::{BTreeMap, BTreeSet, VecDeque},
+    vec::Vec,
+};
+use core::{convert::TryFrom as _, iter, mem, time::Duration};
+use ed25519_dalek::{Signer as _, Verifier as _};
+
+mod round;
+
+/// Configuration for a [`GrandpaVoter`].
+#[derive(Debug)]
+pub struct Config {
+    /// Identifier of the authorities set the voter is part of.
+    pub authorities_set_id: u64,
+
+    /// List of authorities of the set. Must not be empty.
+    pub authorities: Vec<header::GrandpaAuthority>,
+
+    /// Keys of the local node. The voter emits votes signed with the first of these keys that is
+    /// part of [`Config::authorities`]. If none of these keys is part of the authorities, then
+    /// the voter doesn't emit any vote.
+    pub keystore: Vec<ed25519_dalek::Keypair>,
+
+    /// Hash of the latest finalized block. Only descendants of this block can be voted for.
+    pub finalized_block_hash: [u8; 32],
+
+    /// Number of the latest finalized block.
+    ///
+    /// GrandPa votes encode block numbers as 32 bits integers. Blocks whose number doesn't fit
+    /// in 32 bits are ignored.
+    pub finalized_block_number: u64,
+
+    /// Number of the round to start at. Rounds of a new authorities set start at 1.
+    pub start_round: u64,
+
+    /// Duration of the gossip period. The voter emits its prevote after two gossip periods and
+    /// its precommit after four gossip periods, unless the current round is completable
+    /// earlier.
+    pub gossip_duration: Duration,
+}
+
+/// Block targeted by a message.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Vote {
+    /// Hash of the block.
+    pub target_hash: [u8; 32],
+    /// Number of the block.
+    pub target_number: u32,
+}
+
+/// Vote with the signature of the authority that has emitted it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SignedVote {
+    /// Block targeted by the vote.
+    pub vote: Vote,
+    /// Ed25519 signature of the message containing the vote.
+    pub signature: [u8; 64],
+    /// Ed25519 public key of the authority that has emitted the vote.
+    pub authority_public_key: [u8; 32],
+}
+
+/// Message that authorities emit during a round.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Message {
+    /// Block that the authority thinks is the best.
+    Prevote(Vote),
+    /// Highest block that the authority has seen more than two thirds of prevotes for.
+    Precommit(Vote),
+    /// Block proposed by the primary authority of the round.
+    PrimaryPropose(Vote),
+}
+
+impl Message {
+    /// Returns the block targeted by the message.
+    pub fn vote(&self) -> &Vote {
+        match self {
+            Message::Prevote(v) | Message::Precommit(v) | Message::PrimaryPropose(v) => v,
+        }
+    }
+}
+
+/// Message emitted by an authority during a specific round, and its signature.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SignedMessage {
+    /// Round the message belongs to.
+    pub round: u64,
+    /// Identifier of the authorities set of the authority that has emitted the message.
+    pub authorities_set_id: u64,
+    /// The message itself.
+    pub message: Message,
+    /// Ed25519 signature of [`SignedMessage::signed_payload`].
+    pub signature: [u8; 64],
+    /// Ed25519 public key of the authority that has emitted the message.
+    pub authority_public_key: [u8; 32],
+}
+
+impl SignedMessage {
+    /// Returns the payload that the authority has signed.
+    pub fn signed_payload(&self) -> Vec<u8> {
+        signed_payload(&self.message, self.round, self.authorities_set_id)
+    }
+
+    /// Returns `true` if the signature of the message is valid.
+    pub fn verify_signature(&self) -> bool {
+        let public_key = match ed25519_dalek::PublicKey::from_bytes(&self.authority_public_key) {
+            Ok(k) => k,
+            Err(_) => return false,
+        };
+
+        let signature = match ed25519_dalek::Signature::try_from(&self.signature[..]) {
+            Ok(s) => s,
+            Err(_) => return false,
+        };
+
+        public_key
+            .verify(&self.signed_payload(), &signature)
+            .is_ok()
+    }
+}
+
+/// Builds the payload that an authority signs when emitting a message.
+fn signed_payload(message: &Message, round: u64, authorities_set_id: u64) -> Vec<u8> {
+    let discriminant = match message {
+        Message::Prevote(_) => 0u8,
+        Message::Precommit(_) => 1u8,
+        Message::PrimaryPropose(_) => 2u8,
+    };
+
+    let mut out = Vec::with_capacity(1 + 32 + 4 + 8 + 8);
+    out.push(discriminant);
+    out.extend_from_slice(&message.vote().target_hash);
+    out.extend_from_slice(&message.vote().target_number.to_le_bytes());
+    out.extend_from_slice(&round.to_le_bytes());
+    out.extend_from_slice(&authorities_set_id.to_le_bytes());
+    out
+}
+
+/// Proof that a block has been finalized.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Commit {
+    /// Round that has finalized the block.
+    pub round: u64,
+    /// Identifier of the authorities set that has finalized the block.
+    pub authorities_set_id: u64,
+    /// Hash of the finalized block.
+    pub target_hash: [u8; 32],
+    /// Number of the finalized block.
+    pub target_number: u32,
+    /// Precommits targeting the finalized block or one of its descendants.
+    pub precommits: Vec<SignedVote>,
+    /// SCALE-encoded headers of the blocks between the finalized block (excluded) and the
+    /// targets of the precommits (included).
+    pub votes_ancestries: Vec<Vec<u8>>,
+}
+
+impl Commit {
+    /// Returns the SCALE encoding of the justification corresponding to this commit.
+    ///
+    /// The justification can be verified with
+    /// [`verify`](crate::finality::justification::verify::verify).
+    pub fn scale_encoded_justification(&self) -> Vec<u8> {
+        super::gossip::encode_justification(
+            self.round,
+            &self.target_hash,
+            self.target_number,
+            &self.precommits,
+            self.votes_ancestries.iter(),
+        )
+    }
+}
+
+/// Request for the votes of a round more recent than the round of the requester.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct CatchUpRequest {
+    /// Identifier of the authorities set of the requester.
+    pub authorities_set_id: u64,
+    /// Current round of the requester.
+    pub round: u64,
+}
+
+/// Response to a [`CatchUpRequest`]. Contains the votes of a round that is completable.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CatchUp {
+    /// Identifier of the authorities set the votes belong to.
+    pub authorities_set_id: u64,
+    /// Round the votes belong to.
+    pub round: u64,
+    /// Prevotes of the round.
+    pub prevotes: Vec<SignedVote>,
+    /// Precommits of the round.
+    pub precommits: Vec<SignedVote>,
+    /// Hash of the latest block finalized by the node that has generated the response.
+    pub base_hash: [u8; 32],
+    /// Number of the latest block finalized by the node that has generated the response.
+    pub base_number: u32,
+}
+
+/// Action that the user of a [`GrandpaVoter`] must perform.
+#[derive(Debug, Clone)]
+pub enum Action {
+    /// The given message must be sent to the other authorities.
+    Broadcast(SignedMessage),
+    /// A block has been finalized. The commit can be sent to the other nodes of the network,
+    /// and can be turned into a justification.
+    Finalized(Commit),
+    /// Other authorities are at a more advanced round. The request should be sent to the node
+    /// that has sent the message of this round, and the response passed to
+    /// [`GrandpaVoter::inject_catch_up`].
+    RequestCatchUp(CatchUpRequest),
+    /// An authority has emitted two different votes of the same kind during the same round.
+    /// Both votes are taken into account, as the GrandPa algorithm requires, and the
+    /// equivocation can be reported as a misbehaviour of the authority.
+    Equivocation(Equivocation),
+}
+
+/// Two different prevotes or two different precommits emitted by the same authority during the
+/// same round.
+///
+/// The signatures of both messages have been verified.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Equivocation {
+    /// First message of the authority.
+    pub first: SignedMessage,
+    /// Message of the authority that conflicts with [`Equivocation::first`].
+    pub second: SignedMessage,
+}
+
+/// Error that can happen when injecting a message.
+#[derive(Debug, derive_more::Display)]
+pub enum InjectMessageError {
+    /// Message concerns a different authorities set.
+    BadAuthoritiesSetId,
+    /// Public key of the message isn't in the list of authorities.
+    UnknownAuthority,
+    /// Signature of the message is invalid.
+    BadSignature,
+    /// Primary proposal emitted by an authority that isn't the primary of the round.
+    NotPrimary,
+}
+
+/// Error that can happen when injecting a catch-up response.
+#[derive(Debug, derive_more::Display)]
+pub enum CatchUpError {
+    /// Response concerns a different authorities set.
+    BadAuthoritiesSetId,
+    /// Round of the response is older than the current round.
+    Outdated,
+    /// One of the votes is invalid.
+    #[display(fmt = "Invalid vote: {}", _0)]
+    BadVote(InjectMessageError),
+    /// The votes don't make the round completable.
+    NotCompletable,
+}
+
+/// GrandPa voter state machine. See [the module-level documentation](..).
+#[derive(Debug)]
+pub struct GrandpaVoter {
+    /// See [`Config::authorities_set_id`].
+    authorities_set_id: u64,
+
+    /// Public keys of the authorities, in the same order as [`Config::authorities`].
+    authorities: Vec<[u8; 32]>,
+
+    /// Weights of the authorities, in the same order as [`Config::authorities`].
+    weights: Vec<u64>,
+
+    /// Index of the local authority within [`GrandpaVoter::authorities`], and its key. `None`
+    /// if the local node isn't an authority.
+    local_key: Option<(usize, ed25519_dalek::Keypair)>,
+
+    /// See [`Config::gossip_duration`].
+    gossip_duration: Duration,
+
+    /// Blocks that votes can target.
+    blocks: KnownBlocks,
+
+    /// Hash of the current best block. Always in [`GrandpaVoter::blocks`].
+    best_block_hash: [u8; 32],
+
+    /// Latest completed round, if any. Precommits belonging to this round are still accepted, as
+    /// they might finalize more blocks.
+    previous_round: Option<round::Round>,
+
+    /// Round in progress.
+    current_round: round::Round,
+
+    /// Moment when the current round has started. `None` if the round has started during a
+    /// method that doesn't know the current time, in which case the start time is set during
+    /// the next call to [`GrandpaVoter::next_action`].
+    current_round_start: Option<Duration>,
+
+    /// Messages emitted by the local authority during the current round.
+    local_votes: LocalVotes,
+
+    /// Messages belonging to the round following the current one.
+    future_messages: Vec<SignedMessage>,
+
+    /// Current round at the time when the last catch-up request has been emitted. Used in order
+    /// to avoid emitting multiple requests during the same round.
+    catch_up_requested: Option<u64>,
+
+    /// Actions to return from [`GrandpaVoter::next_action`].
+    pending_actions: VecDeque<Action>,
+}
+
+/// Messages emitted by the local authority during the current round.
+#[derive(Debug, Default)]
+struct LocalVotes {
+    /// `true` if the local node has already determined whether it should emit a primary
+    /// proposal.
+    proposed: bool,
+    /// `true` if the local node has emitted a prevote.
+    prevoted: bool,
+    /// `true` if the local node has emitted a precommit.
+    precommitted: bool,
+}
+
+impl GrandpaVoter {
+    /// Initializes a new voter.
+    ///
+    /// # Panic
+    ///
+    /// Panics if [`Config::authorities`] is empty.
+    ///
+    pub fn new(config: Config) -> Self {
+        assert!(!config.authorities.is_empty());
+
+        let authorities = config
+            .authorities
+            .iter()
+            .map(|a| a.public_key)
+            .collect::<Vec<_>>();
+        let weights = config.authorities.iter().map(|a| a.weight).collect();
+
+        let local_key = config.keystore.into_iter().find_map(|key| {
+            let index = authorities
+                .iter()
+                .position(|a| a == key.public.as_bytes())?;
+            Some((index, key))
+        });
+
+        GrandpaVoter {
+            authorities_set_id: config.authorities_set_id,
+            authorities,
+            weights,
+            local_key,
+            gossip_duration: config.gossip_duration,
+            blocks: KnownBlocks::new(config.finalized_block_hash, config.finalized_block_number),
+            best_block_hash: config.finalized_block_hash,
+            previous_round: None,
+            current_round: round::Round::new(config.start_round),
+            current_round_start: None,
+            local_votes: LocalVotes::default(),
+            future_messages: Vec::new(),
+            catch_up_requested: None,
+            pending_actions: VecDeque::new(),
+        }
+    }
+
+    /// Returns the identifier of the authorities set the voter belongs to.
+    pub fn authorities_set_id(&self) -> u64 {
+        self.authorities_set_id
+    }
+
+    /// Returns the number of the round in progress.
+    pub fn current_round(&self) -> u64 {
+        self.current_round.number
+    }
+
+    /// Returns the hash and number of the latest finalized block.
+    pub fn finalized_block(&self) -> ([u8; 32], u64) {
+        self.blocks.finalized()
+    }
+
+    /// Informs the voter of a new block.
+    ///
+    /// Blocks must be reported in order, parents before children. Blocks that don't descend
+    /// from the latest finalized block are ignored.
+    pub fn block_imported(&mut self, scale_encoded_header: &[u8]) -> Result<(), header::Error> {
+        self.blocks.insert(scale_encoded_header)
+    }
+
+    /// Sets the block that the local authority thinks is the best. Ignored if the block wasn't
+    /// passed to [`GrandpaVoter::block_imported`] or doesn't descend from the latest finalized
+    /// block.
+    pub fn set_best_block(&mut self, hash: &[u8; 32]) {
+        if self.blocks.is_descendant(&self.blocks.finalized().0, hash) {
+            self.best_block_hash = *hash;
+        }
+    }
+
+    /// Sets the latest finalized block, for example after a justification has been received
+    /// from the network.
+    ///
+    /// Ignored if the block is unknown, or if it doesn't descend from the latest finalized
+    /// block.
+    pub fn set_finalized_block(&mut self, hash: &[u8; 32]) {
+        self.blocks.set_finalized(hash);
+        if !self
+            .blocks
+            .is_descendant(&self.blocks.finalized().0, &self.best_block_hash)
+        {
+            self.best_block_hash = self.blocks.finalized().0;
+        }
+    }
+
+    /// Injects a message received from the network.
+    ///
+    /// Messages that belong to a round that is too old are silently ignored.
+    pub fn inject_message(&mut self, message: SignedMessage) -> Result<(), InjectMessageError> {
+        let authority_index = self.verify_message(&message)?;
+
+        let current_round = self.current_round.number;
+        if message.round == current_round {
+            let equivocation = insert_message(&mut self.current_round, authority_index, &message);
+            self.pending_actions
+                .extend(equivocation.map(Action::Equivocation));
+        } else if message.round == current_round + 1 {
+            // Messages of the next round are buffered in order to not lose them if the round
+            // changes soon. The size of the buffer is bounded to three messages per authority.
+            if self.future_messages.len() < self.authorities.len() * 3 {
+                self.future_messages.push(message);
+            }
+        } else if message.round > current_round + 1 {
+            self.note_peer_round(message.authorities_set_id, message.round);
+        } else if let Some(previous_round) = self
+            .previous_round
+            .as_mut()
+            .filter(|r| r.number == message.round)
+        {
+            let equivocation = insert_message(previous_round, authority_index, &message);
+            self.pending_actions
+                .extend(equivocation.map(Action::Equivocation));
+        }
+
+        Ok(())
+    }
+
+    /// Informs the voter that another node is at the given round, for example after having
+    /// received a neighbor packet.
+    ///
+    /// Generates a [`CatchUpRequest`] if the other node is too far ahead.
+    pub fn note_peer_round(&mut self, authorities_set_id: u64, round: u64) {
+        if authorities_set_id != self.authorities_set_id {
+            return;
+        }
+
+        let current_round = self.current_round.number;
+        if round <= current_round + 1 || self.catch_up_requested == Some(current_round) {
+            return;
+        }
+
+        self.catch_up_requested = Some(current_round);
+        self.pending_actions
+            .push_back(Action::RequestCatchUp(CatchUpRequest {
+                authorities_set_id: self.authorities_set_id,
+                round: current_round,
+            }));
+    }
+
+    /// Builds the response to a catch-up request received from the network. Returns `None` if
+    /// the local node isn't ahead of the requester.
+    pub fn answer_catch_up_request(&self, request: &CatchUpRequest) -> Option<CatchUp> {
+        if request.authorities_set_id != self.authorities_set_id {
+            return None;
+        }
+
+        let previous_round = self.previous_round.as_ref()?;
+        if previous_round.number < request.round {
+            return None;
+        }
+
+        let (base_hash, base_number) = self.blocks.finalized();
+        Some(CatchUp {
+            authorities_set_id: self.authorities_set_id,
+            round: previous_round.number,
+            prevotes: previous_round
+                .prevotes
+                .iter()
+                .map(|(_, v)| v.clone())
+                .collect(),
+            precommits: previous_round
+                .precommits
+                .iter()
+                .map(|(_, v)| v.clone())
+                .collect(),
+            base_hash,
+            base_number: u32::try_from(base_number).unwrap(),
+        })
+    }
+
+    /// Injects a response to a [`CatchUpRequest`].
+    ///
+    /// On success, the round of the response is considered as completed, and the voter moves to
+    /// the round following it.
+    pub fn inject_catch_up(&mut self, catch_up: CatchUp) -> Result<(), CatchUpError> {
+        if catch_up.authorities_set_id != self.authorities_set_id {
+            return Err(CatchUpError::BadAuthoritiesSetId);
+        }
+
+        if catch_up.round < self.current_round.number {
+            return Err(CatchUpError::Outdated);
+        }
+
+        let mut round = round::Round::new(catch_up.round);
+
+        let messages = catch_up
+            .prevotes
+            .iter()
+            .map(|v| (Message::Prevote(v.vote), v))
+            .chain(
+                catch_up
+                    .precommits
+                    .iter()
+                    .map(|v| (Message::Precommit(v.vote), v)),
+            );
+        for (message, vote) in messages {
+            let message = SignedMessage {
+                round: catch_up.round,
+                authorities_set_id: catch_up.authorities_set_id,
+                message,
+                signature: vote.signature,
+                authority_public_key: vote.authority_public_key,
+            };
+
+            let authority_index = self
+                .verify_message(&message)
+                .map_err(CatchUpError::BadVote)?;
+            let equivocation = insert_message(&mut round, authority_index, &message);
+            self.pending_actions
+                .extend(equivocation.map(Action::Equivocation));
+        }
+
+        if !round.is_completable(&self.context()) {
+            return Err(CatchUpError::NotCompletable);
+        }
+
+        self.current_round = round;
+        self.start_next_round(None);
+        Ok(())
+    }
+
+    /// Returns the moment when [`GrandpaVoter::next_action`] should be called again, in
+    /// addition to after the state of the voter has been modified. Returns `None` if there is
+    /// no need to wake up.
+    pub fn next_wake_up(&self) -> Option<Duration> {
+        let round_start = match self.current_round_start {
+            Some(s) => s,
+            None => return Some(Duration::new(0, 0)),
+        };
+
+        if self.local_key.is_none() {
+            return None;
+        }
+
+        if !self.local_votes.prevoted {
+            Some(round_start + self.gossip_duration * 2)
+        } else if !self.local_votes.precommitted {
+            Some(round_start + self.gossip_duration * 4)
+        } else {
+            None
+        }
+    }
+
+    /// Advances the state machine and returns the next action to perform, if any.
+    pub fn next_action(&mut self, now: Duration) -> Option<Action> {
+        loop {
+            if let Some(action) = self.pending_actions.pop_front() {
+                return Some(action);
+            }
+
+            self.check_finality();
+            if let Some(action) = self.pending_actions.pop_front() {
+                return Some(action);
+            }
+
+            if !self.step(now) {
+                return None;
+            }
+        }
+    }
+
+    /// Performs one step of the current round. Returns `false` if nothing has happened.
+    fn step(&mut self, now: Duration) -> bool {
+        let round_start = *self.current_round_start.get_or_insert(now);
+
+        let finalized = self.blocks.finalized();
+        let (previous_estimate, completable, prevote_ghost) = {
+            let context = self.context();
+            let previous_estimate = self
+                .previous_round
+                .as_ref()
+                .and_then(|r| r.estimate(&context).0)
+                .unwrap_or(finalized);
+            (
+                previous_estimate,
+                self.current_round.is_completable(&context),
+                self.current_round.prevote_ghost(&context),
+            )
+        };
+
+        if let Some(local_index) = self.local_key.as_ref().map(|(index, _)| *index) {
+            // The primary authority of the round proposes the estimate of the previous round,
+            // if it isn't finalized yet.
+            if !self.local_votes.proposed {
+                self.local_votes.proposed = true;
+                if local_index == self.primary_index(self.current_round.number)
+                    && previous_estimate.1 > finalized.1
+                {
+                    self.vote(Message::PrimaryPropose(vote(previous_estimate)));
+                }
+                return true;
+            }
+
+            if !self.local_votes.prevoted
+                && (now >= round_start + self.gossip_duration * 2 || completable)
+            {
+                self.local_votes.prevoted = true;
+                let target = self.prevote_target();
+                self.vote(Message::Prevote(vote(target)));
+                return true;
+            }
+
+            if self.local_votes.prevoted
+                && !self.local_votes.precommitted
+                && (now >= round_start + self.gossip_duration * 4 || completable)
+            {
+                // Precommitting is only possible if the prevote GHOST is superior or equal to
+                // the estimate of the previous round.
+                if let Some(prevote_ghost) =
+                    prevote_ghost.filter(|g| self.blocks.is_descendant(&previous_estimate.0, &g.0))
+                {
+                    self.local_votes.precommitted = true;
+                    self.vote(Message::Precommit(vote(prevote_ghost)));
+                    return true;
+                }
+            }
+        }
+
+        // The local authority moves to the next round once it has precommitted. If it couldn't
+        // precommit in time, it doesn't hold back and follows the other authorities.
+        if completable
+            && (self.local_key.is_none()
+                || self.local_votes.precommitted
+                || now >= round_start + self.gossip_duration * 4)
+        {
+            self.start_next_round(Some(now));
+            return true;
+        }
+
+        false
+    }
+
+    /// Determines the block the local authority should prevote for.
+    fn prevote_target(&self) -> ([u8; 32], u64) {
+        let context = self.context();
+        let finalized = self.blocks.finalized();
+
+        let (previous_estimate, previous_prevote_ghost) = match &self.previous_round {
+            Some(r) => (
+                r.estimate(&context).0.unwrap_or(finalized),
+                r.prevote_ghost(&context).unwrap_or(finalized),
+            ),
+            None => (finalized, finalized),
+        };
+
+        // The primary proposal is used as base if it is strictly superior to the estimate of the
+        // previous round and inferior or equal to its prevote GHOST.
+        let base = match self.current_round.primary_proposal {
+            Some(proposal)
+                if proposal != previous_estimate
+                    && self.blocks.is_descendant(&previous_estimate.0, &proposal.0)
+                    && self
+                        .blocks
+                        .is_descendant(&proposal.0, &previous_prevote_ghost.0) =>
+            {
+                proposal
+            }
+            _ => previous_estimate,
+        };
+
+        // The estimate of the previous round might be older than the finalized block if the
+        // latter has been updated through `set_finalized_block`.
+        let base = if self.blocks.is_descendant(&finalized.0, &base.0) {
+            base
+        } else {
+            finalized
+        };
+
+        if self.blocks.is_descendant(&base.0, &self.best_block_hash) {
+            let number = self.blocks.number(&self.best_block_hash).unwrap();
+            (self.best_block_hash, number)
+        } else {
+            base
+        }
+    }
+
+    /// Signs the given message with the local key, adds it to the current round, and queues
+    /// it for broadcasting.
+    ///
+    /// # Panic
+    ///
+    /// Panics if the local node isn't an authority.
+    ///
+    fn vote(&mut self, message: Message) {
+        let (local_index, local_key) = self.local_key.as_ref().unwrap();
+        let local_index = *local_index;
+
+        let signature = local_key.sign(&signed_payload(
+            &message,
+            self.current_round.number,
+            self.authorities_set_id,
+        ));
+
+        let message = SignedMessage {
+            round: self.current_round.number,
+            authorities_set_id: self.authorities_set_id,
+            message,
+            signature: signature.to_bytes(),
+            authority_public_key: *local_key.public.as_bytes(),
+        };
+
+        // The local authority never emits two different votes of the same kind.
+        let _equivocation = insert_message(&mut self.current_round, local_index, &message);
+        debug_assert!(_equivocation.is_none());
+        self.pending_actions.push_back(Action::Broadcast(message));
+    }
+
+    /// Moves the current round to the previous round, and starts the round following it.
+    fn start_next_round(&mut self, now: Option<Duration>) {
+        let next_round = round::Round::new(self.current_round.number + 1);
+        self.previous_round = Some(mem::replace(&mut self.current_round, next_round));
+        self.current_round_start = now;
+        self.local_votes = LocalVotes::default();
+        self.catch_up_requested = None;
+
+        for message in mem::take(&mut self.future_messages) {
+            if message.round != self.current_round.number {
+                continue;
+            }
+
+            // The message has already been verified when it was injected.
+            let authority_index = self
+                .authorities
+                .iter()
+                .position(|a| *a == message.authority_public_key)
+                .unwrap();
+            let equivocation = insert_message(&mut self.current_round, authority_index, &message);
+            self.pending_actions
+                .extend(equivocation.map(Action::Equivocation));
+        }
+    }
+
+    /// Checks whether the previous or current round finalizes a new block. If so, queues an
+    /// [`Action::Finalized`].
+    fn check_finality(&mut self) {
+        let context = self.context();
+        let finalized = self.blocks.finalized();
+
+        let newly_finalized = self
+            .previous_round
+            .iter()
+            .chain(iter::once(&self.current_round))
+            .filter_map(|round| Some((round, round.finalized(&context)?)))
+            .filter(|(_, (hash, number))| {
+                *number > finalized.1 && self.blocks.is_descendant(&finalized.0, hash)
+            })
+            .max_by_key(|(_, (_, number))| *number);
+
+        let (round, (target_hash, target_number)) = match newly_finalized {
+            Some(f) => f,
+            None => return,
+        };
+
+        let round_number = round.number;
+        let precommits = round.precommits_for(&context, &target_hash);
+
+        let mut votes_ancestries = Vec::new();
+        let mut in_votes_ancestries = BTreeSet::new();
+        for precommit in &precommits {
+            let ancestry = self.blocks.ancestry(&precommit.vote.target_hash).unwrap();
+            for (hash, _) in ancestry.into_iter().take_while(|(h, _)| *h != target_hash) {
+                if in_votes_ancestries.insert(hash) {
+                    votes_ancestries.push(self.blocks.blocks[&hash].scale_encoded_header.clone());
+                }
+            }
+        }
+
+        let commit = Commit {
+            round: round_number,
+            authorities_set_id: self.authorities_set_id,
+            target_hash,
+            target_number: u32::try_from(target_number).unwrap(),
+            precommits,
+            votes_ancestries,
+        };
+
+        self.set_finalized_block(&target_hash);
+        self.pending_actions.push_back(Action::Finalized(commit));
+    }
+
+    /// Checks the authorities set, author, and signature of the given message. On success,
+    /// returns the index of the authority that has emitted it.
+    fn verify_message(&self, message: &SignedMessage) -> Result<usize, InjectMessageError> {
+        if message.authorities_set_id != self.authorities_set_id {
+            return Err(InjectMessageError::BadAuthoritiesSetId);
+        }
+
+        let authority_index = self
+            .authorities
+            .iter()
+            .position(|a| *a == message.authority_public_key)
+            .ok_or(InjectMessageError::UnknownAuthority)?;
+
+        if matches!(message.message, Message::PrimaryPropose(_))
+            && authority_index != self.primary_index(message.round)
+        {
+            return Err(InjectMessageError::NotPrimary);
+        }
+
+        if !message.verify_signature() {
+            return Err(InjectMessageError::BadSignature);
+        }
+
+        Ok(authority_index)
+    }
+
+    /// Returns the index of the primary authority of the given round.
+    fn primary_index(&self, round: u64) -> usize {
+        usize::try_from(round % u64::try_from(self.authorities.len()).unwrap()).unwrap()
+    }
+
+    /// Returns the context necessary for the computations of the rounds.
+    fn context(&self) -> round::Context<'_> {
+        round::Context {
+            blocks: &self.blocks,
+            weights: &self.weights,
+        }
+    }
+}
+
+/// Adds a verified message to the given round.
+///
+/// Returns the equivocation that the message constitutes, if any.
+fn insert_message(
+    round: &mut round::Round,
+    authority_index: usize,
+    message: &SignedMessage,
+) -> Option<Equivocation> {
+    let signed_vote = SignedVote {
+        vote: *message.message.vote(),
+        signature: message.signature,
+        authority_public_key: message.authority_public_key,
+    };
+
+    let outcome = match message.message {
+        Message::Prevote(_) => round.prevotes.insert(authority_index, signed_vote),
+        Message::Precommit(_) => round.precommits.insert(authority_index, signed_vote),
+        Message::PrimaryPropose(vote) => {
+            // Only the first proposal is taken into account. The author is assumed to be the
+            // primary of the round.
+            if round.primary_proposal.is_none() {
+                round.primary_proposal = Some((vote.target_hash, u64::from(vote.target_number)));
+            }
+            return None;
+        }
+    };
+
+    match outcome {
+        round::InsertOutcome::New | round::InsertOutcome::Duplicate => None,
+        round::InsertOutcome::Equivocation(first) => Some(Equivocation {
+            first: SignedMessage {
+                message: match message.message {
+                    Message::Prevote(_) => Message::Prevote(first.vote),
+                    _ => Message::Precommit(first.vote),
+                },
+                signature: first.signature,
+                ..message.clone()
+            },
+            second: message.clone(),
+        }),
+    }
+}
+
+/// Builds a [`Vote`] from a block hash and number.
+fn vote((target_hash, target_number): ([u8; 32], u64)) -> Vote {
+    Vote {
+        target_hash,
+        // All the blocks in `KnownBlocks` have a number that fits in 32 bits.
+        target_number: u32::try_from(target_number).unwrap(),
+    }
+}
+
+/// Blocks that votes can target.
+///
+/// Contains the finalized block at the time when the voter was created (the root), and the
+/// blocks that descend from it and that are either ancestors or descendants of the latest
+/// finalized block.
+#[derive(Debug)]
+struct KnownBlocks {
+    /// Hash of the root block.
+    root_hash: [u8; 32],
+    /// Number of the root block.
+    root_number: u64,
+    /// Hash of the latest finalized block.
+    finalized_hash: [u8; 32],
+    /// Number of the latest finalized block.
+    finalized_number: u64,
+    /// Blocks other than the root, indexed by hash.
+    blocks: BTreeMap<[u8; 32], KnownBlock>,
+}
+
+/// Block in a [`KnownBlocks`].
+#[derive(Debug)]
+struct KnownBlock {
+    /// Number of the block.
+    number: u64,
+    /// Hash of the parent of the block.
+    parent_hash: [u8; 32],
+    /// SCALE-encoded header of the block.
+    scale_encoded_header: Vec<u8>,
+}
+
+impl KnownBlocks {
+    /// Builds a new [`KnownBlocks`] containing only the root.
+    fn new(root_hash: [u8; 32], root_number: u64) -> Self {
+        KnownBlocks {
+            root_hash,
+            root_number,
+            finalized_hash: root_hash,
+            finalized_number: root_number,
+            blocks: BTreeMap::new(),
+        }
+    }
+
+    /// Returns the hash and number of the latest finalized block.
+    fn finalized(&self) -> ([u8; 32], u64) {
+        (self.finalized_hash, self.finalized_number)
+    }
+
+    /// Returns the number of the given block, or `None` if it is unknown.
+    fn number(&self, hash: &[u8; 32]) -> Option<u64> {
+        if *hash == self.root_hash {
+            Some(self.root_number)
+        } else {
+            self.blocks.get(hash).map(|b| b.number)
+        }
+    }
+
+    /// Returns the hashes and numbers of the given block (included) and of its ancestors, down
+    /// to the root (included). Returns `None` if the block is unknown.
+    fn ancestry(&self, hash: &[u8; 32]) -> Option<Vec<([u8; 32], u64)>> {
+        let mut out = Vec::new();
+        let mut current = *hash;
+        loop {
+            if current == self.root_hash {
+                out.push((current, self.root_number));
+                return Some(out);
+            }
+
+            let block = self.blocks.get(&current)?;
+            out.push((current, block.number));
+            current = block.parent_hash;
+        }
+    }
+
+    /// Returns `true` if `descendant` is equal to `ancestor` or is one of its descendants.
+    /// Returns `false` if either block is unknown.
+    fn is_descendant(&self, ancestor: &[u8; 32], descendant: &[u8; 32]) -> bool {
+        self.ancestry(descendant).map_or(false, |ancestry| {
+            ancestry.iter().any(|(h, _)| h == ancestor)
+        })
+    }
+
+    /// Inserts a new block. Ignored if the block doesn't descend from the latest finalized
+    /// block, or if its number doesn't fit in 32 bits.
+    fn insert(&mut self, scale_encoded_header: &[u8]) -> Result<(), header::Error> {
+        let decoded = header::decode(scale_encoded_header)?;
+
+        if u32::try_from(decoded.number).is_err() {
+            return Ok(());
+        }
+
+        if !self.is_descendant(&self.finalized_hash, decoded.parent_hash)
+            || self.number(decoded.parent_hash) != decoded.number.checked_sub(1)
+        {
+            return Ok(());
+        }
+
+        self.blocks
+            .entry(decoded.hash())
+            .or_insert_with(|| KnownBlock {
+                number: decoded.number,
+                parent_hash: *decoded.parent_hash,
+                scale_encoded_header: scale_encoded_header.to_vec(),
+            });
+        Ok(())
+    }
+
+    /// Sets the latest finalized block, and removes the blocks that are neither ancestors nor
+    /// descendants of it. Ignored if the block is unknown or doesn't descend from the current
+    /// latest finalized block.
+    fn set_finalized(&mut self, hash: &[u8; 32]) {
+        if !self.is_descendant(&self.finalized_hash, hash) {
+            return;
+        }
+
+        self.finalized_number = self.number(hash).unwrap();
+        self.finalized_hash = *hash;
+
+        let ancestors = self
+            .ancestry(hash)
+            .unwrap()
+            .into_iter()
+            .map(|(h, _)| h)
+            .collect::<BTreeSet<_>>();
+        let to_remove = self
+            .blocks
+            .keys()
+            .filter(|h| !ancestors.contains(*h) && !self.is_descendant(hash, h))
+            .cloned()
+            .collect::<Vec<_>>();
+        for hash in to_remove {
+            self.blocks.remove(&hash);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Action, Commit, Config, GrandpaVoter, Message, SignedMessage, Vote};
+    use crate::{
+        finality::justification,
+        header,
+        test_utils::{self, ed25519_keypair},
+    };
+
+    use core::{convert::TryFrom as _, time::Duration};
+    use ed25519_dalek::Signer as _;
+
+    const SET_ID: u64 = 5;
+    const GOSSIP_DURATION: Duration = Duration::from_secs(1);
+
+    fn authorities_list(num: u8) -> Vec<header::GrandpaAuthority> {
+        (0..num)
+            .map(|n| header::GrandpaAuthority {
+                public_key: ed25519_keypair(n).public.to_bytes(),
+                weight: 1,
+            })
+            .collect()
+    }
+
+    /// Chain of headers, each being the child of the previous one. The header at index 0 has
+    /// number 0.
+    fn chain(len: u32) -> Vec<Vec<u8>> {
+        let mut headers: Vec<Vec<u8>> = Vec::new();
+        for number in 0..len {
+            let parent_hash = headers
+                .last()
+                .map_or([0; 32], header::hash_from_scale_encoded_header);
+            headers.push(test_utils::encode_header_with_state_root(
+                &parent_hash,
+                u64::from(number),
+                &[1; 32],
+                &[],
+            ));
+        }
+        headers
+    }
+
+    /// Builds a voter for each of the given authorities, and informs it of the chain.
+    /// `None` builds a voter that doesn't vote.
+    fn voters(
+        num_authorities: u8,
+        local_authorities: &[Option<u8>],
+        chain: &[Vec<u8>],
+    ) -> Vec<GrandpaVoter> {
+        local_authorities
+            .iter()
+            .map(|seed| {
+                let mut voter = GrandpaVoter::new(Config {
+                    authorities_set_id: SET_ID,
+                    authorities: authorities_list(num_authorities),
+                    keystore: seed.map(ed25519_keypair).into_iter().collect(),
+                    finalized_block_hash: header::hash_from_scale_encoded_header(&chain[0]),
+                    finalized_block_number: 0,
+                    start_round: 1,
+                    gossip_duration: GOSSIP_DURATION,
+                });
+                for header in &chain[1..] {
+                    voter.block_imported(header).unwrap();
+                }
+                voter.set_best_block(&header::hash_from_scale_encoded_header(
+                    chain.last().unwrap(),
+                ));
+                voter
+            })
+            .collect()
+    }
+
+    /// Drives all the voters between `from` and `to`, delivering each broadcast message to all
+    /// the other voters and answering the catch-up requests. Returns the commits generated by
+    /// each voter.
+    fn run(voters: &mut [GrandpaVoter], from: Duration, to: Duration) -> Vec<Vec<Commit>> {
+        let mut commits = vec![Vec::new(); voters.len()];
+
+        let mut now = from;
+        while now < to {
+            let mut messages = Vec::new();
+            let mut catch_up_requests = Vec::new();
+
+            for (index, voter) in voters.iter_mut().enumerate() {
+                while let Some(action) = voter.next_action(now) {
+                    match action {
+                        Action::Broadcast(message) => messages.push((index, message)),
+                        Action::Finalized(commit) => commits[index].push(commit),
+                        Action::RequestCatchUp(request) => catch_up_requests.push((index, request)),
+                        Action::Equivocation(_) => panic!(),
+                    }
+                }
+            }
+
+            for (source, message) in messages {
+                for (index, voter) in voters.iter_mut().enumerate() {
+                    if index != source {
+                        voter.inject_message(message.clone()).unwrap();
+                    }
+                }
+            }
+
+            for (source, request) in catch_up_requests {
+                let response = voters
+                    .iter()
+                    .find_map(|voter| voter.answer_catch_up_request(&request));
+                if let Some(response) = response {
+                    voters[source].inject_catch_up(response).unwrap();
+                }
+            }
+
+            now += Duration::from_millis(100);
+        }
+
+        commits
+    }
+
+    /// Builds a message of round 1 signed by the authority with the given seed.
+    fn signed_message(seed: u8, message: Message) -> SignedMessage {
+        let signature = ed25519_keypair(seed).sign(&super::signed_payload(&message, 1, SET_ID));
+        SignedMessage {
+            round: 1,
+            authorities_set_id: SET_ID,
+            message,
+            signature: signature.to_bytes(),
+            authority_public_key: ed25519_keypair(seed).public.to_bytes(),
+        }
+    }
+
+    fn verify_commit(commit: &Commit, num_authorities: u8) {
+        let justification = commit.scale_encoded_justification();
+        let authorities = authorities_list(num_authorities);
+        justification::verify::verify(justification::verify::Config {
+            justification: justification::decode::decode(&justification).unwrap(),
+            authorities_set_id: SET_ID,
+            authorities_list: authorities.iter().map(header::GrandpaAuthorityRef::from),
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn all_authorities_online() {
+        let chain = chain(10);
+        let best_hash = header::hash_from_scale_encoded_header(chain.last().unwrap());
+
+        let mut voters = voters(4, &[Some(0), Some(1), Some(2), Some(3), None], &chain);
+        let commits = run(&mut voters, Duration::new(0, 0), Duration::from_secs(10));
+
+        for (voter, commits) in voters.iter().zip(commits) {
+            assert_eq!(voter.finalized_block(), (best_hash, 9));
+            let commit = commits.last().unwrap();
+            assert_eq!(commit.target_hash, best_hash);
+            verify_commit(commit, 4);
+        }
+    }
+
+    #[test]
+    fn one_authority_offline() {
+        let chain = chain(5);
+        let best_hash = header::hash_from_scale_encoded_header(chain.last().unwrap());
+
+        let mut voters = voters(4, &[Some(0), Some(1), Some(3)], &chain);
+        let commits = run(&mut voters, Duration::new(0, 0), Duration::from_secs(10));
+
+        for (voter, commits) in voters.iter().zip(commits) {
+            assert_eq!(voter.finalized_block(), (best_hash, 4));
+            verify_commit(commits.last().unwrap(), 4);
+        }
+    }
+
+    #[test]
+    fn not_enough_authorities_online() {
+        let chain = chain(5);
+        let genesis_hash = header::hash_from_scale_encoded_header(&chain[0]);
+
+        let mut voters = voters(4, &[Some(0), Some(1)], &chain);
+        let commits = run(&mut voters, Duration::new(0, 0), Duration::from_secs(10));
+
+        for (voter, commits) in voters.iter().zip(commits) {
+            assert_eq!(voter.finalized_block(), (genesis_hash, 0));
+            assert!(commits.is_empty());
+            assert_eq!(voter.current_round(), 1);
+        }
+    }
+
+    #[test]
+    fn new_blocks_are_finalized_in_later_rounds() {
+        let chain = chain(8);
+
+        let mut voters = voters(3, &[Some(0), Some(1), Some(2)], &chain[..4]);
+        run(&mut voters, Duration::new(0, 0), Duration::from_secs(10));
+        for voter in &voters {
+            assert_eq!(voter.finalized_block().1, 3);
+        }
+
+        for voter in &mut voters {
+            for header in &chain[4..] {
+                voter.block_imported(header).unwrap();
+            }
+            voter.set_best_block(&header::hash_from_scale_encoded_header(&chain[7]));
+        }
+
+        run(
+            &mut voters,
+            Duration::from_secs(10),
+            Duration::from_secs(25),
+        );
+        for voter in &voters {
+            assert_eq!(voter.finalized_block().1, 7);
+        }
+    }
+
+    #[test]
+    fn keystore_with_other_keys() {
+        let chain = chain(5);
+        let best_hash = header::hash_from_scale_encoded_header(chain.last().unwrap());
+
+        // Each keystore contains a key that isn't an authority before the authority key.
+        let mut voters = (0..3)
+            .map(|seed| {
+                let mut voter = GrandpaVoter::new(Config {
+                    authorities_set_id: SET_ID,
+                    authorities: authorities_list(3),
+                    keystore: vec![ed25519_keypair(100 + seed), ed25519_keypair(seed)],
+                    finalized_block_hash: header::hash_from_scale_encoded_header(&chain[0]),
+                    finalized_block_number: 0,
+                    start_round: 1,
+                    gossip_duration: GOSSIP_DURATION,
+                });
+                for header in &chain[1..] {
+                    voter.block_imported(header).unwrap();
+                }
+                voter.set_best_block(&best_hash);
+                voter
+            })
+            .collect::<Vec<_>>();
+        run(&mut voters, Duration::new(0, 0), Duration::from_secs(10));
+
+        for voter in &voters {
+            assert_eq!(voter.finalized_block(), (best_hash, 4));
+        }
+    }
+
+    #[test]
+    fn precommit_with_wrong_number_not_in_commit() {
+        let chain = chain(5);
+        let best_hash = header::hash_from_scale_encoded_header(chain.last().unwrap());
+
+        let mut voter = voters(4, &[None], &chain).pop().unwrap();
+
+        // The precommit of authority 3 targets the best block but with a wrong number.
+        for (seed, target_number) in &[(3, 3), (0, 4), (1, 4), (2, 4)] {
+            let message = Message::Precommit(Vote {
+                target_hash: best_hash,
+                target_number: *target_number,
+            });
+            voter
+                .inject_message(signed_message(*seed, message))
+                .unwrap();
+        }
+
+        let commit = match voter.next_action(Duration::new(0, 0)) {
+            Some(Action::Finalized(commit)) => commit,
+            _ => panic!(),
+        };
+        assert_eq!(commit.target_hash, best_hash);
+        assert_eq!(commit.precommits.len(), 3);
+        assert!(commit
+            .precommits
+            .iter()
+            .all(|p| p.authority_public_key != ed25519_keypair(3).public.to_bytes()));
+        verify_commit(&commit, 4);
+    }
+
+    #[test]
+    fn equivocations_are_reported() {
+        let chain = chain(5);
+        let vote = |number: usize| Vote {
+            target_hash: header::hash_from_scale_encoded_header(&chain[number]),
+            target_number: u32::try_from(number).unwrap(),
+        };
+
+        let mut voter = voters(4, &[None], &chain).pop().unwrap();
+
+        // Authority 0 precommits two different blocks.
+        let first = signed_message(0, Message::Precommit(vote(4)));
+        let second = signed_message(0, Message::Precommit(vote(3)));
+        voter.inject_message(first.clone()).unwrap();
+        assert!(voter.next_action(Duration::new(0, 0)).is_none());
+        voter.inject_message(second.clone()).unwrap();
+        match voter.next_action(Duration::new(0, 0)) {
+            Some(Action::Equivocation(equivocation)) => {
+                assert_eq!(equivocation.first, first);
+                assert_eq!(equivocation.second, second);
+            }
+            _ => panic!(),
+        }
+
+        // The same equivocation is only reported once.
+        voter.inject_message(second).unwrap();
+        assert!(voter.next_action(Duration::new(0, 0)).is_none());
+
+        // The first vote of the equivocating authority is still taken into account.
+        for seed in 1..3 {
+            voter
+                .inject_message(signed_message(seed, Message::Precommit(vote(4))))
+                .unwrap();
+        }
+        match voter.next_action(Duration::new(0, 0)) {
+            Some(Action::Finalized(commit)) => assert_eq!(commit.target_number, 4),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn late_authority_catches_up() {
+        let chain = chain(5);
+
+        let mut voters = voters(4, &[Some(0), Some(1), Some(2), Some(3)], &chain);
+        let late = voters.pop().unwrap();
+        run(&mut voters, Duration::new(0, 0), Duration::from_secs(20));
+        let round = voters[0].current_round();
+        assert!(round > 3);
+
+        voters.push(late);
+        run(
+            &mut voters,
+            Duration::from_secs(20),
+            Duration::from_secs(30),
+        );
+        let late = voters.pop().unwrap();
+        assert!(late.current_round() >= round);
+        assert_eq!(late.finalized_block().1, 4);
+    }
+}
@@ -0,0 +1,307 @@
+// Substrate-lite
+// Copyright (C) 2019-2020  Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Tally of the votes emitted during a single GrandPa round.
+//!
+//! The GrandPa algorithm relies on the notion of **GHOST** of a set of votes: the highest block
+//! such that the authorities that have voted for this block or one of its descendants weigh
+//! at least the supermajority threshold. Authorities that have equivocated count towards all the
+//! blocks they have voted for.
+
+use super::{KnownBlocks, SignedVote};
+
+use alloc::{
+    collections::{btree_map, BTreeMap, BTreeSet},
+    vec::Vec,
+};
+
+/// Information that the computations of a [`Round`] depend on.
+pub(super) struct Context<'a> {
+    /// Blocks that the votes can target.
+    pub blocks: &'a KnownBlocks,
+    /// Weight of each authority, indexed by authority index.
+    pub weights: &'a [u64],
+}
+
+impl<'a> Context<'a> {
+    /// Returns the sum of the weights of all the authorities.
+    fn total_weight(&self) -> u64 {
+        self.weights.iter().fold(0, |a, b| a.saturating_add(*b))
+    }
+
+    /// Returns the minimum weight that a set of authorities must have in order to form a
+    /// supermajority, in other words strictly more than two thirds of the total weight.
+    pub fn threshold(&self) -> u64 {
+        let total = self.total_weight();
+        total - total.saturating_sub(1) / 3
+    }
+
+    /// Returns the sum of the weights of the given authorities.
+    fn weight_of<'b>(&self, authorities: impl Iterator<Item = &'b usize>) -> u64 {
+        authorities.fold(0, |sum, index| sum.saturating_add(self.weights[*index]))
+    }
+}
+
+/// Votes of one kind (prevotes or precommits) emitted during a round.
+#[derive(Debug, Clone, Default)]
+pub(super) struct Votes {
+    /// Votes indexed by the index of the authority that has emitted them.
+    by_authority: BTreeMap<usize, AuthorityVotes>,
+}
+
+/// Votes of one kind emitted by a single authority during a round.
+#[derive(Debug, Clone)]
+enum AuthorityVotes {
+    /// The authority has behaved correctly.
+    Single(SignedVote),
+    /// The authority has emitted two different votes. Any further vote is ignored.
+    Equivocated(SignedVote, SignedVote),
+}
+
+/// Outcome of [`Votes::insert`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) enum InsertOutcome {
+    /// The vote is the first one of this authority.
+    New,
+    /// The vote was already known.
+    Duplicate,
+    /// The vote is different from the contained vote, which is the first vote of the same
+    /// authority.
+    Equivocation(SignedVote),
+}
+
+impl Votes {
+    /// Adds a vote emitted by the authority with the given index.
+    ///
+    /// The vote is assumed to have been verified.
+    pub fn insert(&mut self, authority_index: usize, vote: SignedVote) -> InsertOutcome {
+        let existing = match self.by_authority.entry(authority_index) {
+            btree_map::Entry::Vacant(entry) => {
+                entry.insert(AuthorityVotes::Single(vote));
+                return InsertOutcome::New;
+            }
+            btree_map::Entry::Occupied(entry) => entry.into_mut(),
+        };
+
+        match existing {
+            AuthorityVotes::Single(first) if first.vote == vote.vote => InsertOutcome::Duplicate,
+            AuthorityVotes::Single(first) => {
+                let first = first.clone();
+                *existing = AuthorityVotes::Equivocated(first.clone(), vote);
+                InsertOutcome::Equivocation(first)
+            }
+            AuthorityVotes::Equivocated(a, b) if a.vote == vote.vote || b.vote == vote.vote => {
+                InsertOutcome::Duplicate
+            }
+            AuthorityVotes::Equivocated(first, _) => InsertOutcome::Equivocation(first.clone()),
+        }
+    }
+
+    /// Returns the list of all the votes, including both votes of equivocating authorities.
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &SignedVote)> {
+        self.by_authority
+            .iter()
+            .flat_map(|(index, votes)| match votes {
+                AuthorityVotes::Single(v) => either::Either::Left(core::iter::once((*index, v))),
+                AuthorityVotes::Equivocated(a, b) => either::Either::Right(
+                    core::iter::once((*index, a)).chain(core::iter::once((*index, b))),
+                ),
+            })
+    }
+
+    /// Returns the total weight of the authorities that have voted.
+    fn participation(&self, context: &Context) -> u64 {
+        context.weight_of(self.by_authority.keys())
+    }
+
+    /// Returns the total weight of the authorities that have equivocated.
+    fn equivocators_weight(&self, context: &Context) -> u64 {
+        context.weight_of(
+            self.by_authority
+                .iter()
+                .filter(|(_, v)| matches!(v, AuthorityVotes::Equivocated(..)))
+                .map(|(index, _)| index),
+        )
+    }
+
+    /// Returns, for each known block that is the target of a vote or an ancestor of the target
+    /// of a vote, its number and the list of authorities that have voted for this block or one
+    /// of its descendants.
+    ///
+    /// Votes targeting unknown blocks are ignored.
+    fn cumulative(&self, context: &Context) -> BTreeMap<[u8; 32], (u64, BTreeSet<usize>)> {
+        let mut out = BTreeMap::<[u8; 32], (u64, BTreeSet<usize>)>::new();
+
+        for (authority_index, vote) in self.iter() {
+            let ancestry = match context.blocks.ancestry(&vote.vote.target_hash) {
+                Some(a) => a,
+                None => continue,
+            };
+
+            // Votes whose number doesn't match the actual block number are ignored.
+            if ancestry.first().map(|(_, n)| *n) != Some(u64::from(vote.vote.target_number)) {
+                continue;
+            }
+
+            for (hash, number) in ancestry {
+                out.entry(hash)
+                    .or_insert_with(|| (number, BTreeSet::new()))
+                    .1
+                    .insert(authority_index);
+            }
+        }
+
+        out
+    }
+
+    /// Returns the highest block whose cumulative weight is superior or equal to the
+    /// supermajority threshold.
+    pub fn ghost(&self, context: &Context) -> Option<([u8; 32], u64)> {
+        let threshold = context.threshold();
+        self.cumulative(context)
+            .into_iter()
+            .filter(|(_, (_, voters))| context.weight_of(voters.iter()) >= threshold)
+            .max_by_key(|(hash, (number, _))| (*number, *hash))
+            .map(|(hash, (number, _))| (hash, number))
+    }
+}
+
+/// State of a single round.
+#[derive(Debug, Clone)]
+pub(super) struct Round {
+    /// Number of the round.
+    pub number: u64,
+    /// Prevotes received so far.
+    pub prevotes: Votes,
+    /// Precommits received so far.
+    pub precommits: Votes,
+    /// Block proposed by the primary authority of this round, if any.
+    pub primary_proposal: Option<([u8; 32], u64)>,
+}
+
+impl Round {
+    /// Builds a new round without any vote.
+    pub fn new(number: u64) -> Self {
+        Round {
+            number,
+            prevotes: Votes::default(),
+            precommits: Votes::default(),
+            primary_proposal: None,
+        }
+    }
+
+    /// Returns the GHOST of the prevotes.
+    pub fn prevote_ghost(&self, context: &Context) -> Option<([u8; 32], u64)> {
+        self.prevotes.ghost(context)
+    }
+
+    /// Returns the block finalized by this round, in other words the GHOST of the precommits.
+    pub fn finalized(&self, context: &Context) -> Option<([u8; 32], u64)> {
+        self.precommits.ghost(context)
+    }
+
+    /// Returns the estimate of the round, and whether the round is completable.
+    ///
+    /// The estimate is the highest ancestor of the prevote GHOST (or the prevote GHOST itself)
+    /// that could still be finalized by this round. It is `None` if the prevote GHOST is `None`.
+    ///
+    /// A round is completable when it is known that the estimate can no longer move up, which
+    /// is the case if the estimate is strictly inferior to the prevote GHOST, or if no block
+    /// above the prevote GHOST can possibly be finalized by this round.
+    pub fn estimate(&self, context: &Context) -> (Option<([u8; 32], u64)>, bool) {
+        let prevote_ghost = match self.prevote_ghost(context) {
+            Some(g) => g,
+            None => return (None, false),
+        };
+
+        let threshold = context.threshold();
+        let precommits_participation = self.precommits.participation(context);
+
+        // As long as not enough precommits have been received, any block could still be
+        // finalized.
+        if precommits_participation < threshold {
+            return (Some(prevote_ghost), false);
+        }
+
+        let cumulative = self.precommits.cumulative(context);
+        let equivocators_weight = self.precommits.equivocators_weight(context);
+        let remaining_weight = context.total_weight() - precommits_participation;
+
+        // Returns true if the given block can still possibly be finalized by the precommits of
+        // this round. Equivocators can count towards any block.
+        let possible = |hash: &[u8; 32]| -> bool {
+            let weight = cumulative.get(hash).map_or(0, |(_, voters)| {
+                context.weight_of(voters.iter().filter(|index| {
+                    !matches!(
+                        self.precommits.by_authority.get(*index),
+                        Some(AuthorityVotes::Equivocated(..))
+                    )
+                }))
+            });
+            weight
+                .saturating_add(equivocators_weight)
+                .saturating_add(remaining_weight)
+                >= threshold
+        };
+
+        let estimate = context
+            .blocks
+            .ancestry(&prevote_ghost.0)
+            .unwrap_or_default()
+            .into_iter()
+            .find(|(hash, _)| possible(hash))
+            .unwrap_or_else(|| context.blocks.finalized());
+
+        let completable = estimate != prevote_ghost || {
+            // Once the remaining weight is below the threshold, only blocks that have already
+            // been voted for could possibly reach the threshold.
+            remaining_weight < threshold
+                && !cumulative.iter().any(|(hash, (number, _))| {
+                    *number > prevote_ghost.1
+                        && context.blocks.is_descendant(&prevote_ghost.0, hash)
+                        && possible(hash)
+                })
+        };
+
+        (Some(estimate), completable)
+    }
+
+    /// Returns `true` if the round is completable. See [`Round::estimate`].
+    pub fn is_completable(&self, context: &Context) -> bool {
+        self.estimate(context).1
+    }
+
+    /// Returns the precommits that justify the finalization of the given block, in other words
+    /// the precommits that target this block or one of its descendants. At most one precommit
+    /// per authority is returned.
+    ///
+    /// Precommits whose number doesn't match the actual block number are ignored, like in
+    /// [`Votes::ghost`], as they would make the justification invalid.
+    pub fn precommits_for(&self, context: &Context, hash: &[u8; 32]) -> Vec<SignedVote> {
+        let mut authorities = BTreeSet::new();
+        self.precommits
+            .iter()
+            .filter(|(_, vote)| {
+                context.blocks.number(&vote.vote.target_hash)
+                    == Some(u64::from(vote.vote.target_number))
+            })
+            .filter(|(_, vote)| context.blocks.is_descendant(hash, &vote.vote.target_hash))
+            .filter(|(index, _)| authorities.insert(*index))
+            .map(|(_, vote)| vote.clone())
+            .collect()
+    }
+}
@@ -15,7 +15,9 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-use crate::finality::justification::decode;
+use crate::{finality::justification::decode, header};
+
+use alloc::collections::{btree_map, BTreeMap};
 use core::convert::TryFrom as _;
 
 /// Configuration for a justification verification process.
@@ -28,17 +30,43 @@ pub struct Config<'a, I> {
     pub authorities_set_id: u64,
 
     /// List of authorities that are allowed to emit pre-commits for the block referred to by
-    /// the justification. Must implement `Iterator<Item = impl AsRef<[u8]>> + Clone`, where
-    /// each item is the public key of an authority.
+    /// the justification. Must implement `Iterator<Item = header::GrandpaAuthorityRef> + Clone`.
+    ///
+    /// The justification is valid only if the authorities that have emitted a pre-commit weigh
+    /// more than two thirds of the total weight of this list.
     pub authorities_list: I,
 }
 
 // TODO: rewrite as a generator-style process?
 
 /// Verifies that a justification is valid.
-pub fn verify<'a>(
-    config: Config<'a, impl Iterator<Item = impl AsRef<[u8]>> + Clone>,
-) -> Result<(), Error> {
+///
+/// On success, returns the list of equivocations found in the justification, if any. An
+/// authority that has emitted two different pre-commits still counts towards the threshold,
+/// and a justification containing equivocations is therefore valid if that threshold is
+/// reached.
+pub fn verify<'a, 'b>(
+    config: Config<'a, impl Iterator<Item = header::GrandpaAuthorityRef<'b>> + Clone>,
+) -> Result<Vec<Equivocation>, Error> {
+    // Headers of the votes ancestries, indexed by hash. The boolean indicates whether the header
+    // has been used in order to prove that a pre-commit targets a descendant of the block
+    // targeted by the justification.
+    let mut votes_ancestries = config
+        .justification
+        .votes_ancestries
+        .clone()
+        .map(|header| (header.hash(), (header, false)))
+        .collect::<BTreeMap<_, _>>();
+
+    // Pre-commits that have been counted, indexed by the public key of their authority, and the
+    // total weight of these authorities.
+    let mut signers = BTreeMap::new();
+    let mut signed_weight = 0u64;
+
+    // For each authority that has emitted two different pre-commits, contains the first two of
+    // them.
+    let mut equivocations = Vec::<(decode::PrecommitRef, decode::PrecommitRef)>::new();
+
     // Verifying all the signatures together brings better performances than verifying them one
     // by one.
     let mut messages = Vec::with_capacity(config.justification.precommits.iter().len());
@@ -46,15 +74,65 @@ pub fn verify<'a>(
     let mut public_keys = Vec::with_capacity(config.justification.precommits.iter().len());
 
     for precommit in config.justification.precommits.iter() {
-        if !config
+        let authority = match config
             .authorities_list
             .clone()
-            .any(|a| a.as_ref() == precommit.authority_public_key)
+            .find(|a| a.public_key == precommit.authority_public_key)
+        {
+            Some(a) => a,
+            None => return Err(Error::NotAuthority(*precommit.authority_public_key)),
+        };
+
+        // A pre-commit for a block also counts as a pre-commit for all the ancestors of this
+        // block. Pre-commits must target either the block of the justification or one of its
+        // descendants, in which case the justification must contain all the headers between
+        // the two.
         {
-            return Err(Error::NotAuthority(*precommit.authority_public_key));
+            let mut hash = *precommit.target_hash;
+            let mut number = u64::from(precommit.target_number);
+            while hash != *config.justification.target_hash
+                || number != u64::from(config.justification.target_number)
+            {
+                if number <= u64::from(config.justification.target_number) {
+                    return Err(Error::PrecommitNotDescendant(*precommit.target_hash));
+                }
+
+                match votes_ancestries.get_mut(&hash) {
+                    Some((header, used)) if header.number == number => {
+                        *used = true;
+                        hash = *header.parent_hash;
+                        number -= 1;
+                    }
+                    _ => return Err(Error::PrecommitNotDescendant(*precommit.target_hash)),
+                }
+            }
         }
 
-        // TODO: must check signed block ancestry using `votes_ancestries`
+        // Each authority is expected to emit at most one pre-commit. Two different pre-commits
+        // from the same authority constitute an equivocation, which is only reported after all
+        // the signatures have been verified, as otherwise anyone could forge one. The weight of
+        // an equivocating authority is only counted once.
+        match signers.entry(*precommit.authority_public_key) {
+            btree_map::Entry::Vacant(entry) => {
+                signed_weight = signed_weight.saturating_add(authority.weight);
+                entry.insert(precommit.clone());
+            }
+            btree_map::Entry::Occupied(entry) => {
+                let first = entry.get();
+                if first.target_hash == precommit.target_hash
+                    && first.target_number == precommit.target_number
+                {
+                    return Err(Error::DuplicatePrecommit(*precommit.authority_public_key));
+                }
+
+                if !equivocations
+                    .iter()
+                    .any(|(f, _)| f.authority_public_key == precommit.authority_public_key)
+                {
+                    equivocations.push((first.clone(), precommit.clone()));
+                }
+            }
+        }
 
         messages.push({
             let mut msg = Vec::with_capacity(1 + 32 + 4 + 8 + 8);
@@ -76,6 +154,28 @@ pub fn verify<'a>(
         );
     }
 
+    // Headers in the votes ancestries that don't serve any purpose make the justification
+    // unnecessarily large. Duplicate headers are considered as not serving any purpose.
+    if votes_ancestries.len() != config.justification.votes_ancestries.len()
+        || votes_ancestries.values().any(|(_, used)| !used)
+    {
+        return Err(Error::UnusedVotesAncestries);
+    }
+
+    // The authorities that have emitted a pre-commit must weigh strictly more than two thirds of
+    // the total weight. In other words, the weight of the authorities that haven't emitted a
+    // pre-commit must be strictly inferior to a third of the total.
+    {
+        let total_weight = config
+            .authorities_list
+            .clone()
+            .fold(0u64, |sum, a| sum.saturating_add(a.weight));
+        let faulty_weight = total_weight.saturating_sub(1) / 3;
+        if total_weight == 0 || signed_weight < total_weight - faulty_weight {
+            return Err(Error::NotEnoughSignatures);
+        }
+    }
+
     debug_assert_eq!(messages.len(), public_keys.len());
     debug_assert_eq!(messages.len(), signatures.len());
     debug_assert_eq!(public_keys.len(), signatures.len());
@@ -90,10 +190,32 @@ pub fn verify<'a>(
             .map_err(|_| Error::BadSignature)?;
     }
 
-    // TODO: must check that votes_ancestries doesn't contain any unused entry
-    // TODO: there's also a "ghost" thing?
+    Ok(equivocations
+        .into_iter()
+        .map(|(first, second)| Equivocation {
+            authorities_set_id: config.authorities_set_id,
+            round: config.justification.round,
+            first: first.into(),
+            second: second.into(),
+        })
+        .collect())
+}
 
-    Ok(())
+/// Two different pre-commits emitted by the same authority during the same round.
+///
+/// The signatures of both pre-commits have been verified. An equivocation is therefore a proof
+/// that the authority has misbehaved, and can be reported as such.
+#[derive(Debug)]
+pub struct Equivocation {
+    /// Identifier of the authorities set the pre-commits have been emitted for.
+    pub authorities_set_id: u64,
+    /// Round the pre-commits have been emitted for.
+    pub round: u64,
+    /// First of the two pre-commits found in the justification.
+    pub first: decode::Precommit,
+    /// Second of the two pre-commits found in the justification. Its target is different from
+    /// the one of [`Equivocation::first`].
+    pub second: decode::Precommit,
 }
 
 /// Error that can happen while verifying a justification.
@@ -106,4 +228,380 @@ pub enum Error {
     /// One of the public keys isn't in the list of authorities.
     #[display(fmt = "One of the public keys isn't in the list of authorities")]
     NotAuthority([u8; 32]),
+    /// One of the pre-commits targets a block that can't be proven to be the block targeted by
+    /// the justification or one of its descendants.
+    #[display(
+        fmt = "Pre-commit targets a block that can't be proven to descend from the justification \
+               target"
+    )]
+    PrecommitNotDescendant([u8; 32]),
+    /// Some of the headers of the votes ancestries aren't necessary to prove the ancestry of
+    /// the pre-commits.
+    UnusedVotesAncestries,
+    /// The authorities that have emitted a pre-commit don't weigh more than two thirds of the
+    /// total weight of the authorities.
+    NotEnoughSignatures,
+    /// The same authority has emitted the same pre-commit multiple times.
+    #[display(fmt = "The same pre-commit is present multiple times")]
+    DuplicatePrecommit([u8; 32]),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{verify, Config, Equivocation, Error};
+    use crate::{
+        finality::justification::decode,
+        header,
+        test_utils::{self, ed25519_keypair},
+    };
+
+    const ROUND: u64 = 12;
+    const SET_ID: u64 = 3;
+
+    /// Chain of headers, each being the child of the previous one. The header at index 0 has
+    /// number 0.
+    fn chain(len: u32, fork: u8) -> Vec<Vec<u8>> {
+        let mut headers: Vec<Vec<u8>> = Vec::new();
+        for number in 0..len {
+            let parent_hash = headers
+                .last()
+                .map_or([0; 32], header::hash_from_scale_encoded_header);
+            // All the forks share the genesis block.
+            let fork = if number == 0 { 0 } else { fork };
+            headers.push(test_utils::encode_header_with_state_root(
+                &parent_hash,
+                u64::from(number),
+                &[fork; 32],
+                &[],
+            ));
+        }
+        headers
+    }
+
+    /// Builds the SCALE encoding of a justification targeting `target`, where each signer emits
+    /// a pre-commit for the given header.
+    fn encode_justification(
+        target: &[u8],
+        precommits: &[(&ed25519_dalek::Keypair, &Vec<u8>)],
+        votes_ancestries: &[&Vec<u8>],
+    ) -> Vec<u8> {
+        let target = header::decode(target).unwrap();
+
+        let precommits = precommits
+            .iter()
+            .map(|(signer, precommit_target)| {
+                let precommit_target = header::decode(precommit_target).unwrap();
+                test_utils::precommit(
+                    signer,
+                    ROUND,
+                    SET_ID,
+                    &precommit_target.hash(),
+                    precommit_target.number,
+                )
+            })
+            .collect();
+
+        test_utils::encode_justification(
+            ROUND,
+            &target.hash(),
+            target.number,
+            precommits,
+            votes_ancestries.iter().map(|h| (*h).clone()).collect(),
+        )
+    }
+
+    fn verify_with_weights(
+        justification: &[u8],
+        authorities: &[(&ed25519_dalek::Keypair, u64)],
+    ) -> Result<Vec<Equivocation>, Error> {
+        let authorities = authorities
+            .iter()
+            .map(|(kp, weight)| header::GrandpaAuthority {
+                public_key: kp.public.to_bytes(),
+                weight: *weight,
+            })
+            .collect::<Vec<_>>();
+
+        verify(Config {
+            justification: decode::decode(justification).unwrap(),
+            authorities_set_id: SET_ID,
+            authorities_list: authorities.iter().map(header::GrandpaAuthorityRef::from),
+        })
+    }
+
+    #[test]
+    fn precommits_on_target_and_proven_descendants() {
+        let (a, b, c) = (ed25519_keypair(1), ed25519_keypair(2), ed25519_keypair(3));
+        let headers = chain(6, 1);
+
+        let justification = encode_justification(
+            &headers[2],
+            &[(&a, &headers[2]), (&b, &headers[4]), (&c, &headers[3])],
+            &[&headers[3], &headers[4]],
+        );
+        verify_with_weights(&justification, &[(&a, 1), (&b, 1), (&c, 1)]).unwrap();
+    }
+
+    #[test]
+    fn descendant_without_ancestry_proof() {
+        let (a, b, c) = (ed25519_keypair(1), ed25519_keypair(2), ed25519_keypair(3));
+        let headers = chain(6, 1);
+
+        // Header #4 is missing from the votes ancestries.
+        let justification = encode_justification(
+            &headers[2],
+            &[(&a, &headers[2]), (&b, &headers[5]), (&c, &headers[2])],
+            &[&headers[3], &headers[5]],
+        );
+        assert!(matches!(
+            verify_with_weights(&justification, &[(&a, 1), (&b, 1), (&c, 1)]),
+            Err(Error::PrecommitNotDescendant(_))
+        ));
+    }
+
+    #[test]
+    fn precommit_on_other_fork() {
+        let (a, b, c) = (ed25519_keypair(1), ed25519_keypair(2), ed25519_keypair(3));
+        let headers = chain(4, 1);
+        let fork = chain(4, 2);
+
+        // The votes ancestries lead to the genesis block, which isn't the justification target.
+        let justification = encode_justification(
+            &headers[2],
+            &[(&a, &headers[2]), (&b, &fork[3]), (&c, &headers[2])],
+            &[&fork[1], &fork[2], &fork[3]],
+        );
+        assert!(matches!(
+            verify_with_weights(&justification, &[(&a, 1), (&b, 1), (&c, 1)]),
+            Err(Error::PrecommitNotDescendant(_))
+        ));
+    }
+
+    #[test]
+    fn precommit_on_ancestor() {
+        let (a, b, c) = (ed25519_keypair(1), ed25519_keypair(2), ed25519_keypair(3));
+        let headers = chain(4, 1);
+
+        let justification = encode_justification(
+            &headers[2],
+            &[(&a, &headers[2]), (&b, &headers[1]), (&c, &headers[2])],
+            &[],
+        );
+        assert!(matches!(
+            verify_with_weights(&justification, &[(&a, 1), (&b, 1), (&c, 1)]),
+            Err(Error::PrecommitNotDescendant(_))
+        ));
+    }
+
+    #[test]
+    fn unused_votes_ancestries() {
+        let (a, b, c) = (ed25519_keypair(1), ed25519_keypair(2), ed25519_keypair(3));
+        let headers = chain(6, 1);
+
+        let justification = encode_justification(
+            &headers[2],
+            &[(&a, &headers[2]), (&b, &headers[3]), (&c, &headers[2])],
+            &[&headers[3], &headers[5]],
+        );
+        assert!(matches!(
+            verify_with_weights(&justification, &[(&a, 1), (&b, 1), (&c, 1)]),
+            Err(Error::UnusedVotesAncestries)
+        ));
+
+        let justification = encode_justification(
+            &headers[2],
+            &[(&a, &headers[2]), (&b, &headers[3]), (&c, &headers[2])],
+            &[&headers[3], &headers[3]],
+        );
+        assert!(matches!(
+            verify_with_weights(&justification, &[(&a, 1), (&b, 1), (&c, 1)]),
+            Err(Error::UnusedVotesAncestries)
+        ));
+    }
+
+    #[test]
+    fn supermajority_by_weight() {
+        let (a, b, c, d) = (
+            ed25519_keypair(1),
+            ed25519_keypair(2),
+            ed25519_keypair(3),
+            ed25519_keypair(4),
+        );
+        let headers = chain(3, 1);
+
+        // Two thirds exactly isn't enough.
+        let justification =
+            encode_justification(&headers[2], &[(&a, &headers[2]), (&b, &headers[2])], &[]);
+        assert!(matches!(
+            verify_with_weights(&justification, &[(&a, 1), (&b, 1), (&c, 1)]),
+            Err(Error::NotEnoughSignatures)
+        ));
+
+        // Three out of four is enough.
+        let justification = encode_justification(
+            &headers[2],
+            &[(&a, &headers[2]), (&b, &headers[2]), (&c, &headers[2])],
+            &[],
+        );
+        verify_with_weights(&justification, &[(&a, 1), (&b, 1), (&c, 1), (&d, 1)]).unwrap();
+
+        // A single authority is enough if it weighs more than two thirds of the total.
+        let justification = encode_justification(&headers[2], &[(&a, &headers[2])], &[]);
+        verify_with_weights(&justification, &[(&a, 7), (&b, 1), (&c, 2)]).unwrap();
+        assert!(matches!(
+            verify_with_weights(&justification, &[(&a, 6), (&b, 1), (&c, 2)]),
+            Err(Error::NotEnoughSignatures)
+        ));
+    }
+
+    #[test]
+    fn duplicate_precommits() {
+        let (a, b, c) = (ed25519_keypair(1), ed25519_keypair(2), ed25519_keypair(3));
+        let headers = chain(3, 1);
+
+        let justification = encode_justification(
+            &headers[2],
+            &[(&a, &headers[2]), (&b, &headers[2]), (&a, &headers[2])],
+            &[],
+        );
+        assert!(matches!(
+            verify_with_weights(&justification, &[(&a, 1), (&b, 1), (&c, 1)]),
+            Err(Error::DuplicatePrecommit(key)) if key == a.public.to_bytes()
+        ));
+    }
+
+    #[test]
+    fn equivocation() {
+        let (a, b, c) = (ed25519_keypair(1), ed25519_keypair(2), ed25519_keypair(3));
+        let headers = chain(4, 1);
+
+        let justification = encode_justification(
+            &headers[2],
+            &[
+                (&a, &headers[2]),
+                (&b, &headers[2]),
+                (&c, &headers[2]),
+                (&a, &headers[3]),
+            ],
+            &[&headers[3]],
+        );
+        let equivocations =
+            verify_with_weights(&justification, &[(&a, 1), (&b, 1), (&c, 1)]).unwrap();
+        assert_eq!(equivocations.len(), 1);
+        let equivocation = &equivocations[0];
+        assert_eq!(equivocation.authorities_set_id, SET_ID);
+        assert_eq!(equivocation.round, ROUND);
+        assert_eq!(equivocation.first.authority_public_key, a.public.to_bytes());
+        assert_eq!(
+            equivocation.second.authority_public_key,
+            a.public.to_bytes()
+        );
+        assert_eq!(equivocation.first.target_number, 2);
+        assert_eq!(equivocation.second.target_number, 3);
+    }
+
+    #[test]
+    fn equivocating_authority_counted_once() {
+        let (a, b, c, d) = (
+            ed25519_keypair(1),
+            ed25519_keypair(2),
+            ed25519_keypair(3),
+            ed25519_keypair(4),
+        );
+        let headers = chain(4, 1);
+
+        // `a` and `b` both equivocate. Their weight is counted once, which together with `c`
+        // reaches the threshold of three out of four.
+        let justification = encode_justification(
+            &headers[2],
+            &[
+                (&a, &headers[2]),
+                (&b, &headers[2]),
+                (&a, &headers[3]),
+                (&c, &headers[2]),
+                (&b, &headers[3]),
+            ],
+            &[&headers[3]],
+        );
+        let equivocations =
+            verify_with_weights(&justification, &[(&a, 1), (&b, 1), (&c, 1), (&d, 1)]).unwrap();
+        assert_eq!(equivocations.len(), 2);
+        assert_eq!(
+            equivocations[0].first.authority_public_key,
+            a.public.to_bytes()
+        );
+        assert_eq!(
+            equivocations[1].first.authority_public_key,
+            b.public.to_bytes()
+        );
+
+        // Without `c`, the equivocating authorities alone don't reach the threshold.
+        let justification = encode_justification(
+            &headers[2],
+            &[
+                (&a, &headers[2]),
+                (&b, &headers[2]),
+                (&a, &headers[3]),
+                (&b, &headers[3]),
+            ],
+            &[&headers[3]],
+        );
+        assert!(matches!(
+            verify_with_weights(&justification, &[(&a, 1), (&b, 1), (&c, 1), (&d, 1)]),
+            Err(Error::NotEnoughSignatures)
+        ));
+    }
+
+    #[test]
+    fn forged_equivocation() {
+        let (a, b, c) = (ed25519_keypair(1), ed25519_keypair(2), ed25519_keypair(3));
+        let headers = chain(4, 1);
+
+        // The second pre-commit of `a` is signed by `b`.
+        let mut justification = encode_justification(
+            &headers[2],
+            &[
+                (&a, &headers[2]),
+                (&b, &headers[2]),
+                (&c, &headers[2]),
+                (&b, &headers[3]),
+            ],
+            &[&headers[3]],
+        );
+        let public_key_offset = justification.len() - headers[3].len() - 1 - 32;
+        justification[public_key_offset..public_key_offset + 32]
+            .copy_from_slice(a.public.as_bytes());
+
+        assert!(matches!(
+            verify_with_weights(&justification, &[(&a, 1), (&b, 1), (&c, 1)]),
+            Err(Error::BadSignature)
+        ));
+    }
+
+    #[test]
+    fn signature_from_other_set_id() {
+        let (a, b, c) = (ed25519_keypair(1), ed25519_keypair(2), ed25519_keypair(3));
+        let headers = chain(3, 1);
+
+        let justification = encode_justification(
+            &headers[2],
+            &[(&a, &headers[2]), (&b, &headers[2]), (&c, &headers[2])],
+            &[],
+        );
+        let authorities = [&a, &b, &c]
+            .iter()
+            .map(|kp| header::GrandpaAuthority {
+                public_key: kp.public.to_bytes(),
+                weight: 1,
+            })
+            .collect::<Vec<_>>();
+        assert!(matches!(
+            verify(Config {
+                justification: decode::decode(&justification).unwrap(),
+                authorities_set_id: SET_ID + 1,
+                authorities_list: authorities.iter().map(header::GrandpaAuthorityRef::from),
+            }),
+            Err(Error::BadSignature)
+        ));
+    }
 }
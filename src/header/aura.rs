@@ -0,0 +1,367 @@
+// Substrate-lite
+// Copyright (C) 2019-2020  Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+use super::Error;
+
+use core::{cmp, convert::TryFrom, fmt, iter, slice};
+use parity_scale_codec::{Decode as _, DecodeAll as _};
+
+/// A consensus log item for Aura.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuraConsensusLogRef<'a> {
+    /// List of authorities has changed. The new list applies to the children of the block that
+    /// contains this log item.
+    AuthoritiesChange(AuraAuthoritiesIter<'a>),
+    /// Disable the authority with given index.
+    ///
+    /// > **Note**: Substrate doesn't take this item into account when verifying blocks, and the
+    /// >           disabled authority can still author blocks.
+    OnDisabled(u32),
+}
+
+impl<'a> AuraConsensusLogRef<'a> {
+    /// Decodes a [`AuraConsensusLogRef`] from a slice of bytes.
+    pub fn from_slice(slice: &'a [u8]) -> Result<Self, Error> {
+        Ok(match slice.get(0) {
+            Some(1) => AuraConsensusLogRef::AuthoritiesChange(AuraAuthoritiesIter::from_slice(
+                &slice[1..],
+            )?),
+            Some(2) => AuraConsensusLogRef::OnDisabled(
+                u32::decode_all(&slice[1..]).map_err(Error::DigestItemDecodeError)?,
+            ),
+            Some(_) => return Err(Error::BadAuraConsensusRefType),
+            None => return Err(Error::TooShort),
+        })
+    }
+
+    /// Returns an iterator to list of buffers which, when concatenated, produces the SCALE
+    /// encoding of that object.
+    pub fn scale_encoding(
+        &self,
+    ) -> impl Iterator<Item = impl AsRef<[u8]> + Clone + 'a> + Clone + 'a {
+        #[derive(Clone)]
+        struct One([u8; 1]);
+        impl AsRef<[u8]> for One {
+            fn as_ref(&self) -> &[u8] {
+                &self.0[..]
+            }
+        }
+
+        let index = iter::once(One(match self {
+            AuraConsensusLogRef::AuthoritiesChange(_) => [1],
+            AuraConsensusLogRef::OnDisabled(_) => [2],
+        }));
+
+        let body = match self {
+            AuraConsensusLogRef::AuthoritiesChange(list) => {
+                let len = parity_scale_codec::Encode::encode(&parity_scale_codec::Compact(
+                    u64::try_from(list.len()).unwrap(),
+                ));
+                either::Either::Left(
+                    iter::once(either::Either::Left(len))
+                        .chain(list.clone().map(|a| either::Either::Right(a.public_key))),
+                )
+            }
+            AuraConsensusLogRef::OnDisabled(index) => either::Either::Right(iter::once(
+                either::Either::Left(parity_scale_codec::Encode::encode(index)),
+            )),
+        };
+
+        index
+            .map(either::Either::Left)
+            .chain(body.map(either::Either::Right))
+    }
+}
+
+impl<'a> From<&'a AuraConsensusLog> for AuraConsensusLogRef<'a> {
+    fn from(a: &'a AuraConsensusLog) -> Self {
+        match a {
+            AuraConsensusLog::AuthoritiesChange(v) => AuraConsensusLogRef::AuthoritiesChange(
+                AuraAuthoritiesIter(AuraAuthoritiesIterInner::List(v.iter())),
+            ),
+            AuraConsensusLog::OnDisabled(v) => AuraConsensusLogRef::OnDisabled(*v),
+        }
+    }
+}
+
+/// A consensus log item for Aura.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuraConsensusLog {
+    /// List of authorities has changed. The new list applies to the children of the block that
+    /// contains this log item.
+    AuthoritiesChange(Vec<AuraAuthority>),
+    /// Disable the authority with given index.
+    ///
+    /// > **Note**: Substrate doesn't take this item into account when verifying blocks, and the
+    /// >           disabled authority can still author blocks.
+    OnDisabled(u32),
+}
+
+impl<'a> From<AuraConsensusLogRef<'a>> for AuraConsensusLog {
+    fn from(a: AuraConsensusLogRef<'a>) -> Self {
+        match a {
+            AuraConsensusLogRef::AuthoritiesChange(v) => {
+                AuraConsensusLog::AuthoritiesChange(v.map(Into::into).collect())
+            }
+            AuraConsensusLogRef::OnDisabled(v) => AuraConsensusLog::OnDisabled(v),
+        }
+    }
+}
+
+/// List of authorities in an Aura context.
+#[derive(Clone)]
+pub struct AuraAuthoritiesIter<'a>(AuraAuthoritiesIterInner<'a>);
+
+#[derive(Clone)]
+enum AuraAuthoritiesIterInner<'a> {
+    List(slice::Iter<'a, AuraAuthority>),
+    Raw(slice::Chunks<'a, u8>),
+}
+
+impl<'a> AuraAuthoritiesIter<'a> {
+    /// Decodes a SCALE-encoded list of authorities.
+    pub fn from_slice(mut slice: &'a [u8]) -> Result<Self, Error> {
+        let authorities_len = usize::try_from(
+            parity_scale_codec::Compact::<u64>::decode(&mut slice)
+                .map_err(Error::DigestItemDecodeError)?
+                .0,
+        )
+        .map_err(|_| Error::TooShort)?;
+
+        if slice.len() != authorities_len.checked_mul(32).ok_or(Error::TooShort)? {
+            return Err(Error::TooShort);
+        }
+
+        Ok(AuraAuthoritiesIter(AuraAuthoritiesIterInner::Raw(
+            slice.chunks(32),
+        )))
+    }
+
+    /// Builds an iterator over the given list of authorities.
+    pub fn from_slice_of_authorities(list: &'a [AuraAuthority]) -> Self {
+        AuraAuthoritiesIter(AuraAuthoritiesIterInner::List(list.iter()))
+    }
+}
+
+impl<'a> Iterator for AuraAuthoritiesIter<'a> {
+    type Item = AuraAuthorityRef<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match &mut self.0 {
+            AuraAuthoritiesIterInner::List(l) => l.next().map(Into::into),
+            AuraAuthoritiesIterInner::Raw(l) => {
+                let item = l.next()?;
+                assert_eq!(item.len(), 32);
+                Some(AuraAuthorityRef {
+                    public_key: <&[u8; 32]>::try_from(item).unwrap(),
+                })
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        match &self.0 {
+            AuraAuthoritiesIterInner::List(l) => l.size_hint(),
+            AuraAuthoritiesIterInner::Raw(l) => l.size_hint(),
+        }
+    }
+}
+
+impl<'a> ExactSizeIterator for AuraAuthoritiesIter<'a> {}
+
+impl<'a> cmp::PartialEq<AuraAuthoritiesIter<'a>> for AuraAuthoritiesIter<'a> {
+    fn eq(&self, other: &AuraAuthoritiesIter<'a>) -> bool {
+        let mut a = self.clone();
+        let mut b = other.clone();
+        loop {
+            match (a.next(), b.next()) {
+                (Some(a), Some(b)) if a == b => {}
+                (None, None) => return true,
+                _ => return false,
+            }
+        }
+    }
+}
+
+impl<'a> cmp::Eq for AuraAuthoritiesIter<'a> {}
+
+impl<'a> fmt::Debug for AuraAuthoritiesIter<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.clone()).finish()
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct AuraAuthorityRef<'a> {
+    /// Sr25519 or ed25519 public key, depending on the chain.
+    pub public_key: &'a [u8; 32],
+}
+
+impl<'a> From<&'a AuraAuthority> for AuraAuthorityRef<'a> {
+    fn from(a: &'a AuraAuthority) -> Self {
+        AuraAuthorityRef {
+            public_key: &a.public_key,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct AuraAuthority {
+    /// Sr25519 or ed25519 public key, depending on the chain.
+    pub public_key: [u8; 32],
+}
+
+impl<'a> From<AuraAuthorityRef<'a>> for AuraAuthority {
+    fn from(a: AuraAuthorityRef<'a>) -> Self {
+        AuraAuthority {
+            public_key: *a.public_key,
+        }
+    }
+}
+
+/// Aura pre-runtime digest. Indicates the slot the block has been authored in.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct AuraPreDigest {
+    /// Slot number when the block was produced.
+    pub slot_number: u64,
+}
+
+impl AuraPreDigest {
+    /// Decodes a [`AuraPreDigest`] from a slice of bytes.
+    pub fn from_slice(slice: &[u8]) -> Result<Self, Error> {
+        let slot_number = u64::decode_all(slice).map_err(Error::DigestItemDecodeError)?;
+        Ok(AuraPreDigest { slot_number })
+    }
+
+    /// Returns an iterator to list of buffers which, when concatenated, produces the SCALE
+    /// encoding of that object.
+    pub fn scale_encoding(&self) -> impl Iterator<Item = impl AsRef<[u8]> + Clone> + Clone {
+        iter::once(self.slot_number.to_le_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AuraAuthority, AuraConsensusLog, AuraConsensusLogRef, AuraPreDigest};
+    use crate::header;
+
+    /// Concatenates the buffers returned by a `scale_encoding` method.
+    fn concat<T: AsRef<[u8]>>(buffers: impl Iterator<Item = T>) -> Vec<u8> {
+        buffers.fold(Vec::new(), |mut out, buffer| {
+            out.extend_from_slice(buffer.as_ref());
+            out
+        })
+    }
+
+    #[test]
+    fn authorities_change_encode_decode() {
+        let log = AuraConsensusLog::AuthoritiesChange(vec![
+            AuraAuthority {
+                public_key: [1; 32],
+            },
+            AuraAuthority {
+                public_key: [2; 32],
+            },
+        ]);
+
+        let encoded = concat(AuraConsensusLogRef::from(&log).scale_encoding());
+        let mut expected = vec![1, 2 << 2];
+        expected.extend_from_slice(&[1; 32]);
+        expected.extend_from_slice(&[2; 32]);
+        assert_eq!(encoded, expected);
+
+        let decoded = AuraConsensusLogRef::from_slice(&encoded).unwrap();
+        assert_eq!(decoded, AuraConsensusLogRef::from(&log));
+        assert_eq!(AuraConsensusLog::from(decoded), log);
+    }
+
+    #[test]
+    fn on_disabled_encode_decode() {
+        let log = AuraConsensusLog::OnDisabled(5);
+        let encoded = concat(AuraConsensusLogRef::from(&log).scale_encoding());
+        assert_eq!(encoded, vec![2, 5, 0, 0, 0]);
+        assert_eq!(
+            AuraConsensusLog::from(AuraConsensusLogRef::from_slice(&encoded).unwrap()),
+            log
+        );
+    }
+
+    #[test]
+    fn invalid_consensus_logs() {
+        assert!(matches!(
+            AuraConsensusLogRef::from_slice(&[]),
+            Err(header::Error::TooShort)
+        ));
+        assert!(matches!(
+            AuraConsensusLogRef::from_slice(&[3, 0]),
+            Err(header::Error::BadAuraConsensusRefType)
+        ));
+
+        // The list announces two authorities but only contains one.
+        let mut truncated = vec![1, 2 << 2];
+        truncated.extend_from_slice(&[1; 32]);
+        assert!(AuraConsensusLogRef::from_slice(&truncated).is_err());
+
+        assert!(AuraConsensusLogRef::from_slice(&[2, 5, 0, 0]).is_err());
+        assert!(AuraConsensusLogRef::from_slice(&[2, 5, 0, 0, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn pre_digest_encode_decode() {
+        let pre_digest = AuraPreDigest {
+            slot_number: 0x0102_0304_0506_0708,
+        };
+        let encoded = concat(pre_digest.scale_encoding());
+        assert_eq!(encoded, vec![8, 7, 6, 5, 4, 3, 2, 1]);
+        assert_eq!(AuraPreDigest::from_slice(&encoded).unwrap(), pre_digest);
+        assert!(AuraPreDigest::from_slice(&encoded[1..]).is_err());
+    }
+
+    #[test]
+    fn header_digest_encode_decode() {
+        let slot_number = 1234;
+        let items = vec![
+            header::DigestItem::AuraPreDigest(AuraPreDigest { slot_number }),
+            header::DigestItem::AuraConsensus(AuraConsensusLog::OnDisabled(1)),
+            header::DigestItem::AuraSeal([7; 64]),
+        ];
+
+        let mut encoded = vec![0; 32];
+        encoded.push(1 << 2);
+        encoded.extend_from_slice(&[0; 64]);
+        encoded.push(3 << 2);
+        for item in &items {
+            encoded.extend(concat(header::DigestItemRef::from(item).scale_encoding()));
+        }
+
+        // Pre-runtime digests, consensus logs and seals are identified by the `aura` engine.
+        assert_eq!(&encoded[98..103], &[6, b'a', b'u', b'r', b'a']);
+
+        let decoded = header::decode(&encoded).unwrap();
+        assert_eq!(
+            decoded.digest.aura_pre_runtime(),
+            Some(AuraPreDigest { slot_number })
+        );
+        assert_eq!(decoded.digest.aura_seal(), Some(&[7; 64]));
+        assert!(decoded
+            .digest
+            .logs()
+            .any(|item| item
+                == header::DigestItemRef::AuraConsensus(AuraConsensusLogRef::OnDisabled(1))));
+        assert_eq!(concat(decoded.scale_encoding()), encoded);
+    }
+}
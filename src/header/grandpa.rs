@@ -76,9 +76,9 @@ impl<'a> GrandpaConsensusLogRef<'a> {
                 GrandpaScheduledChangeRef::from_slice(&slice[1..])?,
             ),
             Some(2) => {
-                let reset_block_height =
-                    u32::decode_all(&slice[1..9]).map_err(Error::DigestItemDecodeError)?;
-                let change = GrandpaScheduledChangeRef::from_slice(&slice[9..])?;
+                let reset_block_height = u32::decode_all(slice.get(1..5).ok_or(Error::TooShort)?)
+                    .map_err(Error::DigestItemDecodeError)?;
+                let change = GrandpaScheduledChangeRef::from_slice(&slice[5..])?;
                 GrandpaConsensusLogRef::ForcedChange {
                     reset_block_height,
                     change,
@@ -120,10 +120,18 @@ impl<'a> GrandpaConsensusLogRef<'a> {
         }));
 
         let body = match self {
-            GrandpaConsensusLogRef::ScheduledChange(change) => {
-                either::Either::Left(change.scale_encoding().map(either::Either::Left))
-            }
-            GrandpaConsensusLogRef::ForcedChange { .. } => todo!(), // TODO:
+            GrandpaConsensusLogRef::ScheduledChange(change) => either::Either::Left(
+                either::Either::Left(change.scale_encoding().map(either::Either::Left)),
+            ),
+            GrandpaConsensusLogRef::ForcedChange {
+                reset_block_height,
+                change,
+            } => either::Either::Left(either::Either::Right(
+                iter::once(either::Either::Right(parity_scale_codec::Encode::encode(
+                    reset_block_height,
+                )))
+                .chain(change.scale_encoding().map(either::Either::Left)),
+            )),
             GrandpaConsensusLogRef::OnDisabled(n) => either::Either::Right(iter::once(
                 either::Either::Right(parity_scale_codec::Encode::encode(n)),
             )),
@@ -206,6 +206,7 @@ pub mod telemetry;
 pub mod trie;
 pub mod verify;
 
+mod test_utils;
 mod util;
 
 /// Builds the header of the genesis block, from the values in storage.
@@ -224,7 +225,7 @@ pub fn calculate_genesis_block_header<'a>(
     genesis_storage: impl Iterator<Item = (&'a [u8], &'a [u8])> + Clone,
 ) -> header::Header {
     let state_root = {
-        let mut calculation = trie::calculate_root::root_merkle_value(None);
+        let mut calculation = trie::calculate_root::root_merkle_value(Default::default(), None);
 
         loop {
             match calculation {
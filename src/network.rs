@@ -54,7 +54,8 @@ use core::fmt;
 pub use libp2p::{Multiaddr, PeerId};
 pub use worker::{
     BlockData, BlocksRequestConfig, BlocksRequestConfigStart, BlocksRequestDirection,
-    BlocksRequestFields, Config, Event, Network, RequestId, ScaleBlockHeader,
+    BlocksRequestFields, Config, Event, Network, RequestId, Role, ScaleBlockHeader, StateResponse,
+    WarpSyncFragment, WarpSyncResponse,
 };
 
 #[doc(inline)]
@@ -19,6 +19,7 @@ use super::{
     discovery::{DiscoveryBehaviour, DiscoveryConfig, DiscoveryOut},
     generic_proto, legacy_message, request_responses,
 };
+use crate::finality::grandpa;
 
 use alloc::{borrow::Cow, collections::VecDeque};
 use core::{
@@ -33,6 +34,9 @@ use libp2p::NetworkBehaviour;
 use parity_scale_codec::{DecodeAll, Encode};
 use primitive_types::H256;
 
+/// Engine id of GrandPa messages sent through the legacy substream.
+const GRANDPA_ENGINE_ID: [u8; 4] = *b"FRNK";
+
 /// General behaviour of the network. Combines all protocols together.
 #[derive(NetworkBehaviour)]
 #[behaviour(out_event = "BehaviourOut", poll_method = "poll")]
@@ -51,6 +55,12 @@ pub struct Behaviour {
     local_best_hash: H256,
     #[behaviour(ignore)]
     local_genesis_hash: H256,
+    /// Roles of the local node, sent to peers in the handshakes.
+    #[behaviour(ignore)]
+    local_roles: legacy_message::Roles,
+    /// Neighbor packet sent to peers on the GrandPa protocol, if any.
+    #[behaviour(ignore)]
+    grandpa_neighbor_packet: Option<grandpa::gossip::NeighborPacket>,
 
     /// Queue of events to produce for the outside.
     #[behaviour(ignore)]
@@ -60,7 +70,12 @@ pub struct Behaviour {
 #[derive(Debug)]
 pub enum BehaviourOut {
     /// An announcement about a block has been gossiped to us.
-    BlockAnnounce(super::ScaleBlockHeader),
+    BlockAnnounce {
+        /// Peer that has sent the announcement.
+        peer_id: PeerId,
+        /// Header of the announced block.
+        header: super::ScaleBlockHeader,
+    },
 
     /// We have received a request from a peer and answered it.
     ///
@@ -82,6 +97,28 @@ pub enum BehaviourOut {
         /// Response sent by the remote or reason for failure.
         outcome: Result<Vec<u8>, request_responses::OutboundFailure>,
     },
+
+    /// A GrandPa commit has been gossiped to us.
+    ///
+    /// The commit hasn't been verified.
+    GrandpaCommit {
+        /// Peer that has sent the commit.
+        peer_id: PeerId,
+        /// The commit message.
+        commit: grandpa::gossip::CommitMessage,
+    },
+
+    /// A GrandPa message other than a commit, in other words a vote, a neighbor packet, or a
+    /// catch-up request or response, has been sent to us. These messages are only useful to
+    /// GrandPa voters.
+    ///
+    /// The message hasn't been verified.
+    GrandpaGossip {
+        /// Peer that has sent the message.
+        peer_id: PeerId,
+        /// The message.
+        message: grandpa::gossip::GossipMessage,
+    },
 }
 
 impl Behaviour {
@@ -97,6 +134,7 @@ impl Behaviour {
         request_response_protocols: Vec<request_responses::ProtocolConfig>,
         local_best_hash: H256,
         local_genesis_hash: H256,
+        local_roles: legacy_message::Roles,
     ) -> Self {
         let peerset_config = sc_peerset::PeersetConfig {
             in_peers: 25,
@@ -107,12 +145,17 @@ impl Behaviour {
         };
 
         let (peerset, _) = sc_peerset::Peerset::from_config(peerset_config);
-        let legacy = generic_proto::GenericProto::new(
+        let mut legacy = generic_proto::GenericProto::new(
             local_public_key.clone().into_peer_id(),
             chain_spec_protocol_id.clone(),
             &[6, 5],
             peerset,
         );
+        // The handshake of the GrandPa protocol consists in the roles of the local node.
+        legacy.register_notif_protocol(
+            grandpa::gossip::PROTOCOL_NAME.as_bytes(),
+            vec![local_roles.bits()],
+        );
 
         Behaviour {
             legacy,
@@ -134,6 +177,8 @@ impl Behaviour {
             },
             local_best_hash,
             local_genesis_hash,
+            local_roles,
+            grandpa_neighbor_packet: None,
             events: VecDeque::new(),
         }
     }
@@ -176,6 +221,78 @@ impl Behaviour {
             .send_request(target, protocol, request)
     }
 
+    /// Sets the neighbor packet to send to peers on the GrandPa protocol, and sends it to all
+    /// the peers we're connected to.
+    ///
+    /// Peers only gossip GrandPa commits that match the authorities set id and finalized height
+    /// found in the neighbor packet.
+    pub fn set_grandpa_neighbor_packet(&mut self, packet: grandpa::gossip::NeighborPacket) {
+        self.grandpa_neighbor_packet = Some(packet);
+
+        let peers = self.legacy.open_peers().cloned().collect::<Vec<_>>();
+        for peer_id in peers {
+            self.send_grandpa_neighbor_packet(&peer_id);
+        }
+    }
+
+    /// Sends the GrandPa neighbor packet, if any, to the given peer.
+    fn send_grandpa_neighbor_packet(&mut self, peer_id: &PeerId) {
+        let packet = match self.grandpa_neighbor_packet {
+            Some(p) => p,
+            None => return,
+        };
+
+        self.send_grandpa_message(peer_id, &grandpa::gossip::GossipMessage::Neighbor(packet));
+    }
+
+    /// Sends a message on the GrandPa protocol to the given peer, for example a response to a
+    /// catch-up request.
+    pub fn send_grandpa_message(
+        &mut self,
+        peer_id: &PeerId,
+        message: &grandpa::gossip::GossipMessage,
+    ) {
+        let message = message.scale_encoding();
+        // Peers that don't support notifications receive the message through the legacy
+        // substream, as a consensus message.
+        let fallback = legacy_message::Message::Consensus(legacy_message::ConsensusMessage {
+            engine_id: GRANDPA_ENGINE_ID,
+            data: message.clone(),
+        });
+
+        self.legacy.write_notification(
+            peer_id,
+            Cow::Borrowed(grandpa::gossip::PROTOCOL_NAME.as_bytes()),
+            message,
+            fallback.encode(),
+        );
+    }
+
+    /// Sends a message on the GrandPa protocol to all the peers we're connected to, for example
+    /// a vote or a commit.
+    pub fn broadcast_grandpa_message(&mut self, message: &grandpa::gossip::GossipMessage) {
+        let peers = self.legacy.open_peers().cloned().collect::<Vec<_>>();
+        for peer_id in peers {
+            self.send_grandpa_message(&peer_id, message);
+        }
+    }
+
+    /// Decodes a message received on the GrandPa protocol and generates an event.
+    fn on_grandpa_message(&mut self, peer_id: PeerId, message: &[u8]) {
+        // TODO: report peers that send undecodable messages
+        match grandpa::gossip::decode(message) {
+            Ok(grandpa::gossip::GossipMessage::Commit(commit)) => {
+                self.events
+                    .push_back(BehaviourOut::GrandpaCommit { peer_id, commit });
+            }
+            Ok(message) => {
+                self.events
+                    .push_back(BehaviourOut::GrandpaGossip { peer_id, message });
+            }
+            Err(_) => {}
+        }
+    }
+
     /// Start querying a record from the DHT. Will later produce either a `ValueFound` or a `ValueNotFound` event.
     pub fn get_value(&mut self, key: &record::Key) {
         self.discovery.get_value(key);
@@ -213,7 +330,7 @@ impl NetworkBehaviourEventProcess<generic_proto::GenericProtoOut> for Behaviour
                 let message = legacy_message::Message::Status(legacy_message::Status {
                     version: 6,
                     min_supported_version: 6,
-                    roles: legacy_message::Roles::LIGHT,
+                    roles: self.local_roles,
                     best_number: 0,
                     best_hash: self.local_best_hash,
                     genesis_hash: self.local_genesis_hash,
@@ -221,24 +338,36 @@ impl NetworkBehaviourEventProcess<generic_proto::GenericProtoOut> for Behaviour
                 });
 
                 self.legacy.send_packet(&peer_id, message.encode());
+                self.send_grandpa_neighbor_packet(&peer_id);
             }
             generic_proto::GenericProtoOut::CustomProtocolClosed { peer_id: _, .. } => {}
-            generic_proto::GenericProtoOut::LegacyMessage {
-                peer_id: _,
-                message,
-            } => {
+            generic_proto::GenericProtoOut::LegacyMessage { peer_id, message } => {
                 match legacy_message::Message::decode_all(&message) {
                     Ok(legacy_message::Message::BlockAnnounce(announcement)) => {
-                        self.events.push_back(BehaviourOut::BlockAnnounce(
-                            super::ScaleBlockHeader(announcement.header.encode()),
-                        ));
+                        self.events.push_back(BehaviourOut::BlockAnnounce {
+                            peer_id,
+                            header: super::ScaleBlockHeader(announcement.header.encode()),
+                        });
                     }
                     Ok(legacy_message::Message::Status(_)) => {}
+                    Ok(legacy_message::Message::Consensus(message))
+                        if message.engine_id == GRANDPA_ENGINE_ID =>
+                    {
+                        self.on_grandpa_message(peer_id, &message.data);
+                    }
                     _msg => {} // TODO: for debugging println!("message from {:?} => {:?}", peer_id, msg),
                 }
             }
             generic_proto::GenericProtoOut::Clogged { .. } => {}
-            generic_proto::GenericProtoOut::Notification { .. } => {} // TODO: !
+            generic_proto::GenericProtoOut::Notification {
+                peer_id,
+                protocol_name,
+                message,
+            } => {
+                if &*protocol_name == grandpa::gossip::PROTOCOL_NAME.as_bytes() {
+                    self.on_grandpa_message(peer_id, &message);
+                }
+            }
         }
     }
 }
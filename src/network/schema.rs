@@ -26,4 +26,7 @@ pub mod v1 {
     pub mod light {
         include!(concat!(env!("OUT_DIR"), "/api.v1.light.rs"));
     }
+    pub mod warp_sync {
+        include!(concat!(env!("OUT_DIR"), "/api.v1.warp_sync.rs"));
+    }
 }
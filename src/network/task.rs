@@ -15,7 +15,7 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-use crate::network;
+use crate::{finality::grandpa, header, network};
 
 // TODO: work in progress
 
@@ -28,18 +28,56 @@ use futures::{
 use hashbrown::HashMap;
 
 /// Starts the network service.
+///
+/// Returns, in addition to the service and the task to run, a stream of the [`Event`]s that
+/// happen on the network. This stream must be polled continuously, otherwise the task will
+/// stall.
 pub fn start_network_service(
     worker: network::Network,
-) -> (NetworkService, impl Future<Output = ()>) {
+) -> (
+    NetworkService,
+    mpsc::Receiver<Event>,
+    impl Future<Output = ()>,
+) {
     let (tx, rx) = mpsc::channel(8);
+    let (events_tx, events_rx) = mpsc::channel(16);
     let num_connections_store = Arc::new(atomic::Atomic::new(0));
-    let task = run_networking_task(worker, rx, num_connections_store.clone());
+    let task = run_networking_task(worker, rx, events_tx, num_connections_store.clone());
     let service = NetworkService {
         sender: Mutex::new(tx),
         num_connections_store,
     };
 
-    (service, task)
+    (service, events_rx, task)
+}
+
+/// Event that happened on the network.
+#[derive(Debug)]
+pub enum Event {
+    /// A peer has announced a block. This block is normally the new best block of this peer.
+    ///
+    /// This information is meant to be passed to the syncing state machine, in order to update
+    /// the best block of the corresponding source and start downloading the block.
+    BlockAnnounce {
+        /// Peer that has sent the announcement.
+        peer_id: network::PeerId,
+        /// Header of the announced block.
+        header: header::Header,
+    },
+    /// A peer has gossiped a GrandPa commit.
+    ///
+    /// The commit hasn't been verified. It is meant to be passed to the syncing state machine,
+    /// which verifies it and, if valid, finalizes the block it targets.
+    GrandpaCommit {
+        /// Peer that has sent the commit.
+        peer_id: network::PeerId,
+        /// The commit message.
+        commit: grandpa::gossip::CommitMessage,
+    },
+    /// Established at least one connection with the given peer.
+    Connected(network::PeerId),
+    /// No longer have any connection with the given peer.
+    Disconnected(network::PeerId),
 }
 
 pub struct NetworkService {
@@ -61,6 +99,53 @@ impl NetworkService {
             .unwrap();
         rx.await.unwrap()
     }
+
+    /// Asks the given peer for the list of blocks that change the GrandPa authorities set, and
+    /// their justifications, starting after the finalized block whose hash is `begin`.
+    pub async fn warp_sync_request(
+        &self,
+        peer_id: network::PeerId,
+        begin: [u8; 32],
+    ) -> Result<network::WarpSyncResponse, ()> {
+        let (tx, rx) = oneshot::channel();
+        self.sender
+            .lock()
+            .await
+            .send(ToWorker::WarpSyncRequest(peer_id, begin, tx))
+            .await
+            .unwrap();
+        rx.await.unwrap()
+    }
+
+    /// Asks the given peer for a proof of the storage entries of the block whose hash is
+    /// `block_hash`, starting after the keys in `start`.
+    pub async fn state_request(
+        &self,
+        peer_id: network::PeerId,
+        block_hash: [u8; 32],
+        start: Vec<Vec<u8>>,
+    ) -> Result<network::StateResponse, ()> {
+        let (tx, rx) = oneshot::channel();
+        self.sender
+            .lock()
+            .await
+            .send(ToWorker::StateRequest(peer_id, block_hash, start, tx))
+            .await
+            .unwrap();
+        rx.await.unwrap()
+    }
+
+    /// Updates the GrandPa neighbor packet sent to peers. Should be called whenever the
+    /// finalized block or the authorities set id changes, in order for peers to gossip the
+    /// relevant GrandPa commits.
+    pub async fn set_grandpa_neighbor_packet(&self, packet: grandpa::gossip::NeighborPacket) {
+        self.sender
+            .lock()
+            .await
+            .send(ToWorker::SetGrandpaNeighborPacket(packet))
+            .await
+            .unwrap();
+    }
 }
 
 /// Message that can be sent to the network task by the service.
@@ -75,48 +160,90 @@ enum ToWorker {
         network::BlocksRequestConfig,
         oneshot::Sender<Result<Vec<network::BlockData>, ()>>,
     ),
+    /// Ask to perform a warp sync request towards the given peer.
+    WarpSyncRequest(
+        network::PeerId,
+        [u8; 32],
+        oneshot::Sender<Result<network::WarpSyncResponse, ()>>,
+    ),
+    /// Ask to perform a state request towards the given peer.
+    StateRequest(
+        network::PeerId,
+        [u8; 32],
+        Vec<Vec<u8>>,
+        oneshot::Sender<Result<network::StateResponse, ()>>,
+    ),
+    /// Update the GrandPa neighbor packet sent to peers.
+    SetGrandpaNeighborPacket(grandpa::gossip::NeighborPacket),
 }
 
 /// Runs the task.
 async fn run_networking_task(
     mut worker: network::Network,
     mut from_service: mpsc::Receiver<ToWorker>,
+    mut events_tx: mpsc::Sender<Event>,
     num_connections_store: Arc<atomic::Atomic<u64>>,
 ) {
     // Associates network-assigned block request ids to senders.
     let mut pending_blocks_requests: HashMap<_, oneshot::Sender<_>, fnv::FnvBuildHasher> =
         HashMap::default();
+    // Associates network-assigned warp sync request ids to senders.
+    let mut pending_warp_sync_requests: HashMap<_, oneshot::Sender<_>, fnv::FnvBuildHasher> =
+        HashMap::default();
+    // Associates network-assigned state request ids to senders.
+    let mut pending_state_requests: HashMap<_, oneshot::Sender<_>, fnv::FnvBuildHasher> =
+        HashMap::default();
 
     loop {
         futures::select! {
             ev = worker.next_event().fuse() => {
                 match ev {
-                    network::Event::BlockAnnounce(header) => {
-                        // TODO:
-                        /*// TOOD: don't unwrap
-                        let decoded_header =
-                            <block::Header as parity_scale_codec::DecodeAll>::decode_all(&header.0).unwrap();
-                        let ev_out = super::Event::BlockAnnounceReceived {
-                            number: decoded_header.number,
-                            hash: decoded_header.block_hash().0.into(),
+                    network::Event::BlockAnnounce { peer_id, header } => {
+                        let header = match header::decode(&header.0) {
+                            Ok(h) => h.into(),
+                            // TODO: the peer should be punished
+                            Err(_) => continue,
                         };
 
-                        if config.to_service_out.send(ev_out).await.is_err() {
+                        let ev_out = Event::BlockAnnounce { peer_id, header };
+                        if events_tx.send(ev_out).await.is_err() {
                             return;
-                        }*/
+                        }
+                    },
+                    network::Event::GrandpaCommit { peer_id, commit } => {
+                        let ev_out = Event::GrandpaCommit { peer_id, commit };
+                        if events_tx.send(ev_out).await.is_err() {
+                            return;
+                        }
                     },
+                    // Not exposed, as no user of the service runs a GrandPa voter.
+                    network::Event::GrandpaGossip { .. } => {}
                     network::Event::BlocksRequestFinished { id, result } => {
                         let sender = pending_blocks_requests.remove(&id).unwrap();
                         let _ = sender.send(result);
                     }
+                    network::Event::WarpSyncRequestFinished { id, result } => {
+                        let sender = pending_warp_sync_requests.remove(&id).unwrap();
+                        let _ = sender.send(result);
+                    }
+                    network::Event::StateRequestFinished { id, result } => {
+                        let sender = pending_state_requests.remove(&id).unwrap();
+                        let _ = sender.send(result);
+                    }
                     network::Event::CallRequestFinished { id, result } => {
                         todo!()
                     }
                     network::Event::Connected(peer_id) => {
                         num_connections_store.fetch_add(1, atomic::Ordering::Relaxed);
+                        if events_tx.send(Event::Connected(peer_id)).await.is_err() {
+                            return;
+                        }
                     },
                     network::Event::Disconnected(peer_id) => {
                         num_connections_store.fetch_sub(1, atomic::Ordering::Relaxed);
+                        if events_tx.send(Event::Disconnected(peer_id)).await.is_err() {
+                            return;
+                        }
                     },
                 }
             }
@@ -130,6 +257,26 @@ async fn run_networking_task(
                             let _ = send_back.send(Err(()));
                         }
                     }
+                    Some(ToWorker::WarpSyncRequest(peer_id, begin, send_back)) => {
+                        if let Ok(id) = worker.start_warp_sync_request(&peer_id, begin).await {
+                            pending_warp_sync_requests.insert(id, send_back);
+                        } else {
+                            let _ = send_back.send(Err(()));
+                        }
+                    }
+                    Some(ToWorker::StateRequest(peer_id, block_hash, start, send_back)) => {
+                        let result = worker
+                            .start_state_request(&peer_id, block_hash, &start)
+                            .await;
+                        if let Ok(id) = result {
+                            pending_state_requests.insert(id, send_back);
+                        } else {
+                            let _ = send_back.send(Err(()));
+                        }
+                    }
+                    Some(ToWorker::SetGrandpaNeighborPacket(packet)) => {
+                        worker.set_grandpa_neighbor_packet(packet);
+                    }
                 }
             }
         }
@@ -16,6 +16,7 @@
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
 use super::{behaviour, legacy_message, request_responses, schema, transport};
+use crate::finality::grandpa;
 
 use alloc::boxed::Box;
 use core::{
@@ -48,13 +49,21 @@ pub struct Network {
 enum RequestTy {
     Block,
     Call,
+    State,
+    WarpSync,
 }
 
 /// Event that can happen on the network.
 #[derive(Debug)]
 pub enum Event {
     /// An announcement about a block has been gossiped to us.
-    BlockAnnounce(ScaleBlockHeader),
+    BlockAnnounce {
+        /// Peer that has sent the announcement. The announced block is normally the new best
+        /// block of this peer.
+        peer_id: PeerId,
+        /// Header of the announced block.
+        header: ScaleBlockHeader,
+    },
 
     /// A blocks request started with [`Network::start_block_request`] has gotten a response.
     BlocksRequestFinished {
@@ -62,6 +71,19 @@ pub enum Event {
         result: Result<Vec<BlockData>, ()>,
     },
 
+    /// A warp sync request started with [`Network::start_warp_sync_request`] has gotten a
+    /// response.
+    WarpSyncRequestFinished {
+        id: RequestId,
+        result: Result<WarpSyncResponse, ()>,
+    },
+
+    /// A state request started with [`Network::start_state_request`] has gotten a response.
+    StateRequestFinished {
+        id: RequestId,
+        result: Result<StateResponse, ()>,
+    },
+
     /// A call request started with [`Network::start_call_request`] has gotten a response.
     CallRequestFinished {
         id: RequestId,
@@ -69,6 +91,27 @@ pub enum Event {
         result: Result<Vec<u8>, ()>,
     },
 
+    /// A GrandPa commit has been gossiped to us.
+    ///
+    /// The commit hasn't been verified.
+    GrandpaCommit {
+        /// Peer that has sent the commit.
+        peer_id: PeerId,
+        /// The commit message.
+        commit: grandpa::gossip::CommitMessage,
+    },
+
+    /// A GrandPa vote, neighbor packet, or catch-up request or response has been sent to us.
+    /// These messages are only useful to GrandPa voters.
+    ///
+    /// The message hasn't been verified.
+    GrandpaGossip {
+        /// Peer that has sent the message.
+        peer_id: PeerId,
+        /// The message.
+        message: grandpa::gossip::GossipMessage,
+    },
+
     /// Established at least one connection with the given peer.
     Connected(PeerId),
     /// No longer have any connection with the given peer.
@@ -95,6 +138,32 @@ pub struct BlockData {
 #[derive(Debug, PartialEq, Eq, Clone, Default)]
 pub struct Extrinsic(pub Vec<u8>);
 
+/// Response to a warp sync request.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct WarpSyncResponse {
+    /// List of blocks that change the GrandPa authorities set, in increasing block number.
+    pub fragments: Vec<WarpSyncFragment>,
+    /// True if the last fragment is the latest finalized block known to the remote.
+    pub is_finished: bool,
+}
+
+/// Block in a [`WarpSyncResponse`].
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct WarpSyncFragment {
+    /// SCALE-encoded header of the block.
+    pub scale_encoded_header: Vec<u8>,
+    /// SCALE-encoded GrandPa justification of the block.
+    pub scale_encoded_justification: Vec<u8>,
+}
+
+/// Response to a state request.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct StateResponse {
+    /// Entries of the proof of the storage entries, as sent by the remote. Made of the compact
+    /// proof of the main trie followed with the compact proofs of the child tries, if any.
+    pub compact_proof: Vec<Vec<u8>>,
+}
+
 // TODO: the BlocksRequestConfig and all derivates should be in the block_requests module, but the
 // block_requests module at the moment is more or less copy-pasted from upstream Substrate, so we
 // have them here to make it easier to update the code
@@ -168,6 +237,10 @@ pub struct Config {
     /// Hash of the genesis block of the local node.
     pub local_genesis_hash: [u8; 32],
 
+    /// Role of the local node, announced to the peers when opening the block announces and
+    /// GrandPa substreams.
+    pub role: Role,
+
     /// Optional external implementation of a libp2p transport. Used in WASM contexts where we
     /// need some binding between the networking provided by the operating system or environment
     /// and libp2p.
@@ -177,6 +250,15 @@ pub struct Config {
     pub wasm_external_transport: Option<wasm_ext::ExtTransport>,
 }
 
+/// Role of a node on the network.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Role {
+    /// Node that holds the full data of the chain, including the bodies and storage of blocks.
+    Full,
+    /// Node that only holds the headers of the blocks of the chain.
+    Light,
+}
+
 impl Network {
     pub async fn start(config: Config) -> Self {
         let local_key_pair = libp2p::identity::Keypair::generate_ed25519();
@@ -206,11 +288,29 @@ impl Network {
                     request_timeout: Duration::from_secs(10),
                     requests_processing: None, // TODO:
                 });
+                protocols.push(request_responses::ProtocolConfig {
+                    name: format!("/{}/sync/warp", chain_spec_protocol_id).into(),
+                    max_request_size: 64,
+                    max_response_size: 16 * 1024 * 1024,
+                    request_timeout: Duration::from_secs(10),
+                    requests_processing: None, // TODO:
+                });
+                protocols.push(request_responses::ProtocolConfig {
+                    name: format!("/{}/state/2", chain_spec_protocol_id).into(),
+                    max_request_size: 1024 * 1024,
+                    max_response_size: 16 * 1024 * 1024,
+                    request_timeout: Duration::from_secs(40),
+                    requests_processing: None, // TODO:
+                });
                 protocols
             },
             // TODO: best hash != genesis_hash
             config.local_genesis_hash.clone().into(),
             config.local_genesis_hash.clone().into(),
+            match config.role {
+                Role::Full => legacy_message::Roles::FULL,
+                Role::Light => legacy_message::Roles::LIGHT,
+            },
         )
         .await;
 
@@ -247,6 +347,29 @@ impl Network {
         unimplemented!()
     }
 
+    /// Updates the GrandPa neighbor packet sent to peers, which indicates the authorities set id
+    /// and the height of the latest finalized block.
+    ///
+    /// Peers only gossip the GrandPa commits that are relevant according to this packet.
+    pub fn set_grandpa_neighbor_packet(&mut self, packet: grandpa::gossip::NeighborPacket) {
+        self.swarm.set_grandpa_neighbor_packet(packet);
+    }
+
+    /// Sends a GrandPa message to the given peer, for example a response to a catch-up request.
+    pub fn send_grandpa_message(
+        &mut self,
+        peer_id: &PeerId,
+        message: &grandpa::gossip::GossipMessage,
+    ) {
+        self.swarm.send_grandpa_message(peer_id, message);
+    }
+
+    /// Sends a GrandPa message, for example a vote or a commit, to all the peers we're connected
+    /// to.
+    pub fn broadcast_grandpa_message(&mut self, message: &grandpa::gossip::GossipMessage) {
+        self.swarm.broadcast_grandpa_message(message);
+    }
+
     /// Starts a block request on the network.
     ///
     /// Despite being asynchronous, this method only *starts* the request and does not wait for a
@@ -311,6 +434,86 @@ impl Network {
         Ok(request_id)
     }
 
+    /// Starts a GrandPa warp sync request on the network.
+    ///
+    /// The remote is asked for the list of blocks that change the GrandPa authorities set, and
+    /// their justifications, starting after the finalized block whose hash is `begin`.
+    ///
+    /// Despite being asynchronous, this method only *starts* the request and does not wait for a
+    /// response to come back. The method will block only in situations where the CPU is
+    /// overwhelmed.
+    pub async fn start_warp_sync_request(
+        &mut self,
+        peer_id: &PeerId,
+        begin: [u8; 32],
+    ) -> Result<RequestId, ()> {
+        let request = schema::v1::warp_sync::WarpSyncRequest {
+            begin: begin.to_vec(),
+        };
+
+        let request_bytes = {
+            let mut buf = Vec::with_capacity(request.encoded_len());
+            if request.encode(&mut buf).is_err() {
+                return Err(());
+            }
+            buf
+        };
+
+        let request_id = self
+            .swarm
+            .send_request(
+                peer_id,
+                &format!("/{}/sync/warp", self.chain_spec_protocol_id),
+                request_bytes,
+            )
+            .map_err(|_| ())?;
+
+        self.request_types.insert(request_id, RequestTy::WarpSync);
+        Ok(request_id)
+    }
+
+    /// Starts a state request on the network.
+    ///
+    /// The remote is asked for a proof of the storage entries of the block whose hash is
+    /// `block_hash`, starting after the keys in `start`. See
+    /// [`state_sync::RequestAction::start`](crate::chain::sync::state_sync::RequestAction::start).
+    ///
+    /// Despite being asynchronous, this method only *starts* the request and does not wait for a
+    /// response to come back. The method will block only in situations where the CPU is
+    /// overwhelmed.
+    pub async fn start_state_request(
+        &mut self,
+        peer_id: &PeerId,
+        block_hash: [u8; 32],
+        start: &[Vec<u8>],
+    ) -> Result<RequestId, ()> {
+        let request = schema::v1::StateRequest {
+            block: block_hash.to_vec(),
+            start: start.to_vec(),
+            no_proof: false,
+        };
+
+        let request_bytes = {
+            let mut buf = Vec::with_capacity(request.encoded_len());
+            if request.encode(&mut buf).is_err() {
+                return Err(());
+            }
+            buf
+        };
+
+        let request_id = self
+            .swarm
+            .send_request(
+                peer_id,
+                &format!("/{}/state/2", self.chain_spec_protocol_id),
+                request_bytes,
+            )
+            .map_err(|_| ())?;
+
+        self.request_types.insert(request_id, RequestTy::State);
+        Ok(request_id)
+    }
+
     /// Starts a remote call request on the network.
     ///
     /// This requests a remote to perform a runtime call and return a proof of execution.
@@ -350,8 +553,25 @@ impl Network {
     pub async fn next_event(&mut self) -> Event {
         loop {
             match self.swarm.next_event().await {
-                SwarmEvent::Behaviour(behaviour::BehaviourOut::BlockAnnounce(header)) => {
-                    return Event::BlockAnnounce(header);
+                SwarmEvent::Behaviour(behaviour::BehaviourOut::BlockAnnounce {
+                    peer_id,
+                    header,
+                }) => {
+                    return Event::BlockAnnounce { peer_id, header };
+                }
+
+                SwarmEvent::Behaviour(behaviour::BehaviourOut::GrandpaCommit {
+                    peer_id,
+                    commit,
+                }) => {
+                    return Event::GrandpaCommit { peer_id, commit };
+                }
+
+                SwarmEvent::Behaviour(behaviour::BehaviourOut::GrandpaGossip {
+                    peer_id,
+                    message,
+                }) => {
+                    return Event::GrandpaGossip { peer_id, message };
                 }
 
                 SwarmEvent::Behaviour(behaviour::BehaviourOut::RequestFinished {
@@ -426,6 +646,52 @@ impl Network {
                                 result: Ok(blocks),
                             };
                         }
+                        RequestTy::WarpSync => {
+                            let response = match schema::v1::warp_sync::WarpSyncResponse::decode(
+                                &response_bytes[..],
+                            ) {
+                                Ok(r) => r,
+                                Err(_) => {
+                                    // TODO: proper error
+                                    return Event::WarpSyncRequestFinished {
+                                        id: request_id,
+                                        result: Err(()),
+                                    };
+                                }
+                            };
+
+                            return Event::WarpSyncRequestFinished {
+                                id: request_id,
+                                result: Ok(WarpSyncResponse {
+                                    fragments: response
+                                        .fragments
+                                        .into_iter()
+                                        .map(|fragment| WarpSyncFragment {
+                                            scale_encoded_header: fragment.header,
+                                            scale_encoded_justification: fragment.justification,
+                                        })
+                                        .collect(),
+                                    is_finished: response.is_finished,
+                                }),
+                            };
+                        }
+                        RequestTy::State => {
+                            let compact_proof =
+                                schema::v1::StateResponse::decode(&response_bytes[..])
+                                    .ok()
+                                    .and_then(|response| {
+                                        <Vec<Vec<u8>> as DecodeAll>::decode_all(&response.proof)
+                                            .ok()
+                                    });
+
+                            // TODO: proper error
+                            return Event::StateRequestFinished {
+                                id: request_id,
+                                result: compact_proof
+                                    .map(|compact_proof| StateResponse { compact_proof })
+                                    .ok_or(()),
+                            };
+                        }
                         RequestTy::Call => todo!(),
                     }
                 }
@@ -441,6 +707,20 @@ impl Network {
                                 result: Err(()),
                             };
                         }
+                        RequestTy::WarpSync => {
+                            // TODO: proper error
+                            return Event::WarpSyncRequestFinished {
+                                id: request_id,
+                                result: Err(()),
+                            };
+                        }
+                        RequestTy::State => {
+                            // TODO: proper error
+                            return Event::StateRequestFinished {
+                                id: request_id,
+                                result: Err(()),
+                            };
+                        }
                         RequestTy::Call => todo!(),
                     }
                 }
@@ -0,0 +1,166 @@
+// Substrate-lite
+// Copyright (C) 2019-2020  Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Internal module. Contains the builders of headers, keys, justifications and GrandPa commits
+//! that the tests of the various modules of this crate share.
+
+#![cfg(test)]
+
+use crate::{finality::grandpa, header};
+
+use core::convert::TryFrom as _;
+use ed25519_dalek::Signer as _;
+
+/// Builds an ed25519 key pair, as used by GrandPa authorities, derived from the given seed.
+pub(crate) fn ed25519_keypair(seed: u8) -> ed25519_dalek::Keypair {
+    let secret = ed25519_dalek::SecretKey::from_bytes(&[seed; 32]).unwrap();
+    let public = ed25519_dalek::PublicKey::from(&secret);
+    ed25519_dalek::Keypair { secret, public }
+}
+
+/// Builds an sr25519 key pair, as used by BABE authorities, derived from the given seed.
+pub(crate) fn sr25519_keypair(seed: u8) -> schnorrkel::Keypair {
+    schnorrkel::MiniSecretKey::from_bytes(&[seed; 32])
+        .unwrap()
+        .expand_to_keypair(schnorrkel::ExpansionMode::Ed25519)
+}
+
+/// Builds the SCALE encoding of a header whose state and extrinsics roots are zero.
+pub(crate) fn encode_header(
+    parent_hash: &[u8; 32],
+    number: u64,
+    digest: &[header::DigestItem],
+) -> Vec<u8> {
+    encode_header_with_state_root(parent_hash, number, &[0; 32], digest)
+}
+
+/// Builds the SCALE encoding of a header with the given state root, and whose extrinsics root
+/// is zero.
+pub(crate) fn encode_header_with_state_root(
+    parent_hash: &[u8; 32],
+    number: u64,
+    state_root: &[u8; 32],
+    digest: &[header::DigestItem],
+) -> Vec<u8> {
+    let mut out = parent_hash.to_vec();
+    out.extend_from_slice(&parity_scale_codec::Encode::encode(
+        &parity_scale_codec::Compact(number),
+    ));
+    out.extend_from_slice(state_root);
+    out.extend_from_slice(&[0; 32]);
+    out.extend_from_slice(&parity_scale_codec::Encode::encode(
+        &parity_scale_codec::Compact(u64::try_from(digest.len()).unwrap()),
+    ));
+    for item in digest {
+        for buffer in header::DigestItemRef::from(item).scale_encoding() {
+            out.extend_from_slice(buffer.as_ref());
+        }
+    }
+    out
+}
+
+/// Builds a GrandPa pre-commit for the given block, signed by `signer`.
+pub(crate) fn precommit(
+    signer: &ed25519_dalek::Keypair,
+    round: u64,
+    authorities_set_id: u64,
+    target_hash: &[u8; 32],
+    target_number: u64,
+) -> grandpa::voter::SignedVote {
+    let vote = grandpa::voter::Vote {
+        target_hash: *target_hash,
+        target_number: u32::try_from(target_number).unwrap(),
+    };
+
+    let message = grandpa::voter::SignedMessage {
+        round,
+        authorities_set_id,
+        message: grandpa::voter::Message::Precommit(vote),
+        signature: [0; 64],
+        authority_public_key: signer.public.to_bytes(),
+    };
+
+    grandpa::voter::SignedVote {
+        vote,
+        signature: signer.sign(&message.signed_payload()).to_bytes(),
+        authority_public_key: signer.public.to_bytes(),
+    }
+}
+
+/// Builds the SCALE encoding of a justification for the given block.
+pub(crate) fn encode_justification(
+    round: u64,
+    target_hash: &[u8; 32],
+    target_number: u64,
+    precommits: Vec<grandpa::voter::SignedVote>,
+    votes_ancestries: Vec<Vec<u8>>,
+) -> Vec<u8> {
+    grandpa::voter::Commit {
+        round,
+        // The authorities set isn't part of the encoding of a justification.
+        authorities_set_id: 0,
+        target_hash: *target_hash,
+        target_number: u32::try_from(target_number).unwrap(),
+        precommits,
+        votes_ancestries,
+    }
+    .scale_encoded_justification()
+}
+
+/// Builds the SCALE encoding of a justification for the given block, whose round is the number
+/// of the block, and that contains a single pre-commit for this block signed by the given
+/// authority of the given authorities set.
+pub(crate) fn justification_signed_by(
+    signer: &ed25519_dalek::Keypair,
+    authorities_set_id: u64,
+    number: u64,
+    hash: &[u8; 32],
+) -> Vec<u8> {
+    let precommit = precommit(signer, number, authorities_set_id, hash, number);
+    encode_justification(number, hash, number, vec![precommit], Vec::new())
+}
+
+/// Builds a GrandPa commit message for the given block.
+pub(crate) fn grandpa_commit(
+    round: u64,
+    authorities_set_id: u64,
+    target_hash: &[u8; 32],
+    target_number: u64,
+    precommits: Vec<grandpa::voter::SignedVote>,
+) -> grandpa::gossip::CommitMessage {
+    grandpa::gossip::CommitMessage {
+        round,
+        authorities_set_id,
+        target_hash: *target_hash,
+        target_number: u32::try_from(target_number).unwrap(),
+        precommits,
+    }
+}
+
+/// Builds a change of the list of GrandPa authorities to the given authority.
+pub(crate) fn grandpa_change_to(
+    authority: &ed25519_dalek::Keypair,
+    delay: u32,
+) -> header::GrandpaScheduledChange {
+    header::GrandpaScheduledChange {
+        next_authorities: vec![header::GrandpaAuthority {
+            public_key: authority.public.to_bytes(),
+            weight: 1,
+        }],
+        delay,
+    }
+}
@@ -83,19 +83,95 @@
 //! its ancestors. As such, the time spent calculating the Merkle value of the root node of a trie
 //! mostly depends on the number of modifications that are performed on it, and only a bit on the
 //! size of the trie.
+//!
+//! # Layouts
+//!
+//! The exact way node values are built and hashed is determined by the [`Layout`] of the trie.
+//! The hash function is configurable using [`HashFunction`]. Additionally, starting with
+//! [`StateVersion::V1`], storage values whose size is superior or equal to 33 bytes are no longer
+//! directly included in the node value of their node. Instead, only their hash is included.
+//!
+//! Substrate/Polkadot chains use [`HashFunction::Blake2`], while [`HashFunction::Keccak256`] is
+//! used for example when verifying proofs coming from Ethereum-like chains.
 
 use alloc::collections::BTreeMap;
 use core::{iter, mem};
 
+mod hasher;
 mod nibble;
 
 pub mod calculate_root;
+pub mod compact_proof;
+pub mod node_codec;
 pub mod node_value;
 pub mod proof_verify;
 pub mod trie_structure;
 
 pub use nibble::{bytes_to_nibbles, BytesToNibbles, Nibble, NibbleFromU8Error};
 
+/// Layout of a trie. Determines how the node values and Merkle values are calculated.
+///
+/// The default layout is [`StateVersion::V0`] with [`HashFunction::Blake2`].
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Hash)]
+pub struct Layout {
+    /// Hash function used to calculate the Merkle values of the nodes and, if applicable, the
+    /// hashes of the storage values.
+    pub hash_function: HashFunction,
+    /// Version of the format of the node values.
+    pub state_version: StateVersion,
+}
+
+impl Layout {
+    /// Returns true if a storage value of the given length is included in the node value of its
+    /// node in the form of its hash rather than directly.
+    pub fn is_value_hashed(&self, value_len: usize) -> bool {
+        match self.state_version {
+            StateVersion::V0 => false,
+            StateVersion::V1 => value_len >= 33,
+        }
+    }
+}
+
+/// Hash function used by a trie.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum HashFunction {
+    /// 256 bits variant of the blake2b hash function.
+    Blake2,
+    /// 256 bits variant of the Keccak hash function.
+    Keccak256,
+}
+
+impl HashFunction {
+    /// Hashes the given data using this hash function.
+    pub fn hash(&self, data: &[u8]) -> [u8; 32] {
+        let mut hasher = hasher::Hasher::new(*self);
+        hasher.update(data);
+        hasher.finalize()
+    }
+}
+
+impl Default for HashFunction {
+    fn default() -> Self {
+        HashFunction::Blake2
+    }
+}
+
+/// Version of the format of the node values of a trie.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum StateVersion {
+    /// Storage values are always included in the node value of their node.
+    V0,
+    /// Storage values whose length is superior or equal to 33 bytes are hashed, and only their
+    /// hash is included in the node value of their node.
+    V1,
+}
+
+impl Default for StateVersion {
+    fn default() -> Self {
+        StateVersion::V0
+    }
+}
+
 /// Radix-16 Merkle-Patricia trie.
 // TODO: probably useless, remove
 pub struct Trie {
@@ -109,13 +185,22 @@ pub struct Trie {
     ///
     /// All the keys have an even number of nibbles.
     entries: BTreeMap<Vec<u8>, Vec<u8>>,
+
+    /// Layout used when calculating the Merkle value of the root node.
+    layout: Layout,
 }
 
 impl Trie {
-    /// Builds a new empty [`Trie`].
+    /// Builds a new empty [`Trie`] using the default [`Layout`].
     pub fn new() -> Trie {
+        Self::with_layout(Default::default())
+    }
+
+    /// Builds a new empty [`Trie`] using the given [`Layout`].
+    pub fn with_layout(layout: Layout) -> Trie {
         Trie {
             entries: BTreeMap::new(),
+            layout,
         }
     }
 
@@ -146,7 +231,7 @@ impl Trie {
         &self,
         mut cache: Option<&mut calculate_root::CalculationCache>,
     ) -> [u8; 32] {
-        let mut calculation = calculate_root::root_merkle_value({
+        let mut calculation = calculate_root::root_merkle_value(self.layout, {
             if let Some(cache) = &mut cache {
                 Some(mem::replace(
                     cache,
@@ -180,9 +265,9 @@ impl Trie {
     }
 }
 
-/// Returns the Merkle value of the root of an empty trie.
+/// Returns the Merkle value of the root of an empty trie using the default [`Layout`].
 pub fn empty_trie_merkle_value() -> [u8; 32] {
-    let mut calculation = calculate_root::root_merkle_value(None);
+    let mut calculation = calculate_root::root_merkle_value(Default::default(), None);
 
     loop {
         match calculation {
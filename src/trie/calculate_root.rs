@@ -35,7 +35,7 @@
 //! storage.insert(b"foo".to_vec(), b"bar".to_vec());
 //!
 //! let trie_root = {
-//!     let mut calculation = calculate_root::root_merkle_value(None);
+//!     let mut calculation = calculate_root::root_merkle_value(Default::default(), None);
 //!     loop {
 //!         match calculation {
 //!             calculate_root::RootMerkleValueCalculation::Finished { hash, .. } => break hash,
@@ -63,10 +63,13 @@
 //!
 //! When using a cache, be careful to properly invalidate cache entries whenever you perform
 //! modifications on the trie associated to it.
+//!
+//! The [`Layout`](super::Layout) passed to [`root_merkle_value`] determines how the Merkle values
+//! are calculated.
 
 use super::{
     nibble::{bytes_to_nibbles, Nibble},
-    node_value, trie_structure,
+    node_value, trie_structure, Layout,
 };
 
 use core::{convert::TryFrom as _, fmt, iter};
@@ -79,6 +82,10 @@ pub struct CalculationCache {
     /// Structure of the trie.
     /// If `Some`, the structure is either fully conforming to the trie.
     structure: Option<trie_structure::TrieStructure<CacheEntry>>,
+
+    /// Layout that was used to calculate the Merkle values in [`CalculationCache::structure`].
+    /// `None` if no calculation has been performed with this cache yet.
+    layout: Option<Layout>,
 }
 
 /// Custom data stored in each node in [`CalculationCache::structure`].
@@ -90,7 +97,10 @@ struct CacheEntry {
 impl CalculationCache {
     /// Builds a new empty cache.
     pub const fn empty() -> Self {
-        CalculationCache { structure: None }
+        CalculationCache {
+            structure: None,
+            layout: None,
+        }
     }
 
     /// Notify the cache that a storage value at the given key has been added, modified or removed.
@@ -213,10 +223,18 @@ impl fmt::Debug for CalculationCache {
 }
 
 /// Start calculating the Merkle value of the root node.
-pub fn root_merkle_value(cache: Option<CalculationCache>) -> RootMerkleValueCalculation {
+///
+/// If the cache was previously used with a different [`Layout`], its content is discarded.
+pub fn root_merkle_value(
+    layout: Layout,
+    cache: Option<CalculationCache>,
+) -> RootMerkleValueCalculation {
     // The calculation that we perform relies on storing values in the cache and reloading them
     // afterwards. If the user didn't pass any cache, we create a temporary one.
-    let cache_or_temporary = if let Some(mut cache) = cache {
+    let mut cache_or_temporary = if let Some(mut cache) = cache {
+        if cache.layout.is_some() && cache.layout != Some(layout) {
+            cache.structure = None;
+        }
         if let Some(structure) = &mut cache.structure {
             if structure.capacity() > structure.len().saturating_mul(2) {
                 structure.shrink_to_fit();
@@ -226,8 +244,10 @@ pub fn root_merkle_value(cache: Option<CalculationCache>) -> RootMerkleValueCalc
     } else {
         CalculationCache::empty()
     };
+    cache_or_temporary.layout = Some(layout);
 
     CalcInner {
+        layout,
         cache: cache_or_temporary,
         current: None,
         coming_from_child: false,
@@ -268,6 +288,9 @@ pub enum RootMerkleValueCalculation {
 /// Due to this order of iteration, we traverse each node which lack a Merkle value twice, and
 /// the Merkle value is calculated that second time.
 struct CalcInner {
+    /// Layout of the trie.
+    layout: Layout,
+
     /// Contains the intermediary steps of the calculation. `None` if the calculation is finished.
     cache: CalculationCache,
 
@@ -302,6 +325,7 @@ impl CalcInner {
                             ty: node_value::NodeTy::Root { key: iter::empty() },
                             children: (0..16).map(|_| None),
                             stored_value: None::<Vec<u8>>,
+                            layout: self.layout,
                         });
 
                         return RootMerkleValueCalculation::Finished {
@@ -386,6 +410,7 @@ impl CalcInner {
                         }
                     }),
                     stored_value: None::<Vec<u8>>,
+                    layout: self.layout,
                 });
 
                 current.user_data().merkle_value = Some(merkle_value);
@@ -477,6 +502,7 @@ impl StorageValue {
                 }
             }),
             stored_value,
+            layout: self.calculation.layout,
         });
 
         current.user_data().merkle_value = Some(merkle_value);
@@ -484,8 +510,335 @@ impl StorageValue {
     }
 }
 
-// TODO: tests
+/// Calculates the Merkle value of the root node of a trie containing the given entries,
+/// spreading the calculation over up to `num_threads` threads.
+///
+/// Contrary to [`root_merkle_value`], this function requires all the keys and values of the
+/// trie to be known ahead of time, and doesn't support any cache. It is meant to be used when
+/// calculating the root of a large trie from scratch, such as the genesis storage of a chain.
+///
+/// Since the Merkle values of the children of a node don't depend on each other, the subtrees
+/// below each node are processed in parallel. The result is always the same as the one of
+/// [`root_merkle_value`].
+///
+/// If the same key is found multiple times in `entries`, only its first value is used.
+///
+/// > **Note**: If spawning a thread fails, for example on platforms that don't support threads,
+/// >           the calculation continues in the current thread.
+pub fn root_merkle_value_parallel<'a>(
+    layout: Layout,
+    entries: impl Iterator<Item = (&'a [u8], &'a [u8])>,
+    num_threads: usize,
+) -> [u8; 32] {
+    let mut entries = entries.collect::<Vec<_>>();
+    entries.sort_by_key(|(key, _)| *key);
+    entries.dedup_by_key(|(key, _)| *key);
+
+    if entries.is_empty() {
+        return node_value::calculate_merkle_root(node_value::Config {
+            ty: node_value::NodeTy::Root { key: iter::empty() },
+            children: (0..16).map(|_| None),
+            stored_value: None::<Vec<u8>>,
+            layout,
+        })
+        .into();
+    }
+
+    subtree_merkle_value(layout, &entries, 0, num_threads.max(1)).into()
+}
+
+/// Calculates the Merkle value of the node that is the closest common ancestor of all the keys of
+/// `entries`.
+///
+/// `entries` must be non-empty, sorted by key, and without duplicate key. The first `start`
+/// nibbles of all keys must be equal. If `start` is 0, the node is considered as the root node.
+fn subtree_merkle_value(
+    layout: Layout,
+    entries: &[(&[u8], &[u8])],
+    start: usize,
+    num_threads: usize,
+) -> node_value::Output {
+    debug_assert!(!entries.is_empty());
+
+    // Since the entries are sorted, the longest prefix shared by all keys is the longest prefix
+    // shared by the first and the last keys.
+    let first_key = entries[0].0;
+    let common_prefix_len = {
+        let last_key = entries[entries.len() - 1].0;
+        let mut len = first_key
+            .iter()
+            .zip(last_key.iter())
+            .take_while(|(a, b)| a == b)
+            .count()
+            * 2;
+        if len < first_key.len() * 2
+            && len < last_key.len() * 2
+            && nibble_at(first_key, len) == nibble_at(last_key, len)
+        {
+            len += 1;
+        }
+        len
+    };
+    debug_assert!(common_prefix_len >= start);
+
+    // If a key is equal to the common prefix, it is necessarily the first one, and the node has
+    // a storage value.
+    let (stored_value, children_entries) = if first_key.len() * 2 == common_prefix_len {
+        (Some(entries[0].1), &entries[1..])
+    } else {
+        (None, entries)
+    };
+
+    // Split the rest of the entries between the children of the node.
+    let mut children_groups = Vec::with_capacity(16);
+    {
+        let mut remaining = children_entries;
+        while !remaining.is_empty() {
+            let child_index = nibble_at(remaining[0].0, common_prefix_len);
+            let group_len = remaining
+                .iter()
+                .position(|(key, _)| nibble_at(key, common_prefix_len) != child_index)
+                .unwrap_or(remaining.len());
+            children_groups.push((child_index, &remaining[..group_len]));
+            remaining = &remaining[group_len..];
+        }
+    }
+
+    // Calculate the Merkle values of the children, possibly in parallel.
+    let mut children = (0..16)
+        .map(|_| None)
+        .collect::<Vec<Option<node_value::Output>>>();
+    if num_threads <= 1 || children_groups.len() <= 1 {
+        for (child_index, group) in children_groups {
+            children[usize::from(u8::from(child_index))] = Some(subtree_merkle_value(
+                layout,
+                group,
+                common_prefix_len + 1,
+                num_threads,
+            ));
+        }
+    } else {
+        let num_workers = num_threads.min(children_groups.len());
+        let threads_per_child = (num_threads / children_groups.len()).max(1);
+
+        let results = std::thread::scope(|scope| {
+            let mut workers_groups = (0..num_workers).map(|_| Vec::new()).collect::<Vec<_>>();
+            for (n, group) in children_groups.into_iter().enumerate() {
+                workers_groups[n % num_workers].push(group);
+            }
+
+            let calculate = move |groups: Vec<(Nibble, &[(&[u8], &[u8])])>| {
+                groups
+                    .into_iter()
+                    .map(|(child_index, group)| {
+                        let merkle_value = subtree_merkle_value(
+                            layout,
+                            group,
+                            common_prefix_len + 1,
+                            threads_per_child,
+                        );
+                        (child_index, merkle_value)
+                    })
+                    .collect::<Vec<_>>()
+            };
+
+            let mut results = Vec::with_capacity(16);
+            let mut handles = Vec::with_capacity(num_workers);
+            for groups in workers_groups {
+                // If spawning the thread fails, `groups` are lost. For this reason, we pass a
+                // clone of it to the new thread.
+                match std::thread::Builder::new().spawn_scoped(scope, {
+                    let groups = groups.clone();
+                    move || calculate(groups)
+                }) {
+                    Ok(handle) => handles.push(handle),
+                    Err(_) => results.extend(calculate(groups)),
+                }
+            }
+
+            for handle in handles {
+                results.extend(handle.join().unwrap());
+            }
+            results
+        });
+
+        for (child_index, merkle_value) in results {
+            children[usize::from(u8::from(child_index))] = Some(merkle_value);
+        }
+    }
+
+    let key_nibbles = (start..common_prefix_len).map(|n| nibble_at(first_key, n));
+    node_value::calculate_merkle_root(node_value::Config {
+        ty: if start == 0 {
+            node_value::NodeTy::Root { key: key_nibbles }
+        } else {
+            node_value::NodeTy::NonRoot {
+                partial_key: key_nibbles,
+            }
+        },
+        children: children.iter().map(|c| c.as_ref()),
+        stored_value,
+        layout,
+    })
+}
+
+/// Returns the nibble of `key` at the given index.
+///
+/// # Panic
+///
+/// Panics if `index >= key.len() * 2`.
+///
+fn nibble_at(key: &[u8], index: usize) -> Nibble {
+    let byte = key[index / 2];
+    let nibble = if index % 2 == 0 {
+        byte >> 4
+    } else {
+        byte & 0xf
+    };
+    Nibble::try_from(nibble).unwrap()
+}
 
 // TODO: add a test that generates a random trie, calculates its root using a cache, modifies it
 // randomly, invalidating the cache in the process, then calculates the root again, once with
 // cache and once without cache, and compares the two values
+
+#[cfg(test)]
+mod tests {
+    use super::super::{HashFunction, Layout, StateVersion, Trie};
+    use core::iter;
+    use rand::{
+        distributions::{Distribution as _, Uniform},
+        seq::SliceRandom as _,
+    };
+
+    #[test]
+    fn parallel_matches_sequential() {
+        for _ in 0..64 {
+            let entries = {
+                let mut list = vec![Vec::new()];
+                for _ in 0..Uniform::new_inclusive(0, 6).sample(&mut rand::thread_rng()) {
+                    for elem in list.clone().into_iter() {
+                        for _ in 0..Uniform::new_inclusive(0, 3).sample(&mut rand::thread_rng()) {
+                            let mut elem = elem.clone();
+                            for _ in 0..Uniform::new_inclusive(0, 2).sample(&mut rand::thread_rng())
+                            {
+                                elem.push(rand::random::<u8>() & 0x11);
+                            }
+                            list.push(elem);
+                        }
+                    }
+                }
+                let mut list = list
+                    .into_iter()
+                    .map(|key| {
+                        let value = (0..Uniform::new_inclusive(0, 40)
+                            .sample(&mut rand::thread_rng()))
+                            .map(|_| rand::random::<u8>())
+                            .collect::<Vec<_>>();
+                        (key, value)
+                    })
+                    .collect::<Vec<_>>();
+                list.shuffle(&mut rand::thread_rng());
+                list
+            };
+
+            let layout = Layout {
+                hash_function: if rand::random() {
+                    HashFunction::Blake2
+                } else {
+                    HashFunction::Keccak256
+                },
+                state_version: if rand::random() {
+                    StateVersion::V0
+                } else {
+                    StateVersion::V1
+                },
+            };
+
+            let mut trie = Trie::with_layout(layout);
+            // Insert in reverse order, so that the first value of each key is the one that ends
+            // up in the trie, like for `root_merkle_value_parallel`.
+            for (key, value) in entries.iter().rev() {
+                trie.insert(key, value.clone());
+            }
+            let expected = trie.root_merkle_value(None);
+
+            for num_threads in &[1, 2, 5, 16] {
+                let obtained = super::root_merkle_value_parallel(
+                    layout,
+                    entries.iter().map(|(k, v)| (&k[..], &v[..])),
+                    *num_threads,
+                );
+                assert_eq!(obtained, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn parallel_empty() {
+        assert_eq!(
+            super::root_merkle_value_parallel(Default::default(), iter::empty(), 4),
+            super::super::empty_trie_merkle_value()
+        );
+    }
+
+    #[test]
+    fn cache_discarded_on_layout_change() {
+        let mut trie = Trie::new();
+        trie.insert(b"foo", vec![0xaa; 64]);
+        trie.insert(b"foobar", vec![0xbb; 64]);
+
+        let mut trie_v1 = Trie::with_layout(Layout {
+            hash_function: HashFunction::Blake2,
+            state_version: StateVersion::V1,
+        });
+        trie_v1.insert(b"foo", vec![0xaa; 64]);
+        trie_v1.insert(b"foobar", vec![0xbb; 64]);
+
+        let mut cache = super::CalculationCache::empty();
+        let v0 = trie.root_merkle_value(Some(&mut cache));
+        let v1 = trie_v1.root_merkle_value(Some(&mut cache));
+        assert_ne!(v0, v1);
+        assert_eq!(v1, trie_v1.root_merkle_value(None));
+    }
+
+    #[test]
+    fn parallel_matches_sequential_on_genesis_specs() {
+        let specs: &[&[u8]] = &[
+            include_bytes!("../../bin/flaming-fir.json"),
+            include_bytes!("../../bin/kusama.json"),
+            include_bytes!("../../bin/westend.json"),
+        ];
+
+        for spec in specs {
+            let chain_spec = crate::chain_spec::ChainSpec::from_json_bytes(spec).unwrap();
+            let storage = chain_spec
+                .genesis_storage()
+                .collect::<std::collections::BTreeMap<_, _>>();
+
+            let sequential = {
+                let mut calculation = super::root_merkle_value(Default::default(), None);
+                loop {
+                    match calculation {
+                        super::RootMerkleValueCalculation::Finished { hash, .. } => break hash,
+                        super::RootMerkleValueCalculation::AllKeys(keys) => {
+                            calculation = keys.inject(storage.keys().map(|k| k.iter().cloned()));
+                        }
+                        super::RootMerkleValueCalculation::StorageValue(value) => {
+                            let key = value.key().collect::<Vec<u8>>();
+                            calculation = value.inject(storage.get(&key[..]));
+                        }
+                    }
+                }
+            };
+
+            let parallel = super::root_merkle_value_parallel(
+                Default::default(),
+                chain_spec.genesis_storage(),
+                8,
+            );
+
+            assert_eq!(sequential, parallel);
+        }
+    }
+}
@@ -0,0 +1,576 @@
+// Substrate-lite
+// Copyright (C) 2019-2020  Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Compact trie proofs.
+//!
+//! A compact proof contains the same information as a regular proof (as accepted by the
+//! [`proof_verify`](super::proof_verify) module), but takes less space.
+//!
+//! # Details
+//!
+//! In a regular proof, the node value of each node contains the Merkle values of its children.
+//! When a child is itself included in the proof, its Merkle value is redundant, as it can be
+//! recalculated from the node value of that child.
+//!
+//! A compact proof is a list of node values ordered in a depth-first manner, starting from the
+//! root node. Parents are always found before their children, and children are found in the
+//! order of their index. When the node value of a child is included in the proof, the Merkle
+//! value of that child in the node value of its parent is replaced with an empty Merkle value.
+//!
+//! Similarly, when the trie uses [`StateVersion::V1`](super::StateVersion::V1) and a node only
+//! contains the hash of its storage value, the storage value can be included in the proof
+//! instead of its hash. In that situation, the node value is prefixed with the byte
+//! `0b0000_0001`, its storage value is replaced with an empty unhashed storage value, and the
+//! storage value itself is found right after it in the proof.
+//!
+//! Contrary to regular proofs, the list of node values of a compact proof can't contain unused
+//! entries, and multiple compact proofs can't be merged into one by concatenating them.
+//!
+//! Use [`decode`] to convert a compact proof into a regular proof, and [`encode`] to do the
+//! opposite. [`decode_concatenated`] decodes multiple compact proofs found one after the other,
+//! which is how Substrate encodes proofs that cover child tries. [`verify_proof`] is a shortcut that directly verifies a compact proof.
+
+use super::{node_codec, proof_verify, Layout};
+
+use alloc::vec::Vec;
+use core::convert::TryFrom as _;
+
+/// Byte prefixed to a node value when its storage value follows it in the compact proof.
+const ESCAPE_HEADER: u8 = 0b0000_0001;
+
+/// Configuration to pass to [`verify_proof`].
+pub struct Config<'a, I> {
+    /// Key whose storage value needs to be found.
+    pub requested_key: &'a [u8],
+
+    /// Merkle value (or node value) of the root node of the trie.
+    pub trie_root_hash: &'a [u8; 32],
+
+    /// Entries of the compact proof, in order.
+    pub proof: I,
+
+    /// Layout of the trie. Determines the hash function used to calculate the Merkle values.
+    pub layout: Layout,
+}
+
+/// Find the storage value of the requested key (as designated by [`Config::requested_key`]).
+///
+/// Returns an error if the proof couldn't be verified.
+/// If the proof could be verified and the key has an associated storage value, `Ok(Some(_))` is
+/// returned, containing that storage value.
+/// If the proof could be verified but the key does not have an associated storage value,
+/// `Ok(None)` is returned.
+///
+/// Contrary to [`proof_verify::verify_proof`], the entire proof is decoded, and an error is
+/// returned if any of its entries is invalid.
+pub fn verify_proof<'a>(
+    config: Config<'a, impl Iterator<Item = &'a [u8]>>,
+) -> Result<Option<Vec<u8>>, Error> {
+    let decoded = decode(config.layout, config.proof).map_err(Error::Decode)?;
+
+    if decoded.trie_root_hash != *config.trie_root_hash {
+        return Err(Error::TrieRootMismatch);
+    }
+
+    let value = proof_verify::verify_proof(proof_verify::Config {
+        requested_key: config.requested_key,
+        trie_root_hash: config.trie_root_hash,
+        proof: decoded.proof.iter().map(|entry| &entry[..]),
+        layout: config.layout,
+    })
+    .map_err(Error::Verify)?;
+
+    Ok(value.map(|v| v.to_vec()))
+}
+
+/// Possible error returned by [`verify_proof`].
+#[derive(Debug, derive_more::Display)]
+pub enum Error {
+    /// Failed to decode the compact proof.
+    #[display(fmt = "Failed to decode the compact proof: {}", _0)]
+    Decode(DecodeError),
+    /// The root of the compact proof doesn't match the expected trie root.
+    TrieRootMismatch,
+    /// Failed to verify the decoded proof.
+    #[display(fmt = "{}", _0)]
+    Verify(proof_verify::Error),
+}
+
+/// Compact proof converted into a regular proof. See [`decode`].
+#[derive(Debug, Clone)]
+pub struct Decoded {
+    /// Merkle value of the root node of the trie.
+    pub trie_root_hash: [u8; 32],
+
+    /// List of node values and storage values, in the format accepted by
+    /// [`proof_verify::Config::proof`].
+    pub proof: Vec<Vec<u8>>,
+}
+
+/// Converts a compact proof into a regular proof, and calculates the Merkle value of the root
+/// node of the trie.
+///
+/// The entries of the returned regular proof are in post-order: the children of a node, and its
+/// storage value if it is hashed, are found before the node itself. The root node is therefore
+/// always the last entry.
+pub fn decode<'a>(
+    layout: Layout,
+    compact_proof: impl IntoIterator<Item = &'a [u8]>,
+) -> Result<Decoded, DecodeError> {
+    let mut compact_proof = compact_proof.into_iter();
+    let mut proof = Vec::new();
+    let trie_root_hash = decode_one(layout, &mut compact_proof, &mut proof)?;
+
+    if compact_proof.next().is_some() {
+        return Err(DecodeError::TrailingEntries);
+    }
+
+    Ok(Decoded {
+        trie_root_hash,
+        proof,
+    })
+}
+
+/// Converts a list of compact proofs concatenated one after the other into a list of regular
+/// proofs.
+///
+/// Substrate encodes a proof that covers child tries in this format: the compact proof of the
+/// main trie is followed with the compact proofs of the child tries, if any.
+///
+/// The returned list contains one element per compact proof, in the same order as they are
+/// found in `compact_proof`.
+pub fn decode_concatenated<'a>(
+    layout: Layout,
+    compact_proof: impl IntoIterator<Item = &'a [u8]>,
+) -> Result<Vec<Decoded>, DecodeError> {
+    let mut compact_proof = compact_proof.into_iter().peekable();
+    let mut out = Vec::with_capacity(1);
+
+    loop {
+        let mut proof = Vec::new();
+        let trie_root_hash = decode_one(layout, &mut compact_proof, &mut proof)?;
+        out.push(Decoded {
+            trie_root_hash,
+            proof,
+        });
+
+        if compact_proof.peek().is_none() {
+            return Ok(out);
+        }
+    }
+}
+
+/// Reads the compact proof of a single trie from `compact_proof`, pushes the entries of the
+/// corresponding regular proof to `proof`, and returns the Merkle value of the root node.
+///
+/// The entries of `compact_proof` that follow the compact proof of the trie are left untouched.
+fn decode_one<'a>(
+    layout: Layout,
+    compact_proof: &mut impl Iterator<Item = &'a [u8]>,
+    proof: &mut Vec<Vec<u8>>,
+) -> Result<[u8; 32], DecodeError> {
+    // Nodes whose node value has been read from the proof but whose children haven't all been
+    // processed yet. The first element is the root node, and each element is a child of the
+    // previous one.
+    let mut stack = Vec::<InProgress>::with_capacity(16);
+
+    loop {
+        // Read the next node value from the compact proof and push it on top of the stack.
+        let node_value = compact_proof.next().ok_or(DecodeError::MissingEntry)?;
+        let (has_escape, node_value) = match node_value.split_first() {
+            Some((&ESCAPE_HEADER, rest)) => (true, rest),
+            _ => (false, node_value),
+        };
+
+        let decoded = node_codec::decode(node_value).map_err(DecodeError::InvalidNodeValue)?;
+
+        let storage_value_hash = if has_escape {
+            if decoded.storage_value != node_codec::StorageValue::Unhashed(&[]) {
+                return Err(DecodeError::UnexpectedStorageValue);
+            }
+            let storage_value = compact_proof.next().ok_or(DecodeError::MissingEntry)?;
+            let hash = layout.hash_function.hash(storage_value);
+            proof.push(storage_value.to_vec());
+            Some(hash)
+        } else {
+            None
+        };
+
+        stack.push(InProgress {
+            decoded,
+            storage_value_hash,
+            children: Default::default(),
+            next_child: 0,
+        });
+
+        // Pop the nodes of the stack whose children are all known, and propagate their Merkle
+        // value to their parent.
+        loop {
+            let top = stack.last_mut().unwrap();
+            if let Some(omitted_child) = (top.next_child..16)
+                .find(|n| top.decoded.children[usize::from(*n)] == Some(&[][..]))
+            {
+                // The next entry in the proof is the node value of `omitted_child`.
+                top.next_child = omitted_child;
+                break;
+            }
+
+            let node = stack.pop().unwrap();
+            let node_value = node.encode()?;
+
+            let parent = match stack.last_mut() {
+                Some(p) => p,
+                None => {
+                    // `node` is the root node.
+                    let trie_root_hash = layout.hash_function.hash(&node_value);
+                    proof.push(node_value);
+                    return Ok(trie_root_hash);
+                }
+            };
+
+            // Children whose node value is shorter than 32 bytes are always inlined in the node
+            // value of their parent and can't be omitted.
+            if node_value.len() < 32 {
+                return Err(DecodeError::InlineChildOmitted);
+            }
+
+            let merkle_value = layout.hash_function.hash(&node_value);
+            proof.push(node_value);
+            parent.children[usize::from(parent.next_child)] = Some(merkle_value);
+            parent.next_child += 1;
+        }
+    }
+}
+
+/// Possible error returned by [`decode`].
+#[derive(Debug, derive_more::Display)]
+pub enum DecodeError {
+    /// The proof is missing entries.
+    MissingEntry,
+    /// The proof contains entries after the one of the root node.
+    TrailingEntries,
+    /// One of the node values in the proof has an invalid format.
+    #[display(fmt = "Invalid node value: {}", _0)]
+    InvalidNodeValue(node_codec::Error),
+    /// A node value is prefixed with the escape header but doesn't have an empty storage value.
+    UnexpectedStorageValue,
+    /// A child whose node value is shorter than 32 bytes has been omitted from its parent.
+    InlineChildOmitted,
+}
+
+/// Node whose node value has been read from the compact proof during [`decode`].
+struct InProgress<'a> {
+    decoded: node_codec::Decoded<'a, node_codec::PartialKey<'a>>,
+    /// If `Some`, the storage value has been found in the proof and this is its hash.
+    storage_value_hash: Option<[u8; 32]>,
+    /// Merkle values of the children that were omitted from the node value and have since then
+    /// been calculated.
+    children: [Option<[u8; 32]>; 16],
+    /// Index of the next child to process.
+    next_child: u8,
+}
+
+impl<'a> InProgress<'a> {
+    /// Builds the node value of the node as found in a regular proof.
+    fn encode(self) -> Result<Vec<u8>, DecodeError> {
+        let mut children = self.decoded.children;
+        for (child, calculated) in children.iter_mut().zip(self.children.iter()) {
+            if let Some(calculated) = calculated {
+                *child = Some(&calculated[..]);
+            }
+        }
+
+        let storage_value = match &self.storage_value_hash {
+            Some(hash) => node_codec::StorageValue::Hashed(hash),
+            None => self.decoded.storage_value,
+        };
+
+        // Can only fail if a child Merkle value is too large, which `node_codec::decode` has
+        // already verified.
+        Ok(node_codec::encode(node_codec::Decoded {
+            partial_key: self.decoded.partial_key,
+            children,
+            storage_value,
+        })
+        .unwrap())
+    }
+}
+
+/// Converts a regular proof into a compact proof.
+///
+/// Only the entries of the regular proof that can be reached from the root node are included in
+/// the compact proof. Other entries are ignored.
+pub fn encode<'a>(
+    layout: Layout,
+    trie_root_hash: &[u8; 32],
+    proof: impl IntoIterator<Item = &'a [u8]>,
+) -> Result<Vec<Vec<u8>>, EncodeError> {
+    // Entries of the proof, indexed by their hash.
+    let entries = proof
+        .into_iter()
+        .map(|entry| (layout.hash_function.hash(entry), entry))
+        .collect::<hashbrown::HashMap<_, _, fnv::FnvBuildHasher>>();
+
+    let mut out = Vec::with_capacity(entries.len());
+
+    // Node values that must be added to `out`, in reverse order.
+    let mut to_visit = vec![*entries
+        .get(trie_root_hash)
+        .ok_or(EncodeError::TrieRootNotFound)?];
+
+    while let Some(node_value) = to_visit.pop() {
+        let mut decoded = node_codec::decode(node_value).map_err(EncodeError::InvalidNodeValue)?;
+
+        // Children whose node value is in the proof.
+        let mut omitted = Vec::with_capacity(16);
+        for child in decoded.children.iter_mut() {
+            let child_node_value = child
+                .and_then(|c| <&[u8; 32]>::try_from(c).ok())
+                .and_then(|hash| entries.get(hash));
+            if let Some(child_node_value) = child_node_value {
+                omitted.push(*child_node_value);
+                *child = Some(&[]);
+            }
+        }
+
+        // Storage value, if it is only present in the node value in the form of its hash and
+        // that it is in the proof.
+        let storage_value = match decoded.storage_value {
+            node_codec::StorageValue::Hashed(hash) => entries.get(hash).copied(),
+            _ => None,
+        };
+        if storage_value.is_some() {
+            decoded.storage_value = node_codec::StorageValue::Unhashed(&[]);
+        }
+
+        let mut compact_node_value = Vec::with_capacity(node_value.len() + 1);
+        if storage_value.is_some() {
+            compact_node_value.push(ESCAPE_HEADER);
+        }
+        // Can't fail, as `node_codec::decode` has succeeded.
+        compact_node_value.extend(node_codec::encode(decoded).unwrap());
+        out.push(compact_node_value);
+        out.extend(storage_value.map(|v| v.to_vec()));
+
+        to_visit.extend(omitted.into_iter().rev());
+    }
+
+    Ok(out)
+}
+
+/// Possible error returned by [`encode`].
+#[derive(Debug, derive_more::Display)]
+pub enum EncodeError {
+    /// Trie root wasn't found in the proof.
+    TrieRootNotFound,
+    /// One of the node values in the proof has an invalid format.
+    #[display(fmt = "Invalid node value: {}", _0)]
+    InvalidNodeValue(node_codec::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{node_codec, HashFunction, Layout, Nibble, StateVersion};
+    use core::{convert::TryFrom as _, iter};
+
+    fn polkadot_proof() -> (Vec<Vec<u8>>, Vec<u8>, [u8; 32]) {
+        // Same proof as in the `proof_verify` module.
+        let proof = vec![
+            hex::decode("7d01542596adb05d6140c170ac479edf7cfd5aa35357590acfe5d11a804d944e500d1456fdda7b8ec7f9e5c794cd83194f0593e4ea").unwrap(),
+            hex::decode("803f93804e4c6c4222b747e507008ef1def063bb0d2deeadf17ef4b10e71624d3a0cf81c80241f2c06f22ec58968fb68d432319e25e6c8faa3ad2c5ca9ee48f2e8ed158e2480ad8a68234932269846bc40240a47cfd8d8857b1d81e167bfb24c947a4cdad9e680c84590e39f8b79a2694ad2bf7e7258af686b472f38b064bbce7d08404931a430805c72f25b1b6304d16667e2766fa1a906cb081788eb4502787df7c3597412b17b806e21c5f1a24a196615b4e5b36d21280cdcc80098c1e2bce8eeaf301e9951767480424f1acd80ba074a2ce8d180bf3488a5ca91cb81fba96c8c3c1d33eacbb18160805e849d5c148ca361a55a2c9b384e17ce919e936ccb8011a4f72504e9f93db8cd80edd005a1495c70250d77f81c24c15a9919f034f7983df8e505e53a5af7b402138012a0dd90497b65312bda67ea15996578eeb3891bca8666951a326612418e3143").unwrap(),
+            hex::decode("80555d8043fb497c1b2a7b9e4feb59f410c1a29e28b2a628ff9c6003e080f6b9fadd95f9806e8d911b6818038eb7c8534af8e78e9920a1ab8d939c36d3e69b0a1e5928110b80ba4d3f543957f422b40c8e74af9de00acbeba8154afca57a7f80fbbcfebb1e4a803d1b8f5cf1788b294537b8fd2d34acec4646a7627c6cd3d2039af64ff5d1976d80e7620f21cf13964f29d34ba708c3b44ea45ea11c58fbbedda29d13470bc80ca080f98aae4f83d81bf15d88019e5c303d7c19d0524e84c714e05f61517cde0b138280d518faf566fdc4d045094abe372bb3bbecd4753f76db8c41ba9fc015558bf23a80908f991126d12ce7acd55508ff1e7dffa56f742401e1814fc1469658a78c7a7f8001b0a08da0c83253d5c0cb877286c062da2f530ae424fe2545377941fd016913").unwrap(),
+            hex::decode("80b3a780a29fac7f7dfae21d05d9506e7da6515b7fa1ad970ff876de35f1bec2599ec002805b6772dc6a4e7604c8d0652479f95b343607c2d9138c59eeb799d85bf43b6bbf803d12becb6a4b9919ddc7c5973d04eed7696c834f90c779fc1fcf7350ccc28d6b805f33ebcf191fddcf3b3f346ec336c105c74b40a4d35dfda0c592f2bea00084e980f764c733d6e35771a9b26a1fa86b9bec59742b046f698be6c140af1073897d3d80cd3bc8c3ce3cf8359f7371a13316f02fd22b02a3d327684a2b61f4a47e0022b880da752afaeb925d5300e45b851052c5f8a9c5aae884f15d64764edf961b8b22c880bf1fa9c7e4c94340dbafd75cbe016c980d0e5d5b4e76823fa11e61629014c34b804f54a15e5d51d02b84e8cae94c9833ae81e56b8f0b684d257f6f722ee66cadf98094833fb2dce8c78d443cd6786e0c01d8974a4b779c178ef5e66b49e021dd7f1a").unwrap(),
+            hex::decode("9f0c5d795d0297be56027a4b2464e33397609280f332ff556abf5daf0d34523df7c8cd1369bcb6adbb23a48093bf070a9711bf3480382934134aa919b59c16ff8de8d97a7fdcc2448ea327b26f44005d756d1785878081d634140b36ce031c4b6c6266e2a7c19d9a88e38fdd8ad23abd3db20e714f6980fde17041f22f09609d79dbe38dcccefcaac139c7a10fb23bd284c1c492b004fd80d287ad1d0ade65e64d3969f4ab85a37076816031438cea0bf8c33b7b2bc6c330").unwrap(),
+            hex::decode("9f03e6d3c1fb15805edfd024172ea4817dffff80152833e34a852e9751cfc0f954aeb835e1f843936ba9979853a40e439937255f806a36e0ad23fb3224fff6e6db62048463a7f27ccb92f65b4e348acd5a7aa3a0688027b6e099c11581fb2e8acf3b6b94eaed442277b9a74ce7f922f6e3bf2959867b80fd0cc2c846db6a9ed19a715d6c3cd46a48b7f409883c70b2d4c978b306de379e80ab008a78c340f5cc75d99cdb905951936686445c834719be21f7620b950dcd5c806d86af54d5dfb1c06f3fefdd5a430861c0d19e25fad4bad07c6e70d4a679f0b880f35edc5400b6661fb1e6fba7c599c8ba891458d14400030fa506999a1972369f80746cdaa0b7da2e9c3864971f50f12d9b4281f804d5a2dba6ebe06959b2a9fb47802ecfde11456423c87fed8068f414a5ba44ebe3ae91b06d14cc231a78d4aba68e80f655291833a49cf23d057bb15c42d377c55d50f5885329060b0aaab22283cbb1808c95fb2b62baf30718b8330ef68a527c97c1bc9960304353224d8a8ae88a79d58045c1b6d9904ae171d573bdcebaa05142d81648bdbeb16ceeddc54a0ed15d3e2b80a8ea193282fe85b6481707091c77c9218ea19de914e75950925fe86400fb0cb080c222ceab5355eaa41da807146f2e2df7ff648c3e8bbb6d8ee23274ba724551b18008f142dc3c59bf1151c829ecefea35919e80453db5e9669f5a73899aaa5166ee804f1d21fbdc0180c4de886bf40f91dfc2202b3eb6d42548d476908041dd617bb8").unwrap(),
+        ];
+
+        let requested_key = hex::decode("9c5d795d0297be56027a4b2464e3339763e6d3c1fb15805edfd024172ea4817d7081542596adb05d6140c170ac479edf7cfd5aa35357590acfe5d11a804d944e").unwrap();
+
+        let trie_root = {
+            let bytes =
+                hex::decode(&"29d0d972cd27cbc511e9589fcb7a4506d5eb6a9e8df205f00472e5ab354a4e17")
+                    .unwrap();
+            <[u8; 32]>::try_from(&bytes[..]).unwrap()
+        };
+
+        (proof, requested_key, trie_root)
+    }
+
+    #[test]
+    fn round_trip_polkadot() {
+        let (proof, requested_key, trie_root) = polkadot_proof();
+        let layout = Layout::default();
+
+        let compact = super::encode(layout, &trie_root, proof.iter().map(|p| &p[..])).unwrap();
+        assert_eq!(compact.len(), proof.len());
+        assert!(
+            compact.iter().map(|e| e.len()).sum::<usize>()
+                < proof.iter().map(|e| e.len()).sum::<usize>()
+        );
+
+        let decoded = super::decode(layout, compact.iter().map(|p| &p[..])).unwrap();
+        assert_eq!(decoded.trie_root_hash, trie_root);
+        let mut expected = proof.clone();
+        expected.sort();
+        let mut obtained = decoded.proof;
+        obtained.sort();
+        assert_eq!(obtained, expected);
+
+        let value = super::verify_proof(super::Config {
+            requested_key: &requested_key,
+            trie_root_hash: &trie_root,
+            proof: compact.iter().map(|p| &p[..]),
+            layout,
+        })
+        .unwrap();
+        assert_eq!(
+            value,
+            Some(hex::decode("0d1456fdda7b8ec7f9e5c794cd83194f0593e4ea").unwrap())
+        );
+    }
+
+    #[test]
+    fn unreachable_entries_ignored() {
+        let (mut proof, _, trie_root) = polkadot_proof();
+        let layout = Layout::default();
+        let expected = super::encode(layout, &trie_root, proof.iter().map(|p| &p[..])).unwrap();
+        proof.push(vec![0xaa; 40]);
+        let compact = super::encode(layout, &trie_root, proof.iter().map(|p| &p[..])).unwrap();
+        assert_eq!(compact, expected);
+    }
+
+    #[test]
+    fn hashed_storage_value() {
+        let layout = Layout {
+            hash_function: HashFunction::Keccak256,
+            state_version: StateVersion::V1,
+        };
+
+        let long_value = [0x42; 40];
+        let long_value_hash = layout.hash_function.hash(&long_value);
+
+        // Leaf with a hashed storage value, which is a child of the root.
+        let leaf = node_codec::encode(node_codec::Decoded {
+            partial_key: iter::once(Nibble::try_from(0).unwrap()),
+            children: [None; 16],
+            storage_value: node_codec::StorageValue::Hashed(&long_value_hash),
+        })
+        .unwrap();
+        let leaf_hash = layout.hash_function.hash(&leaf);
+
+        let mut children = [None; 16];
+        children[3] = Some(&leaf_hash[..]);
+        children[7] = Some(&b"inline"[..]);
+        let root = node_codec::encode(node_codec::Decoded {
+            partial_key: iter::empty(),
+            children,
+            storage_value: node_codec::StorageValue::Unhashed(b"foo"),
+        })
+        .unwrap();
+        let root_hash = layout.hash_function.hash(&root);
+
+        let proof = vec![leaf.clone(), long_value.to_vec(), root.clone()];
+        let compact = super::encode(layout, &root_hash, proof.iter().map(|p| &p[..])).unwrap();
+        assert_eq!(compact.len(), 3);
+        assert_eq!(compact[0].len(), root.len() - 32);
+        assert_eq!(compact[1][0], super::ESCAPE_HEADER);
+        assert_eq!(compact[2], long_value);
+
+        let decoded = super::decode(layout, compact.iter().map(|p| &p[..])).unwrap();
+        assert_eq!(decoded.trie_root_hash, root_hash);
+        assert_eq!(decoded.proof, vec![long_value.to_vec(), leaf, root]);
+
+        let value = super::verify_proof(super::Config {
+            requested_key: &[0x30],
+            trie_root_hash: &root_hash,
+            proof: compact.iter().map(|p| &p[..]),
+            layout,
+        })
+        .unwrap();
+        assert_eq!(value, Some(long_value.to_vec()));
+    }
+
+    #[test]
+    fn truncated_or_trailing_rejected() {
+        let (proof, _, trie_root) = polkadot_proof();
+        let layout = Layout::default();
+        let mut compact = super::encode(layout, &trie_root, proof.iter().map(|p| &p[..])).unwrap();
+
+        assert!(matches!(
+            super::decode(layout, compact[..compact.len() - 1].iter().map(|p| &p[..])),
+            Err(super::DecodeError::MissingEntry)
+        ));
+
+        compact.push(vec![0]);
+        assert!(matches!(
+            super::decode(layout, compact.iter().map(|p| &p[..])),
+            Err(super::DecodeError::TrailingEntries)
+        ));
+    }
+
+    #[test]
+    fn wrong_root_rejected() {
+        let (proof, requested_key, mut trie_root) = polkadot_proof();
+        let layout = Layout::default();
+        let compact = super::encode(layout, &trie_root, proof.iter().map(|p| &p[..])).unwrap();
+
+        trie_root[0] ^= 1;
+        assert!(matches!(
+            super::verify_proof(super::Config {
+                requested_key: &requested_key,
+                trie_root_hash: &trie_root,
+                proof: compact.iter().map(|p| &p[..]),
+                layout,
+            }),
+            Err(super::Error::TrieRootMismatch)
+        ));
+    }
+
+    #[test]
+    fn concatenated_proofs() {
+        let (proof, _, trie_root) = polkadot_proof();
+        let layout = Layout::default();
+        let compact = super::encode(layout, &trie_root, proof.iter().map(|p| &p[..])).unwrap();
+
+        // A trie made of a single leaf, whose node value is longer than 32 bytes.
+        let leaf = node_codec::encode(node_codec::Decoded {
+            partial_key: iter::once(Nibble::try_from(1).unwrap()),
+            children: [None; 16],
+            storage_value: node_codec::StorageValue::Unhashed(&[0xaa; 40]),
+        })
+        .unwrap();
+        let leaf_hash = layout.hash_function.hash(&leaf);
+
+        let concatenated = compact
+            .iter()
+            .chain(compact.iter())
+            .chain(iter::once(&leaf));
+        let decoded =
+            super::decode_concatenated(layout, concatenated.clone().map(|p| &p[..])).unwrap();
+        assert_eq!(decoded.len(), 3);
+        assert_eq!(decoded[0].trie_root_hash, trie_root);
+        assert_eq!(decoded[1].trie_root_hash, trie_root);
+        assert_eq!(decoded[1].proof, decoded[0].proof);
+        assert_eq!(decoded[2].trie_root_hash, leaf_hash);
+        assert_eq!(decoded[2].proof, vec![leaf.clone()]);
+
+        assert!(matches!(
+            super::decode(layout, concatenated.map(|p| &p[..])),
+            Err(super::DecodeError::TrailingEntries)
+        ));
+    }
+}
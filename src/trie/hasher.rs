@@ -0,0 +1,62 @@
+// Substrate-lite
+// Copyright (C) 2019-2020  Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Streaming hasher wrapping the hash functions supported by [`HashFunction`].
+
+use super::HashFunction;
+
+use tiny_keccak::Hasher as _;
+
+/// Hasher state. Data can be pushed to it with [`Hasher::update`].
+pub(super) enum Hasher {
+    Blake2(blake2_rfc::blake2b::Blake2b),
+    Keccak256(tiny_keccak::Keccak),
+}
+
+impl Hasher {
+    /// Initializes a new hasher for the given hash function.
+    pub(super) fn new(hash_function: HashFunction) -> Self {
+        match hash_function {
+            HashFunction::Blake2 => Hasher::Blake2(blake2_rfc::blake2b::Blake2b::new(32)),
+            HashFunction::Keccak256 => Hasher::Keccak256(tiny_keccak::Keccak::v256()),
+        }
+    }
+
+    /// Adds data to the hasher.
+    pub(super) fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Blake2(hasher) => hasher.update(data),
+            Hasher::Keccak256(hasher) => hasher.update(data),
+        }
+    }
+
+    /// Finishes the calculation and returns the hash.
+    pub(super) fn finalize(self) -> [u8; 32] {
+        match self {
+            Hasher::Blake2(hasher) => {
+                let mut out = [0; 32];
+                out.copy_from_slice(hasher.finalize().as_bytes());
+                out
+            }
+            Hasher::Keccak256(hasher) => {
+                let mut out = [0; 32];
+                hasher.finalize(&mut out);
+                out
+            }
+        }
+    }
+}
@@ -0,0 +1,716 @@
+// Substrate-lite
+// Copyright (C) 2019-2020  Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Encoding and decoding of the node value of a single trie node.
+//!
+//! > **Note**: For reminder, the node value of a node is the bytes representation of this node,
+//! >           while its Merkle value is the hash of the node value, or the node value directly
+//! >           if its length is smaller than 32 bytes.
+//!
+//! A node value is made of the following components, in this order:
+//!
+//! - A header, containing the type of node (leaf, branch with or without a storage value, and
+//! whether the storage value is hashed) and the length of the partial key in nibbles.
+//! - The partial key of the node, two nibbles per byte. If the number of nibbles is odd, the
+//! first byte only contains one nibble.
+//! - If the node has children, a 16 bits little-endian bitmap indicating which children exist.
+//! - If the node has a storage value, the SCALE-compact-encoded length of this storage value
+//! followed with the storage value itself. Alternatively, if the storage value is hashed (see
+//! [`StateVersion::V1`](super::StateVersion::V1)), the 32 bytes hash of the storage value.
+//! - For each child, the SCALE-compact-encoded length of the Merkle value of this child followed
+//! with this Merkle value.
+//!
+//! Use [`decode`] to turn a node value into a [`Decoded`], and [`encode`] to perform the
+//! opposite operation.
+//!
+//! # Example
+//!
+//! ```
+//! use substrate_lite::trie::node_codec;
+//!
+//! let node_value = [
+//!     195, 8, 193, 4, 4, 44, 104, 101, 108, 108, 111, 32, 119, 111, 114, 108, 100, 12, 102,
+//!     111, 111, 12, 98, 97, 114,
+//! ];
+//!
+//! let decoded = node_codec::decode(&node_value).unwrap();
+//! assert_eq!(
+//!     decoded.partial_key.clone().map(u8::from).collect::<Vec<_>>(),
+//!     vec![8, 12, 1]
+//! );
+//! assert_eq!(decoded.children[2], Some(&b"foo"[..]));
+//! assert_eq!(decoded.children[10], Some(&b"bar"[..]));
+//! assert_eq!(
+//!     decoded.storage_value,
+//!     node_codec::StorageValue::Unhashed(&b"hello world"[..])
+//! );
+//!
+//! // Encoding the decoded node gives back the original node value.
+//! assert_eq!(node_codec::encode(decoded).unwrap(), &node_value[..]);
+//! ```
+
+use super::nibble::Nibble;
+
+use core::{convert::TryFrom as _, fmt, iter};
+use parity_scale_codec::{CompactLen as _, Encode as _};
+
+/// Decoded node value. See [`decode`] and [`encode`].
+#[derive(Debug, Clone)]
+pub struct Decoded<'a, I> {
+    /// Partial key of the node, as an iterator of nibbles.
+    ///
+    /// For the root node, this is the key of the node.
+    pub partial_key: I,
+
+    /// Merkle values of the 16 possible children of the node. `None` if there is no child at this
+    /// index.
+    pub children: [Option<&'a [u8]>; 16],
+
+    /// Value of the node in the storage.
+    pub storage_value: StorageValue<'a>,
+}
+
+/// Storage value of a node, as found in its node value.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum StorageValue<'a> {
+    /// The node doesn't have any storage value.
+    None,
+    /// The storage value is directly included in the node value.
+    Unhashed(&'a [u8]),
+    /// Only the hash of the storage value is included in the node value. The hash function is
+    /// the one of the [`Layout`](super::Layout) of the trie.
+    Hashed(&'a [u8; 32]),
+}
+
+impl<'a> StorageValue<'a> {
+    /// Returns true if this is [`StorageValue::None`].
+    pub fn is_none(&self) -> bool {
+        matches!(self, StorageValue::None)
+    }
+
+    /// Returns true if this isn't [`StorageValue::None`].
+    pub fn is_some(&self) -> bool {
+        !self.is_none()
+    }
+}
+
+impl<'a, I> Decoded<'a, I> {
+    /// Returns a bitmap where each bit is `1` if there exists a child at the corresponding index.
+    pub fn children_bitmap(&self) -> u16 {
+        let mut children_bitmap = 0u16;
+        for (child_index, child) in self.children.iter().enumerate() {
+            if child.is_some() {
+                children_bitmap |= 1 << u32::try_from(child_index).unwrap();
+            }
+        }
+        children_bitmap
+    }
+}
+
+/// Decodes a node value found in a proof or in a database into its components.
+///
+/// The [`Decoded`] that is returned borrows from `node_value`. No allocation is performed.
+///
+/// An error is returned if `node_value` isn't a canonical encoding of a node. In other words, if
+/// this function returns `Ok`, then passing the [`Decoded`] to [`encode`] always gives back
+/// `node_value`.
+pub fn decode(mut node_value: &[u8]) -> Result<Decoded<'_, PartialKey<'_>>, Error> {
+    if node_value.is_empty() {
+        return Err(Error::Empty);
+    }
+
+    // The most significant bits of the header contain the type of node. The other bits contain
+    // the beginning of the length of the partial key.
+    let (kind, pk_len_mask) = match node_value[0] {
+        0b0000_0000 => {
+            // The only node that has neither children nor a storage value is the root node of an
+            // empty trie, whose node value is always exactly `[0]`.
+            if node_value.len() != 1 {
+                return Err(Error::InvalidHeader);
+            }
+
+            return Ok(Decoded {
+                partial_key: PartialKey {
+                    bytes: &[],
+                    start: 0,
+                    end: 0,
+                },
+                children: [None; 16],
+                storage_value: StorageValue::None,
+            });
+        }
+        0b0000_0001..=0b0000_1111 => return Err(Error::InvalidHeader),
+        0b0001_0000..=0b0001_1111 => (NodeKind::BranchHashedValue, 0b1111),
+        0b0010_0000..=0b0011_1111 => (NodeKind::LeafHashedValue, 0b1_1111),
+        0b0100_0000..=0b0111_1111 => (NodeKind::Leaf, 0b11_1111),
+        0b1000_0000..=0b1011_1111 => (NodeKind::BranchNoValue, 0b11_1111),
+        0b1100_0000..=0b1111_1111 => (NodeKind::BranchWithValue, 0b11_1111),
+    };
+
+    let has_children = matches!(
+        kind,
+        NodeKind::BranchNoValue | NodeKind::BranchWithValue | NodeKind::BranchHashedValue
+    );
+
+    // Length of the partial key, in nibbles.
+    let pk_len = {
+        let mut accumulator = usize::from(node_value[0] & pk_len_mask);
+        node_value = &node_value[1..];
+        let mut continue_iter = accumulator == usize::from(pk_len_mask);
+        while continue_iter {
+            if node_value.is_empty() {
+                return Err(Error::PartialKeyLenTooShort);
+            }
+            continue_iter = node_value[0] == 255;
+            accumulator = accumulator
+                .checked_add(usize::from(node_value[0]))
+                .ok_or(Error::PartialKeyLenOverflow)?;
+            node_value = &node_value[1..];
+        }
+        accumulator
+    };
+
+    // Length of the partial key, in bytes.
+    let pk_len_bytes = pk_len / 2 + pk_len % 2;
+    if node_value.len() < pk_len_bytes {
+        return Err(Error::PartialKeyTooShort);
+    }
+    // If the number of nibbles is odd, the padding nibble must be zero.
+    if pk_len % 2 != 0 && (node_value[0] & 0xf0) != 0 {
+        return Err(Error::InvalidPartialKeyPadding);
+    }
+    let partial_key = PartialKey {
+        bytes: &node_value[..pk_len_bytes],
+        start: pk_len % 2,
+        end: pk_len_bytes * 2,
+    };
+    node_value = &node_value[pk_len_bytes..];
+
+    // After the partial key, the node value optionally contains a bitfield of child nodes.
+    let children_bitmap = if has_children {
+        if node_value.len() < 2 {
+            return Err(Error::ChildrenBitmapTooShort);
+        }
+        let val = u16::from_le_bytes(<[u8; 2]>::try_from(&node_value[..2]).unwrap());
+        if val == 0 {
+            return Err(Error::EmptyChildrenBitmap);
+        }
+        node_value = &node_value[2..];
+        val
+    } else {
+        0
+    };
+
+    // Now at the storage value, if any.
+    let storage_value = match kind {
+        NodeKind::Leaf | NodeKind::BranchWithValue => {
+            let (node_value_update, value) = decode_scale_bytes(node_value)?;
+            node_value = node_value_update;
+            StorageValue::Unhashed(value)
+        }
+        NodeKind::LeafHashedValue | NodeKind::BranchHashedValue => {
+            if node_value.len() < 32 {
+                return Err(Error::ValueTooShort);
+            }
+            let hash = <&[u8; 32]>::try_from(&node_value[..32]).unwrap();
+            node_value = &node_value[32..];
+            StorageValue::Hashed(hash)
+        }
+        NodeKind::BranchNoValue => StorageValue::None,
+    };
+
+    // Finally, the Merkle values of the children.
+    let mut children = [None; 16];
+    for (child_index, child) in children.iter_mut().enumerate() {
+        if children_bitmap & (1 << child_index) == 0 {
+            continue;
+        }
+
+        let (node_value_update, merkle_value) = decode_scale_bytes(node_value)?;
+        if merkle_value.len() > 32 {
+            return Err(Error::ChildMerkleValueTooLarge);
+        }
+        node_value = node_value_update;
+        *child = Some(merkle_value);
+    }
+
+    if !node_value.is_empty() {
+        return Err(Error::TrailingData);
+    }
+
+    Ok(Decoded {
+        partial_key,
+        children,
+        storage_value,
+    })
+}
+
+/// Encodes the components of a node into a node value.
+///
+/// This is the opposite operation of [`decode`].
+///
+/// Returns an error if the node has neither children nor a storage value but has a non-empty
+/// partial key, or if the Merkle value of a child is longer than 32 bytes.
+pub fn encode(
+    decoded: Decoded<impl ExactSizeIterator<Item = Nibble>>,
+) -> Result<Vec<u8>, EncodeError> {
+    let children_bitmap = decoded.children_bitmap();
+    let has_children = children_bitmap != 0;
+    let mut partial_key = decoded.partial_key;
+
+    if decoded
+        .children
+        .iter()
+        .filter_map(|c| *c)
+        .any(|c| c.len() > 32)
+    {
+        return Err(EncodeError::ChildMerkleValueTooLarge);
+    }
+
+    let kind = match (decoded.storage_value, has_children) {
+        (StorageValue::None, false) => {
+            // Only the root node of an empty trie can be in this situation.
+            if partial_key.len() != 0 {
+                return Err(EncodeError::NoChildrenNoValue);
+            }
+            return Ok(vec![0]);
+        }
+        (StorageValue::Unhashed(_), false) => NodeKind::Leaf,
+        (StorageValue::Hashed(_), false) => NodeKind::LeafHashedValue,
+        (StorageValue::None, true) => NodeKind::BranchNoValue,
+        (StorageValue::Unhashed(_), true) => NodeKind::BranchWithValue,
+        (StorageValue::Hashed(_), true) => NodeKind::BranchHashedValue,
+    };
+
+    let mut out = Vec::with_capacity(
+        2 + partial_key.len() / 2
+            + 2
+            + match decoded.storage_value {
+                StorageValue::None => 0,
+                StorageValue::Unhashed(v) => v.len() + 5,
+                StorageValue::Hashed(_) => 32,
+            }
+            + decoded
+                .children
+                .iter()
+                .filter_map(|c| *c)
+                .map(|c| c.len() + 1)
+                .sum::<usize>(),
+    );
+
+    out.extend(encode_header(kind, partial_key.len()));
+
+    // Partial key, two nibbles per byte. If the number of nibbles is odd, the first byte only
+    // contains one nibble.
+    if partial_key.len() % 2 != 0 {
+        // next().unwrap() can't panic, otherwise `len() % 2` would have returned 0.
+        out.push(u8::from(partial_key.next().unwrap()));
+    }
+    {
+        let mut previous = None;
+        for nibble in partial_key {
+            if let Some(prev) = previous.take() {
+                out.push((u8::from(prev) << 4) | u8::from(nibble));
+            } else {
+                previous = Some(nibble);
+            }
+        }
+        assert!(previous.is_none());
+    }
+
+    if has_children {
+        out.extend_from_slice(&children_bitmap.to_le_bytes());
+    }
+
+    match decoded.storage_value {
+        StorageValue::None => {}
+        StorageValue::Unhashed(storage_value) => {
+            parity_scale_codec::Compact(u64::try_from(storage_value.len()).unwrap())
+                .encode_to(&mut out);
+            out.extend_from_slice(storage_value);
+        }
+        StorageValue::Hashed(hash) => out.extend_from_slice(&hash[..]),
+    }
+
+    for child in decoded.children.iter().filter_map(|c| *c) {
+        parity_scale_codec::Compact(u64::try_from(child.len()).unwrap()).encode_to(&mut out);
+        out.extend_from_slice(child);
+    }
+
+    Ok(out)
+}
+
+/// Type of node, as indicated in the header of its node value.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(super) enum NodeKind {
+    /// Node without children and with an unhashed storage value.
+    Leaf,
+    /// Node without children and with a hashed storage value.
+    LeafHashedValue,
+    /// Node with children and without storage value.
+    BranchNoValue,
+    /// Node with children and with an unhashed storage value.
+    BranchWithValue,
+    /// Node with children and with a hashed storage value.
+    BranchHashedValue,
+}
+
+/// Returns the bytes of the header of a node value, given the type of node and the number of
+/// nibbles of its partial key.
+pub(super) fn encode_header(kind: NodeKind, pk_len: usize) -> impl Iterator<Item = u8> {
+    // The most significant bits of the first byte contain the type of node, and the other bits
+    // the length of the partial key. If the length doesn't fit, then all these other bits are
+    // set to 1, and the rest of the length is in the following bytes. Each following byte is
+    // added to the length, and the length continues in the next byte only if its value is 255.
+    let (prefix, pk_len_mask): (u8, u8) = match kind {
+        NodeKind::Leaf => (0b0100_0000, 0b11_1111),
+        NodeKind::BranchNoValue => (0b1000_0000, 0b11_1111),
+        NodeKind::BranchWithValue => (0b1100_0000, 0b11_1111),
+        NodeKind::LeafHashedValue => (0b0010_0000, 0b1_1111),
+        NodeKind::BranchHashedValue => (0b0001_0000, 0b1111),
+    };
+
+    let first_byte = prefix
+        | u8::try_from(pk_len)
+            .unwrap_or(u8::max_value())
+            .min(pk_len_mask);
+    let mut remaining = pk_len.checked_sub(usize::from(pk_len_mask));
+
+    iter::once(first_byte).chain(iter::from_fn(move || {
+        let rem = remaining?;
+        if rem >= 255 {
+            remaining = Some(rem - 255);
+            Some(255)
+        } else {
+            remaining = None;
+            Some(u8::try_from(rem).unwrap())
+        }
+    }))
+}
+
+/// Decodes a SCALE-compact-encoded length followed with this number of bytes.
+///
+/// Non-canonical length prefixes are rejected.
+fn decode_scale_bytes(node_value: &[u8]) -> Result<(&[u8], &[u8]), Error> {
+    let (after_len, len) = crate::util::nom_scale_compact_usize(node_value)
+        .map_err(|_: nom::Err<(&[u8], nom::error::ErrorKind)>| Error::InvalidLengthPrefix)?;
+
+    // Make sure that the length prefix is the canonical encoding of `len`.
+    let len_prefix_size = node_value.len() - after_len.len();
+    if parity_scale_codec::Compact::<u64>::compact_len(&u64::try_from(len).unwrap())
+        != len_prefix_size
+    {
+        return Err(Error::InvalidLengthPrefix);
+    }
+
+    if after_len.len() < len {
+        return Err(Error::ValueTooShort);
+    }
+
+    Ok((&after_len[len..], &after_len[..len]))
+}
+
+/// Partial key of a [`Decoded`] node value. Iterator of the nibbles of the key.
+#[derive(Clone)]
+pub struct PartialKey<'a> {
+    /// Bytes of the partial key, as found in the node value.
+    bytes: &'a [u8],
+    /// Index of the next nibble to yield within [`PartialKey::bytes`].
+    start: usize,
+    /// Index of the nibble after the last nibble to yield within [`PartialKey::bytes`].
+    end: usize,
+}
+
+impl<'a> Iterator for PartialKey<'a> {
+    type Item = Nibble;
+
+    fn next(&mut self) -> Option<Nibble> {
+        if self.start == self.end {
+            return None;
+        }
+
+        let byte = self.bytes[self.start / 2];
+        let nibble = if self.start % 2 == 0 {
+            byte >> 4
+        } else {
+            byte & 0xf
+        };
+
+        self.start += 1;
+        Some(Nibble::try_from(nibble).unwrap())
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = self.end - self.start;
+        (len, Some(len))
+    }
+}
+
+impl<'a> ExactSizeIterator for PartialKey<'a> {}
+
+impl<'a> fmt::Debug for PartialKey<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.clone()).finish()
+    }
+}
+
+/// Possible error returned by [`decode`].
+#[derive(Debug, Clone, derive_more::Display)]
+pub enum Error {
+    /// Node value is empty.
+    Empty,
+    /// Header of the node value is invalid.
+    InvalidHeader,
+    /// Node value ends in the middle of the partial key length.
+    PartialKeyLenTooShort,
+    /// Length of the partial key doesn't fit in a `usize`.
+    PartialKeyLenOverflow,
+    /// Node value is too short to contain the partial key.
+    PartialKeyTooShort,
+    /// Partial key has an odd number of nibbles, and the padding nibble isn't zero.
+    InvalidPartialKeyPadding,
+    /// Node value is too short to contain the children bitmap.
+    ChildrenBitmapTooShort,
+    /// Node is a branch node but the children bitmap is empty.
+    EmptyChildrenBitmap,
+    /// Length prefix of a storage value or child Merkle value is invalid.
+    InvalidLengthPrefix,
+    /// Node value is too short to contain a storage value or child Merkle value.
+    ValueTooShort,
+    /// Merkle value of a child is longer than 32 bytes.
+    ChildMerkleValueTooLarge,
+    /// Node value contains unexpected data after its end.
+    TrailingData,
+}
+
+/// Possible error returned by [`encode`].
+#[derive(Debug, Clone, derive_more::Display)]
+pub enum EncodeError {
+    /// Node has neither children nor storage value, but has a non-empty partial key.
+    NoChildrenNoValue,
+    /// Merkle value of a child is longer than 32 bytes.
+    ChildMerkleValueTooLarge,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{nibble::Nibble, node_value, HashFunction, Layout, StateVersion};
+    use core::convert::TryFrom as _;
+    use rand::{
+        distributions::{Distribution as _, Uniform},
+        Rng as _,
+    };
+
+    #[test]
+    fn basic() {
+        let node_value = [
+            195, 8, 193, 4, 4, 44, 104, 101, 108, 108, 111, 32, 119, 111, 114, 108, 100, 12, 102,
+            111, 111, 12, 98, 97, 114,
+        ];
+
+        let decoded = super::decode(&node_value).unwrap();
+        assert_eq!(
+            decoded
+                .partial_key
+                .clone()
+                .map(u8::from)
+                .collect::<Vec<_>>(),
+            vec![8, 12, 1]
+        );
+        assert_eq!(decoded.children_bitmap(), 0x404);
+        assert_eq!(decoded.children[2], Some(&b"foo"[..]));
+        assert_eq!(decoded.children[10], Some(&b"bar"[..]));
+        assert_eq!(
+            decoded.storage_value,
+            super::StorageValue::Unhashed(&b"hello world"[..])
+        );
+
+        assert_eq!(super::encode(decoded).unwrap(), &node_value[..]);
+    }
+
+    #[test]
+    fn hashed_value() {
+        let mut node_value = vec![0x23, 0x01, 0x23];
+        node_value.extend_from_slice(&[0xaa; 32]);
+
+        let decoded = super::decode(&node_value).unwrap();
+        assert_eq!(
+            decoded
+                .partial_key
+                .clone()
+                .map(u8::from)
+                .collect::<Vec<_>>(),
+            vec![1, 2, 3]
+        );
+        assert!(decoded.children.iter().all(|c| c.is_none()));
+        assert_eq!(
+            decoded.storage_value,
+            super::StorageValue::Hashed(&[0xaa; 32])
+        );
+
+        assert_eq!(super::encode(decoded).unwrap(), node_value);
+    }
+
+    #[test]
+    fn empty_trie_root() {
+        let decoded = super::decode(&[0]).unwrap();
+        assert_eq!(decoded.partial_key.len(), 0);
+        assert!(decoded.children.iter().all(|c| c.is_none()));
+        assert!(decoded.storage_value.is_none());
+        assert_eq!(super::encode(decoded).unwrap(), &[0]);
+    }
+
+    #[test]
+    fn long_partial_key_len() {
+        // Length of the partial key exactly `63 + 255` nibbles, which requires a terminating
+        // zero byte after the `255`.
+        let partial_key = (0..318)
+            .map(|n| Nibble::try_from((n % 16) as u8).unwrap())
+            .collect::<Vec<_>>();
+
+        let encoded = super::encode(super::Decoded {
+            partial_key: partial_key.iter().cloned(),
+            children: [None; 16],
+            storage_value: super::StorageValue::Unhashed(&b"foo"[..]),
+        })
+        .unwrap();
+        assert_eq!(&encoded[..3], &[0x40 + 63, 255, 0]);
+
+        let decoded = super::decode(&encoded).unwrap();
+        assert_eq!(decoded.partial_key.collect::<Vec<_>>(), partial_key);
+    }
+
+    #[test]
+    fn invalid_padding_rejected() {
+        // Leaf with 1 nibble, whose padding nibble is non-zero.
+        assert!(super::decode(&[0x41, 0x13, 0]).is_err());
+        assert!(super::decode(&[0x41, 0x03, 0]).is_ok());
+    }
+
+    #[test]
+    fn fuzzing_round_trip() {
+        fn uniform_sample(min: u8, max: u8) -> u8 {
+            Uniform::new_inclusive(min, max).sample(&mut rand::thread_rng())
+        }
+
+        for _ in 0..2048 {
+            let layout = Layout {
+                hash_function: if rand::random() {
+                    HashFunction::Blake2
+                } else {
+                    HashFunction::Keccak256
+                },
+                state_version: if rand::random() {
+                    StateVersion::V0
+                } else {
+                    StateVersion::V1
+                },
+            };
+
+            let partial_key = (0..Uniform::new_inclusive(0, 400).sample(&mut rand::thread_rng()))
+                .map(|_| Nibble::try_from(uniform_sample(0, 15)).unwrap())
+                .collect::<Vec<_>>();
+
+            let children_values = (0..16)
+                .map(|_| {
+                    if rand::thread_rng().gen_bool(0.3) {
+                        Some(
+                            (0..uniform_sample(0, 32))
+                                .map(|_| rand::random::<u8>())
+                                .collect::<Vec<_>>(),
+                        )
+                    } else {
+                        None
+                    }
+                })
+                .collect::<Vec<_>>();
+            let mut children = [None; 16];
+            for (child, value) in children.iter_mut().zip(children_values.iter()) {
+                *child = value.as_ref().map(|v| &v[..]);
+            }
+
+            let storage_value =
+                if rand::thread_rng().gen_bool(0.5) || children.iter().all(|c| c.is_none()) {
+                    Some(
+                        (0..Uniform::new_inclusive(0, 300).sample(&mut rand::thread_rng()))
+                            .map(|_| rand::random::<u8>())
+                            .collect::<Vec<_>>(),
+                    )
+                } else {
+                    None
+                };
+
+            let storage_value_hash = storage_value.as_ref().map(|v| layout.hash_function.hash(v));
+            let encoded_storage_value = match (&storage_value, &storage_value_hash) {
+                (Some(v), Some(hash)) if layout.is_value_hashed(v.len()) => {
+                    super::StorageValue::Hashed(hash)
+                }
+                (Some(v), _) => super::StorageValue::Unhashed(&v[..]),
+                (None, _) => super::StorageValue::None,
+            };
+
+            let encoded = super::encode(super::Decoded {
+                partial_key: partial_key.iter().cloned(),
+                children,
+                storage_value: encoded_storage_value,
+            })
+            .unwrap();
+
+            let decoded = super::decode(&encoded).unwrap();
+            assert_eq!(decoded.partial_key.clone().collect::<Vec<_>>(), partial_key);
+            assert_eq!(decoded.children, children);
+            assert_eq!(decoded.storage_value, encoded_storage_value);
+
+            // The node value must match what `node_value` calculates.
+            let merkle_value = node_value::calculate_merkle_root(node_value::Config {
+                ty: node_value::NodeTy::NonRoot {
+                    partial_key: partial_key.iter().cloned(),
+                },
+                children: children_values
+                    .iter()
+                    .map(|c| c.as_ref().map(|c| node_value::Output::from_bytes(c)))
+                    .collect::<Vec<_>>()
+                    .iter()
+                    .map(|c| c.as_ref()),
+                stored_value: storage_value.as_ref(),
+                layout,
+            });
+            if encoded.len() < 32 {
+                assert_eq!(merkle_value.as_ref(), &encoded[..]);
+            } else {
+                assert_eq!(
+                    merkle_value.as_ref(),
+                    &layout.hash_function.hash(&encoded)[..]
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn fuzzing_random_bytes() {
+        for _ in 0..32768 {
+            let len = Uniform::new_inclusive(0, 64).sample(&mut rand::thread_rng());
+            let node_value = (0..len).map(|_| rand::random::<u8>()).collect::<Vec<_>>();
+
+            // Decoding must never panic, and successfully decoded node values must be encoded
+            // back to the exact same bytes.
+            if let Ok(decoded) = super::decode(&node_value) {
+                assert_eq!(super::encode(decoded).unwrap(), node_value);
+            }
+        }
+    }
+}
@@ -20,6 +20,8 @@
 //! Use the [`calculate_merkle_root`] function to calculate the Merkle value. The [`Config`]
 //! struct contains all the input required for the calculation.
 //!
+//! See also the [`node_codec`](super::node_codec) module for the format of node values.
+//!
 //! # Example
 //!
 //! ```
@@ -56,19 +58,20 @@
 //!         },
 //!         children: children.iter().map(|opt| opt.as_ref()),
 //!         stored_value: Some(b"hello world"),
+//!         layout: Default::default(),
 //!     })
 //! };
 //!
 //! assert_eq!(
 //!     merkle_value.as_ref(),
 //!     &[
-//!         195, 8, 193, 4, 4, 12, 102, 111, 111, 12, 98, 97, 114, 44, 104, 101, 108, 108, 111,
-//!         32, 119, 111, 114, 108, 100
+//!         195, 8, 193, 4, 4, 44, 104, 101, 108, 108, 111, 32, 119, 111, 114, 108, 100, 12,
+//!         102, 111, 111, 12, 98, 97, 114
 //!     ]
 //! );
 //! ```
 
-use super::nibble::Nibble;
+use super::{hasher::Hasher, nibble::Nibble, node_codec, HashFunction, Layout};
 
 use arrayvec::ArrayVec;
 use core::{convert::TryFrom as _, fmt};
@@ -87,6 +90,9 @@ pub struct Config<TChIter, TPKey, TVal> {
 
     /// Value of the node in the storage.
     pub stored_value: Option<TVal>,
+
+    /// Layout of the trie the node belongs to.
+    pub layout: Layout,
 }
 
 /// Type of node whose node value is to be calculated.
@@ -133,9 +139,9 @@ where
 
     // This value will be used as the sink for all the components of the merkle value.
     let mut merkle_value_sink = if matches!(config.ty, NodeTy::Root { .. }) {
-        HashOrInline::Hasher(blake2_rfc::blake2b::Blake2b::new(32))
+        HashOrInline::Hasher(Hasher::new(config.layout.hash_function))
     } else {
-        HashOrInline::Inline(ArrayVec::new())
+        HashOrInline::Inline(ArrayVec::new(), config.layout.hash_function)
     };
 
     // For node value calculation purposes, the root key is treated the same as the partial key.
@@ -144,35 +150,34 @@ where
         NodeTy::NonRoot { partial_key } => partial_key,
     };
 
+    // Depending on the layout, the storage value might have to be replaced with its hash.
+    let stored_value_hash = match &config.stored_value {
+        Some(v) if config.layout.is_value_hashed(v.as_ref().len()) => {
+            Some(config.layout.hash_function.hash(v.as_ref()))
+        }
+        _ => None,
+    };
+
     // Push the header of the node to `merkle_value_sink`.
     {
-        // The first two most significant bits of the header contain the type of node.
-        let two_msb: u8 = {
-            let has_stored_value = config.stored_value.is_some();
-            match (has_stored_value, has_children) {
-                (false, false) => {
-                    // This should only ever be reached if we compute the root node of an
-                    // empty trie.
-                    0b00
-                }
-                (true, false) => 0b01,
-                (false, true) => 0b10,
-                (true, true) => 0b11,
+        let kind = match (&config.stored_value, &stored_value_hash, has_children) {
+            (None, _, false) => {
+                // This should only ever be reached if we compute the root node of an
+                // empty trie.
+                merkle_value_sink.update(&[0]);
+                None
             }
+            (Some(_), None, false) => Some(node_codec::NodeKind::Leaf),
+            (Some(_), Some(_), false) => Some(node_codec::NodeKind::LeafHashedValue),
+            (None, _, true) => Some(node_codec::NodeKind::BranchNoValue),
+            (Some(_), None, true) => Some(node_codec::NodeKind::BranchWithValue),
+            (Some(_), Some(_), true) => Some(node_codec::NodeKind::BranchHashedValue),
         };
 
-        // Another weird algorithm to encode the partial key length into the header.
-        let mut pk_len = partial_key.len();
-        if pk_len >= 63 {
-            pk_len -= 63;
-            merkle_value_sink.update(&[(two_msb << 6) + 63]);
-            while pk_len > 255 {
-                pk_len -= 255;
-                merkle_value_sink.update(&[255]);
+        if let Some(kind) = kind {
+            for byte in node_codec::encode_header(kind, partial_key.len()) {
+                merkle_value_sink.update(&[byte]);
             }
-            merkle_value_sink.update(&[u8::try_from(pk_len).unwrap()]);
-        } else {
-            merkle_value_sink.update(&[(two_msb << 6) + u8::try_from(pk_len).unwrap()]);
         }
     }
 
@@ -196,33 +201,32 @@ where
 
     // Compute the node subvalue and push it to `merkle_value_sink`.
 
-    // If there isn't any children, the node subvalue only consists in the storage value.
-    // We take a shortcut and end the calculation now.
-    if !has_children {
-        if let Some(stored_value) = config.stored_value {
-            // Doing something like `merkle_value_sink.update(stored_value.encode());` would be
-            // quite expensive because we would duplicate the storage value. Instead, we do the
-            // encoding manually by pushing the length then the value.
-            parity_scale_codec::Compact(u64::try_from(stored_value.as_ref().len()).unwrap())
-                .encode_to(&mut merkle_value_sink);
-            merkle_value_sink.update(stored_value.as_ref());
-        }
-
-        return merkle_value_sink.finalize();
-    }
-
     // If there is any child, we a `u16` where each bit is `1` if there exists a child there.
-    merkle_value_sink.update({
-        let mut children_bitmap = 0u16;
-        for (child_index, child) in config.children.clone().enumerate() {
-            if child.is_some() {
-                children_bitmap |= 1 << u32::try_from(child_index).unwrap();
+    if has_children {
+        merkle_value_sink.update({
+            let mut children_bitmap = 0u16;
+            for (child_index, child) in config.children.clone().enumerate() {
+                if child.is_some() {
+                    children_bitmap |= 1 << u32::try_from(child_index).unwrap();
+                }
             }
-        }
-        &children_bitmap.to_le_bytes()[..]
-    });
+            &children_bitmap.to_le_bytes()[..]
+        });
+    }
 
-    // Push the merkle values of all the children.
+    // Then, add our own stored value.
+    if let Some(stored_value_hash) = &stored_value_hash {
+        merkle_value_sink.update(&stored_value_hash[..]);
+    } else if let Some(stored_value) = config.stored_value {
+        // Doing something like `merkle_value_sink.update(stored_value.encode());` would be
+        // quite expensive because we would duplicate the storage value. Instead, we do the
+        // encoding manually by pushing the length then the value.
+        parity_scale_codec::Compact(u64::try_from(stored_value.as_ref().len()).unwrap())
+            .encode_to(&mut merkle_value_sink);
+        merkle_value_sink.update(stored_value.as_ref());
+    }
+
+    // Finally, push the merkle values of all the children.
     for child in config.children.clone() {
         let child_merkle_value = match child {
             Some(v) => v,
@@ -237,16 +241,6 @@ where
         merkle_value_sink.update(child_merkle_value.as_ref());
     }
 
-    // Finally, add our own stored value.
-    if let Some(stored_value) = config.stored_value {
-        // Doing something like `merkle_value_sink.update(stored_value.encode());` would be
-        // quite expensive because we would duplicate the storage value. Instead, we do the
-        // encoding manually by pushing the length then the value.
-        parity_scale_codec::Compact(u64::try_from(stored_value.as_ref().len()).unwrap())
-            .encode_to(&mut merkle_value_sink);
-        merkle_value_sink.update(stored_value.as_ref());
-    }
-
     merkle_value_sink.finalize()
 }
 
@@ -259,7 +253,7 @@ pub struct Output {
 #[derive(Clone)]
 enum OutputInner {
     Inline(ArrayVec<[u8; 31]>),
-    Hasher(blake2_rfc::blake2b::Blake2bResult),
+    Hash([u8; 32]),
     Bytes(ArrayVec<[u8; 32]>),
 }
 
@@ -286,7 +280,7 @@ impl AsRef<[u8]> for Output {
     fn as_ref(&self) -> &[u8] {
         match &self.inner {
             OutputInner::Inline(a) => a.as_slice(),
-            OutputInner::Hasher(a) => a.as_bytes(),
+            OutputInner::Hash(a) => &a[..],
             OutputInner::Bytes(a) => a.as_slice(),
         }
     }
@@ -313,8 +307,9 @@ impl fmt::Debug for Output {
 /// values in buffers then hashing the node value as a whole, we push the elements of the node
 /// value to this struct which automatically switches to hashing if the value exceeds 32 bytes.
 enum HashOrInline {
-    Inline(ArrayVec<[u8; 31]>),
-    Hasher(blake2_rfc::blake2b::Blake2b),
+    /// Node value so far, and hash function to use in case it becomes too long.
+    Inline(ArrayVec<[u8; 31]>, HashFunction),
+    Hasher(Hasher),
 }
 
 impl HashOrInline {
@@ -322,9 +317,9 @@ impl HashOrInline {
     /// go above 32 bytes, then we switch to a hasher.
     fn update(&mut self, data: &[u8]) {
         match self {
-            HashOrInline::Inline(curr) => {
+            HashOrInline::Inline(curr, hash_function) => {
                 if curr.try_extend_from_slice(data).is_err() {
-                    let mut hasher = blake2_rfc::blake2b::Blake2b::new(32);
+                    let mut hasher = Hasher::new(*hash_function);
                     hasher.update(&curr);
                     hasher.update(data);
                     *self = HashOrInline::Hasher(hasher);
@@ -339,8 +334,8 @@ impl HashOrInline {
     fn finalize(self) -> Output {
         Output {
             inner: match self {
-                HashOrInline::Inline(b) => OutputInner::Inline(b),
-                HashOrInline::Hasher(h) => OutputInner::Hasher(h.finalize()),
+                HashOrInline::Inline(b, _) => OutputInner::Inline(b),
+                HashOrInline::Hasher(h) => OutputInner::Hash(h.finalize()),
             },
         }
     }
@@ -363,6 +358,7 @@ mod tests {
             ty: super::NodeTy::Root { key: iter::empty() },
             children: (0..16).map(|_| None),
             stored_value: None::<Vec<u8>>,
+            layout: Default::default(),
         });
 
         assert_eq!(
@@ -382,6 +378,7 @@ mod tests {
             },
             children: (0..16).map(|_| None),
             stored_value: None::<Vec<u8>>,
+            layout: Default::default(),
         });
 
         assert_eq!(obtained.as_ref(), &[0u8]);
@@ -417,17 +414,57 @@ mod tests {
             },
             children: children.iter().map(|opt| opt.as_ref()),
             stored_value: Some(b"hello world"),
+            layout: Default::default(),
         });
 
         assert_eq!(
             obtained.as_ref(),
             &[
-                195, 8, 193, 4, 4, 12, 102, 111, 111, 12, 98, 97, 114, 44, 104, 101, 108, 108, 111,
-                32, 119, 111, 114, 108, 100
+                195, 8, 193, 4, 4, 44, 104, 101, 108, 108, 111, 32, 119, 111, 114, 108, 100, 12,
+                102, 111, 111, 12, 98, 97, 114
             ]
         );
     }
 
+    #[test]
+    fn stored_value_before_children() {
+        // Regression test: the storage value must be encoded between the children bitmap and
+        // the Merkle values of the children, and not after the children.
+        let mut children = vec![None; 16];
+        children[0] = Some(super::Output::from_bytes(b"c"));
+        let obtained = super::calculate_merkle_root(super::Config {
+            ty: super::NodeTy::NonRoot {
+                partial_key: iter::empty(),
+            },
+            children: children.iter().map(|opt| opt.as_ref()),
+            stored_value: Some(b"v"),
+            layout: Default::default(),
+        });
+
+        assert_eq!(obtained.as_ref(), &[0xc0, 1, 0, 4, b'v', 4, b'c']);
+    }
+
+    #[test]
+    fn partial_key_len_63_plus_255() {
+        // Regression test: a partial key length whose remainder after 63 is exactly 255 must be
+        // followed with a zero byte, otherwise decoders continue reading the length.
+        let obtained = super::calculate_merkle_root(super::Config {
+            ty: super::NodeTy::NonRoot {
+                partial_key: (0..63 + 255).map(|_| Nibble::try_from(0).unwrap()),
+            },
+            children: (0..16).map(|_| None),
+            stored_value: Some(b""),
+            layout: Default::default(),
+        });
+
+        let mut expected_node_value = vec![0x40 + 63, 255, 0];
+        expected_node_value.extend((0..(63 + 255) / 2).map(|_| 0));
+        expected_node_value.push(0);
+        let expected = blake2_rfc::blake2b::blake2b(32, &[], &expected_node_value);
+
+        assert_eq!(obtained.as_ref(), expected.as_bytes());
+    }
+
     #[test]
     #[should_panic]
     fn bad_children_len() {
@@ -437,6 +474,7 @@ mod tests {
             },
             children: iter::empty(),
             stored_value: None::<Vec<u8>>,
+            layout: Default::default(),
         });
     }
 }
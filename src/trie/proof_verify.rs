@@ -44,9 +44,17 @@
 //! >           access to the storage of a block sends to a machine that doesn't all the proofs
 //! >           corresponding to the storage entries necessary for a certain runtime call.
 //!
+//! # Hashed storage values
+//!
+//! When the trie uses [`StateVersion::V1`](super::StateVersion::V1), the node value of a node
+//! might contain the hash of its storage value rather than the storage value itself. In that
+//! situation, the storage value must also be included in the proof, as if it was a node value.
+//!
 
-use super::nibble;
-use core::{convert::TryFrom as _, iter};
+use super::{nibble, node_codec, Layout};
+
+use alloc::collections::BTreeMap;
+use core::convert::TryFrom as _;
 
 /// Configuration to pass to [`verify_proof`].
 pub struct Config<'a, I> {
@@ -62,6 +70,9 @@ pub struct Config<'a, I> {
     /// values between the root node and the node closest to the requested key have to be included
     /// in the list in order for the verification to be able to succeed.
     pub proof: I,
+
+    /// Layout of the trie. Determines the hash function used to calculate the Merkle values.
+    pub layout: Layout,
 }
 
 /// Find the storage value of the requested key (as designated by [`Config::requested_key`]).
@@ -85,11 +96,7 @@ pub fn verify_proof<'a>(
         .clone()
         .map(|proof_entry| -> arrayvec::ArrayVec<[u8; 32]> {
             if proof_entry.len() >= 32 {
-                blake2_rfc::blake2b::blake2b(32, &[], proof_entry)
-                    .as_bytes()
-                    .iter()
-                    .cloned()
-                    .collect()
+                config.layout.hash_function.hash(proof_entry).into()
             } else {
                 proof_entry.iter().cloned().collect()
             }
@@ -106,142 +113,212 @@ pub fn verify_proof<'a>(
     let mut expected_nibbles_iter = nibble::bytes_to_nibbles(config.requested_key.iter().copied());
     loop {
         // Decode the node value of `proof_iter`.
-        // `node_value` is updated as the decoding progresses.
-        let mut node_value = config.proof.clone().nth(proof_iter).unwrap();
-        if node_value.is_empty() {
-            return Err(Error::InvalidNodeValue);
+        let node_value = config.proof.clone().nth(proof_iter).unwrap();
+        let decoded = node_codec::decode(node_value).map_err(Error::InvalidNodeValue)?;
+
+        // Iterating over the partial key, checking if it matches `expected_nibbles_iter`.
+        for nibble in decoded.partial_key {
+            if expected_nibbles_iter.next() != Some(nibble) {
+                return Ok(None);
+            }
         }
 
-        let has_children = (node_value[0] & 0x80) != 0;
-        let has_storage_value = (node_value[0] & 0x40) != 0;
-
-        // Iterator to the partial key found in the node value of `proof_iter`.
-        let mut partial_key = {
-            // Length of the partial key, in nibbles.
-            let pk_len = {
-                let mut accumulator = usize::from(node_value[0] & 0x3f);
-                node_value = &node_value[1..];
-                let mut continue_iter = accumulator == 63;
-                while continue_iter {
-                    if node_value.is_empty() {
-                        return Err(Error::InvalidNodeValue);
-                    }
-                    continue_iter = node_value[0] == 255;
-                    accumulator = accumulator
-                        .checked_add(usize::from(node_value[0]))
-                        .ok_or(Error::InvalidNodeValue)?;
-                    node_value = &node_value[1..];
-                }
-                accumulator
+        if let Some(expected_nibble) = expected_nibbles_iter.next() {
+            // The iteration needs to continue with the child whose index matches next nibble that
+            // was just pulled from `expected_nibbles_iter`.
+            let child_merkle_value = match decoded.children[usize::from(u8::from(expected_nibble))]
+            {
+                Some(v) => v,
+                // No child with the requested index exists.
+                None => return Ok(None),
             };
 
-            // Length of the partial key, in bytes.
-            let pk_len_bytes = if pk_len == 0 {
-                0
-            } else {
-                1 + ((pk_len - 1) / 2)
+            // Find the entry in `proof` matching this Merkle value and update `proof_iter`.
+            proof_iter = merkle_values
+                .iter()
+                .position(|v| &v[..] == child_merkle_value)
+                .ok_or(Error::MissingProofEntry)?;
+        } else {
+            // The current node (as per `proof_iter`) exactly matches the requested key.
+            return match decoded.storage_value {
+                node_codec::StorageValue::None => Ok(None),
+                node_codec::StorageValue::Unhashed(value) => Ok(Some(value)),
+                node_codec::StorageValue::Hashed(hash) => {
+                    // The storage value itself is found in the proof.
+                    let position = merkle_values
+                        .iter()
+                        .position(|v| &v[..] == &hash[..])
+                        .ok_or(Error::MissingProofEntry)?;
+                    Ok(Some(config.proof.clone().nth(position).unwrap()))
+                }
             };
-            if node_value.len() < pk_len_bytes {
-                return Err(Error::InvalidNodeValue);
-            }
+        }
+    }
+}
 
-            let pk_nibbles_iter = node_value
-                .iter()
-                .take(pk_len_bytes)
-                .flat_map(|byte| nibble::bytes_to_nibbles(iter::once(*byte)))
-                .skip(pk_len % 2);
-            node_value = &node_value[pk_len_bytes..];
-            pk_nibbles_iter
-        };
+/// Configuration to pass to [`verify_range_proof`].
+pub struct RangeConfig<'a, I> {
+    /// Smallest key (inclusive) of the range of entries to find.
+    pub start_key: &'a [u8],
 
-        // Iterating over this partial key, checking if it matches `expected_nibbles_iter`.
-        while let Some(nibble) = partial_key.next() {
-            if expected_nibbles_iter.next() != Some(nibble) {
-                return Ok(None);
-            }
-        }
+    /// Merkle value (or node value) of the root node of the trie.
+    pub trie_root_hash: &'a [u8; 32],
 
-        // After the partial key, the node value optionally contains a bitfield of child nodes.
-        let children_bitmap = if has_children {
-            if node_value.len() < 2 {
-                return Err(Error::InvalidNodeValue);
+    /// List of node values of nodes found in the trie. No specific order is required.
+    pub proof: I,
+
+    /// Layout of the trie. Determines the hash function used to calculate the Merkle values.
+    pub layout: Layout,
+}
+
+/// Outcome of a successful call to [`verify_range_proof`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RangeProofOutcome<'a> {
+    /// List of keys and storage values found in the proof, ordered by key.
+    ///
+    /// All the storage entries of the trie whose key is superior or equal to
+    /// [`RangeConfig::start_key`] and inferior to the first key that the proof doesn't cover are
+    /// guaranteed to be in this list.
+    pub entries: Vec<(Vec<u8>, &'a [u8])>,
+
+    /// `true` if [`RangeProofOutcome::entries`] contains all the storage entries of the trie
+    /// whose key is superior or equal to [`RangeConfig::start_key`]. If `false`, the rest of
+    /// the entries can be obtained through another proof starting after the last key of
+    /// [`RangeProofOutcome::entries`].
+    pub complete: bool,
+}
+
+/// Finds all the storage entries of the trie whose key is superior or equal to
+/// [`RangeConfig::start_key`], in increasing key order, stopping at the first node that is
+/// missing from the proof.
+///
+/// Contrary to [`verify_proof`], which only proves the value of a single key, the outcome of
+/// this function also proves that no key is missing in the range that the proof covers. It is
+/// consequently possible to download the entire storage of a trie by requesting consecutive
+/// ranges of keys.
+///
+/// Returns an error if the proof couldn't be verified.
+pub fn verify_range_proof<'a>(
+    config: RangeConfig<'a, impl Iterator<Item = &'a [u8]> + Clone>,
+) -> Result<RangeProofOutcome<'a>, Error> {
+    // Index the entries of the proof by their hash. Node values shorter than 32 bytes are
+    // indexed as well, as they can be the node value of the root node.
+    let entries_by_hash = config
+        .proof
+        .clone()
+        .map(|entry| (config.layout.hash_function.hash(entry), entry))
+        .collect::<BTreeMap<_, _>>();
+
+    let root_node_value = *entries_by_hash
+        .get(config.trie_root_hash)
+        .ok_or(Error::TrieRootNotFound)?;
+
+    let start_nibbles = nibble::bytes_to_nibbles(config.start_key.iter().copied())
+        .map(u8::from)
+        .collect::<Vec<_>>();
+
+    // The trie is traversed in a depth-first manner, which yields the keys in increasing order.
+    // Each element of the stack contains the node value to visit, the nibbles of the key of the
+    // node minus its partial key, and whether this key is a prefix of `start_nibbles`. When an
+    // element is `None`, the node is missing from the proof and the traversal stops.
+    let mut stack = vec![Some((root_node_value, Vec::new(), true))];
+    let mut entries = Vec::new();
+
+    while let Some(element) = stack.pop() {
+        let (node_value, mut key, mut is_start_prefix) = match element {
+            Some(e) => e,
+            None => {
+                return Ok(RangeProofOutcome {
+                    entries,
+                    complete: false,
+                })
             }
-            let val = u16::from_le_bytes(<[u8; 2]>::try_from(&node_value[..2]).unwrap());
-            node_value = &node_value[2..];
-            val
-        } else {
-            0
         };
 
-        if let Some(expected_nibble) = expected_nibbles_iter.next() {
-            // The iteration needs to continue with another node.
-            // Update `proof_iter` to the point to the child whose index matches next nibble that
-            // was just pulled from `expected_nibbles_iter`.
+        let decoded = node_codec::decode(node_value).map_err(Error::InvalidNodeValue)?;
+        key.extend(decoded.partial_key.map(u8::from));
 
-            // No child with the requested index exists.
-            if children_bitmap & (1 << u8::from(expected_nibble)) == 0 {
-                return Ok(None);
+        // Compare the key of the node with the start key. If the node is before the start key
+        // and isn't an ancestor of it, the node and all its descendants are skipped. If the key
+        // of the node is after the start key, then all of its descendants are after it as well.
+        if is_start_prefix {
+            match key
+                .iter()
+                .zip(start_nibbles.iter())
+                .find(|(a, b)| a != b)
+                .map(|(a, b)| a < b)
+            {
+                Some(true) => continue,
+                Some(false) => is_start_prefix = false,
+                None if key.len() >= start_nibbles.len() => is_start_prefix = false,
+                None => {}
             }
+        }
 
-            for n in 0.. {
-                if children_bitmap & (1 << n) == 0 {
-                    continue;
-                }
+        // A storage value can only be associated to a key made of an even number of nibbles.
+        if !is_start_prefix && key.len() % 2 == 0 {
+            let value = match decoded.storage_value {
+                node_codec::StorageValue::None => None,
+                node_codec::StorageValue::Unhashed(value) => Some(value),
+                node_codec::StorageValue::Hashed(hash) => match entries_by_hash.get(hash) {
+                    Some(value) => Some(*value),
+                    None => {
+                        return Ok(RangeProofOutcome {
+                            entries,
+                            complete: false,
+                        })
+                    }
+                },
+            };
 
-                // Find the Merkle value of that child in `node_value`.
-                let (node_value_update, len) = crate::util::nom_scale_compact_usize(node_value)
-                    .map_err(|_: nom::Err<(&[u8], nom::error::ErrorKind)>| {
-                        Error::InvalidNodeValue
-                    })?;
-                node_value = node_value_update;
-                if node_value.len() < len {
-                    return Err(Error::InvalidNodeValue);
-                }
+            if let Some(value) = value {
+                let key_bytes = key
+                    .chunks(2)
+                    .map(|chunk| (chunk[0] << 4) | chunk[1])
+                    .collect::<Vec<_>>();
+                entries.push((key_bytes, value));
+            }
+        }
 
-                // The Merkle value that was just found is the one that interests us.
-                if n == u8::from(expected_nibble) {
-                    // Find the entry in `proof` matching this Merkle value and update
-                    // `proof_iter`.
-                    proof_iter = merkle_values
-                        .iter()
-                        .position(|v| &v[..] == &node_value[..len])
-                        .ok_or(Error::MissingProofEntry)?;
-                    break;
-                }
+        // Children are pushed in reverse order so that they are popped in increasing order.
+        for (child_index, child) in decoded.children.iter().enumerate().rev() {
+            let child_merkle_value = match child {
+                Some(c) => *c,
+                None => continue,
+            };
 
-                node_value = &node_value[len..];
-            }
-        } else if has_storage_value {
-            // The current node (as per `proof_iter`) exactly matches the requested key, and
-            // a storage value exists.
-
-            // Skip over the Merkle values of the children.
-            for _ in 0..children_bitmap.count_ones() {
-                let (node_value_update, len) = crate::util::nom_scale_compact_usize(node_value)
-                    .map_err(|_: nom::Err<(&[u8], nom::error::ErrorKind)>| {
-                        Error::InvalidNodeValue
-                    })?;
-                node_value = node_value_update;
-                if node_value.len() < len {
-                    return Err(Error::InvalidNodeValue);
+            let child_nibble = u8::try_from(child_index).unwrap();
+            let child_is_start_prefix = if is_start_prefix {
+                let start_nibble = start_nibbles[key.len()];
+                if child_nibble < start_nibble {
+                    continue;
                 }
-                node_value = &node_value[len..];
-            }
+                child_nibble == start_nibble
+            } else {
+                false
+            };
 
-            // Now at the value that interests us.
-            let (node_value_update, len) = crate::util::nom_scale_compact_usize(node_value)
-                .map_err(|_: nom::Err<(&[u8], nom::error::ErrorKind)>| Error::InvalidNodeValue)?;
-            node_value = node_value_update;
-            if node_value.len() != len {
-                return Err(Error::InvalidNodeValue);
-            }
-            return Ok(Some(node_value));
-        } else {
-            // The current node (as per `proof_iter`) exactly matches the requested key, but no
-            // storage value exists.
-            return Ok(None);
+            let mut child_key = key.clone();
+            child_key.push(child_nibble);
+
+            // Merkle values shorter than 32 bytes are the node value of the child itself.
+            let child_node_value = if child_merkle_value.len() < 32 {
+                Some(child_merkle_value)
+            } else {
+                <&[u8; 32]>::try_from(child_merkle_value)
+                    .ok()
+                    .and_then(|mv| entries_by_hash.get(mv))
+                    .copied()
+            };
+
+            stack.push(child_node_value.map(|nv| (nv, child_key, child_is_start_prefix)));
         }
     }
+
+    Ok(RangeProofOutcome {
+        entries,
+        complete: true,
+    })
 }
 
 /// Possible error returned by [`verify_proof`]
@@ -250,14 +327,16 @@ pub enum Error {
     /// Trie root wasn't found in the proof.
     TrieRootNotFound,
     /// One of the node values in the proof has an invalid format.
-    InvalidNodeValue,
+    #[display(fmt = "Invalid node value: {}", _0)]
+    InvalidNodeValue(node_codec::Error),
     /// Missing an entry in the proof.
     MissingProofEntry,
 }
 
 #[cfg(test)]
 mod tests {
-    use core::convert::TryFrom as _;
+    use super::super::Nibble;
+    use core::{convert::TryFrom as _, iter};
 
     #[test]
     fn basic_works() {
@@ -285,6 +364,7 @@ mod tests {
             requested_key: &requested_key[..],
             trie_root_hash: &trie_root,
             proof: proof.iter().map(|p| &p[..]),
+            layout: Default::default(),
         })
         .unwrap();
 
@@ -292,5 +372,155 @@ mod tests {
             obtained,
             Some(&hex::decode("0d1456fdda7b8ec7f9e5c794cd83194f0593e4ea").unwrap()[..])
         );
+
+        // The same proof also covers a range of keys starting with the requested key.
+        let range = super::verify_range_proof(super::RangeConfig {
+            start_key: &requested_key[..],
+            trie_root_hash: &trie_root,
+            proof: proof.iter().map(|p| &p[..]),
+            layout: Default::default(),
+        })
+        .unwrap();
+
+        assert!(!range.complete);
+        assert_eq!(range.entries[0].0, requested_key);
+        assert_eq!(Some(range.entries[0].1), obtained);
+    }
+
+    /// Builds a trie containing the keys `0x12`, `0x34` and `0x56`, and returns the node values
+    /// of the root node and of the three leaves, and the hash of the root node.
+    fn small_trie() -> (Vec<u8>, [Vec<u8>; 3], [u8; 32]) {
+        let layout = super::Layout::default();
+
+        let leaves = [(2, [0xaa; 40]), (4, [0xbb; 40]), (6, [0xcc; 40])]
+            .iter()
+            .map(|(nibble, value)| {
+                super::node_codec::encode(super::node_codec::Decoded {
+                    partial_key: iter::once(Nibble::try_from(*nibble).unwrap()),
+                    children: [None; 16],
+                    storage_value: super::node_codec::StorageValue::Unhashed(&value[..]),
+                })
+                .unwrap()
+            })
+            .collect::<Vec<_>>();
+        let leaves_hashes = leaves
+            .iter()
+            .map(|leaf| layout.hash_function.hash(leaf))
+            .collect::<Vec<_>>();
+
+        let mut children = [None; 16];
+        children[1] = Some(&leaves_hashes[0][..]);
+        children[3] = Some(&leaves_hashes[1][..]);
+        children[5] = Some(&leaves_hashes[2][..]);
+        let root = super::node_codec::encode(super::node_codec::Decoded {
+            partial_key: iter::empty(),
+            children,
+            storage_value: super::node_codec::StorageValue::None,
+        })
+        .unwrap();
+        let root_hash = layout.hash_function.hash(&root);
+
+        let leaves = [leaves[0].clone(), leaves[1].clone(), leaves[2].clone()];
+        (root, leaves, root_hash)
+    }
+
+    #[test]
+    fn range_complete() {
+        let (root, leaves, root_hash) = small_trie();
+        let proof = [&leaves[2][..], &root[..], &leaves[0][..], &leaves[1][..]];
+
+        let outcome = super::verify_range_proof(super::RangeConfig {
+            start_key: &[],
+            trie_root_hash: &root_hash,
+            proof: proof.iter().copied(),
+            layout: Default::default(),
+        })
+        .unwrap();
+
+        assert!(outcome.complete);
+        assert_eq!(
+            outcome.entries,
+            vec![
+                (vec![0x12], &[0xaa; 40][..]),
+                (vec![0x34], &[0xbb; 40][..]),
+                (vec![0x56], &[0xcc; 40][..]),
+            ]
+        );
+    }
+
+    #[test]
+    fn range_start_key() {
+        let (root, leaves, root_hash) = small_trie();
+
+        // The first leaf is before the start key and doesn't need to be in the proof.
+        let proof = [&root[..], &leaves[1][..], &leaves[2][..]];
+        let outcome = super::verify_range_proof(super::RangeConfig {
+            start_key: &[0x30],
+            trie_root_hash: &root_hash,
+            proof: proof.iter().copied(),
+            layout: Default::default(),
+        })
+        .unwrap();
+        assert!(outcome.complete);
+        assert_eq!(
+            outcome.entries,
+            vec![(vec![0x34], &[0xbb; 40][..]), (vec![0x56], &[0xcc; 40][..])]
+        );
+
+        // The start key itself is included in the range.
+        let outcome = super::verify_range_proof(super::RangeConfig {
+            start_key: &[0x34],
+            trie_root_hash: &root_hash,
+            proof: proof.iter().copied(),
+            layout: Default::default(),
+        })
+        .unwrap();
+        assert_eq!(outcome.entries.len(), 2);
+
+        let outcome = super::verify_range_proof(super::RangeConfig {
+            start_key: &[0x34, 0x00],
+            trie_root_hash: &root_hash,
+            proof: proof.iter().copied(),
+            layout: Default::default(),
+        })
+        .unwrap();
+        assert!(outcome.complete);
+        assert_eq!(outcome.entries, vec![(vec![0x56], &[0xcc; 40][..])]);
+    }
+
+    #[test]
+    fn range_stops_at_missing_node() {
+        let (root, leaves, root_hash) = small_trie();
+
+        // The second leaf is missing. The third leaf must not be reported, otherwise the range
+        // would be missing the second key.
+        let proof = [&root[..], &leaves[0][..], &leaves[2][..]];
+        let outcome = super::verify_range_proof(super::RangeConfig {
+            start_key: &[],
+            trie_root_hash: &root_hash,
+            proof: proof.iter().copied(),
+            layout: Default::default(),
+        })
+        .unwrap();
+
+        assert!(!outcome.complete);
+        assert_eq!(outcome.entries, vec![(vec![0x12], &[0xaa; 40][..])]);
+    }
+
+    #[test]
+    fn range_wrong_root() {
+        let (root, leaves, mut root_hash) = small_trie();
+        root_hash[0] ^= 1;
+        let proof = [&root[..], &leaves[0][..], &leaves[1][..], &leaves[2][..]];
+
+        assert!(matches!(
+            super::verify_range_proof(super::RangeConfig {
+                start_key: &[],
+                trie_root_hash: &root_hash,
+                proof: proof.iter().copied(),
+                layout: Default::default(),
+            }),
+            Err(super::Error::TrieRootNotFound)
+        ));
     }
 }
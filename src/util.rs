@@ -84,11 +84,49 @@ pub(crate) fn nom_scale_compact_usize<'a, E: nom::error::ParseError<&'a [u8]>>(
             let value = (byte3 << 22) | (byte2 << 14) | (byte1 << 6) | byte0;
             let value = match usize::try_from(value) {
                 Ok(v) => v,
-                Err(_) => todo!(), // TODO:
+                Err(_) => {
+                    return Err(nom::Err::Error(nom::error::make_error(
+                        bytes,
+                        nom::error::ErrorKind::Digit,
+                    )))
+                }
             };
             Ok((&bytes[4..], value))
         }
-        0b11 => todo!(), // TODO:
+        0b11 => {
+            // In this mode, the six upper bits of the first byte contain the number of bytes of
+            // the value minus four.
+            let num_bytes = usize::from(bytes[0] >> 2) + 4;
+            if bytes.len() < num_bytes + 1 {
+                return Err(nom::Err::Error(nom::error::make_error(
+                    bytes,
+                    nom::error::ErrorKind::Eof,
+                )));
+            }
+
+            // The value is encoded in little endian.
+            let mut value: usize = 0;
+            for (n, byte) in bytes[1..=num_bytes].iter().enumerate() {
+                if *byte == 0 {
+                    continue;
+                }
+                let shifted = u32::try_from(n * 8)
+                    .ok()
+                    .and_then(|shift| usize::from(*byte).checked_shl(shift))
+                    .filter(|v| v >> (n * 8) == usize::from(*byte));
+                match shifted {
+                    Some(v) => value |= v,
+                    None => {
+                        return Err(nom::Err::Error(nom::error::make_error(
+                            bytes,
+                            nom::error::ErrorKind::Digit,
+                        )))
+                    }
+                }
+            }
+
+            Ok((&bytes[num_bytes + 1..], value))
+        }
         _ => unreachable!(),
     }
 }
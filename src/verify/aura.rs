@@ -0,0 +1,518 @@
+// Substrate-lite
+// Copyright (C) 2019-2020  Parity Technologies (UK) Ltd.
+// SPDX-License-Identifier: GPL-3.0-or-later WITH Classpath-exception-2.0
+
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+
+// You should have received a copy of the GNU General Public License
+// along with this program.  If not, see <http://www.gnu.org/licenses/>.
+
+//! Aura consensus.
+//!
+//! Aura, or Authority Round, is a simple consensus algorithm in which authorities take turns
+//! producing blocks.
+//!
+//! # Overview of Aura
+//!
+//! Time is divided into non-overlapping **slots**, whose duration is determined by calling the
+//! `AuraApi_slot_duration` runtime entry point. The current slot number is
+//! `unix_timestamp / slot_duration`.
+//!
+//! Each slot is attributed to exactly one authority, in a round-robin fashion: the authority
+//! allowed to produce a block during slot `N` is the one at index `N % num_authorities` in the
+//! list of authorities. The list of authorities of block #1 is obtained by calling the
+//! `AuraApi_authorities` runtime entry point on the genesis block, after which a block can
+//! modify this list for its children with a [`header::AuraConsensusLog::AuthoritiesChange`]
+//! digest log item.
+//!
+//! The header of each block contains a pre-runtime digest indicating the slot it belongs to, and
+//! ends with a seal containing the signature of the author over the hash of the header without
+//! that seal. Depending on the chain, authorities use either sr25519 or ed25519 keys. See
+//! [`AuraGenesisConfiguration::signature_scheme`].
+//!
+//! A block can also contain a [`header::AuraConsensusLog::OnDisabled`] digest log item, which
+//! indicates that an authority has been disabled by the runtime. Substrate doesn't take these
+//! items into account when verifying blocks, and a disabled authority can still author blocks
+//! during its slots. In order to not diverge from the rest of the network, these items are
+//! ignored here as well.
+//!
+//! ## Chain selection
+//!
+//! The "best" block of a chain in the Aura algorithm is simply the one with the highest number.
+//!
+//! # Usage
+//!
+//! Before any block can be verified, one needs to create an [`AuraGenesisConfiguration`] using
+//! the genesis block. See the documentation of [`AuraGenesisConfiguration`] for more information.
+//!
+//! Contrary to BABE, verifying an Aura block is done in a single step by calling
+//! [`verify_header`]. The list of authorities of the parent block must be provided.
+//!
+
+use crate::{
+    chain::chain_information::aura::{AuraGenesisConfiguration, AuraSignatureScheme},
+    header,
+};
+
+use core::{convert::TryFrom as _, time::Duration};
+
+/// Configuration for [`verify_header`].
+pub struct VerifyConfig<'a> {
+    /// Header of the block to verify.
+    pub header: header::HeaderRef<'a>,
+
+    /// Header of the parent of the block to verify.
+    ///
+    /// [`verify_header`] assumes that this block has been successfully verified before.
+    ///
+    /// The hash of this header must be the one referenced in [`VerifyConfig::header`].
+    pub parent_block_header: header::HeaderRef<'a>,
+
+    /// Aura configuration retrieved from the genesis block.
+    ///
+    /// Can be obtained by calling [`AuraGenesisConfiguration::from_virtual_machine_prototype`]
+    /// with the runtime of the genesis block.
+    pub genesis_configuration: &'a AuraGenesisConfiguration,
+
+    /// Time elapsed since [the Unix Epoch](https://en.wikipedia.org/wiki/Unix_time) (i.e.
+    /// 00:00:00 UTC on 1 January 1970), ignoring leap seconds.
+    ///
+    /// Used in order to determine the current slot. See [`current_slot`].
+    pub now_from_unix_epoch: Duration,
+
+    /// Number of slots the slot of the block is allowed to be ahead of the current slot.
+    ///
+    /// Clocks of the various nodes of the network are never perfectly synchronized. A non-zero
+    /// value makes it possible to accept blocks produced by nodes whose clock is slightly ahead.
+    pub max_slot_drift: u64,
+
+    /// List of authorities allowed to author the block. This is the list found in the
+    /// [`AuraGenesisConfiguration`] if the parent is the genesis block, or otherwise the list
+    /// announced by the most recent ancestor containing a
+    /// [`header::AuraConsensusLog::AuthoritiesChange`].
+    pub current_authorities: header::AuraAuthoritiesIter<'a>,
+}
+
+/// Information yielded back after successfully verifying a block.
+#[derive(Debug)]
+pub struct VerifySuccess {
+    /// Slot number the block belongs to.
+    pub slot_number: u64,
+
+    /// True if the block contains a [`header::AuraConsensusLog::AuthoritiesChange`]. The list of
+    /// authorities that it contains must later be provided back as part of the
+    /// [`VerifyConfig`] when verifying the children of this block.
+    pub authorities_change: bool,
+}
+
+/// Failure to verify a block.
+#[derive(Debug, derive_more::Display)]
+pub enum VerifyError {
+    /// The seal (containing the signature of the authority) is missing from the header.
+    MissingSeal,
+    /// No pre-runtime digest in the block header.
+    MissingPreRuntimeDigest,
+    /// Parent block doesn't contain any Aura information.
+    ParentIsntAuraConsensus,
+    /// Slot number must be strictly increasing between a parent and its child.
+    SlotNumberNotIncreasing,
+    /// List of authorities is empty.
+    EmptyAuthorities,
+    /// Block header signature is invalid.
+    BadSignature,
+    /// Slot of the block is too far ahead of the current slot. The block might become valid
+    /// later.
+    #[display(
+        fmt = "Slot {} of the block is too far ahead of the current slot {}",
+        slot_number,
+        current_slot
+    )]
+    TooFarInFuture {
+        /// Slot number of the block.
+        slot_number: u64,
+        /// Current slot, as determined from [`VerifyConfig::now_from_unix_epoch`].
+        current_slot: u64,
+    },
+}
+
+/// Verifies whether a block header provides a correct proof of the legitimacy of the authorship.
+///
+/// # Panic
+///
+/// Panics if `config.parent_block_header` is invalid.
+///
+pub fn verify_header<'a>(config: VerifyConfig<'a>) -> Result<VerifySuccess, VerifyError> {
+    let slot_number = match config.header.digest.aura_pre_runtime() {
+        Some(pre_runtime) => pre_runtime.slot_number,
+        None => return Err(VerifyError::MissingPreRuntimeDigest),
+    };
+
+    // Blocks can't be produced ahead of their slot, unless the clock of their author is ahead
+    // of ours.
+    let current_slot = current_slot(config.now_from_unix_epoch, config.genesis_configuration);
+    if slot_number > current_slot.saturating_add(config.max_slot_drift) {
+        return Err(VerifyError::TooFarInFuture {
+            slot_number,
+            current_slot,
+        });
+    }
+
+    // Make sure that the slot number is strictly increasing. The genesis block doesn't belong to
+    // any slot.
+    if config.parent_block_header.number != 0 {
+        let parent_slot_number = match config.parent_block_header.digest.aura_pre_runtime() {
+            Some(pre_runtime) => pre_runtime.slot_number,
+            None => return Err(VerifyError::ParentIsntAuraConsensus),
+        };
+
+        if slot_number <= parent_slot_number {
+            return Err(VerifyError::SlotNumberNotIncreasing);
+        }
+    }
+
+    let authorities_change = config.header.digest.logs().any(|item| match item {
+        header::DigestItemRef::AuraConsensus(header::AuraConsensusLogRef::AuthoritiesChange(_)) => {
+            true
+        }
+        _ => false,
+    });
+
+    let seal_signature = match config.header.digest.aura_seal() {
+        Some(seal) => seal,
+        None => return Err(VerifyError::MissingSeal),
+    };
+
+    // The signature in the seal applies to the header from where the signature isn't present.
+    // Build the hash that is expected to be signed.
+    let pre_seal_hash = {
+        let mut unsealed_header = config.header;
+        let _popped = unsealed_header.digest.pop_aura_seal();
+        debug_assert!(_popped.is_some());
+        unsealed_header.hash()
+    };
+
+    // Slots are attributed to authorities in a round-robin fashion.
+    let signing_authority = {
+        let authorities_len = u64::try_from(config.current_authorities.len()).unwrap();
+        if authorities_len == 0 {
+            return Err(VerifyError::EmptyAuthorities);
+        }
+        let index = usize::try_from(slot_number % authorities_len).unwrap();
+        config.current_authorities.clone().nth(index).unwrap()
+    };
+
+    // Whether the authorities use sr25519 or ed25519 keys depends on the chain.
+    let signature_valid = match config.genesis_configuration.signature_scheme {
+        AuraSignatureScheme::Sr25519 => match (
+            schnorrkel::PublicKey::from_bytes(signing_authority.public_key),
+            schnorrkel::Signature::from_bytes(seal_signature),
+        ) {
+            (Ok(public_key), Ok(signature)) => public_key
+                .verify_simple(b"substrate", &pre_seal_hash, &signature)
+                .is_ok(),
+            _ => false,
+        },
+        AuraSignatureScheme::Ed25519 => {
+            match ed25519_dalek::PublicKey::from_bytes(signing_authority.public_key) {
+                Ok(public_key) => public_key
+                    .verify_strict(
+                        &pre_seal_hash,
+                        &ed25519_dalek::Signature::new(*seal_signature),
+                    )
+                    .is_ok(),
+                Err(_) => false,
+            }
+        }
+    };
+
+    if !signature_valid {
+        return Err(VerifyError::BadSignature);
+    }
+
+    Ok(VerifySuccess {
+        slot_number,
+        authorities_change,
+    })
+}
+
+/// Returns the slot that is ongoing at the given time.
+///
+/// Slot `N` starts at `N * slot_duration` since the Unix epoch.
+pub fn current_slot(
+    now_from_unix_epoch: Duration,
+    genesis_config: &AuraGenesisConfiguration,
+) -> u64 {
+    u64::try_from(now_from_unix_epoch.as_millis()).unwrap_or(u64::max_value())
+        / genesis_config.slot_duration.get()
+}
+
+/// Returns the time, since the Unix epoch, at which the given slot starts.
+pub fn slot_start_time(slot_number: u64, genesis_config: &AuraGenesisConfiguration) -> Duration {
+    Duration::from_millis(slot_number.saturating_mul(genesis_config.slot_duration.get()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{verify_header, VerifyConfig, VerifyError, VerifySuccess};
+    use crate::{
+        chain::chain_information::aura::{AuraGenesisConfiguration, AuraSignatureScheme},
+        header,
+        test_utils::{ed25519_keypair, encode_header, sr25519_keypair},
+    };
+
+    use core::{convert::TryFrom as _, num::NonZeroU64, time::Duration};
+    use ed25519_dalek::Signer as _;
+    use rand::SeedableRng as _;
+
+    const SLOT_DURATION_MS: u64 = 6000;
+    const BLOCK1_SLOT_NUMBER: u64 = 1000;
+    /// Time at which all the slots used by the tests have started.
+    const NOW: Duration = Duration::from_millis((BLOCK1_SLOT_NUMBER + 100) * SLOT_DURATION_MS);
+
+    /// Key of an authority.
+    enum Key {
+        Sr25519(schnorrkel::Keypair),
+        Ed25519(ed25519_dalek::Keypair),
+    }
+
+    impl Key {
+        fn public_key(&self) -> [u8; 32] {
+            match self {
+                Key::Sr25519(kp) => kp.public.to_bytes(),
+                Key::Ed25519(kp) => kp.public.to_bytes(),
+            }
+        }
+
+        fn sign(&self, message: &[u8]) -> [u8; 64] {
+            match self {
+                Key::Sr25519(kp) => kp
+                    .sign(schnorrkel::context::attach_rng(
+                        schnorrkel::signing_context(b"substrate").bytes(message),
+                        rand_chacha::ChaCha20Rng::seed_from_u64(0),
+                    ))
+                    .to_bytes(),
+                Key::Ed25519(kp) => kp.sign(message).to_bytes(),
+            }
+        }
+    }
+
+    /// Builds the sr25519 keys of the given number of authorities.
+    fn authorities(num: u8) -> Vec<Key> {
+        (0..num)
+            .map(|n| Key::Sr25519(sr25519_keypair(10 + n)))
+            .collect()
+    }
+
+    /// Builds the ed25519 keys of the given number of authorities.
+    fn ed25519_authorities(num: u8) -> Vec<Key> {
+        (0..num)
+            .map(|n| Key::Ed25519(ed25519_keypair(10 + n)))
+            .collect()
+    }
+
+    /// Converts a list of keys into a list of Aura authorities.
+    fn authorities_list(authorities: &[Key]) -> Vec<header::AuraAuthority> {
+        authorities
+            .iter()
+            .map(|key| header::AuraAuthority {
+                public_key: key.public_key(),
+            })
+            .collect()
+    }
+
+    /// Builds a child of `parent` in the given slot, with the given additional digest log items,
+    /// sealed by `author`.
+    fn sealed_header_with_logs(
+        parent: &[u8],
+        slot_number: u64,
+        logs: Vec<header::DigestItem>,
+        author: &Key,
+    ) -> Vec<u8> {
+        let parent_hash = header::hash_from_scale_encoded_header(parent);
+        let number = header::decode(parent).unwrap().number + 1;
+
+        let mut digest = vec![header::DigestItem::AuraPreDigest(header::AuraPreDigest {
+            slot_number,
+        })];
+        digest.extend(logs);
+
+        let pre_seal_hash =
+            header::hash_from_scale_encoded_header(encode_header(&parent_hash, number, &digest));
+        digest.push(header::DigestItem::AuraSeal(author.sign(&pre_seal_hash)));
+
+        encode_header(&parent_hash, number, &digest)
+    }
+
+    /// Builds a child of `parent` in the given slot, sealed by `author`.
+    fn sealed_header(parent: &[u8], slot_number: u64, author: &Key) -> Vec<u8> {
+        sealed_header_with_logs(parent, slot_number, Vec::new(), author)
+    }
+
+    /// Verifies `header`, child of `parent`, authored by one of `authorities` using the given
+    /// signature scheme.
+    fn verify_with_scheme(
+        authorities: &[header::AuraAuthority],
+        signature_scheme: AuraSignatureScheme,
+        header: &[u8],
+        parent: &[u8],
+    ) -> Result<VerifySuccess, VerifyError> {
+        let genesis_configuration = AuraGenesisConfiguration {
+            authorities_list: authorities.to_vec(),
+            slot_duration: NonZeroU64::new(SLOT_DURATION_MS).unwrap(),
+            signature_scheme,
+        };
+
+        verify_header(VerifyConfig {
+            header: header::decode(header).unwrap(),
+            parent_block_header: header::decode(parent).unwrap(),
+            genesis_configuration: &genesis_configuration,
+            now_from_unix_epoch: NOW,
+            max_slot_drift: 0,
+            current_authorities: header::AuraAuthoritiesIter::from_slice_of_authorities(
+                authorities,
+            ),
+        })
+    }
+
+    /// Verifies `header`, child of `parent`, authored by one of `authorities` using sr25519.
+    fn verify(
+        authorities: &[header::AuraAuthority],
+        header: &[u8],
+        parent: &[u8],
+    ) -> Result<VerifySuccess, VerifyError> {
+        verify_with_scheme(authorities, AuraSignatureScheme::Sr25519, header, parent)
+    }
+
+    #[test]
+    fn round_robin_authors() {
+        let authorities = authorities(3);
+        let list = authorities_list(&authorities);
+        let mut parent = encode_header(&[0; 32], 0, &[]);
+
+        // Slots don't need to be contiguous.
+        for slot_number in
+            (BLOCK1_SLOT_NUMBER..BLOCK1_SLOT_NUMBER + 6).chain(Some(BLOCK1_SLOT_NUMBER + 8))
+        {
+            let author = &authorities[usize::try_from(slot_number % 3).unwrap()];
+            let block = sealed_header(&parent, slot_number, author);
+            let success = verify(&list, &block, &parent).unwrap();
+            assert_eq!(success.slot_number, slot_number);
+            assert!(!success.authorities_change);
+            parent = block;
+        }
+    }
+
+    #[test]
+    fn wrong_author_is_refused() {
+        let authorities = authorities(3);
+        let genesis = encode_header(&[0; 32], 0, &[]);
+        let author = &authorities[usize::try_from((BLOCK1_SLOT_NUMBER + 1) % 3).unwrap()];
+        let block1 = sealed_header(&genesis, BLOCK1_SLOT_NUMBER, author);
+
+        assert!(matches!(
+            verify(&authorities_list(&authorities), &block1, &genesis),
+            Err(VerifyError::BadSignature)
+        ));
+    }
+
+    #[test]
+    fn round_robin_after_authorities_change() {
+        let authorities = authorities(4);
+        let genesis = encode_header(&[0; 32], 0, &[]);
+
+        // Block #1 hands over authorship to the last three authorities.
+        let new_list = authorities_list(&authorities[1..]);
+        let block1 = sealed_header_with_logs(
+            &genesis,
+            BLOCK1_SLOT_NUMBER,
+            vec![header::DigestItem::AuraConsensus(
+                header::AuraConsensusLog::AuthoritiesChange(new_list.clone()),
+            )],
+            &authorities[usize::try_from(BLOCK1_SLOT_NUMBER % 4).unwrap()],
+        );
+        let success = verify(&authorities_list(&authorities), &block1, &genesis).unwrap();
+        assert!(success.authorities_change);
+
+        // The slots of the children are attributed in a round-robin fashion within the new list.
+        let slot_number = BLOCK1_SLOT_NUMBER + 1;
+        let expected = 1 + usize::try_from(slot_number % 3).unwrap();
+        for (index, author) in authorities.iter().enumerate() {
+            let block2 = sealed_header(&block1, slot_number, author);
+            let result = verify(&new_list, &block2, &block1);
+            if index == expected {
+                assert!(result.is_ok());
+            } else {
+                assert!(matches!(result, Err(VerifyError::BadSignature)));
+            }
+        }
+    }
+
+    #[test]
+    fn disabled_authority_can_still_author() {
+        let authorities = authorities(2);
+        let list = authorities_list(&authorities);
+        let genesis = encode_header(&[0; 32], 0, &[]);
+
+        let slot_number = BLOCK1_SLOT_NUMBER + (BLOCK1_SLOT_NUMBER % 2);
+        let block1 = sealed_header_with_logs(
+            &genesis,
+            slot_number,
+            vec![header::DigestItem::AuraConsensus(
+                header::AuraConsensusLog::OnDisabled(1),
+            )],
+            &authorities[0],
+        );
+        let success = verify(&list, &block1, &genesis).unwrap();
+        assert!(!success.authorities_change);
+
+        // As in Substrate, the disabled authority keeps its slots.
+        let block2 = sealed_header(&block1, slot_number + 1, &authorities[1]);
+        assert!(verify(&list, &block2, &block1).is_ok());
+    }
+
+    #[test]
+    fn signature_scheme() {
+        let sr25519 = authorities(1);
+        let ed25519 = ed25519_authorities(1);
+        let genesis = encode_header(&[0; 32], 0, &[]);
+
+        let block1 = sealed_header(&genesis, BLOCK1_SLOT_NUMBER, &ed25519[0]);
+        let list = authorities_list(&ed25519);
+        assert!(verify_with_scheme(&list, AuraSignatureScheme::Ed25519, &block1, &genesis).is_ok());
+        assert!(matches!(
+            verify_with_scheme(&list, AuraSignatureScheme::Sr25519, &block1, &genesis),
+            Err(VerifyError::BadSignature)
+        ));
+
+        let block1 = sealed_header(&genesis, BLOCK1_SLOT_NUMBER, &sr25519[0]);
+        let list = authorities_list(&sr25519);
+        assert!(verify_with_scheme(&list, AuraSignatureScheme::Sr25519, &block1, &genesis).is_ok());
+        assert!(matches!(
+            verify_with_scheme(&list, AuraSignatureScheme::Ed25519, &block1, &genesis),
+            Err(VerifyError::BadSignature)
+        ));
+    }
+
+    #[test]
+    fn slot_number_must_increase() {
+        let authorities = authorities(1);
+        let list = authorities_list(&authorities);
+        let genesis = encode_header(&[0; 32], 0, &[]);
+        let block1 = sealed_header(&genesis, BLOCK1_SLOT_NUMBER, &authorities[0]);
+        let block2 = sealed_header(&block1, BLOCK1_SLOT_NUMBER, &authorities[0]);
+
+        assert!(verify(&list, &block1, &genesis).is_ok());
+        assert!(matches!(
+            verify(&list, &block2, &block1),
+            Err(VerifyError::SlotNumberNotIncreasing)
+        ));
+    }
+}
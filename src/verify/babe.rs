@@ -69,8 +69,13 @@
 //! public keys allowed to generate blocks in that epoch. The weight associated to that public key
 //! determines the allowed threshold.
 //!
-//! The "randomess value" of an epoch `N` is calculated by combining the generated numbers of all
-//! the blocks of the epoch `N - 2`.
+//! The "randomess value" of an epoch `N` is calculated by combining the randomness value of the
+//! epoch `N - 1` with the generated numbers of all the blocks of the epoch `N - 2` that contain
+//! a VRF output, in other words primary slot claims and secondary VRF slot claims. More
+//! precisely, it is equal to
+//! `blake2_256(randomness(N - 1) ++ N.to_le_bytes() ++ contribution(block1) ++ ...)`, where
+//! `contribution` is a value derived from the output of the VRF of the block. See
+//! [`VerifySuccess::randomness_contribution`].
 //!
 //! ## Secondary slots
 //!
@@ -86,6 +91,11 @@
 //! Secondary slot claims are a way to guarantee that all slots can potentially lead to a block
 //! being produced.
 //!
+//! Secondary slot claims come in two flavours: "plain" claims only contain the index of the
+//! author, while "VRF" claims additionally contain a VRF output and its proof, generated the same
+//! way as for primary slot claims but without any threshold. The configuration of each epoch (see
+//! [`header::BabeAllowedSlots`]) indicates which of these two flavours is allowed, if any.
+//!
 //! ## Chain selection
 //!
 //! The "best" block of a chain in the BABE algorithm is the one with the highest slot number.
@@ -125,6 +135,10 @@
 //! - The [`header::BabeNextEpoch`] structs corresponding to each epoch number. An [`header::BabeNextEpoch`]
 //! can be extracted from a block's header, therefore for long-term storage you only need to store
 //! which block contains the information about each epoch number, and that block's header.
+//! - The [`VerifySuccess::randomness_contribution`] of each block of the current epoch. When
+//! verifying the first block of the next epoch, these contributions must be provided as part of
+//! the [`VerifyConfig`] in order for the randomness value that this block announces to be
+//! verified.
 //!
 //! In both situations, you need to be aware of forks. There can be multiple block 1s, and
 //! multiple blocks which contain an [`header::BabeNextEpoch`] for a given epoch number. Only the
@@ -133,6 +147,7 @@
 
 use crate::{chain::chain_information::babe::BabeGenesisConfiguration, header};
 
+use alloc::vec::Vec;
 use core::{convert::TryFrom as _, num::NonZeroU64, time::Duration};
 use num_traits::{cast::ToPrimitive as _, identities::One as _};
 
@@ -143,9 +158,16 @@ pub struct VerifyConfig<'a> {
 
     /// Time elapsed since [the Unix Epoch](https://en.wikipedia.org/wiki/Unix_time) (i.e.
     /// 00:00:00 UTC on 1 January 1970), ignoring leap seconds.
-    // TODO: unused, should check against a block's slot
+    ///
+    /// Used in order to determine the current slot. See [`current_slot`].
     pub now_from_unix_epoch: Duration,
 
+    /// Number of slots the slot of the block is allowed to be ahead of the current slot.
+    ///
+    /// Clocks of the various nodes of the network are never perfectly synchronized. A non-zero
+    /// value makes it possible to accept blocks produced by nodes whose clock is slightly ahead.
+    pub max_slot_drift: u64,
+
     /// Header of the parent of the block to verify.
     ///
     /// [`start_verify_header`] assumes that this block has been successfully verified before.
@@ -162,6 +184,27 @@ pub struct VerifyConfig<'a> {
     /// Slot number of block #1. **Must** be provided, unless the block being verified is block
     /// #1 itself.
     pub block1_slot_number: Option<u64>,
+
+    /// If the block contains an epoch change, the [`VerifySuccess::randomness_contribution`]s
+    /// of the blocks of the epoch of the parent block, from the first block of that epoch to the
+    /// parent block included, in that order. Blocks that don't have any contribution must be
+    /// skipped.
+    ///
+    /// If `Some`, the randomness value announced by the epoch change is verified against these
+    /// contributions. If `None`, for example because some of these blocks are unknown, the
+    /// randomness value isn't verified. Ignored if the block doesn't contain an epoch change.
+    ///
+    /// > **Note**: The randomness values of epochs #1 and #2 depend on the storage of the
+    /// >           genesis block and are never verified.
+    pub parent_epoch_randomness_contributions: Option<&'a [[u8; 32]]>,
+
+    /// Indices of the authorities of the epoch of the parent block that have been disabled by
+    /// the parent block or by its ancestors belonging to the same epoch, through
+    /// [`header::BabeConsensusLog::OnDisabled`] digest log items.
+    ///
+    /// Blocks authored by a disabled authority are refused. Ignored if the block is the first
+    /// block of a new epoch, as authorities are no longer disabled after an epoch change.
+    pub parent_disabled_authorities: &'a [u32],
 }
 
 /// Information yielded back after successfully verifying a block.
@@ -180,6 +223,13 @@ pub struct VerifySuccess {
     ///
     /// > **Note**: If `Some`, the value is always equal to [`VerifySuccess::epoch_number`] + 1.
     pub epoch_transition_target: Option<NonZeroU64>,
+
+    /// If the block is a primary slot claim or a secondary VRF slot claim, value derived from the
+    /// output of its VRF and that contributes to the randomness value of the epoch after the next
+    /// one. `None` for secondary plain slot claims, which don't contain any VRF output.
+    ///
+    /// See [`VerifyConfig::parent_epoch_randomness_contributions`].
+    pub randomness_contribution: Option<[u8; 32]>,
 }
 
 /// Failure to verify a block.
@@ -197,18 +247,45 @@ pub enum VerifyError {
     UnexpectedEpochChangeLog,
     /// Block is the first block after a new epoch, but it is missing an epoch change digest log.
     MissingEpochChangeLog,
+    /// Randomness value announced by the epoch change digest log doesn't match the one derived
+    /// from the previous epochs.
+    BadEpochRandomness,
     /// Authority index stored within block is out of range.
     InvalidAuthorityIndex,
+    /// Authority that has authored the block has been disabled by an ancestor of the block.
+    DisabledAuthority,
     /// Block header signature is invalid.
     BadSignature,
-    /// VRF proof in the block header is invalid.
+    /// VRF proof of the primary slot claim in the block header is invalid.
     BadVrfProof,
+    /// VRF proof of the secondary VRF slot claim in the block header is invalid.
+    BadSecondaryVrfProof,
     /// Block is a secondary slot claim and its author is not the expected author.
     BadSecondarySlotAuthor,
     /// VRF output is over threshold required to claim the primary slot.
     OverPrimaryClaimThreshold,
-    /// Type of slot claim forbidden by current configuration.
-    ForbiddenSlotType,
+    /// Block is a secondary slot claim, while the configuration of the epoch only allows
+    /// primary slot claims.
+    SecondarySlotForbidden,
+    /// Block is a secondary plain slot claim, while the configuration of the epoch only allows
+    /// secondary slot claims that contain a VRF output.
+    SecondaryPlainSlotForbidden,
+    /// Block is a secondary VRF slot claim, while the configuration of the epoch only allows
+    /// secondary plain slot claims.
+    SecondaryVrfSlotForbidden,
+    /// Slot of the block is too far ahead of the current slot. The block might become valid
+    /// later.
+    #[display(
+        fmt = "Slot {} of the block is too far ahead of the current slot {}",
+        slot_number,
+        current_slot
+    )]
+    TooFarInFuture {
+        /// Slot number of the block.
+        slot_number: u64,
+        /// Current slot, as determined from [`VerifyConfig::now_from_unix_epoch`].
+        current_slot: u64,
+    },
 }
 
 /// Verifies whether a block header provides a correct proof of the legitimacy of the authorship.
@@ -222,8 +299,6 @@ pub enum VerifyError {
 /// Panics if `config.block1_slot_number` is `None` and `config.header.number` is not 1.
 ///
 pub fn start_verify_header<'a>(config: VerifyConfig<'a>) -> Result<SuccessOrPending, VerifyError> {
-    // TODO: handle OnDisabled
-
     // Gather the BABE-related information from the header.
     let (authority_index, slot_number, primary, vrf) = match config.header.digest.babe_pre_runtime()
     {
@@ -245,6 +320,16 @@ pub fn start_verify_header<'a>(config: VerifyConfig<'a>) -> Result<SuccessOrPend
         None => return Err(VerifyError::MissingPreRuntimeDigest),
     };
 
+    // Blocks can't be produced ahead of their slot, unless the clock of their author is ahead
+    // of ours. This check is cheap and is therefore performed before any other.
+    let current_slot = current_slot(config.now_from_unix_epoch, config.genesis_configuration);
+    if slot_number > current_slot.saturating_add(config.max_slot_drift) {
+        return Err(VerifyError::TooFarInFuture {
+            slot_number,
+            current_slot,
+        });
+    }
+
     // Determine the epoch number the block we verify belongs to.
     let epoch_number = match (slot_number, config.block1_slot_number) {
         (curr, Some(block1)) => {
@@ -284,9 +369,19 @@ pub fn start_verify_header<'a>(config: VerifyConfig<'a>) -> Result<SuccessOrPend
         .babe_epoch_information()
         .map(|_| NonZeroU64::new(epoch_number + 1).unwrap());
 
-    // TODO: in case of epoch change, should also check the randomness value; while the runtime
-    //       checks that the randomness value is correct, light clients in particular do not
-    //       execute the runtime
+    // In case of epoch change, the randomness value announced by the block is later checked
+    // against the one derived from the blocks of the parent's epoch. While the runtime also
+    // checks that the randomness value is correct, light clients in particular do not execute
+    // the runtime.
+    let epoch_randomness_check = match (
+        config.header.digest.babe_epoch_information(),
+        config.parent_epoch_randomness_contributions,
+    ) {
+        (Some((new_epoch, _)), Some(contributions)) => {
+            Some((*new_epoch.randomness, contributions.to_vec()))
+        }
+        _ => None,
+    };
 
     // Make sure that the expected epoch transitions correspond to what the blocks report.
     match (
@@ -299,6 +394,16 @@ pub fn start_verify_header<'a>(config: VerifyConfig<'a>) -> Result<SuccessOrPend
         (None, true) => return Err(VerifyError::MissingEpochChangeLog),
     };
 
+    // Authorities can be disabled by the runtime until the end of the current epoch, for example
+    // after they have misbehaved.
+    if epoch_transition_target.is_none()
+        && config
+            .parent_disabled_authorities
+            .contains(&authority_index)
+    {
+        return Err(VerifyError::DisabledAuthority);
+    }
+
     let seal_signature = match config.header.digest.babe_seal() {
         Some(seal) => {
             schnorrkel::Signature::from_bytes(seal).map_err(|_| VerifyError::BadSignature)?
@@ -320,6 +425,7 @@ pub fn start_verify_header<'a>(config: VerifyConfig<'a>) -> Result<SuccessOrPend
         pre_seal_hash,
         seal_signature,
         epoch_transition_target,
+        epoch_randomness_check,
         epoch_number,
         slot_number,
         authority_index,
@@ -360,6 +466,10 @@ pub struct PendingVerify {
     /// If `Some`, block is at an epoch transition.
     /// This can only happen for blocks that are the first block of an epoch.
     epoch_transition_target: Option<NonZeroU64>,
+    /// If `Some`, randomness value announced by the epoch transition of the block, and
+    /// randomness contributions of the blocks of the parent's epoch that it must be checked
+    /// against.
+    epoch_randomness_check: Option<([u8; 32], Vec<[u8; 32]>)>,
     /// Epoch number the block belongs to.
     epoch_number: u64,
     /// Slot number the block belongs to.
@@ -394,13 +504,21 @@ impl PendingVerify {
         match (
             epoch_info.1.allowed_slots,
             self.primary_slot_claim,
-            self.vrf_output_and_proof,
+            self.vrf_output_and_proof.is_some(),
         ) {
-            (_, true, None) => unreachable!(),
-            (_, true, Some(_)) => {}
-            (header::BabeAllowedSlots::PrimaryAndSecondaryPlainSlots, false, None) => {}
-            (header::BabeAllowedSlots::PrimaryAndSecondaryVRFSlots, false, Some(_)) => {}
-            _ => return Err(VerifyError::ForbiddenSlotType),
+            (_, true, false) => unreachable!(),
+            (_, true, true) => {}
+            (header::BabeAllowedSlots::PrimarySlots, false, _) => {
+                return Err(VerifyError::SecondarySlotForbidden)
+            }
+            (header::BabeAllowedSlots::PrimaryAndSecondaryPlainSlots, false, false) => {}
+            (header::BabeAllowedSlots::PrimaryAndSecondaryPlainSlots, false, true) => {
+                return Err(VerifyError::SecondaryVrfSlotForbidden)
+            }
+            (header::BabeAllowedSlots::PrimaryAndSecondaryVRFSlots, false, true) => {}
+            (header::BabeAllowedSlots::PrimaryAndSecondaryVRFSlots, false, false) => {
+                return Err(VerifyError::SecondaryPlainSlotForbidden)
+            }
         }
 
         // Fetch the authority that has supposedly signed the block.
@@ -427,6 +545,7 @@ impl PendingVerify {
         // Now verify the VRF output and proof, if any.
         // The lack of VRF output/proof in the header is checked when we check whether the slot
         // type is allowed by the current configuration.
+        let mut randomness_contribution = None;
         if let Some((vrf_output, vrf_proof)) = self.vrf_output_and_proof {
             // In order to verify the VRF output, we first need to create a transcript containing all
             // the data to verify the VRF against.
@@ -445,7 +564,13 @@ impl PendingVerify {
 
             let (vrf_in_out, _) = signing_public_key
                 .vrf_verify(transcript, &vrf_output, &vrf_proof)
-                .map_err(|_| VerifyError::BadVrfProof)?;
+                .map_err(|_| {
+                    if self.primary_slot_claim {
+                        VerifyError::BadVrfProof
+                    } else {
+                        VerifyError::BadSecondaryVrfProof
+                    }
+                })?;
 
             // If this is a primary slot claim, we need to make sure that the VRF output is below
             // a certain threshold, otherwise all the authorities could claim all the slots.
@@ -461,6 +586,11 @@ impl PendingVerify {
                     return Err(VerifyError::OverPrimaryClaimThreshold);
                 }
             }
+
+            // The VRF outputs of both primary and secondary VRF slot claims are used by the
+            // runtime in order to generate the randomness of future epochs.
+            randomness_contribution =
+                Some(vrf_in_out.make_bytes::<[u8; 32]>(b"BabeVRFInOutContext"));
         } else {
             debug_assert!(!self.primary_slot_claim);
         }
@@ -490,15 +620,53 @@ impl PendingVerify {
             }
         }
 
+        // The randomness of the epoch after the one of this block is derived from the randomness
+        // of this block's epoch, which `epoch_info` describes.
+        if let Some((announced_randomness, contributions)) = self.epoch_randomness_check {
+            let expected_randomness = {
+                let mut hash = blake2_rfc::blake2b::Blake2b::new(32);
+                hash.update(epoch_info.0.randomness);
+                hash.update(&(self.epoch_number + 1).to_le_bytes());
+                for contribution in &contributions {
+                    hash.update(contribution);
+                }
+                hash.finalize()
+            };
+
+            if expected_randomness.as_bytes() != announced_randomness {
+                return Err(VerifyError::BadEpochRandomness);
+            }
+        }
+
         // Success! 🚀
         Ok(VerifySuccess {
             epoch_transition_target: self.epoch_transition_target,
             slot_number: self.slot_number,
             epoch_number: self.epoch_number,
+            randomness_contribution,
         })
     }
 }
 
+/// Returns the slot that is ongoing at the given time.
+///
+/// Slot `N` starts at `N * slot_duration` since the Unix epoch. Returns `u64::max_value()` if the
+/// slot duration of the chain is 0.
+pub fn current_slot(
+    now_from_unix_epoch: Duration,
+    genesis_config: &BabeGenesisConfiguration,
+) -> u64 {
+    u64::try_from(now_from_unix_epoch.as_millis())
+        .unwrap_or(u64::max_value())
+        .checked_div(genesis_config.slot_duration())
+        .unwrap_or(u64::max_value())
+}
+
+/// Returns the time, since the Unix epoch, at which the given slot starts.
+pub fn slot_start_time(slot_number: u64, genesis_config: &BabeGenesisConfiguration) -> Duration {
+    Duration::from_millis(slot_number.saturating_mul(genesis_config.slot_duration()))
+}
+
 /// Turns a slot number into an epoch number.
 ///
 /// Returns an error if `slot_number` is inferior to `block1_slot_number`.
@@ -545,3 +713,627 @@ fn calculate_primary_threshold(
         .to_u128()
         .unwrap()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{start_verify_header, SuccessOrPending, VerifyConfig, VerifyError, VerifySuccess};
+    use crate::{
+        chain::chain_information::babe::BabeGenesisConfiguration,
+        header,
+        test_utils::{encode_header, sr25519_keypair},
+    };
+
+    use core::{convert::TryFrom as _, time::Duration};
+    use rand::SeedableRng as _;
+
+    const SLOT_DURATION_MS: u64 = 6000;
+    const SLOTS_PER_EPOCH: u64 = 8;
+    const BLOCK1_SLOT_NUMBER: u64 = 1000;
+    /// Value of `c` of the test chain. Nearly all the primary slot claims are below the
+    /// threshold.
+    const C: (u64, u64) = (999, 1000);
+    /// Time at which all the slots used by the tests have started.
+    const NOW: Duration = Duration::from_millis((BLOCK1_SLOT_NUMBER + 100) * SLOT_DURATION_MS);
+
+    /// Information about an epoch whose authorities are `authorities`.
+    fn epoch(authorities: &[schnorrkel::Keypair], randomness: [u8; 32]) -> header::BabeNextEpoch {
+        header::BabeNextEpoch {
+            authorities: authorities
+                .iter()
+                .map(|kp| header::BabeAuthority {
+                    public_key: kp.public.to_bytes(),
+                    weight: 1,
+                })
+                .collect(),
+            randomness,
+        }
+    }
+
+    /// Digest log items announcing an epoch whose authorities are `authorities`. Must be present
+    /// in the first block of each epoch.
+    fn epoch_change(
+        authorities: &[schnorrkel::Keypair],
+        randomness: [u8; 32],
+    ) -> Vec<header::DigestItem> {
+        vec![header::DigestItem::BabeConsensus(
+            header::BabeConsensusLog::NextEpochData(epoch(authorities, randomness)),
+        )]
+    }
+
+    /// Builds a genesis configuration whose epoch #0 is the given one.
+    fn genesis_configuration(
+        epoch0: header::BabeNextEpoch,
+        allowed_slots: header::BabeAllowedSlots,
+    ) -> BabeGenesisConfiguration {
+        BabeGenesisConfiguration::from_parts(
+            SLOT_DURATION_MS,
+            SLOTS_PER_EPOCH,
+            C,
+            allowed_slots,
+            epoch0,
+        )
+    }
+
+    /// Builds a child of `parent` with the given pre-runtime digest and additional digest log
+    /// items, sealed by `author`.
+    fn sealed_header(
+        parent: &[u8],
+        pre_digest: header::BabePreDigest,
+        logs: Vec<header::DigestItem>,
+        author: &schnorrkel::Keypair,
+    ) -> Vec<u8> {
+        let parent_hash = header::hash_from_scale_encoded_header(parent);
+        let number = header::decode(parent).unwrap().number + 1;
+
+        let mut digest = vec![header::DigestItem::BabePreDigest(pre_digest)];
+        digest.extend(logs);
+
+        let pre_seal_hash =
+            header::hash_from_scale_encoded_header(encode_header(&parent_hash, number, &digest));
+        let signature = author.sign(schnorrkel::context::attach_rng(
+            schnorrkel::signing_context(b"substrate").bytes(&pre_seal_hash),
+            rand_chacha::ChaCha20Rng::seed_from_u64(number),
+        ));
+        digest.push(header::DigestItem::BabeSeal(signature.to_bytes()));
+
+        encode_header(&parent_hash, number, &digest)
+    }
+
+    /// Evaluates the VRF of `author` for the given slot of the given epoch.
+    ///
+    /// Returns the VRF output, its proof, and the randomness contribution derived from it.
+    fn vrf_sign(
+        author: &schnorrkel::Keypair,
+        slot_number: u64,
+        epoch: &header::BabeNextEpoch,
+    ) -> ([u8; 32], [u8; 64], [u8; 32]) {
+        let transcript = {
+            let mut transcript = merlin::Transcript::new(&b"BABE"[..]);
+            transcript.append_u64(b"slot number", slot_number);
+            transcript.append_u64(
+                b"current epoch",
+                (slot_number - BLOCK1_SLOT_NUMBER) / SLOTS_PER_EPOCH,
+            );
+            transcript.append_message(b"chain randomness", &epoch.randomness[..]);
+            transcript
+        };
+        // The randomness used to generate the proof is taken from the "extra" transcript.
+        let (vrf_in_out, vrf_proof, _) = author.vrf_sign_extra(
+            transcript,
+            schnorrkel::context::attach_rng(
+                merlin::Transcript::new(b"VRF"),
+                rand_chacha::ChaCha20Rng::seed_from_u64(slot_number),
+            ),
+        );
+        (
+            vrf_in_out.to_output().to_bytes(),
+            vrf_proof.to_bytes(),
+            vrf_in_out.make_bytes::<[u8; 32]>(b"BabeVRFInOutContext"),
+        )
+    }
+
+    /// Builds a secondary VRF pre-runtime digest for the given authority.
+    fn secondary_vrf(
+        authority_index: u32,
+        author: &schnorrkel::Keypair,
+        slot_number: u64,
+        epoch: &header::BabeNextEpoch,
+    ) -> header::BabePreDigest {
+        let (vrf_output, vrf_proof, _) = vrf_sign(author, slot_number, epoch);
+        header::BabePreDigest::SecondaryVRF(header::BabeSecondaryVRFPreDigest {
+            authority_index,
+            slot_number,
+            vrf_output,
+            vrf_proof,
+        })
+    }
+
+    /// Builds a secondary plain pre-runtime digest for the given authority.
+    fn secondary_plain(authority_index: u32, slot_number: u64) -> header::BabePreDigest {
+        header::BabePreDigest::SecondaryPlain(header::BabeSecondaryPlainPreDigest {
+            authority_index,
+            slot_number,
+        })
+    }
+
+    /// Builds a verification configuration for `header`, child of `parent`.
+    fn config<'a>(
+        genesis_configuration: &'a BabeGenesisConfiguration,
+        header: &'a [u8],
+        parent: &'a [u8],
+    ) -> VerifyConfig<'a> {
+        VerifyConfig {
+            header: header::decode(header).unwrap(),
+            now_from_unix_epoch: NOW,
+            max_slot_drift: 0,
+            parent_block_header: header::decode(parent).unwrap(),
+            genesis_configuration,
+            block1_slot_number: Some(BLOCK1_SLOT_NUMBER),
+            parent_epoch_randomness_contributions: None,
+            parent_disabled_authorities: &[],
+        }
+    }
+
+    /// Verifies a block. If the block doesn't belong to epoch #0, `epoch` must be the
+    /// information about its epoch.
+    fn verify(
+        config: VerifyConfig,
+        epoch: Option<(&header::BabeNextEpoch, header::BabeAllowedSlots)>,
+    ) -> Result<VerifySuccess, VerifyError> {
+        match start_verify_header(config)? {
+            SuccessOrPending::Success(success) => Ok(success),
+            SuccessOrPending::Pending(pending) => {
+                let (epoch, allowed_slots) = epoch.unwrap();
+                pending.finish((
+                    epoch.into(),
+                    header::BabeNextConfig {
+                        c: C,
+                        allowed_slots,
+                    },
+                ))
+            }
+        }
+    }
+
+    #[test]
+    fn secondary_slot_claims() {
+        let authorities = [sr25519_keypair(1)];
+        let epoch0 = epoch(&authorities, [0; 32]);
+        let genesis = encode_header(&[0; 32], 0, &[]);
+
+        let plain_config = genesis_configuration(
+            epoch0.clone(),
+            header::BabeAllowedSlots::PrimaryAndSecondaryPlainSlots,
+        );
+        let block1 = sealed_header(
+            &genesis,
+            secondary_plain(0, BLOCK1_SLOT_NUMBER),
+            epoch_change(&authorities, [1; 32]),
+            &authorities[0],
+        );
+        let success = verify(config(&plain_config, &block1, &genesis), None).unwrap();
+        assert_eq!(success.slot_number, BLOCK1_SLOT_NUMBER);
+        assert_eq!(success.epoch_number, 0);
+
+        let vrf_config = genesis_configuration(
+            epoch0.clone(),
+            header::BabeAllowedSlots::PrimaryAndSecondaryVRFSlots,
+        );
+        let block1 = sealed_header(
+            &genesis,
+            secondary_vrf(0, &authorities[0], BLOCK1_SLOT_NUMBER, &epoch0),
+            epoch_change(&authorities, [1; 32]),
+            &authorities[0],
+        );
+        assert!(verify(config(&vrf_config, &block1, &genesis), None).is_ok());
+    }
+
+    #[test]
+    fn randomness_contributions() {
+        let authorities = [sr25519_keypair(1)];
+        let epoch0 = epoch(&authorities, [0; 32]);
+        let genesis = encode_header(&[0; 32], 0, &[]);
+        let vrf_config = genesis_configuration(
+            epoch0.clone(),
+            header::BabeAllowedSlots::PrimaryAndSecondaryVRFSlots,
+        );
+
+        // Primary slot claims contribute the output of their VRF.
+        let (vrf_output, vrf_proof, expected) =
+            vrf_sign(&authorities[0], BLOCK1_SLOT_NUMBER, &epoch0);
+        let block1 = sealed_header(
+            &genesis,
+            header::BabePreDigest::Primary(header::BabePrimaryPreDigest {
+                authority_index: 0,
+                slot_number: BLOCK1_SLOT_NUMBER,
+                vrf_output,
+                vrf_proof,
+            }),
+            epoch_change(&authorities, [1; 32]),
+            &authorities[0],
+        );
+        let success = verify(config(&vrf_config, &block1, &genesis), None).unwrap();
+        assert_eq!(success.randomness_contribution, Some(expected));
+
+        // So do secondary VRF slot claims.
+        let block1 = sealed_header(
+            &genesis,
+            secondary_vrf(0, &authorities[0], BLOCK1_SLOT_NUMBER, &epoch0),
+            epoch_change(&authorities, [1; 32]),
+            &authorities[0],
+        );
+        let success = verify(config(&vrf_config, &block1, &genesis), None).unwrap();
+        assert_eq!(success.randomness_contribution, Some(expected));
+
+        // Secondary plain slot claims don't have any VRF output to contribute.
+        let plain_config = genesis_configuration(
+            epoch0,
+            header::BabeAllowedSlots::PrimaryAndSecondaryPlainSlots,
+        );
+        let block1 = sealed_header(
+            &genesis,
+            secondary_plain(0, BLOCK1_SLOT_NUMBER),
+            epoch_change(&authorities, [1; 32]),
+            &authorities[0],
+        );
+        let success = verify(config(&plain_config, &block1, &genesis), None).unwrap();
+        assert_eq!(success.randomness_contribution, None);
+    }
+
+    #[test]
+    fn secondary_slot_author() {
+        let authorities = [
+            sr25519_keypair(1),
+            sr25519_keypair(2),
+            sr25519_keypair(3),
+            sr25519_keypair(4),
+        ];
+        let epoch0 = epoch(&authorities, [0; 32]);
+        let genesis = encode_header(&[0; 32], 0, &[]);
+        let genesis_configuration = genesis_configuration(
+            epoch0,
+            header::BabeAllowedSlots::PrimaryAndSecondaryPlainSlots,
+        );
+
+        // The author of a secondary slot is derived from the randomness of the epoch and the
+        // slot number, rather than being `slot_number % authorities.len()`.
+        let expected_author = |slot_number: u64| {
+            let mut hash = blake2_rfc::blake2b::Blake2b::new(32);
+            hash.update(&[0; 32]);
+            hash.update(&slot_number.to_le_bytes());
+            let hash = primitive_types::U256::from_big_endian(hash.finalize().as_bytes());
+            (hash % primitive_types::U256::from(authorities.len())).as_u32()
+        };
+        let slot_number = (BLOCK1_SLOT_NUMBER..)
+            .find(|slot| u64::from(expected_author(*slot)) != slot % 4)
+            .unwrap();
+
+        for (index, author) in authorities.iter().enumerate() {
+            let index = u32::try_from(index).unwrap();
+            let block1 = sealed_header(
+                &genesis,
+                secondary_plain(index, slot_number),
+                epoch_change(&authorities, [1; 32]),
+                author,
+            );
+            let result = verify(
+                VerifyConfig {
+                    block1_slot_number: None,
+                    ..config(&genesis_configuration, &block1, &genesis)
+                },
+                None,
+            );
+            if index == expected_author(slot_number) {
+                assert!(result.is_ok());
+            } else {
+                assert!(matches!(result, Err(VerifyError::BadSecondarySlotAuthor)));
+            }
+        }
+    }
+
+    #[test]
+    fn forbidden_slot_claim_types_are_refused() {
+        let authorities = [sr25519_keypair(1)];
+        let epoch0 = epoch(&authorities, [0; 32]);
+        let genesis = encode_header(&[0; 32], 0, &[]);
+
+        let plain_block = sealed_header(
+            &genesis,
+            secondary_plain(0, BLOCK1_SLOT_NUMBER),
+            epoch_change(&authorities, [1; 32]),
+            &authorities[0],
+        );
+        let vrf_block = sealed_header(
+            &genesis,
+            secondary_vrf(0, &authorities[0], BLOCK1_SLOT_NUMBER, &epoch0),
+            epoch_change(&authorities, [1; 32]),
+            &authorities[0],
+        );
+
+        let expect_error = |allowed_slots, block: &[u8], expected: VerifyError| {
+            let genesis_configuration = genesis_configuration(epoch0.clone(), allowed_slots);
+            match verify(config(&genesis_configuration, block, &genesis), None) {
+                Err(err) => assert_eq!(err.to_string(), expected.to_string()),
+                Ok(_) => panic!(),
+            }
+        };
+
+        expect_error(
+            header::BabeAllowedSlots::PrimarySlots,
+            &plain_block,
+            VerifyError::SecondarySlotForbidden,
+        );
+        expect_error(
+            header::BabeAllowedSlots::PrimarySlots,
+            &vrf_block,
+            VerifyError::SecondarySlotForbidden,
+        );
+        expect_error(
+            header::BabeAllowedSlots::PrimaryAndSecondaryVRFSlots,
+            &plain_block,
+            VerifyError::SecondaryPlainSlotForbidden,
+        );
+        expect_error(
+            header::BabeAllowedSlots::PrimaryAndSecondaryPlainSlots,
+            &vrf_block,
+            VerifyError::SecondaryVrfSlotForbidden,
+        );
+    }
+
+    #[test]
+    fn bad_secondary_vrf_proof_is_refused() {
+        let authorities = [sr25519_keypair(1)];
+        let genesis = encode_header(&[0; 32], 0, &[]);
+
+        // The VRF output is generated against a different randomness than the one of epoch #0.
+        let block1 = sealed_header(
+            &genesis,
+            secondary_vrf(
+                0,
+                &authorities[0],
+                BLOCK1_SLOT_NUMBER,
+                &epoch(&authorities, [1; 32]),
+            ),
+            epoch_change(&authorities, [1; 32]),
+            &authorities[0],
+        );
+
+        let genesis_configuration = genesis_configuration(
+            epoch(&authorities, [0; 32]),
+            header::BabeAllowedSlots::PrimaryAndSecondaryVRFSlots,
+        );
+        assert!(matches!(
+            verify(config(&genesis_configuration, &block1, &genesis), None),
+            Err(VerifyError::BadSecondaryVrfProof)
+        ));
+    }
+
+    #[test]
+    fn blocks_from_future_slots_are_refused() {
+        let authorities = [sr25519_keypair(1)];
+        let genesis = encode_header(&[0; 32], 0, &[]);
+        let genesis_configuration = genesis_configuration(
+            epoch(&authorities, [0; 32]),
+            header::BabeAllowedSlots::PrimaryAndSecondaryPlainSlots,
+        );
+        let block1 = sealed_header(
+            &genesis,
+            secondary_plain(0, BLOCK1_SLOT_NUMBER + 2),
+            epoch_change(&authorities, [1; 32]),
+            &authorities[0],
+        );
+
+        let now = Duration::from_millis(BLOCK1_SLOT_NUMBER * SLOT_DURATION_MS);
+        assert!(matches!(
+            verify(
+                VerifyConfig {
+                    now_from_unix_epoch: now,
+                    max_slot_drift: 1,
+                    ..config(&genesis_configuration, &block1, &genesis)
+                },
+                None
+            ),
+            Err(VerifyError::TooFarInFuture {
+                slot_number,
+                current_slot: BLOCK1_SLOT_NUMBER,
+            }) if slot_number == BLOCK1_SLOT_NUMBER + 2
+        ));
+
+        // The drift allows the slot to be one slot ahead.
+        assert!(verify(
+            VerifyConfig {
+                now_from_unix_epoch: now + Duration::from_millis(SLOT_DURATION_MS),
+                max_slot_drift: 1,
+                ..config(&genesis_configuration, &block1, &genesis)
+            },
+            None
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn disabled_authority_is_refused() {
+        let authorities = [sr25519_keypair(1)];
+        let epoch0 = epoch(&authorities, [0; 32]);
+        let epoch1 = epoch(&authorities, [1; 32]);
+        let allowed_slots = header::BabeAllowedSlots::PrimaryAndSecondaryPlainSlots;
+        let genesis_configuration = genesis_configuration(epoch0, allowed_slots);
+
+        let genesis = encode_header(&[0; 32], 0, &[]);
+        let block1 = sealed_header(
+            &genesis,
+            secondary_plain(0, BLOCK1_SLOT_NUMBER),
+            vec![header::DigestItem::BabeConsensus(
+                header::BabeConsensusLog::NextEpochData(epoch1.clone()),
+            )],
+            &authorities[0],
+        );
+
+        let block2 = sealed_header(
+            &block1,
+            secondary_plain(0, BLOCK1_SLOT_NUMBER + 1),
+            Vec::new(),
+            &authorities[0],
+        );
+        assert!(matches!(
+            verify(
+                VerifyConfig {
+                    parent_disabled_authorities: &[0],
+                    ..config(&genesis_configuration, &block2, &block1)
+                },
+                None
+            ),
+            Err(VerifyError::DisabledAuthority)
+        ));
+
+        // Authorities are enabled again at the first block of the next epoch.
+        let block2 = sealed_header(
+            &block1,
+            secondary_plain(0, BLOCK1_SLOT_NUMBER + SLOTS_PER_EPOCH),
+            vec![header::DigestItem::BabeConsensus(
+                header::BabeConsensusLog::NextEpochData(epoch(&authorities, [2; 32])),
+            )],
+            &authorities[0],
+        );
+        assert!(verify(
+            VerifyConfig {
+                parent_disabled_authorities: &[0],
+                ..config(&genesis_configuration, &block2, &block1)
+            },
+            Some((&epoch1, allowed_slots))
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn bad_epoch_randomness_is_refused() {
+        let authorities = [sr25519_keypair(1)];
+        let allowed_slots = header::BabeAllowedSlots::PrimaryAndSecondaryPlainSlots;
+        let genesis_configuration =
+            genesis_configuration(epoch(&authorities, [0; 32]), allowed_slots);
+        let epoch2 = epoch(&authorities, [2; 32]);
+
+        // Block in epoch #1, whose parent is irrelevant.
+        let parent = sealed_header(
+            &encode_header(&[0; 32], 10, &[]),
+            secondary_plain(0, BLOCK1_SLOT_NUMBER + 2 * SLOTS_PER_EPOCH - 1),
+            Vec::new(),
+            &authorities[0],
+        );
+
+        // The first block of epoch #2 announces the randomness of epoch #3, derived from the
+        // randomness of epoch #2 and the contributions of the blocks of epoch #1.
+        let contributions = [[5; 32], [6; 32]];
+        let expected_randomness = {
+            let mut hash = blake2_rfc::blake2b::Blake2b::new(32);
+            hash.update(&epoch2.randomness);
+            hash.update(&3u64.to_le_bytes());
+            hash.update(&contributions[0]);
+            hash.update(&contributions[1]);
+            let mut out = [0; 32];
+            out.copy_from_slice(hash.finalize().as_bytes());
+            out
+        };
+
+        let block = |randomness| {
+            sealed_header(
+                &parent,
+                secondary_plain(0, BLOCK1_SLOT_NUMBER + 2 * SLOTS_PER_EPOCH),
+                vec![header::DigestItem::BabeConsensus(
+                    header::BabeConsensusLog::NextEpochData(epoch(&authorities, randomness)),
+                )],
+                &authorities[0],
+            )
+        };
+
+        let valid = block(expected_randomness);
+        assert!(verify(
+            VerifyConfig {
+                parent_epoch_randomness_contributions: Some(&contributions),
+                ..config(&genesis_configuration, &valid, &parent)
+            },
+            Some((&epoch2, allowed_slots))
+        )
+        .is_ok());
+
+        let invalid = block([0xff; 32]);
+        assert!(matches!(
+            verify(
+                VerifyConfig {
+                    parent_epoch_randomness_contributions: Some(&contributions),
+                    ..config(&genesis_configuration, &invalid, &parent)
+                },
+                Some((&epoch2, allowed_slots))
+            ),
+            Err(VerifyError::BadEpochRandomness)
+        ));
+
+        // The randomness isn't verified if the contributions are unknown.
+        assert!(verify(
+            config(&genesis_configuration, &invalid, &parent),
+            Some((&epoch2, allowed_slots))
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn epoch_randomness_of_epoch2_is_verified() {
+        let authorities = [sr25519_keypair(1)];
+        let allowed_slots = header::BabeAllowedSlots::PrimaryAndSecondaryPlainSlots;
+        let genesis_configuration =
+            genesis_configuration(epoch(&authorities, [0; 32]), allowed_slots);
+        let epoch1 = epoch(&authorities, [1; 32]);
+
+        // Last block of epoch #0, whose parent is irrelevant.
+        let parent = sealed_header(
+            &encode_header(&[0; 32], 10, &[]),
+            secondary_plain(0, BLOCK1_SLOT_NUMBER + SLOTS_PER_EPOCH - 1),
+            Vec::new(),
+            &authorities[0],
+        );
+
+        // The first block of epoch #1 announces the randomness of epoch #2, derived from the
+        // randomness of epoch #1 and the contributions of the blocks of epoch #0.
+        let contributions = [[5; 32]];
+        let expected_randomness = {
+            let mut hash = blake2_rfc::blake2b::Blake2b::new(32);
+            hash.update(&epoch1.randomness);
+            hash.update(&2u64.to_le_bytes());
+            hash.update(&contributions[0]);
+            let mut out = [0; 32];
+            out.copy_from_slice(hash.finalize().as_bytes());
+            out
+        };
+
+        let block = |randomness| {
+            sealed_header(
+                &parent,
+                secondary_plain(0, BLOCK1_SLOT_NUMBER + SLOTS_PER_EPOCH),
+                epoch_change(&authorities, randomness),
+                &authorities[0],
+            )
+        };
+
+        let valid = block(expected_randomness);
+        assert!(verify(
+            VerifyConfig {
+                parent_epoch_randomness_contributions: Some(&contributions),
+                ..config(&genesis_configuration, &valid, &parent)
+            },
+            Some((&epoch1, allowed_slots))
+        )
+        .is_ok());
+
+        let invalid = block([0xff; 32]);
+        assert!(matches!(
+            verify(
+                VerifyConfig {
+                    parent_epoch_randomness_contributions: Some(&contributions),
+                    ..config(&genesis_configuration, &invalid, &parent)
+                },
+                Some((&epoch1, allowed_slots))
+            ),
+            Err(VerifyError::BadEpochRandomness)
+        ));
+    }
+}
@@ -536,9 +536,10 @@ impl VerifyInner {
 
                 executor::WasmVm::ExternalStorageRoot(req) => {
                     if self.root_calculation.is_none() {
-                        self.root_calculation = Some(calculate_root::root_merkle_value(Some(
-                            self.top_trie_root_calculation_cache.take().unwrap(),
-                        )));
+                        self.root_calculation = Some(calculate_root::root_merkle_value(
+                            Default::default(),
+                            Some(self.top_trie_root_calculation_cache.take().unwrap()),
+                        ));
                     }
 
                     match self.root_calculation.take().unwrap() {
@@ -17,8 +17,10 @@
 
 use super::execute_block;
 use crate::{
-    chain::chain_information::babe::BabeGenesisConfiguration, executor, header,
-    trie::calculate_root, verify::babe,
+    chain::chain_information::{aura::AuraGenesisConfiguration, babe::BabeGenesisConfiguration},
+    executor, header,
+    trie::calculate_root,
+    verify::{aura, babe},
 };
 
 use core::{num::NonZeroU64, time::Duration};
@@ -35,16 +37,8 @@ pub struct Config<'a, TBody> {
     /// The hash of this header must be the one referenced in [`Config::block_header`].
     pub parent_block_header: header::HeaderRef<'a>,
 
-    /// BABE configuration retrieved from the genesis block.
-    ///
-    /// See the documentation of [`BabeGenesisConfiguration`] to know how to get this.
-    pub babe_genesis_configuration: &'a BabeGenesisConfiguration,
-
-    /// Slot number of block #1. **Must** be provided, unless the block being verified is block
-    /// #1 itself.
-    ///
-    /// Must be the value of [`Success::slot_number`] for block #1.
-    pub block1_slot_number: Option<u64>,
+    /// Configuration items related to the consensus engine.
+    pub consensus: ConfigConsensus<'a>,
 
     /// Time elapsed since [the Unix Epoch](https://en.wikipedia.org/wiki/Unix_time) (i.e.
     /// 00:00:00 UTC on 1 January 1970), ignoring leap seconds.
@@ -64,21 +58,59 @@ pub struct Config<'a, TBody> {
     pub top_trie_root_calculation_cache: Option<calculate_root::CalculationCache>,
 }
 
+/// Extra items of [`Config`] that are dependant on the consensus engine of the chain.
+pub enum ConfigConsensus<'a> {
+    /// Chain is using the Aura consensus engine.
+    Aura {
+        /// Aura configuration retrieved from the genesis block.
+        ///
+        /// See the documentation of [`AuraGenesisConfiguration`] to know how to get this.
+        genesis_configuration: &'a AuraGenesisConfiguration,
+
+        /// Aura authorities that must validate the block. See
+        /// [`aura::VerifyConfig::current_authorities`].
+        current_authorities: header::AuraAuthoritiesIter<'a>,
+
+        /// Number of slots the Aura slot of the block is allowed to be ahead of the current
+        /// slot. See [`aura::VerifyConfig::max_slot_drift`].
+        max_slot_drift: u64,
+    },
+
+    /// Chain is using the BABE consensus engine.
+    Babe {
+        /// BABE configuration retrieved from the genesis block.
+        ///
+        /// See the documentation of [`BabeGenesisConfiguration`] to know how to get this.
+        genesis_configuration: &'a BabeGenesisConfiguration,
+
+        /// Slot number of block #1. **Must** be provided, unless the block being verified is
+        /// block #1 itself.
+        ///
+        /// Must be the slot number found in the [`SuccessConsensus::Babe`] of block #1.
+        block1_slot_number: Option<u64>,
+
+        /// Number of slots the BABE slot of the block is allowed to be ahead of the current
+        /// slot. See [`babe::VerifyConfig::max_slot_drift`].
+        max_slot_drift: u64,
+
+        /// If the block contains a BABE epoch change, the BABE randomness contributions of the
+        /// blocks of the epoch of the parent. See
+        /// [`babe::VerifyConfig::parent_epoch_randomness_contributions`].
+        parent_epoch_randomness_contributions: Option<&'a [[u8; 32]]>,
+
+        /// Indices of the BABE authorities disabled as of the parent block. See
+        /// [`babe::VerifyConfig::parent_disabled_authorities`].
+        parent_disabled_authorities: &'a [u32],
+    },
+}
+
 /// Block successfully verified.
 pub struct Success {
     /// Runtime that was passed by [`Config`].
     pub parent_runtime: executor::WasmVmPrototype,
 
-    /// If `Some`, the verified block contains an epoch transition describing the given epoch.
-    /// This epoch transition must later be provided back as part of the [`Config`] when verifying
-    /// the blocks that are part of that epoch.
-    pub babe_epoch_transition_target: Option<NonZeroU64>,
-
-    /// Slot number the block belongs to.
-    pub slot_number: u64,
-
-    /// Epoch number the block belongs to.
-    pub epoch_number: u64,
+    /// Extra items in [`Success`] relevant to the consensus engine.
+    pub consensus: SuccessConsensus,
 
     /// List of changes to the storage top trie that the block performs.
     pub storage_top_trie_changes: HashMap<Vec<u8>, Option<Vec<u8>>, fnv::FnvBuildHasher>,
@@ -93,11 +125,56 @@ pub struct Success {
     pub logs: String,
 }
 
+/// Extra items in [`Success`] relevant to the consensus engine.
+pub enum SuccessConsensus {
+    /// Chain is using the Aura consensus engine.
+    Aura {
+        /// Slot number the block belongs to.
+        slot_number: u64,
+
+        /// True if the block contains a change in the list of Aura authorities. The new list
+        /// must later be provided back as part of the [`Config`] when verifying the children of
+        /// the block.
+        authorities_change: bool,
+    },
+
+    /// Chain is using the BABE consensus engine.
+    Babe {
+        /// If `Some`, the verified block contains an epoch transition describing the given
+        /// epoch. This epoch transition must later be provided back as part of the [`Config`]
+        /// when verifying the blocks that are part of that epoch.
+        epoch_transition_target: Option<NonZeroU64>,
+
+        /// Slot number the block belongs to.
+        slot_number: u64,
+
+        /// Epoch number the block belongs to.
+        epoch_number: u64,
+
+        /// Value that the block contributes to the randomness of a future BABE epoch. See
+        /// [`babe::VerifySuccess::randomness_contribution`].
+        randomness_contribution: Option<[u8; 32]>,
+    },
+}
+
+impl From<babe::VerifySuccess> for SuccessConsensus {
+    fn from(success: babe::VerifySuccess) -> Self {
+        SuccessConsensus::Babe {
+            epoch_transition_target: success.epoch_transition_target,
+            slot_number: success.slot_number,
+            epoch_number: success.epoch_number,
+            randomness_contribution: success.randomness_contribution,
+        }
+    }
+}
+
 /// Error that can happen during the verification.
 #[derive(Debug, derive_more::Display)]
 pub enum Error {
     /// Error while verifying the unsealed block.
     Unsealed(execute_block::Error),
+    /// Failed to verify the authenticity of the block with the Aura algorithm.
+    AuraVerification(aura::VerifyError),
     /// Failed to verify the authenticity of the block with the BABE algorithm.
     BabeVerification(babe::VerifyError),
 }
@@ -106,40 +183,94 @@ pub enum Error {
 pub fn verify<'a>(
     config: Config<'a, impl ExactSizeIterator<Item = impl AsRef<[u8]> + Clone> + Clone>,
 ) -> Verify {
-    // Start the BABE verification process.
-    let babe_verification = {
-        let result = babe::start_verify_header(babe::VerifyConfig {
-            header: config.block_header.clone(),
-            parent_block_header: config.parent_block_header,
-            genesis_configuration: config.babe_genesis_configuration,
-            now_from_unix_epoch: config.now_from_unix_epoch,
-            block1_slot_number: config.block1_slot_number,
-        });
-
-        match result {
-            Ok(s) => s,
-            Err(err) => return Verify::Finished(Err(Error::BabeVerification(err))),
+    match config.consensus {
+        ConfigConsensus::Aura {
+            genesis_configuration,
+            current_authorities,
+            max_slot_drift,
+        } => {
+            let result = aura::verify_header(aura::VerifyConfig {
+                header: config.block_header.clone(),
+                parent_block_header: config.parent_block_header,
+                genesis_configuration,
+                now_from_unix_epoch: config.now_from_unix_epoch,
+                max_slot_drift,
+                current_authorities,
+            });
+
+            let aura_success = match result {
+                Ok(s) => s,
+                Err(err) => return Verify::Finished(Err(Error::AuraVerification(err))),
+            };
+
+            // Aura adds a seal at the end of the digest logs. This seal is guaranteed to be the
+            // last item. We need to remove it before we can verify the unsealed header.
+            let mut unsealed_header = config.block_header.clone();
+            let _seal_log = unsealed_header.digest.pop_aura_seal();
+            debug_assert!(_seal_log.is_some());
+
+            let import_process = execute_block::execute_block(execute_block::Config {
+                parent_runtime: config.parent_runtime,
+                block_header: unsealed_header,
+                block_body: config.block_body,
+                top_trie_root_calculation_cache: config.top_trie_root_calculation_cache,
+            });
+
+            VerifyInner::Unsealed {
+                inner: import_process,
+                consensus_success: SuccessConsensus::Aura {
+                    slot_number: aura_success.slot_number,
+                    authorities_change: aura_success.authorities_change,
+                },
+            }
+            .run()
+        }
+        ConfigConsensus::Babe {
+            genesis_configuration,
+            block1_slot_number,
+            max_slot_drift,
+            parent_epoch_randomness_contributions,
+            parent_disabled_authorities,
+        } => {
+            // Start the BABE verification process.
+            let babe_verification = {
+                let result = babe::start_verify_header(babe::VerifyConfig {
+                    header: config.block_header.clone(),
+                    parent_block_header: config.parent_block_header,
+                    genesis_configuration,
+                    now_from_unix_epoch: config.now_from_unix_epoch,
+                    max_slot_drift,
+                    block1_slot_number,
+                    parent_epoch_randomness_contributions,
+                    parent_disabled_authorities,
+                });
+
+                match result {
+                    Ok(s) => s,
+                    Err(err) => return Verify::Finished(Err(Error::BabeVerification(err))),
+                }
+            };
+
+            // BABE adds a seal at the end of the digest logs. This seal is guaranteed to be the
+            // last item. We need to remove it before we can verify the unsealed header.
+            let mut unsealed_header = config.block_header.clone();
+            let _seal_log = unsealed_header.digest.pop_babe_seal();
+            debug_assert!(_seal_log.is_some());
+
+            let import_process = execute_block::execute_block(execute_block::Config {
+                parent_runtime: config.parent_runtime,
+                block_header: unsealed_header,
+                block_body: config.block_body,
+                top_trie_root_calculation_cache: config.top_trie_root_calculation_cache,
+            });
+
+            VerifyInner::Babe {
+                babe_verification,
+                import_process,
+            }
+            .run()
         }
-    };
-
-    // BABE adds a seal at the end of the digest logs. This seal is guaranteed to be the last
-    // item. We need to remove it before we can verify the unsealed header.
-    let mut unsealed_header = config.block_header.clone();
-    let _seal_log = unsealed_header.digest.pop_babe_seal();
-    debug_assert!(_seal_log.is_some());
-
-    let import_process = execute_block::execute_block(execute_block::Config {
-        parent_runtime: config.parent_runtime,
-        block_header: unsealed_header,
-        block_body: config.block_body,
-        top_trie_root_calculation_cache: config.top_trie_root_calculation_cache,
-    });
-
-    VerifyInner::Babe {
-        babe_verification,
-        import_process,
     }
-    .run()
 }
 
 /// Current state of the verification.
@@ -168,7 +299,7 @@ enum VerifyInner {
     /// Verifying the unsealed block.
     Unsealed {
         inner: execute_block::Verify,
-        babe_success: babe::VerifySuccess,
+        consensus_success: SuccessConsensus,
     },
 }
 
@@ -183,7 +314,7 @@ impl VerifyInner {
                     babe::SuccessOrPending::Success(babe_success) => {
                         self = VerifyInner::Unsealed {
                             inner: import_process,
-                            babe_success,
+                            consensus_success: babe_success.into(),
                         };
                         continue;
                     }
@@ -197,16 +328,14 @@ impl VerifyInner {
                 VerifyInner::BabeError(err) => Verify::Finished(Err(Error::BabeVerification(err))),
                 VerifyInner::Unsealed {
                     inner,
-                    babe_success,
+                    consensus_success,
                 } => match inner {
                     execute_block::Verify::Finished(Err(err)) => {
                         Verify::Finished(Err(Error::Unsealed(err)))
                     }
                     execute_block::Verify::Finished(Ok(success)) => Verify::Finished(Ok(Success {
                         parent_runtime: success.parent_runtime,
-                        babe_epoch_transition_target: babe_success.epoch_transition_target,
-                        slot_number: babe_success.slot_number,
-                        epoch_number: babe_success.epoch_number,
+                        consensus: consensus_success,
                         storage_top_trie_changes: success.storage_top_trie_changes,
                         offchain_storage_changes: success.offchain_storage_changes,
                         top_trie_root_calculation_cache: success.top_trie_root_calculation_cache,
@@ -214,18 +343,18 @@ impl VerifyInner {
                     })),
                     execute_block::Verify::StorageGet(inner) => Verify::StorageGet(StorageGet {
                         inner,
-                        babe_success,
+                        consensus_success,
                     }),
                     execute_block::Verify::PrefixKeys(inner) => {
                         Verify::StoragePrefixKeys(StoragePrefixKeys {
                             inner,
-                            babe_success,
+                            consensus_success,
                         })
                     }
                     execute_block::Verify::NextKey(inner) => {
                         Verify::StorageNextKey(StorageNextKey {
                             inner,
-                            babe_success,
+                            consensus_success,
                         })
                     }
                 },
@@ -262,7 +391,7 @@ impl BabeEpochInformation {
         match self.inner.finish(epoch_info) {
             Ok(babe_success) => VerifyInner::Unsealed {
                 inner: self.import_process,
-                babe_success,
+                consensus_success: babe_success.into(),
             }
             .run(),
             Err(err) => VerifyInner::BabeError(err).run(),
@@ -274,7 +403,7 @@ impl BabeEpochInformation {
 #[must_use]
 pub struct StorageGet {
     inner: execute_block::StorageGet,
-    babe_success: babe::VerifySuccess,
+    consensus_success: SuccessConsensus,
 }
 
 impl StorageGet {
@@ -294,7 +423,7 @@ impl StorageGet {
     pub fn inject_value(self, value: Option<&[u8]>) -> Verify {
         VerifyInner::Unsealed {
             inner: self.inner.inject_value(value),
-            babe_success: self.babe_success,
+            consensus_success: self.consensus_success,
         }
         .run()
     }
@@ -304,7 +433,7 @@ impl StorageGet {
 #[must_use]
 pub struct StoragePrefixKeys {
     inner: execute_block::PrefixKeys,
-    babe_success: babe::VerifySuccess,
+    consensus_success: SuccessConsensus,
 }
 
 impl StoragePrefixKeys {
@@ -317,7 +446,7 @@ impl StoragePrefixKeys {
     pub fn inject_keys(self, keys: impl Iterator<Item = impl AsRef<[u8]>>) -> Verify {
         VerifyInner::Unsealed {
             inner: self.inner.inject_keys(keys),
-            babe_success: self.babe_success,
+            consensus_success: self.consensus_success,
         }
         .run()
     }
@@ -327,7 +456,7 @@ impl StoragePrefixKeys {
 #[must_use]
 pub struct StorageNextKey {
     inner: execute_block::NextKey,
-    babe_success: babe::VerifySuccess,
+    consensus_success: SuccessConsensus,
 }
 
 impl StorageNextKey {
@@ -345,7 +474,7 @@ impl StorageNextKey {
     pub fn inject_key(self, key: Option<impl AsRef<[u8]>>) -> Verify {
         VerifyInner::Unsealed {
             inner: self.inner.inject_key(key),
-            babe_success: self.babe_success,
+            consensus_success: self.consensus_success,
         }
         .run()
     }
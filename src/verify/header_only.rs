@@ -15,7 +15,11 @@
 // You should have received a copy of the GNU General Public License
 // along with this program.  If not, see <http://www.gnu.org/licenses/>.
 
-use crate::{chain::chain_information::babe::BabeGenesisConfiguration, header, verify::babe};
+use crate::{
+    chain::chain_information::{aura::AuraGenesisConfiguration, babe::BabeGenesisConfiguration},
+    header,
+    verify::{aura, babe},
+};
 
 use core::{num::NonZeroU64, time::Duration};
 
@@ -26,16 +30,8 @@ pub struct Config<'a> {
     /// The hash of this header must be the one referenced in [`Config::block_header`].
     pub parent_block_header: header::HeaderRef<'a>,
 
-    /// BABE configuration retrieved from the genesis block.
-    ///
-    /// See the documentation of [`BabeGenesisConfiguration`] to know how to get this.
-    pub babe_genesis_configuration: &'a BabeGenesisConfiguration,
-
-    /// Slot number of block #1. **Must** be provided, unless the block being verified is block
-    /// #1 itself.
-    ///
-    /// Must be the value of [`Success::slot_number`] for block #1.
-    pub block1_slot_number: Option<u64>,
+    /// Configuration items related to the consensus engine.
+    pub consensus: ConfigConsensus<'a>,
 
     /// Time elapsed since [the Unix Epoch](https://en.wikipedia.org/wiki/Unix_time) (i.e.
     /// 00:00:00 UTC on 1 January 1970), ignoring leap seconds.
@@ -48,18 +44,82 @@ pub struct Config<'a> {
     pub block_header: header::HeaderRef<'a>,
 }
 
+/// Extra items of [`Config`] that are dependant on the consensus engine of the chain.
+pub enum ConfigConsensus<'a> {
+    /// Chain is using the Aura consensus engine.
+    Aura {
+        /// Aura configuration retrieved from the genesis block.
+        ///
+        /// See the documentation of [`AuraGenesisConfiguration`] to know how to get this.
+        genesis_configuration: &'a AuraGenesisConfiguration,
+
+        /// Aura authorities that must validate the block. See
+        /// [`aura::VerifyConfig::current_authorities`].
+        current_authorities: header::AuraAuthoritiesIter<'a>,
+
+        /// Number of slots the Aura slot of the block is allowed to be ahead of the current
+        /// slot. See [`aura::VerifyConfig::max_slot_drift`].
+        max_slot_drift: u64,
+    },
+
+    /// Chain is using the BABE consensus engine.
+    Babe {
+        /// BABE configuration retrieved from the genesis block.
+        ///
+        /// See the documentation of [`BabeGenesisConfiguration`] to know how to get this.
+        genesis_configuration: &'a BabeGenesisConfiguration,
+
+        /// Slot number of block #1. **Must** be provided, unless the block being verified is
+        /// block #1 itself.
+        ///
+        /// Must be the slot number found in the [`Success::Babe`] of block #1.
+        block1_slot_number: Option<u64>,
+
+        /// Number of slots the BABE slot of the block is allowed to be ahead of the current
+        /// slot. See [`babe::VerifyConfig::max_slot_drift`].
+        max_slot_drift: u64,
+
+        /// If the block contains a BABE epoch change, the BABE randomness contributions of the
+        /// blocks of the epoch of the parent. See
+        /// [`babe::VerifyConfig::parent_epoch_randomness_contributions`].
+        parent_epoch_randomness_contributions: Option<&'a [[u8; 32]]>,
+
+        /// Indices of the BABE authorities disabled as of the parent block. See
+        /// [`babe::VerifyConfig::parent_disabled_authorities`].
+        parent_disabled_authorities: &'a [u32],
+    },
+}
+
 /// Block successfully verified.
-pub struct Success {
-    /// If `Some`, the verified block contains an epoch transition describing the given epoch.
-    /// This epoch transition must later be provided back as part of the [`Config`] when verifying
-    /// the blocks that are part of that epoch.
-    pub babe_epoch_transition_target: Option<NonZeroU64>,
+pub enum Success {
+    /// Chain is using the Aura consensus engine.
+    Aura {
+        /// Slot number the block belongs to.
+        slot_number: u64,
 
-    /// Slot number the block belongs to.
-    pub slot_number: u64,
+        /// True if the block contains a change in the list of Aura authorities. The new list
+        /// must later be provided back as part of the [`Config`] when verifying the children of
+        /// the block.
+        authorities_change: bool,
+    },
 
-    /// Epoch number the block belongs to.
-    pub epoch_number: u64,
+    /// Chain is using the BABE consensus engine.
+    Babe {
+        /// If `Some`, the verified block contains an epoch transition describing the given
+        /// epoch. This epoch transition must later be provided back as part of the [`Config`]
+        /// when verifying the blocks that are part of that epoch.
+        epoch_transition_target: Option<NonZeroU64>,
+
+        /// Slot number the block belongs to.
+        slot_number: u64,
+
+        /// Epoch number the block belongs to.
+        epoch_number: u64,
+
+        /// Value that the block contributes to the randomness of a future BABE epoch. See
+        /// [`babe::VerifySuccess::randomness_contribution`].
+        randomness_contribution: Option<[u8; 32]>,
+    },
 }
 
 /// Error that can happen during the verification.
@@ -69,6 +129,9 @@ pub enum Error {
     BadBlockNumber,
     /// Hash of the parent block doesn't match the hash in the header to verify.
     BadParentHash,
+    /// Failed to verify the authenticity of the block with the Aura algorithm.
+    #[display(fmt = "{}", _0)]
+    AuraVerification(aura::VerifyError),
     /// Failed to verify the authenticity of the block with the BABE algorithm.
     #[display(fmt = "{}", _0)]
     BabeVerification(babe::VerifyError),
@@ -97,28 +160,59 @@ pub fn verify<'a>(config: Config<'a>) -> Verify {
         return Verify::Finished(Err(Error::BadBlockNumber));
     }
 
-    // Start the BABE verification process.
-    let babe_verification = {
-        let result = babe::start_verify_header(babe::VerifyConfig {
-            header: config.block_header.clone(),
-            parent_block_header: config.parent_block_header,
-            genesis_configuration: config.babe_genesis_configuration,
-            now_from_unix_epoch: config.now_from_unix_epoch,
-            block1_slot_number: config.block1_slot_number,
-        });
-
-        match result {
-            Ok(s) => s,
-            Err(err) => return Verify::Finished(Err(Error::BabeVerification(err))),
+    match config.consensus {
+        ConfigConsensus::Aura {
+            genesis_configuration,
+            current_authorities,
+            max_slot_drift,
+        } => {
+            let result = aura::verify_header(aura::VerifyConfig {
+                header: config.block_header.clone(),
+                parent_block_header: config.parent_block_header,
+                genesis_configuration,
+                now_from_unix_epoch: config.now_from_unix_epoch,
+                max_slot_drift,
+                current_authorities,
+            });
+
+            // TODO: need to verify that there's no grandpa scheduled change header if there's already an active grandpa scheduled change
+
+            Verify::ReadyToRun(ReadyToRun {
+                inner: ReadyToRunInner::AuraFinished(result),
+            })
         }
-    };
+        ConfigConsensus::Babe {
+            genesis_configuration,
+            block1_slot_number,
+            max_slot_drift,
+            parent_epoch_randomness_contributions,
+            parent_disabled_authorities,
+        } => {
+            // Start the BABE verification process.
+            let result = babe::start_verify_header(babe::VerifyConfig {
+                header: config.block_header.clone(),
+                parent_block_header: config.parent_block_header,
+                genesis_configuration,
+                now_from_unix_epoch: config.now_from_unix_epoch,
+                max_slot_drift,
+                block1_slot_number,
+                parent_epoch_randomness_contributions,
+                parent_disabled_authorities,
+            });
 
-    // TODO: need to verify the changes trie stuff maybe?
-    // TODO: need to verify that there's no grandpa scheduled change header if there's already an active grandpa scheduled change
+            let babe_verification = match result {
+                Ok(s) => s,
+                Err(err) => return Verify::Finished(Err(Error::BabeVerification(err))),
+            };
 
-    Verify::ReadyToRun(ReadyToRun {
-        inner: ReadyToRunInner::Babe(babe_verification),
-    })
+            // TODO: need to verify the changes trie stuff maybe?
+            // TODO: need to verify that there's no grandpa scheduled change header if there's already an active grandpa scheduled change
+
+            Verify::ReadyToRun(ReadyToRun {
+                inner: ReadyToRunInner::Babe(babe_verification),
+            })
+        }
+    }
 }
 
 /// Current state of the verification.
@@ -139,6 +233,8 @@ pub struct ReadyToRun {
 }
 
 enum ReadyToRunInner {
+    /// Aura verification finished.
+    AuraFinished(Result<aura::VerifySuccess, aura::VerifyError>),
     /// Verification finished
     Finished(Result<babe::VerifySuccess, babe::VerifyError>),
     /// Verifying BABE.
@@ -157,10 +253,18 @@ impl ReadyToRun {
                     Verify::BabeEpochInformation(BabeEpochInformation { inner: pending })
                 }
             },
-            ReadyToRunInner::Finished(Ok(s)) => Verify::Finished(Ok(Success {
-                babe_epoch_transition_target: s.epoch_transition_target,
+            ReadyToRunInner::AuraFinished(Ok(s)) => Verify::Finished(Ok(Success::Aura {
+                slot_number: s.slot_number,
+                authorities_change: s.authorities_change,
+            })),
+            ReadyToRunInner::AuraFinished(Err(err)) => {
+                Verify::Finished(Err(Error::AuraVerification(err)))
+            }
+            ReadyToRunInner::Finished(Ok(s)) => Verify::Finished(Ok(Success::Babe {
+                epoch_transition_target: s.epoch_transition_target,
                 slot_number: s.slot_number,
                 epoch_number: s.epoch_number,
+                randomness_contribution: s.randomness_contribution,
             })),
             ReadyToRunInner::Finished(Err(err)) => {
                 Verify::Finished(Err(Error::BabeVerification(err)))